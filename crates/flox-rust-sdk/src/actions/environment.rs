@@ -1,16 +1,20 @@
 use std::fs;
 use std::path::PathBuf;
 
+use fs_extra;
 use log::{info, warn};
+use nix_editor;
 use runix::arguments::eval::EvaluationArgs;
 use runix::arguments::NixArgs;
 use runix::command::Build;
 use runix::installable::Installable;
 use runix::{NixBackend, Run};
+use tempfile;
 use thiserror::Error;
-use {fs_extra, nix_editor, tempfile};
 
 use crate::flox::{Flox, FloxNixApi};
+use crate::models::audit::{AuditEntry, AuditOperation};
+use crate::models::policy::PolicyDenied;
 use crate::prelude::flox_package::FloxPackage;
 use crate::utils::copy_file_without_permissions;
 use crate::utils::errors::IoError;
@@ -86,6 +90,9 @@ where
 
     #[error(transparent)]
     Build(#[from] EnvironmentBuildError<Nix>),
+
+    #[error(transparent)]
+    Denied(#[from] PolicyDenied),
 }
 
 #[derive(Error, Debug)]
@@ -162,6 +169,10 @@ impl<'flox> Environment<'flox> {
     where
         Build: Run<Nix>,
     {
+        if let Some(policy) = &self.flox.install_policy {
+            policy.check(packages)?;
+        }
+
         let original_file_contents = self.read_flox_nix().await?;
 
         let (edited, n_new) = packages.iter().try_fold(
@@ -181,6 +192,15 @@ impl<'flox> Environment<'flox> {
         if n_new > 0 {
             let built_environment = self.build(&edited).await?;
             self.write_environment(&edited, &built_environment).await?;
+
+            // environments edited in place have no generations
+            let name = self
+                .subdir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let entry = AuditEntry::new(AuditOperation::Install, &name, packages.to_vec(), None);
+            self.flox.append_audit_log(&name, &[entry]).await;
         }
 
         match n_new {
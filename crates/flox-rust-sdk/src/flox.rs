@@ -1,7 +1,9 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use derive_more::Constructor;
 use log::{debug, info, warn};
@@ -10,29 +12,72 @@ use runix::arguments::common::NixCommonArgs;
 use runix::arguments::config::NixConfigArgs;
 use runix::arguments::flake::{FlakeArgs, OverrideInput};
 use runix::arguments::{EvalArgs, NixArgs};
-use runix::command::Eval;
-use runix::command_line::{DefaultArgs, NixCommandLine};
+use runix::command::{Eval, FlakeInit};
+use runix::command_line::{DefaultArgs, NixCommandLine, ToArgs};
 use runix::installable::Installable;
-use runix::{NixBackend, RunJson};
+use runix::{NixBackend, Run, RunJson};
 use serde::Deserialize;
 use thiserror::Error;
 
 use crate::actions::environment::{Environment, EnvironmentError};
 use crate::actions::package::Package;
-use crate::environment::{self, default_nix_subprocess_env};
+use crate::environment::{self, default_nix_subprocess_env, GITHUB_TOKEN};
+use crate::models::audit::{self, AuditEntry, AuditLogError};
 use crate::models::channels::ChannelRegistry;
 pub use crate::models::environment_ref::{self, *};
-use crate::models::flake_ref::ToFlakeRef;
+use crate::models::events::{EventSink, FloxEvent, FloxWarning};
+use crate::models::flake_ref::{FlakeRefError, ToFlakeRef};
+use crate::models::flake_registry;
 pub use crate::models::flox_installable::*;
+use crate::models::flox_nix::{EnvInterpolation, FloxNixNames};
+use crate::models::policy::InstallPolicy;
+use crate::models::project::environment::{
+    self as project_environment,
+    Environment as ProjectEnvironment,
+    PruneGcRootsError,
+    PruneReport,
+};
+use crate::models::project::scaffold::{self, ScaffoldError};
+use crate::models::project::{
+    self,
+    CreateDefaultEnvError,
+    GetEnvironmentError,
+    Index,
+    InitProjectError,
+    OpenProjectError,
+    RecoverTransactionError,
+    TransactionCommitError,
+    TransactionEnterError,
+};
+use crate::models::resolver::{FloxCatalogResolver, PackageResolver, ResolvePackageError};
+use crate::models::root::git::ProjectInitGitError;
+use crate::models::root::reference::ProjectDiscoverGitError;
+use crate::models::root::transaction::{GitSandBox, ReadOnly};
 use crate::models::root::{self, Root};
 use crate::models::stability::Stability;
+use crate::models::system::System;
+use crate::models::verbosity::Verbosity;
 use crate::providers::git::GitProvider;
+use crate::utils::guard::Guard;
+use crate::utils::permissions::PermissionsPolicy;
+
+#[cfg(feature = "schema")]
+pub mod schema;
 
 static INPUT_CHARS: Lazy<Vec<char>> = Lazy::new(|| ('a'..='t').into_iter().collect());
 
+/// Resolver of [Flox::package_resolver] unless another one is configured
+static DEFAULT_PACKAGE_RESOLVER: FloxCatalogResolver = FloxCatalogResolver::new();
+
 pub const FLOX_SH: &str = env!("FLOX_SH");
 pub const FLOX_VERSION: &str = env!("FLOX_VERSION");
 
+/// Directory in [Flox::config_dir] holding the project of the default environment
+pub const DEFAULT_ENVIRONMENT_DIR: &str = "default-environment";
+
+/// Directory in [Flox::cache_dir] holding nix build logs
+pub const LOG_DIR: &str = "logs";
+
 /// The main API struct for our flox implementation
 ///
 /// A [Flox] instance serves as the context for nix invocations
@@ -43,31 +88,409 @@ pub const FLOX_VERSION: &str = env!("FLOX_VERSION");
 /// [Flox] will provide a preconfigured instance of the Nix API.
 /// By default this nix API uses the nix CLI.
 /// Preconfiguration includes environment variables and flox specific arguments.
+///
+/// Instances are configured with a [FloxBuilder].
 #[derive(Debug, Default)]
 pub struct Flox {
     /// The directory pointing to the users flox configuration
     ///
     /// TODO: set a default in the lib or CLI?
-    pub config_dir: PathBuf,
-    pub cache_dir: PathBuf,
-    pub data_dir: PathBuf,
-    pub temp_dir: PathBuf,
+    pub(crate) config_dir: PathBuf,
+    pub(crate) cache_dir: PathBuf,
+    pub(crate) data_dir: PathBuf,
+    pub(crate) temp_dir: PathBuf,
 
     /// access tokens injected in nix.conf
     ///
     /// Use [Vec] to preserve original ordering
-    pub access_tokens: Vec<(String, String)>,
-    pub netrc_file: PathBuf,
+    ///
+    /// Explicit tokens take precedence over the environment:
+    /// `GITHUB_TOKEN` is only used for `github.com`
+    /// if no token for `github.com` is configured here.
+    pub(crate) access_tokens: Vec<(String, String)>,
+    /// netrc file passed to nix for authenticating
+    /// fetches that do not support access tokens (e.g. `https` tarballs)
+    pub(crate) netrc_file: PathBuf,
+
+    pub(crate) channels: ChannelRegistry,
+
+    /// Fail instead of warning when encountering
+    /// the legacy `pkgs/default.nix` package layout
+    pub(crate) reject_legacy_layout: bool,
+
+    /// Filenames of environment definitions, `flox.nix` by default
+    pub(crate) flox_nix_names: FloxNixNames,
+
+    /// Receiver of progress events, events are not produced if unset
+    pub(crate) event_sink: Option<EventSink>,
+    /// Warnings already reported through [Flox::report_warning_once]
+    pub(crate) reported_warnings: Mutex<HashSet<FloxWarning>>,
+
+    /// Maximum number of environments built at once by
+    /// [Project::build_all](crate::models::project::Project::build_all)
+    ///
+    /// Values below 1 are treated as 1, the default,
+    /// since nix already parallelizes each build internally.
+    pub(crate) max_parallel_builds: usize,
+
+    /// Append committed install and uninstall operations to the
+    /// [audit log](crate::models::audit), see [Flox::audit_log]
+    pub(crate) audit: bool,
+
+    /// Whether variables and hooks may reference host variables on activation,
+    /// see [interpolate_env](crate::models::flox_nix::interpolate_env)
+    pub(crate) env_interpolation: EnvInterpolation,
+
+    /// Consulted before installing packages, all packages are allowed if unset
+    pub(crate) install_policy: Option<InstallPolicy>,
+
+    /// Resolves short package names for search and install,
+    /// [FloxCatalogResolver] if unset
+    pub(crate) package_resolver: Option<Arc<dyn PackageResolver>>,
+
+    /// Seconds nix waits for connections to be established, 5 if unset
+    pub(crate) nix_connect_timeout: Option<u64>,
+    /// Maximum number of parallel connections of nix, nix' default if unset
+    pub(crate) nix_http_connections: Option<u64>,
+
+    /// Keep the directories of transaction sandboxes instead of removing them,
+    /// to inspect failed transactions
+    ///
+    /// Kept sandboxes are logged and have to be removed manually.
+    pub(crate) keep_sandboxes: bool,
+
+    /// Nixpkgs that channels are evaluated against when resolving installables,
+    /// see [Flox::resolve_matches], and describing them, see [Flox::search_results]
+    ///
+    /// Overrides the `nixpkgs` input of every channel,
+    /// channels without an input of that name are evaluated unchanged
+    /// and nixpkgs imported other than through the input is not affected.
+    /// Installed environments are evaluated against the inputs locked by their project
+    /// and are not affected either.
+    pub(crate) pinned_nixpkgs: Option<ToFlakeRef>,
+
+    /// List the `devShells` of flakes without `floxEnvs` as environments,
+    /// see [ProjectEnvironment::is_compat]
+    pub(crate) compat_devshells: bool,
+
+    /// Modes of the config dir and files created by flox
+    pub(crate) permissions: PermissionsPolicy,
+
+    /// Verbosity of the SDK's logs and nix, see [Verbosity::log_directive]
+    ///
+    /// Passed to every nix invocation as `--quiet` or `-v` flags.
+    pub(crate) verbosity: Verbosity,
+
+    pub(crate) system: System,
+
+    pub(crate) uuid: uuid::Uuid,
+}
+
+/// Builder for [Flox] instances
+///
+/// Options that are not set keep the defaults of [Flox].
+#[derive(Debug, Default)]
+pub struct FloxBuilder {
+    flox: Flox,
+}
+
+impl FloxBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn config_dir(mut self, config_dir: impl Into<PathBuf>) -> Self {
+        self.flox.config_dir = config_dir.into();
+        self
+    }
+
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.flox.cache_dir = cache_dir.into();
+        self
+    }
+
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.flox.data_dir = data_dir.into();
+        self
+    }
+
+    pub fn temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.flox.temp_dir = temp_dir.into();
+        self
+    }
+
+    pub fn access_tokens(mut self, access_tokens: Vec<(String, String)>) -> Self {
+        self.flox.access_tokens = access_tokens;
+        self
+    }
+
+    pub fn netrc_file(mut self, netrc_file: impl Into<PathBuf>) -> Self {
+        self.flox.netrc_file = netrc_file.into();
+        self
+    }
+
+    pub fn channels(mut self, channels: ChannelRegistry) -> Self {
+        self.flox.channels = channels;
+        self
+    }
+
+    pub fn reject_legacy_layout(mut self, reject_legacy_layout: bool) -> Self {
+        self.flox.reject_legacy_layout = reject_legacy_layout;
+        self
+    }
+
+    pub fn flox_nix_names(mut self, flox_nix_names: FloxNixNames) -> Self {
+        self.flox.flox_nix_names = flox_nix_names;
+        self
+    }
+
+    pub fn event_sink(mut self, event_sink: impl Into<Option<EventSink>>) -> Self {
+        self.flox.event_sink = event_sink.into();
+        self
+    }
+
+    pub fn max_parallel_builds(mut self, max_parallel_builds: usize) -> Self {
+        self.flox.max_parallel_builds = max_parallel_builds;
+        self
+    }
+
+    pub fn audit(mut self, audit: bool) -> Self {
+        self.flox.audit = audit;
+        self
+    }
+
+    pub fn env_interpolation(mut self, env_interpolation: EnvInterpolation) -> Self {
+        self.flox.env_interpolation = env_interpolation;
+        self
+    }
+
+    pub fn install_policy(mut self, install_policy: impl Into<Option<InstallPolicy>>) -> Self {
+        self.flox.install_policy = install_policy.into();
+        self
+    }
+
+    pub fn package_resolver(mut self, package_resolver: impl PackageResolver + 'static) -> Self {
+        self.flox.package_resolver = Some(Arc::new(package_resolver));
+        self
+    }
+
+    pub fn nix_connect_timeout(mut self, seconds: impl Into<Option<u64>>) -> Self {
+        self.flox.nix_connect_timeout = seconds.into();
+        self
+    }
+
+    pub fn nix_http_connections(mut self, connections: impl Into<Option<u64>>) -> Self {
+        self.flox.nix_http_connections = connections.into();
+        self
+    }
+
+    pub fn keep_sandboxes(mut self, keep_sandboxes: bool) -> Self {
+        self.flox.keep_sandboxes = keep_sandboxes;
+        self
+    }
+
+    pub fn pinned_nixpkgs(mut self, pinned_nixpkgs: impl Into<Option<ToFlakeRef>>) -> Self {
+        self.flox.pinned_nixpkgs = pinned_nixpkgs.into();
+        self
+    }
+
+    pub fn compat_devshells(mut self, compat_devshells: bool) -> Self {
+        self.flox.compat_devshells = compat_devshells;
+        self
+    }
+
+    pub fn permissions(mut self, permissions: PermissionsPolicy) -> Self {
+        self.flox.permissions = permissions;
+        self
+    }
+
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.flox.verbosity = verbosity;
+        self
+    }
+
+    pub fn system(mut self, system: System) -> Self {
+        self.flox.system = system;
+        self
+    }
+
+    pub fn uuid(mut self, uuid: uuid::Uuid) -> Self {
+        self.flox.uuid = uuid;
+        self
+    }
+
+    /// Check that all directories are set and create them if necessary
+    ///
+    /// The config dir is restricted to [PermissionsPolicy::private_dir_mode].
+    pub fn build(self) -> Result<Flox, FloxBuildError> {
+        let flox = self.flox;
+        for (name, dir) in [
+            ("config_dir", &flox.config_dir),
+            ("cache_dir", &flox.cache_dir),
+            ("data_dir", &flox.data_dir),
+            ("temp_dir", &flox.temp_dir),
+        ] {
+            if dir.as_os_str().is_empty() {
+                return Err(FloxBuildError::MissingDir(name));
+            }
+            let created = if dir == &flox.config_dir {
+                flox.permissions.create_private_dir(dir)
+            } else {
+                std::fs::create_dir_all(dir)
+            };
+            created.map_err(|e| FloxBuildError::CreateDir(dir.clone(), e))?;
+        }
+        Ok(flox)
+    }
+}
+
+impl Flox {
+    pub fn config_dir(&self) -> &Path {
+        &self.config_dir
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    pub fn temp_dir(&self) -> &Path {
+        &self.temp_dir
+    }
+
+    pub fn access_tokens(&self) -> &[(String, String)] {
+        &self.access_tokens
+    }
+
+    pub fn netrc_file(&self) -> &Path {
+        &self.netrc_file
+    }
+
+    pub fn channels(&self) -> &ChannelRegistry {
+        &self.channels
+    }
+
+    /// Register channels after building, e.g. for a stability chosen per command
+    pub fn channels_mut(&mut self) -> &mut ChannelRegistry {
+        &mut self.channels
+    }
+
+    pub fn reject_legacy_layout(&self) -> bool {
+        self.reject_legacy_layout
+    }
+
+    pub fn flox_nix_names(&self) -> &FloxNixNames {
+        &self.flox_nix_names
+    }
+
+    pub fn event_sink(&self) -> Option<&EventSink> {
+        self.event_sink.as_ref()
+    }
+
+    /// Report a caveat of an otherwise successful operation
+    ///
+    /// Emitted as [FloxEvent::Warning] if an [EventSink] is attached, logged otherwise,
+    /// so that frontends can show warnings apart from the log.
+    pub fn report_warning(&self, warning: FloxWarning) {
+        match &self.event_sink {
+            Some(sink) => sink.emit(&FloxEvent::Warning(warning)),
+            None => warn!("{warning}"),
+        }
+    }
+
+    /// Like [Flox::report_warning], but only the first time `warning` is reported
+    ///
+    /// For caveats of settings that apply to every operation of a session,
+    /// e.g. each evaluation of an impure environment.
+    pub fn report_warning_once(&self, warning: FloxWarning) {
+        let first = self
+            .reported_warnings
+            .lock()
+            .expect("warnings lock is not poisoned")
+            .insert(warning.clone());
+        if first {
+            self.report_warning(warning);
+        }
+    }
+
+    pub fn max_parallel_builds(&self) -> usize {
+        self.max_parallel_builds
+    }
+
+    pub fn audit(&self) -> bool {
+        self.audit
+    }
+
+    pub fn env_interpolation(&self) -> EnvInterpolation {
+        self.env_interpolation
+    }
+
+    pub fn install_policy(&self) -> Option<&InstallPolicy> {
+        self.install_policy.as_ref()
+    }
+
+    pub fn package_resolver(&self) -> &dyn PackageResolver {
+        self.package_resolver
+            .as_deref()
+            .unwrap_or(&DEFAULT_PACKAGE_RESOLVER)
+    }
+
+    pub fn nix_connect_timeout(&self) -> Option<u64> {
+        self.nix_connect_timeout
+    }
+
+    pub fn nix_http_connections(&self) -> Option<u64> {
+        self.nix_http_connections
+    }
+
+    pub fn keep_sandboxes(&self) -> bool {
+        self.keep_sandboxes
+    }
+
+    pub fn pinned_nixpkgs(&self) -> Option<&ToFlakeRef> {
+        self.pinned_nixpkgs.as_ref()
+    }
 
-    pub channels: ChannelRegistry,
+    fn pinned_nixpkgs_url(&self) -> Result<Option<String>, FlakeRefError> {
+        self.pinned_nixpkgs
+            .as_ref()
+            .map(|nixpkgs| nixpkgs.to_url().map(String::from))
+            .transpose()
+    }
 
-    pub system: String,
+    pub fn compat_devshells(&self) -> bool {
+        self.compat_devshells
+    }
 
-    pub uuid: uuid::Uuid,
+    pub fn permissions(&self) -> &PermissionsPolicy {
+        &self.permissions
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    pub fn system(&self) -> &System {
+        &self.system
+    }
+
+    pub fn uuid(&self) -> uuid::Uuid {
+        self.uuid
+    }
 }
 
 pub trait FloxNixApi: NixBackend {
     fn new(flox: &Flox, default_nix_args: DefaultArgs) -> Self;
+
+    /// `nix <subcommand>` for operations runix has no command for,
+    /// configured with the environment, config, common and extra args of this backend
+    ///
+    /// Eval and flake args are left out, not every subcommand accepts them.
+    /// The arguments of the subcommand are appended by the caller.
+    fn command(&self, subcommand: &[&str]) -> tokio::process::Command;
 }
 
 impl FloxNixApi for NixCommandLine {
@@ -77,6 +500,19 @@ impl FloxNixApi for NixCommandLine {
             defaults: default_nix_args,
         }
     }
+
+    fn command(&self, subcommand: &[&str]) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new(self.nix_bin.as_deref().unwrap_or("nix"));
+        command
+            .envs(&self.defaults.environment)
+            .args(self.defaults.config_args.to_args())
+            .args(self.defaults.common_args.to_args())
+            .args(subcommand)
+            // before the arguments of the subcommand,
+            // which may end in arguments for another program, e.g. `--command`
+            .args(&self.defaults.extra_args);
+        command
+    }
 }
 
 /// Typed matching installable outputted by our Nix evaluation
@@ -100,8 +536,202 @@ where
     Eval(<Eval as RunJson<Nix>>::JsonError),
     #[error("Error parsing installable eval output: {0}")]
     Parse(#[from] serde_json::Error),
+    #[error(transparent)]
+    Registry(#[from] ResolveRegistryError),
+    #[error("Invalid pinned nixpkgs: {0}")]
+    PinnedNixpkgs(FlakeRefError),
+}
+
+#[derive(Error, Debug)]
+pub enum ListTemplatesError<Nix: FloxNixApi>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error("Error evaluating templates: {0}")]
+    Eval(<Eval as RunJson<Nix>>::JsonError),
+    #[error("Error parsing templates: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum SearchResultsError<Nix: FloxNixApi>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error("Error evaluating package metadata: {0}")]
+    Eval(<Eval as RunJson<Nix>>::JsonError),
+    #[error("Error parsing package metadata: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Invalid pinned nixpkgs: {0}")]
+    PinnedNixpkgs(FlakeRefError),
 }
 
+#[derive(Error, Debug)]
+pub enum SearchError<Nix: FloxNixApi>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    Resolve(ResolvePackageError),
+    #[error(transparent)]
+    Describe(SearchResultsError<Nix>),
+}
+
+#[derive(Error, Debug)]
+pub enum ResolveRegistryError {
+    #[error("Could not read flake registry {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Could not parse flake registry {0}: {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error("Could not parse channel registry: {0}")]
+    Channels(serde_json::Error),
+    #[error("'{0}' is not a known flake registry alias or channel")]
+    NotFound(String),
+}
+
+#[derive(Error, Debug)]
+pub enum DefaultEnvironmentError<Git: GitProvider, Nix: FloxNixApi>
+where
+    FlakeInit: Run<Nix>,
+    Eval: RunJson<Nix>,
+{
+    #[error("Failed to create default environment directory: {0}")]
+    CreateDir(std::io::Error),
+    #[error(transparent)]
+    DiscoverGit(ProjectDiscoverGitError<Git>),
+    #[error(transparent)]
+    InitGit(ProjectInitGitError<Git>),
+    #[error(transparent)]
+    OpenProject(OpenProjectError),
+    #[error(transparent)]
+    InitProject(InitProjectError<Nix, Git>),
+    #[error("Failed to create default environment")]
+    EnterTransaction(TransactionEnterError<Git>),
+    #[error("Failed to create default environment: {0}")]
+    CreateDefaultEnv(CreateDefaultEnvError),
+    #[error("Failed to create default environment")]
+    CommitTransaction(TransactionCommitError<Git>),
+    #[error(transparent)]
+    GetEnvironment(GetEnvironmentError<Nix>),
+}
+
+/// What [Flox::open_or_init] did to provide a project
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProjectOpening {
+    /// The directory already contained a project
+    Opened,
+    /// A project was initialized in an existing git repository
+    InitializedProject,
+    /// A git repository and a project were initialized
+    InitializedRepository,
+}
+
+#[derive(Error, Debug)]
+pub enum OpenOrInitError<Git: GitProvider, Nix: FloxNixApi>
+where
+    FlakeInit: Run<Nix>,
+{
+    #[error(transparent)]
+    DiscoverGit(ProjectDiscoverGitError<Git>),
+    #[error(transparent)]
+    InitGit(ProjectInitGitError<Git>),
+    #[error(transparent)]
+    OpenProject(OpenProjectError),
+    #[error(transparent)]
+    InitProject(InitProjectError<Nix, Git>),
+}
+
+#[derive(Error, Debug)]
+pub enum FloxBuildError {
+    #[error("{0} is not set")]
+    MissingDir(&'static str),
+    #[error("Failed to create {0:?}: {1}")]
+    CreateDir(PathBuf, std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum GcLogsError {
+    #[error("Could not read log directory {0:?}: {1}")]
+    ReadDir(PathBuf, std::io::Error),
+    #[error("Could not remove log file {0:?}: {1}")]
+    Remove(PathBuf, std::io::Error),
+}
+
+/// A template of the flox channel, see [Flox::list_templates]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// A package found by a search, see [Flox::search_results]
+#[derive(Debug)]
+pub struct SearchResult {
+    pub package: ResolvedInstallableMatch,
+    /// `meta.description`, empty if the package does not declare one
+    pub description: String,
+    pub version: Option<String>,
+    pub homepage: Option<String>,
+    /// SPDX identifier or name of the (first) license
+    pub license: Option<String>,
+}
+
+/// Page of matches returned by [Flox::search]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SearchOptions {
+    /// Maximum number of results, all remaining matches if [None]
+    pub limit: Option<usize>,
+    /// Number of matches to skip
+    pub offset: usize,
+}
+
+/// Result of [Flox::search]
+#[derive(Debug)]
+pub struct SearchPage {
+    /// The requested page of matches
+    pub results: Vec<SearchResult>,
+    /// Number of matches across all pages
+    pub total_matches: usize,
+}
+
+/// Metadata of a package as evaluated by [PACKAGE_META_APPLY]
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+struct PackageMeta {
+    #[serde(default)]
+    description: String,
+    version: Option<String>,
+    homepage: Option<String>,
+    license: Option<String>,
+}
+
+/// Nix function reading the [PackageMeta] of the package at an attribute path of a flake
+///
+/// Packages without `meta` or only some of its attributes are described by the defaults,
+/// as are packages whose metadata fails to evaluate (by `throw` or `assert`),
+/// so that they do not fail the evaluation of the other packages.
+const PACKAGE_META_APPLY: &str = r#"outputs: path:
+    let
+      describe = package:
+        let
+          meta = package.meta or { };
+          first = value: if builtins.isList value
+            then (if value == [ ] then null else builtins.head value)
+            else value;
+          license = first (meta.license or null);
+        in {
+          description = meta.description or "";
+          version = package.version or meta.version or null;
+          homepage = first (meta.homepage or null);
+          license = if license == null || builtins.isString license
+            then license
+            else license.spdxId or license.fullName or null;
+        };
+      described = builtins.tryEval (
+        let meta = describe (builtins.foldl' (set: attr: set.${attr}) outputs path);
+        in builtins.deepSeq meta meta
+      );
+    in if described.success then described.value else { }"#;
+
 /// Typed output of our Nix evaluation to find matching installables
 type InstallableEvalQueryOut = BTreeSet<InstallableEvalQueryEntry>;
 
@@ -116,6 +746,14 @@ pub struct ResolvedInstallableMatch {
 }
 
 impl ResolvedInstallableMatch {
+    /// Attribute path of the match in its flake
+    fn attr_path(&self) -> Vec<&str> {
+        let mut attr_path = vec![self.prefix.as_str()];
+        attr_path.extend(self.system.as_deref());
+        attr_path.extend(self.key.iter().map(String::as_str));
+        attr_path
+    }
+
     pub fn installable(self) -> Installable {
         // Build the multi-part key into a Nix-safe single string
         let nix_str_key = self
@@ -167,6 +805,440 @@ impl Flox {
         Environment::new(self, dir)
     }
 
+    /// The global environment used when no project is present
+    ///
+    /// The environment lives in a flox managed project in [Flox::config_dir],
+    /// which is initialized on first use.
+    pub async fn default_environment<Git: GitProvider, Nix: FloxNixApi>(
+        &self,
+    ) -> Result<ProjectEnvironment<Git, ReadOnly<Git>>, DefaultEnvironmentError<Git, Nix>>
+    where
+        FlakeInit: Run<Nix>,
+        Eval: RunJson<Nix>,
+    {
+        let dir = self.config_dir.join(DEFAULT_ENVIRONMENT_DIR);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(DefaultEnvironmentError::CreateDir)?;
+        let dir = tokio::fs::canonicalize(&dir)
+            .await
+            .map_err(DefaultEnvironmentError::CreateDir)?;
+
+        let git = self
+            .resource(dir.clone())
+            .guard::<Git>()
+            .await
+            .map_err(DefaultEnvironmentError::DiscoverGit)?;
+        // discovery finds repositories above `dir`, e.g. a config dir kept in git,
+        // but the default environment lives in a repository of its own
+        let git = match git {
+            Guard::Initialized(root) if root.workdir() != Some(dir.as_path()) => {
+                Guard::Uninitialized(Root::closed(self, dir))
+            },
+            git => git,
+        };
+
+        let project = git
+            .init_git()
+            .await
+            .map_err(DefaultEnvironmentError::InitGit)?
+            .guard()
+            .await
+            .map_err(DefaultEnvironmentError::OpenProject)?
+            .init_project::<Nix>(Vec::new())
+            .await
+            .map_err(DefaultEnvironmentError::InitProject)?;
+
+        match project.environment::<Nix>("default").await {
+            Ok(environment) => return Ok(environment),
+            Err(GetEnvironmentError::NotFound(_)) => {},
+            Err(e) => return Err(DefaultEnvironmentError::GetEnvironment(e)),
+        }
+
+        let (project, mut index) = project
+            .enter_transaction()
+            .await
+            .map_err(DefaultEnvironmentError::EnterTransaction)?;
+        project
+            .create_default_env(&mut index)
+            .await
+            .map_err(DefaultEnvironmentError::CreateDefaultEnv)?;
+        let project = project
+            .commit_transaction(index, "Create default environment", false)
+            .await
+            .map_err(DefaultEnvironmentError::CommitTransaction)?
+            .committed()
+            .expect("not a dry run");
+
+        project
+            .environment::<Nix>("default")
+            .await
+            .map_err(DefaultEnvironmentError::GetEnvironment)
+    }
+
+    /// Open the project at `path`, initializing it first if necessary
+    ///
+    /// Initializes a git repository if `path` is not within one
+    /// and a project from the default template if the repository has no `flake.nix`.
+    /// The returned [ProjectOpening] tells which of these steps were taken.
+    pub async fn open_or_init<Git: GitProvider, Nix: FloxNixApi>(
+        &self,
+        path: &Path,
+    ) -> Result<(project::Project<Git, ReadOnly<Git>>, ProjectOpening), OpenOrInitError<Git, Nix>>
+    where
+        FlakeInit: Run<Nix>,
+    {
+        let git = self
+            .resource(path.to_path_buf())
+            .guard::<Git>()
+            .await
+            .map_err(OpenOrInitError::DiscoverGit)?;
+        let init_git = git.is_uninitialized();
+
+        let project = git
+            .init_git()
+            .await
+            .map_err(OpenOrInitError::InitGit)?
+            .guard()
+            .await
+            .map_err(OpenOrInitError::OpenProject)?;
+        let init_project = project.is_uninitialized();
+
+        let project = project
+            .init_project::<Nix>(Vec::new())
+            .await
+            .map_err(OpenOrInitError::InitProject)?;
+
+        let opening = if init_git {
+            ProjectOpening::InitializedRepository
+        } else if init_project {
+            ProjectOpening::InitializedProject
+        } else {
+            ProjectOpening::Opened
+        };
+        Ok((project, opening))
+    }
+
+    /// Sandboxes of transactions left behind, see [project::pending_transactions]
+    pub async fn pending_transactions(&self) -> Result<Vec<PathBuf>, std::io::Error> {
+        project::pending_transactions(self).await
+    }
+
+    /// Recover a project transaction left behind by an interrupted process
+    ///
+    /// See [project::Project::recover_transaction]
+    pub async fn recover_transaction<Git: GitProvider>(
+        &self,
+        sandbox_path: &Path,
+    ) -> Result<(project::Project<Git, GitSandBox<Git>>, Index), RecoverTransactionError<Git>> {
+        project::Project::recover_transaction(self, sandbox_path).await
+    }
+
+    /// Materialize the package `template` in `dir` without any git operations
+    ///
+    /// `dir` is created if needed and does not have to be part of a git repository,
+    /// created files are left for the caller to stage, e.g. after a later `git init`.
+    /// Returns the created files relative to `dir`.
+    ///
+    /// See [project::Project::init_flox_package] for the same within a project.
+    pub async fn scaffold<Nix: FloxNixApi>(
+        &self,
+        dir: &Path,
+        template: Installable,
+        name: &str,
+    ) -> Result<Vec<PathBuf>, ScaffoldError<Nix>>
+    where
+        FlakeInit: Run<Nix>,
+    {
+        scaffold::scaffold(self, dir, template, name).await
+    }
+
+    /// Path for a new log file in [LOG_DIR], named by the current time
+    ///
+    /// The file itself is not created.
+    pub fn new_log_file(&self) -> PathBuf {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.cache_dir
+            .join(LOG_DIR)
+            .join(format!("{timestamp}.log"))
+    }
+
+    /// Remove all but the `keep` most recent log files in [LOG_DIR]
+    ///
+    /// Returns the removed files.
+    pub async fn gc_logs(&self, keep: usize) -> Result<Vec<PathBuf>, GcLogsError> {
+        let log_dir = self.cache_dir.join(LOG_DIR);
+
+        let mut entries = match tokio::fs::read_dir(&log_dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(GcLogsError::ReadDir(log_dir, err)),
+        };
+
+        let mut logs = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|err| GcLogsError::ReadDir(log_dir.clone(), err))?
+        {
+            logs.push(entry.path());
+        }
+
+        // timestamps of the same width sort chronologically
+        logs.sort_by_key(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            (name.len(), name.into_owned())
+        });
+
+        logs.truncate(logs.len().saturating_sub(keep));
+        for log in &logs {
+            tokio::fs::remove_file(log)
+                .await
+                .map_err(|err| GcLogsError::Remove(log.clone(), err))?;
+        }
+
+        Ok(logs)
+    }
+
+    /// Read the entries of the audit log, oldest first
+    ///
+    /// Entries are only added while [Flox::audit] is set.
+    pub async fn audit_log(&self) -> Result<Vec<AuditEntry>, AuditLogError> {
+        audit::read(&self.config_dir).await
+    }
+
+    /// Append `entries` for changes to `environment` to the audit log, if [Flox::audit] is set
+    ///
+    /// The audited changes are applied already,
+    /// so failing to write the log is reported as a warning rather than an error.
+    pub(crate) async fn append_audit_log(&self, environment: &str, entries: &[AuditEntry]) {
+        if !self.audit || entries.is_empty() {
+            return;
+        }
+        if let Err(e) = audit::append(&self.config_dir, entries, &self.permissions).await {
+            self.report_warning(FloxWarning::AuditNotWritten {
+                environment: environment.to_string(),
+                error: e.to_string(),
+            });
+        }
+    }
+
+    /// Remove gc roots of environments that no longer exist
+    ///
+    /// Built environments are kept alive by gc roots in [Flox::cache_dir].
+    /// Once removed, `nix-collect-garbage` can reclaim their store paths.
+    /// See [project_environment::GC_ROOTS_DIR].
+    pub async fn prune_gc_roots(&self) -> Result<PruneReport, PruneGcRootsError> {
+        project_environment::prune_gc_roots(&self.cache_dir).await
+    }
+
+    /// Resolve a flake registry alias such as `nixpkgs` to the flakeref it points to
+    ///
+    /// Registries are consulted in the order nix applies them:
+    /// the user registry, the system registry and finally flox' channels,
+    /// which flox uses as nix' global registry.
+    pub async fn resolve_registry(&self, alias: &str) -> Result<ToFlakeRef, ResolveRegistryError> {
+        let user_registry = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .map(|config| config.join("nix/registry.json"));
+        let system_registry = PathBuf::from("/etc/nix/registry.json");
+
+        for path in user_registry.into_iter().chain([system_registry]) {
+            let contents = match tokio::fs::read_to_string(&path).await {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(ResolveRegistryError::Read(path, err)),
+            };
+
+            if let Some(flake_ref) = flake_registry::lookup(&contents, alias)
+                .map_err(|err| ResolveRegistryError::Parse(path.clone(), err))?
+            {
+                debug!("Resolved {alias} using {path:?}");
+                return Ok(flake_ref);
+            }
+        }
+
+        let channels =
+            serde_json::to_string(&self.channels).map_err(ResolveRegistryError::Channels)?;
+        flake_registry::lookup(&channels, alias)
+            .map_err(ResolveRegistryError::Channels)?
+            .ok_or_else(|| ResolveRegistryError::NotFound(alias.to_string()))
+    }
+
+    /// List the templates of the flox channel, usable with
+    /// [Project::init_flox_package](crate::models::project::Project::init_flox_package)
+    ///
+    /// A channel without a `templates` output provides no templates.
+    pub async fn list_templates<Nix: FloxNixApi>(
+        &self,
+    ) -> Result<Vec<TemplateInfo>, ListTemplatesError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        // an empty attribute path refers to the flake outputs as a whole
+        let installable = Installable::new("flake:flox".to_string(), ".".to_string());
+        self.fetch_flake::<Nix>(&installable.flakeref).await;
+        let apply = r#"outputs: builtins.mapAttrs
+            (_: template: template.description or null)
+            (outputs.templates or { })"#;
+
+        let eval = Eval {
+            eval_args: EvalArgs {
+                installable: Some(installable.into()),
+                apply: Some(apply.to_string().into()),
+            },
+            ..Default::default()
+        };
+
+        let json_out = eval
+            .run_json(&self.nix::<Nix>(vec![]), &NixArgs::default())
+            .await
+            .map_err(ListTemplatesError::Eval)?;
+        let templates: BTreeMap<String, Option<String>> = serde_json::from_value(json_out)?;
+
+        Ok(templates
+            .into_iter()
+            .map(|(name, description)| TemplateInfo { name, description })
+            .collect())
+    }
+
+    /// Resolve a short package name, e.g. `hello`, with the [PackageResolver]
+    pub async fn resolve_package(
+        &self,
+        name: &str,
+        stability: &Stability,
+    ) -> Result<Vec<ResolvedInstallableMatch>, ResolvePackageError> {
+        self.package_resolver().resolve(self, name, stability).await
+    }
+
+    /// Search the packages whose attribute path contains `term`, ignoring case
+    ///
+    /// All packages of the [PackageResolver] are resolved by name only,
+    /// metadata is evaluated just for the page selected by `options`.
+    pub async fn search<Nix: FloxNixApi>(
+        &self,
+        term: &str,
+        stability: &Stability,
+        options: SearchOptions,
+    ) -> Result<SearchPage, SearchError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        // an empty name matches all packages
+        let matches = self
+            .resolve_package("", stability)
+            .await
+            .map_err(SearchError::Resolve)?;
+
+        let term = term.to_lowercase();
+        let matches: Vec<ResolvedInstallableMatch> = matches
+            .into_iter()
+            .filter(|package| package.key.join(".").to_lowercase().contains(&term))
+            .collect();
+        let total_matches = matches.len();
+
+        let page = matches
+            .into_iter()
+            .skip(options.offset)
+            .take(options.limit.unwrap_or(usize::MAX))
+            .collect();
+        let results = self
+            .search_results::<Nix>(page)
+            .await
+            .map_err(SearchError::Describe)?;
+
+        Ok(SearchPage {
+            results,
+            total_matches,
+        })
+    }
+
+    /// Describe `matches`, e.g. of a search, by the metadata of the packages
+    ///
+    /// The packages of each flake are evaluated at once,
+    /// against [Flox::pinned_nixpkgs] if set.
+    /// Results are in the order of `matches`.
+    pub async fn search_results<Nix: FloxNixApi>(
+        &self,
+        matches: Vec<ResolvedInstallableMatch>,
+    ) -> Result<Vec<SearchResult>, SearchResultsError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let mut by_flakeref: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, package) in matches.iter().enumerate() {
+            by_flakeref.entry(&package.flakeref).or_default().push(i);
+        }
+
+        let pinned_nixpkgs = self
+            .pinned_nixpkgs_url()
+            .map_err(SearchResultsError::PinnedNixpkgs)?;
+
+        let mut metas: Vec<PackageMeta> = matches.iter().map(|_| Default::default()).collect();
+        for (flakeref, indices) in by_flakeref {
+            let attr_paths = indices
+                .iter()
+                .map(|i| {
+                    let attr_path = matches[*i]
+                        .attr_path()
+                        .iter()
+                        .map(|attr| format!("{attr:?}"))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    format!("[ {attr_path} ]")
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            // an empty attribute path refers to the flake outputs as a whole
+            let installable = Installable::new(flakeref.to_string(), ".".to_string());
+            self.fetch_flake::<Nix>(&installable.flakeref).await;
+            let apply = format!("outputs: map (({PACKAGE_META_APPLY}) outputs) [ {attr_paths} ]");
+            let eval = Eval {
+                flake: FlakeArgs {
+                    no_write_lock_file: true.into(),
+                    override_inputs: pinned_nixpkgs
+                        .iter()
+                        .map(|nixpkgs| OverrideInput {
+                            from: "nixpkgs".to_string(),
+                            to: nixpkgs.clone(),
+                        })
+                        .collect(),
+                },
+                eval_args: EvalArgs {
+                    installable: Some(installable.into()),
+                    apply: Some(apply.into()),
+                },
+                ..Default::default()
+            };
+
+            let json_out = eval
+                .run_json(&self.nix::<Nix>(vec![]), &NixArgs::default())
+                .await
+                .map_err(SearchResultsError::Eval)?;
+            let evaluated: Vec<PackageMeta> = serde_json::from_value(json_out)?;
+            for (i, meta) in indices.into_iter().zip(evaluated) {
+                metas[i] = meta;
+            }
+        }
+
+        Ok(matches
+            .into_iter()
+            .zip(metas)
+            .map(|(package, meta)| SearchResult {
+                package,
+                description: meta.description,
+                version: meta.version,
+                homepage: meta.homepage,
+                license: meta.license,
+            })
+            .collect())
+    }
+
     /// Invoke Nix to convert a FloxInstallable into a list of matches
     pub async fn resolve_matches<Nix: FloxNixApi, Git: GitProvider>(
         &self,
@@ -214,6 +1286,18 @@ Could not parse flake_ref {flake_ref}
             })
             .collect::<Vec<_>>();
 
+        // Fail early with a clear error for unknown registry aliases,
+        // rather than with whatever the eval reports
+        for flox_installable in flox_installables {
+            if let Some(alias) = flox_installable
+                .source
+                .as_deref()
+                .and_then(flake_registry::registry_alias)
+            {
+                self.resolve_registry(alias).await?;
+            }
+        }
+
         // Optimize for installable resolutions that do not require an eval
         // Match against exactly 1 flakeref and 1 prefix
         let mut optimized = vec![];
@@ -340,6 +1424,10 @@ Could not parse flake_ref {flake_ref}
         // Construct the `apply` argument for the nix eval call to find what installables match
         let eval_apply = format!(r#"(x: ({}))"#, installable_resolve_strs.join(" ++ "));
 
+        let pinned_nixpkgs = self
+            .pinned_nixpkgs_url()
+            .map_err(ResolveFloxInstallableError::PinnedNixpkgs)?;
+
         // The super resolver we're currently using to evaluate multiple whole flakerefs at once
         let resolve_installable: Installable =
             format!("path://{}#resolve", env!("FLOX_RESOLVER_SRC")).into();
@@ -348,13 +1436,7 @@ Could not parse flake_ref {flake_ref}
             flake: FlakeArgs {
                 no_write_lock_file: true.into(),
                 // Use the flakeref map from earlier as input overrides so all the inputs point to the correct flakerefs
-                override_inputs: flakeref_inputs
-                    .iter()
-                    .map(|(c, flakeref)| OverrideInput {
-                        from: c.to_string(),
-                        to: flakeref.to_string(),
-                    })
-                    .collect(),
+                override_inputs: resolver_overrides(&flakeref_inputs, pinned_nixpkgs.as_deref()),
             },
             // Use the super resolver as the installable (which we use as this only takes one)
             eval_args: EvalArgs {
@@ -417,7 +1499,7 @@ Could not parse flake_ref {flake_ref}
                 let temp_registry_file = self.temp_dir.join("registry.json");
 
                 std::fs::File::options()
-                    .mode(0o600)
+                    .mode(self.permissions.private_file_mode)
                     .create_new(true)
                     .write(true)
                     .open(&temp_registry_file)
@@ -446,17 +1528,28 @@ Could not parse flake_ref {flake_ref}
                 .map(String::from)
                 .to_vec()
                 .into(),
-                extra_access_tokens: self.access_tokens.clone().into(),
+                extra_access_tokens: with_ambient_github_token(
+                    self.access_tokens.clone(),
+                    std::env::var(GITHUB_TOKEN).ok(),
+                )
+                .into(),
                 flake_registry: Some(global_registry_file.into()),
                 netrc_file: Some(self.netrc_file.clone().into()),
                 connect_timeout: 5.into(),
                 ..Default::default()
             };
 
-            let nix_config = format!(
+            let mut nix_config = format!(
                 "# Automatically generated - do not edit.\n{}\n",
                 config.to_config_string()
             );
+            // later settings take precedence, overriding the defaults above
+            if let Some(timeout) = self.nix_connect_timeout {
+                nix_config.push_str(&format!("connect-timeout = {timeout}\n"));
+            }
+            if let Some(connections) = self.nix_http_connections {
+                nix_config.push_str(&format!("http-connections = {connections}\n"));
+            }
 
             // Write nix.conf file if it does not exist or has changed
             let global_config_file_path = self.config_dir.join("nix.conf");
@@ -472,7 +1565,7 @@ Could not parse flake_ref {flake_ref}
                 let temp_config_file_path = self.temp_dir.join("nix.conf");
 
                 std::fs::File::options()
-                    .mode(0o600)
+                    .mode(self.permissions.private_file_mode)
                     .create_new(true)
                     .write(true)
                     .open(&temp_config_file_path)
@@ -497,13 +1590,529 @@ Could not parse flake_ref {flake_ref}
             ..Default::default()
         };
 
+        let mut args = self.verbosity.nix_args();
+        args.extend(extra_args);
+
         let default_nix_args = DefaultArgs {
             environment,
             common_args,
-            extra_args,
+            extra_args: args,
             ..Default::default()
         };
 
         Nix::new(self, default_nix_args)
     }
 }
+
+/// Input overrides of the resolver flake
+///
+/// Points the inputs to the flakerefs they stand for
+/// and, if `pinned_nixpkgs` is set, the `nixpkgs` input of each of them to `pinned_nixpkgs`.
+fn resolver_overrides(
+    flakeref_inputs: &HashMap<char, String>,
+    pinned_nixpkgs: Option<&str>,
+) -> Vec<OverrideInput> {
+    let mut overrides: Vec<OverrideInput> = flakeref_inputs
+        .iter()
+        .map(|(c, flakeref)| OverrideInput {
+            from: c.to_string(),
+            to: flakeref.to_string(),
+        })
+        .collect();
+
+    if let Some(nixpkgs) = pinned_nixpkgs {
+        overrides.extend(flakeref_inputs.keys().map(|c| OverrideInput {
+            from: format!("{c}/nixpkgs"),
+            to: nixpkgs.to_string(),
+        }));
+    }
+    overrides
+}
+
+/// Add the token from `GITHUB_TOKEN` for `github.com`,
+/// unless a token for `github.com` was configured explicitly
+fn with_ambient_github_token(
+    mut access_tokens: Vec<(String, String)>,
+    ambient: Option<String>,
+) -> Vec<(String, String)> {
+    match ambient {
+        Some(token)
+            if !token.is_empty() && !access_tokens.iter().any(|(host, _)| host == "github.com") =>
+        {
+            access_tokens.push(("github.com".to_string(), token))
+        },
+        _ => {},
+    }
+    access_tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::providers::git::GitCommandProvider;
+
+    #[test]
+    fn explicit_github_token_takes_precedence() {
+        let explicit = vec![("github.com".to_string(), "explicit".to_string())];
+        assert_eq!(
+            with_ambient_github_token(explicit.clone(), Some("ambient".to_string())),
+            explicit
+        );
+
+        let other = vec![("gitlab.com".to_string(), "other".to_string())];
+        assert_eq!(
+            with_ambient_github_token(other, Some("ambient".to_string())),
+            vec![
+                ("gitlab.com".to_string(), "other".to_string()),
+                ("github.com".to_string(), "ambient".to_string())
+            ]
+        );
+
+        assert!(with_ambient_github_token(vec![], Some(String::new())).is_empty());
+    }
+
+    #[test]
+    fn builder_creates_directories() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = FloxBuilder::new()
+            .config_dir(tempdir.path().join("config"))
+            .cache_dir(tempdir.path().join("cache"))
+            .data_dir(tempdir.path().join("data"))
+            .temp_dir(tempdir.path().join("temp"))
+            .keep_sandboxes(true)
+            .build()
+            .unwrap();
+
+        assert!(flox.config_dir().is_dir());
+        assert!(flox.cache_dir().is_dir());
+        assert!(flox.data_dir().is_dir());
+        assert!(flox.temp_dir().is_dir());
+        assert!(flox.keep_sandboxes());
+        let mode = std::fs::metadata(flox.config_dir())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let missing = FloxBuilder::new()
+            .config_dir(tempdir.path().join("config"))
+            .build()
+            .unwrap_err();
+        assert!(matches!(missing, FloxBuildError::MissingDir("cache_dir")));
+    }
+
+    #[test]
+    fn warnings_are_emitted_to_the_sink() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink_warnings = warnings.clone();
+        let flox = Flox {
+            event_sink: Some(EventSink::new(move |event| {
+                if let FloxEvent::Warning(warning) = event {
+                    sink_warnings.lock().unwrap().push(warning.clone())
+                }
+            })),
+            ..Default::default()
+        };
+
+        flox.report_warning(FloxWarning::LegacyLayout);
+        assert_eq!(*warnings.lock().unwrap(), vec![FloxWarning::LegacyLayout]);
+    }
+
+    #[test]
+    fn resolver_overrides_pin_nixpkgs() {
+        let inputs = HashMap::from([('a', "github:flox/floxpkgs".to_string())]);
+        let overrides = |pinned: Option<&str>| {
+            resolver_overrides(&inputs, pinned)
+                .into_iter()
+                .map(|OverrideInput { from, to }| (from, to))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(overrides(None), vec![(
+            "a".to_string(),
+            "github:flox/floxpkgs".to_string()
+        )]);
+
+        let pinned = "github:NixOS/nixpkgs/nixos-23.05";
+        assert_eq!(overrides(Some(pinned)), vec![
+            ("a".to_string(), "github:flox/floxpkgs".to_string()),
+            ("a/nixpkgs".to_string(), pinned.to_string())
+        ]);
+    }
+
+    #[tokio::test]
+    async fn open_or_init_opens_existing_project() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        GitCommandProvider::init(&project_dir, false).await.unwrap();
+        std::fs::write(project_dir.join("flake.nix"), "{}").unwrap();
+
+        let (_project, opening) = flox
+            .open_or_init::<GitCommandProvider, NixCommandLine>(&project_dir)
+            .await
+            .unwrap();
+        assert_eq!(opening, ProjectOpening::Opened);
+        assert_eq!(
+            std::fs::read_to_string(project_dir.join("flake.nix")).unwrap(),
+            "{}"
+        );
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn default_environment_uses_own_repository() {
+        let tempdir = tempfile::tempdir().unwrap();
+        // a config dir tracked in git must not host the default environment
+        GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().join("temp"),
+            config_dir: tempdir.path().join("config"),
+            ..Default::default()
+        };
+        std::fs::create_dir(&flox.temp_dir).unwrap();
+
+        let environment = flox
+            .default_environment::<GitCommandProvider, NixCommandLine>()
+            .await
+            .unwrap();
+        let definition = environment.definition_path().await.unwrap();
+        let dir = std::fs::canonicalize(flox.config_dir.join(DEFAULT_ENVIRONMENT_DIR)).unwrap();
+        assert!(definition.starts_with(&dir));
+        assert!(dir.join(".git").exists());
+
+        // later uses open the existing environment
+        std::fs::write(&definition, "{ packages.nixpkgs-flox.hello = {}; }").unwrap();
+        let environment = flox
+            .default_environment::<GitCommandProvider, NixCommandLine>()
+            .await
+            .unwrap();
+        assert_eq!(environment.definition_path().await.unwrap(), definition);
+        assert_eq!(
+            std::fs::read_to_string(&definition).unwrap(),
+            "{ packages.nixpkgs-flox.hello = {}; }"
+        );
+    }
+
+    #[tokio::test]
+    async fn gc_logs_keeps_most_recent() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().to_path_buf(),
+            ..Default::default()
+        };
+        assert!(flox.gc_logs(1).await.unwrap().is_empty());
+
+        let log_dir = flox.cache_dir.join(LOG_DIR);
+        std::fs::create_dir_all(&log_dir).unwrap();
+        for timestamp in ["999", "1000", "1001"] {
+            std::fs::write(log_dir.join(format!("{timestamp}.log")), "").unwrap();
+        }
+
+        assert_eq!(flox.gc_logs(1).await.unwrap(), vec![
+            log_dir.join("999.log"),
+            log_dir.join("1000.log")
+        ]);
+        assert!(log_dir.join("1001.log").exists());
+    }
+
+    #[test]
+    fn access_tokens_are_passed_to_nix() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            config_dir: tempdir.path().join("config"),
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().join("temp"),
+            access_tokens: vec![("github.com".to_string(), "secret-token".to_string())],
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&flox.config_dir).unwrap();
+        std::fs::create_dir_all(&flox.temp_dir).unwrap();
+
+        let nix: NixCommandLine = flox.nix(Default::default());
+
+        let nix_conf = std::fs::read_to_string(flox.config_dir.join("nix.conf")).unwrap();
+        assert!(nix_conf.contains("github.com=secret-token"));
+        assert_eq!(
+            nix.defaults.environment.get("NIX_USER_CONF_FILES"),
+            Some(
+                &flox
+                    .config_dir
+                    .join("nix.conf")
+                    .to_string_lossy()
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn verbosity_is_passed_to_nix() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut flox = Flox {
+            config_dir: tempdir.path().join("config"),
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().join("temp"),
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&flox.config_dir).unwrap();
+        std::fs::create_dir_all(&flox.temp_dir).unwrap();
+
+        let nix: NixCommandLine = flox.nix(vec!["--offline".to_string()]);
+        assert_eq!(nix.defaults.extra_args, ["--quiet", "--offline"]);
+
+        flox.verbosity = Verbosity::Verbose(2);
+        let nix: NixCommandLine = flox.nix(Default::default());
+        assert_eq!(nix.defaults.extra_args, ["-v", "-v"]);
+
+        let command = nix.command(&["path-info"]);
+        let args: Vec<_> = command.as_std().get_args().collect();
+        let subcommand = args.iter().position(|arg| *arg == "path-info").unwrap();
+        assert_eq!(args[subcommand + 1..], ["-v", "-v"]);
+        assert!(command
+            .as_std()
+            .get_envs()
+            .any(|(name, _)| name == "NIX_USER_CONF_FILES"));
+    }
+
+    #[test]
+    fn package_meta_defaults_to_empty_description() {
+        let meta: PackageMeta = serde_json::from_str(r#"{ "version": "2.12.1" }"#).unwrap();
+        assert_eq!(meta, PackageMeta {
+            version: Some("2.12.1".to_string()),
+            ..Default::default()
+        });
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn search_results_describe_packages() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            config_dir: tempdir.path().join("config"),
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().join("temp"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&flox.config_dir).unwrap();
+        std::fs::create_dir_all(&flox.temp_dir).unwrap();
+
+        let flake_dir = tempdir.path().join("flake");
+        std::fs::create_dir_all(&flake_dir).unwrap();
+        std::fs::write(
+            flake_dir.join("flake.nix"),
+            r#"{
+              outputs = _: {
+                packages.aarch64-darwin.hello = {
+                  version = "2.12.1";
+                  meta = {
+                    description = "A program that produces a familiar, friendly greeting";
+                    homepage = [ "https://www.gnu.org/software/hello/manual/" ];
+                    license = [ { spdxId = "GPL-3.0-or-later"; } ];
+                  };
+                };
+                packages.aarch64-darwin.bare = { };
+                packages.aarch64-darwin.broken = {
+                  version = "1.0";
+                  meta.description = throw "broken";
+                };
+              };
+            }"#,
+        )
+        .unwrap();
+
+        let flakeref = format!("path:{}", flake_dir.display());
+        let package = |name: &str| {
+            ResolvedInstallableMatch::new(
+                flakeref.clone(),
+                "packages".to_string(),
+                Some("aarch64-darwin".to_string()),
+                false,
+                vec![name.to_string()],
+                None,
+            )
+        };
+
+        let results = flox
+            .search_results::<NixCommandLine>(vec![
+                package("hello"),
+                package("bare"),
+                package("broken"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0].description,
+            "A program that produces a familiar, friendly greeting"
+        );
+        assert_eq!(results[0].version.as_deref(), Some("2.12.1"));
+        assert_eq!(
+            results[0].homepage.as_deref(),
+            Some("https://www.gnu.org/software/hello/manual/")
+        );
+        assert_eq!(results[0].license.as_deref(), Some("GPL-3.0-or-later"));
+
+        assert_eq!(results[1].package.key, ["bare"]);
+        assert_eq!(results[1].description, "");
+        assert_eq!(results[1].version, None);
+        assert_eq!(results[1].license, None);
+
+        assert_eq!(results[2].package.key, ["broken"]);
+        assert_eq!(results[2].description, "");
+        assert_eq!(results[2].version, None);
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn search_results_use_pinned_nixpkgs() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let write_flake = |name: &str, flake: String| {
+            let dir = tempdir.path().join(name);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("flake.nix"), flake).unwrap();
+            format!("path:{}", dir.display())
+        };
+        let locked = write_flake(
+            "locked",
+            r#"{ outputs = _: { version = "locked"; }; }"#.into(),
+        );
+        let pinned = write_flake(
+            "pinned",
+            r#"{ outputs = _: { version = "pinned"; }; }"#.into(),
+        );
+        let channel = write_flake(
+            "channel",
+            format!(
+                r#"{{
+                  inputs.nixpkgs.url = "{locked}";
+                  outputs = {{ nixpkgs, ... }}: {{
+                    packages.aarch64-darwin.hello = {{ inherit (nixpkgs) version; }};
+                  }};
+                }}"#
+            ),
+        );
+
+        let flox = Flox {
+            config_dir: tempdir.path().join("config"),
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().join("temp"),
+            pinned_nixpkgs: Some(ToFlakeRef::from_str(&pinned).unwrap()),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&flox.config_dir).unwrap();
+        std::fs::create_dir_all(&flox.temp_dir).unwrap();
+
+        let package = ResolvedInstallableMatch::new(
+            channel,
+            "packages".to_string(),
+            Some("aarch64-darwin".to_string()),
+            false,
+            vec!["hello".to_string()],
+            None,
+        );
+        let results = flox
+            .search_results::<NixCommandLine>(vec![package])
+            .await
+            .unwrap();
+        assert_eq!(results[0].version.as_deref(), Some("pinned"));
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn search_describes_only_requested_page() {
+        /// Resolves names in the packages of a single flake
+        #[derive(Debug)]
+        struct FlakeResolver(String);
+
+        #[async_trait::async_trait(?Send)]
+        impl PackageResolver for FlakeResolver {
+            async fn resolve(
+                &self,
+                flox: &Flox,
+                name: &str,
+                _stability: &Stability,
+            ) -> Result<Vec<ResolvedInstallableMatch>, ResolvePackageError> {
+                let installable = FloxInstallable {
+                    source: None,
+                    attr_path: if name.is_empty() {
+                        vec![]
+                    } else {
+                        vec![name.to_string()]
+                    },
+                };
+                Ok(flox
+                    .resolve_matches::<NixCommandLine, GitCommandProvider>(
+                        &[installable],
+                        &[&self.0],
+                        &[("packages", true)],
+                        true,
+                        None,
+                    )
+                    .await?)
+            }
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+
+        // metadata of packages p00 to p24 fails to evaluate outside of p10 to p19
+        let flake_dir = tempdir.path().join("flake");
+        std::fs::create_dir_all(&flake_dir).unwrap();
+        std::fs::write(
+            flake_dir.join("flake.nix"),
+            r#"{
+              outputs = _: {
+                packages.aarch64-darwin = builtins.listToAttrs (builtins.genList (i: rec {
+                  name = "p${if i < 10 then "0" else ""}${toString i}";
+                  value.meta = if i >= 10 && i < 20
+                    then { description = name; }
+                    else throw "evaluated metadata of ${name}";
+                }) 25);
+              };
+            }"#,
+        )
+        .unwrap();
+        let git = GitCommandProvider::init(&flake_dir, false).await.unwrap();
+        git.add(&[Path::new(".")]).await.unwrap();
+        git.commit("add packages").await.unwrap();
+        let flakeref = format!("git+file://{}", flake_dir.display());
+
+        let flox = Flox {
+            system: System::Aarch64Darwin,
+            config_dir: tempdir.path().join("config"),
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().join("temp"),
+            package_resolver: Some(Arc::new(FlakeResolver(flakeref))),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&flox.config_dir).unwrap();
+        std::fs::create_dir_all(&flox.temp_dir).unwrap();
+
+        let page = flox
+            .search::<NixCommandLine>("p", &Stability::default(), SearchOptions {
+                limit: Some(10),
+                offset: 10,
+            })
+            .await
+            .unwrap();
+        assert_eq!(page.total_matches, 25);
+        assert_eq!(
+            page.results
+                .iter()
+                .map(|result| result.description.as_str())
+                .collect::<Vec<_>>(),
+            (10..20).map(|i| format!("p{i}")).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! JSON schema of the flox.nix attributes understood by flox
+//!
+//! Editors can use it to complete and validate flox.nix files.
+//! The schema is derived from the types flox reads the attributes as in
+//! [project::environment](crate::models::project::environment).
+
+use std::collections::BTreeMap;
+
+use schemars::gen::SchemaSettings;
+use schemars::JsonSchema;
+use serde_json::Value;
+
+use crate::models::project::environment::{NixConfigValue, PackageDecl, ServiceDecl};
+
+/// URI of the JSON schema dialect used by [flox_nix_schema]
+pub const SCHEMA_DIALECT: &str = "http://json-schema.org/draft-07/schema#";
+
+/// Declaration of a flox environment
+///
+/// Only describes the schema, flox.nix is read attribute by attribute.
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[schemars(title = "flox.nix")]
+struct FloxNixDecl {
+    /// flox.nix files merged into this environment, relative to this file
+    imports: Option<Vec<String>>,
+    /// Packages installed into the environment by channel and name
+    packages: Option<BTreeMap<String, BTreeMap<String, PackageDecl>>>,
+    /// Environment variables set on activation
+    ///
+    /// Values may reference `${packages.<channel>.<name>}` and `$VAR`.
+    vars: Option<BTreeMap<String, String>>,
+    /// Deprecated alias of `vars`
+    #[deprecated]
+    environment_variables: Option<BTreeMap<String, String>>,
+    hook: Option<HookDecl>,
+    shell: Option<ShellDecl>,
+    /// Long-lived processes run alongside the environment
+    services: Option<BTreeMap<String, ServiceDecl>>,
+    /// nix.conf settings applied when building the environment
+    nix_config: Option<BTreeMap<String, NixConfigValue>>,
+    /// Evaluate the environment with `--impure`
+    #[serde(default)]
+    impure: bool,
+}
+
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct HookDecl {
+    /// Script run by the shell on activation
+    on_activate: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+struct ShellDecl {
+    /// Script run by the shell on activation
+    hook: Option<String>,
+}
+
+/// JSON schema describing a flox.nix
+pub fn flox_nix_schema() -> Value {
+    let settings = SchemaSettings::draft07().with(|settings| {
+        settings.meta_schema = Some(SCHEMA_DIALECT.to_string());
+        settings.option_add_null_type = false;
+    });
+    let schema = settings
+        .into_generator()
+        .into_root_schema_for::<FloxNixDecl>();
+    serde_json::to_value(schema).expect("schemas serialize to JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn describes_supported_attributes() {
+        let schema = flox_nix_schema();
+        assert_eq!(schema["$schema"], json!(SCHEMA_DIALECT));
+        assert_eq!(schema["title"], json!("flox.nix"));
+        assert_eq!(schema.get("required"), None);
+
+        let properties = &schema["properties"];
+        assert_eq!(properties["imports"]["items"], json!({ "type": "string" }));
+        assert_eq!(
+            properties["environmentVariables"]["additionalProperties"],
+            json!({ "type": "string" })
+        );
+        assert_eq!(
+            properties["environmentVariables"]["deprecated"],
+            json!(true)
+        );
+        assert_eq!(properties["impure"]["type"], json!("boolean"));
+        assert_eq!(properties["impure"]["default"], json!(false));
+        assert_eq!(
+            properties["hook"],
+            json!({ "$ref": "#/definitions/HookDecl" })
+        );
+        assert_eq!(
+            schema["definitions"]["HookDecl"]["properties"]["onActivate"]["type"],
+            json!("string")
+        );
+        assert_eq!(
+            properties["shell"],
+            json!({ "$ref": "#/definitions/ShellDecl" })
+        );
+        assert_eq!(
+            schema["definitions"]["ShellDecl"]["properties"]["hook"]["type"],
+            json!("string")
+        );
+    }
+
+    /// Attributes read through the model types of the environment follow them
+    #[test]
+    fn follows_environment_models() {
+        let schema = flox_nix_schema();
+        let definitions = &schema["definitions"];
+
+        assert_eq!(
+            schema["properties"]["services"]["additionalProperties"],
+            json!({ "$ref": "#/definitions/ServiceDecl" })
+        );
+        assert_eq!(definitions["ServiceDecl"]["required"], json!(["command"]));
+
+        assert_eq!(
+            schema["properties"]["packages"]["additionalProperties"]["additionalProperties"],
+            json!({ "$ref": "#/definitions/PackageDecl" })
+        );
+        assert_eq!(
+            definitions["PackageDecl"]["properties"]["version"]["type"],
+            json!("string")
+        );
+        assert_eq!(definitions["PackageDecl"].get("required"), None);
+
+        assert_eq!(
+            schema["properties"]["nixConfig"]["additionalProperties"],
+            json!({ "$ref": "#/definitions/NixConfigValue" })
+        );
+        let types = definitions["NixConfigValue"]["anyOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|variant| variant["type"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(types, [
+            json!("string"),
+            json!("boolean"),
+            json!("number"),
+            json!("array")
+        ]);
+        assert_eq!(
+            definitions["NixConfigValue"]["anyOf"][3]["items"],
+            json!({ "$ref": "#/definitions/NixConfigValue" })
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Audit trail of changes to environments
+//!
+//! If [Flox::audit](crate::flox::Flox::audit) is set, committed install, uninstall and upgrade
+//! operations are appended as JSON lines to [AUDIT_LOG] in the config dir.
+//! Unlike the `log` facade, entries are structured and meant to be kept,
+//! e.g. for compliance.
+//! As the operations are committed already, failing to write an entry does not fail them,
+//! but is reported as a [FloxWarning](crate::models::events::FloxWarning).
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+use super::flox_package::FloxPackage;
+use crate::utils::permissions::PermissionsPolicy;
+
+/// Name of the audit log in [Flox::config_dir](crate::flox::Flox::config_dir)
+pub const AUDIT_LOG: &str = "audit.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOperation {
+    Install,
+    Uninstall,
+    Upgrade,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub operation: AuditOperation,
+    pub environment: String,
+    pub packages: Vec<FloxPackage>,
+    /// Generation of the environment after committing the operation
+    ///
+    /// Unknown if the operation is not committed by itself,
+    /// e.g. with a [squashed](crate::models::root::transaction::CommitStrategy) transaction,
+    /// or for environments that are not versioned.
+    pub generation: Option<usize>,
+    /// Login name of the user performing the operation
+    pub user: String,
+}
+
+impl AuditEntry {
+    /// Create an entry for an operation performed now by the current user
+    pub(crate) fn new(
+        operation: AuditOperation,
+        environment: &str,
+        packages: Vec<FloxPackage>,
+        generation: Option<usize>,
+    ) -> Self {
+        AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default(),
+            operation,
+            environment: environment.to_string(),
+            packages,
+            generation,
+            user: std::env::var("USER").unwrap_or_else(|_| "unknown".to_string()),
+        }
+    }
+}
+
+/// Append `entries` to the audit log in `config_dir`
+///
+/// The log is restricted to [PermissionsPolicy::private_file_mode].
+pub(crate) async fn append(
+    config_dir: &Path,
+    entries: &[AuditEntry],
+    permissions: &PermissionsPolicy,
+) -> Result<(), AuditLogError> {
+    let path = config_dir.join(AUDIT_LOG);
+
+    let mut lines = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut lines, entry).expect("should serialize audit entry");
+        lines.push(b'\n');
+    }
+
+    tokio::fs::create_dir_all(config_dir)
+        .await
+        .map_err(|e| AuditLogError::Write(path.clone(), e))?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(permissions.private_file_mode)
+        .open(&path)
+        .await
+        .map_err(|e| AuditLogError::Write(path.clone(), e))?;
+    // logs created before the policy was applied may be readable by others
+    permissions
+        .restrict_private_file(&path)
+        .await
+        .map_err(|e| AuditLogError::Write(path.clone(), e))?;
+    file.write_all(&lines)
+        .await
+        .map_err(|e| AuditLogError::Write(path, e))
+}
+
+/// Read all entries of the audit log in `config_dir`, oldest first
+pub(crate) async fn read(config_dir: &Path) -> Result<Vec<AuditEntry>, AuditLogError> {
+    let path = config_dir.join(AUDIT_LOG);
+    let contents = match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(AuditLogError::Read(path, e)),
+    };
+
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| AuditLogError::Parse(path.clone(), e)))
+        .collect()
+}
+
+#[derive(Error, Debug)]
+pub enum AuditLogError {
+    #[error("Could not write audit log {0:?}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("Could not read audit log {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Invalid entry in audit log {0:?}: {1}")]
+    Parse(PathBuf, serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn appends_entries() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let config_dir = tempdir.path().join("config");
+
+        assert!(read(&config_dir).await.unwrap().is_empty());
+
+        let install = AuditEntry::new(
+            AuditOperation::Install,
+            "default",
+            vec!["nixpkgs-flox.hello".to_string()],
+            Some(1),
+        );
+        let uninstall = AuditEntry::new(
+            AuditOperation::Uninstall,
+            "default",
+            vec!["nixpkgs-flox.hello".to_string()],
+            None,
+        );
+        let permissions = PermissionsPolicy::default();
+        append(&config_dir, std::slice::from_ref(&install), &permissions)
+            .await
+            .unwrap();
+        append(&config_dir, std::slice::from_ref(&uninstall), &permissions)
+            .await
+            .unwrap();
+
+        assert_eq!(read(&config_dir).await.unwrap(), [install, uninstall]);
+
+        let contents = std::fs::read_to_string(config_dir.join(AUDIT_LOG)).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn audit_log_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join(AUDIT_LOG);
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let entry = AuditEntry::new(AuditOperation::Install, "default", vec![], Some(1));
+        append(tempdir.path(), &[entry], &PermissionsPolicy::default())
+            .await
+            .unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -17,7 +17,7 @@ impl<'flox, Git: GitProvider> CommonEnvironment<'flox, Git> {
     pub async fn installable(&self) -> runix::installable::Installable {
         match self {
             CommonEnvironment::Named(n) => n.installable(Default::default()).await.unwrap(),
-            CommonEnvironment::Project(p) => p.installable(),
+            CommonEnvironment::Project(p) => p.installable().await.unwrap(),
         }
     }
 
@@ -3,6 +3,7 @@ use std::path::{Path, PathBuf};
 
 use log::debug;
 use runix::command::Eval;
+use runix::command_line::NixCommandLine;
 use runix::installable::Installable;
 use runix::RunJson;
 use thiserror::Error;
@@ -373,7 +374,7 @@ impl EnvironmentRef<'_> {
             EnvironmentRef::Project(project_ref) => Ok(project_ref.installable.clone()),
             EnvironmentRef::Named(named_ref) => {
                 let gen = named_ref.get_current_gen(flox).await?;
-                Ok(named_ref.get_installable(flox, &flox.system, &gen))
+                Ok(named_ref.get_installable(flox, flox.system.as_str(), &gen))
             },
         }
     }
@@ -425,7 +426,7 @@ impl EnvironmentRef<'_> {
                     .open()
                     .ok()?;
 
-                project.environment(name).await.ok()
+                project.environment::<NixCommandLine>(name).await.ok()
             },
             EnvironmentRef::Named(_) => None,
         }
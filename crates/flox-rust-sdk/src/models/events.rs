@@ -0,0 +1,413 @@
+//! Progress events of long running operations
+//!
+//! Frontends attach an [EventSink] to [Flox](crate::flox::Flox) to render progress.
+//! Without a sink, operations skip any work that is only needed to produce events.
+//!
+//! Caveats of operations that succeed nonetheless are reported as [FloxWarning]s,
+//! through the sink if one is attached, otherwise they are logged.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use super::project::lock::FLOX_LOCK;
+use super::verbosity::Verbosity;
+
+/// Nix activity type of a download (`actFileTransfer`)
+const ACTIVITY_FILE_TRANSFER: u64 = 101;
+/// Nix activity type of copying paths between stores (`actCopyPaths`)
+const ACTIVITY_COPY_PATHS: u64 = 103;
+/// Nix activity type of waiting for a build slot (`actBuildWaiting`)
+const ACTIVITY_BUILD_WAITING: u64 = 111;
+/// Nix result type reporting the progress of an activity (`resProgress`)
+const RESULT_PROGRESS: u64 = 105;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FloxEvent {
+    /// Progress of copying a project into a transaction sandbox
+    CopyProgress {
+        /// files and directories copied so far
+        copied: u64,
+        /// files and directories to copy in total
+        total: u64,
+        /// bytes of file contents copied so far
+        bytes: u64,
+    },
+    /// Progress of a download by nix, e.g. of a flake input
+    DownloadProgress {
+        url: String,
+        /// bytes downloaded so far
+        downloaded: u64,
+        /// expected size in bytes, 0 if unknown
+        total: u64,
+    },
+    /// Progress of copying store paths to or from another store with `nix copy`
+    StoreCopyProgress {
+        /// bytes copied so far
+        copied: u64,
+        /// bytes to copy in total
+        total: u64,
+    },
+    Warning(FloxWarning),
+}
+
+/// Caveat of an operation that succeeded,
+/// see [Flox::report_warning](crate::flox::Flox::report_warning)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FloxWarning {
+    /// A template uses the deprecated `pkgs/default.nix` package layout
+    LegacyLayout,
+    /// An environment is evaluated with `--impure`
+    ImpureEval { environment: String },
+    /// A nix setting of an environment that nix only applies for trusted users
+    UntrustedNixSetting {
+        environment: String,
+        setting: String,
+    },
+    /// The lock of an environment is ignored, as its flox.nix changed since it was pinned
+    StaleLock { environment: String },
+    /// Changes stashed for a committed transaction could not be restored
+    StashNotRestored { stash: String, error: String },
+    /// An environment references a host variable that is not set, it resolves to an empty string
+    UndefinedVariable {
+        environment: String,
+        variable: String,
+    },
+    /// Committed changes to an environment could not be added to the audit log
+    AuditNotWritten { environment: String, error: String },
+}
+
+impl fmt::Display for FloxWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FloxWarning::LegacyLayout => write!(
+                f,
+                "The template uses the deprecated 'pkgs/default.nix' layout, \
+                 which will stop being supported. \
+                 Templates should provide 'pkgs/<name>/default.nix' instead."
+            ),
+            FloxWarning::ImpureEval { environment } => write!(
+                f,
+                "Evaluating environment {environment} impurely, its build may not be reproducible"
+            ),
+            FloxWarning::UntrustedNixSetting {
+                environment,
+                setting,
+            } => write!(
+                f,
+                "nixConfig.{setting} of environment {environment} is ignored by nix unless you are a trusted user"
+            ),
+            FloxWarning::StaleLock { environment } => write!(
+                f,
+                "Environment {environment} changed since it was pinned, ignoring {FLOX_LOCK}"
+            ),
+            FloxWarning::StashNotRestored { stash, error } => write!(
+                f,
+                "Could not restore your uncommitted changes, they are kept as stash {stash}: {error}"
+            ),
+            FloxWarning::UndefinedVariable {
+                environment,
+                variable,
+            } => write!(
+                f,
+                "Environment {environment} references the unset variable {variable}, \
+                 it is left empty"
+            ),
+            FloxWarning::AuditNotWritten { environment, error } => write!(
+                f,
+                "Could not add the changes to environment {environment} to the audit log: {error}"
+            ),
+        }
+    }
+}
+
+/// Receiver of [FloxEvent]s
+///
+/// Clones share the same callback.
+#[derive(Clone)]
+pub struct EventSink(Arc<dyn Fn(&FloxEvent) + Send + Sync>);
+
+impl EventSink {
+    pub fn new(callback: impl Fn(&FloxEvent) + Send + Sync + 'static) -> Self {
+        EventSink(Arc::new(callback))
+    }
+
+    pub fn emit(&self, event: &FloxEvent) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventSink")
+    }
+}
+
+/// What nix prints for `line` of its `--log-format internal-json` output
+///
+/// nix' structured log is only requested to report progress,
+/// so that logs show messages and activities like without it.
+/// Activities are only shown up to the level of `verbosity`, as nix would,
+/// lines that nix would not print yield [None].
+/// Unstructured lines are returned as they are.
+pub(crate) fn render_nix_log_line(line: &str, verbosity: Verbosity) -> Option<String> {
+    #[derive(Deserialize)]
+    #[serde(tag = "action", rename_all = "camelCase")]
+    enum LogEvent {
+        Msg {
+            msg: String,
+        },
+        Start {
+            level: u64,
+            #[serde(rename = "type")]
+            activity_type: u64,
+            #[serde(default)]
+            text: String,
+        },
+        #[serde(other)]
+        Other,
+    }
+
+    let json = match line.strip_prefix("@nix ") {
+        Some(json) => json,
+        None => return Some(line.to_string()),
+    };
+    match serde_json::from_str(json).ok()? {
+        LogEvent::Msg { msg } => Some(msg),
+        LogEvent::Start {
+            level,
+            activity_type,
+            text,
+        } if level <= verbosity.nix_level()
+            && !text.is_empty()
+            && activity_type != ACTIVITY_BUILD_WAITING =>
+        {
+            Some(format!("{text}..."))
+        },
+        _ => None,
+    }
+}
+
+/// Tracks downloads in nix' `--log-format internal-json` output
+#[derive(Debug, Default)]
+pub(crate) struct NixDownloads {
+    /// URLs of running downloads by activity id
+    urls: HashMap<u64, String>,
+}
+
+impl NixDownloads {
+    /// Record a line of nix' structured log,
+    /// returning a [FloxEvent::DownloadProgress] if it reports progress of a download
+    pub(crate) fn record_log_line(&mut self, line: &str) -> Option<FloxEvent> {
+        #[derive(Deserialize)]
+        #[serde(tag = "action", rename_all = "camelCase")]
+        enum LogEvent {
+            Start {
+                id: u64,
+                #[serde(rename = "type")]
+                activity_type: u64,
+                #[serde(default)]
+                text: String,
+                #[serde(default)]
+                fields: Vec<serde_json::Value>,
+            },
+            Result {
+                id: u64,
+                #[serde(rename = "type")]
+                result_type: u64,
+                #[serde(default)]
+                fields: Vec<serde_json::Value>,
+            },
+            Stop {
+                id: u64,
+            },
+            #[serde(other)]
+            Other,
+        }
+
+        let event = line
+            .strip_prefix("@nix ")
+            .and_then(|json| serde_json::from_str(json).ok())?;
+
+        match event {
+            LogEvent::Start {
+                id,
+                activity_type: ACTIVITY_FILE_TRANSFER,
+                text,
+                fields,
+            } => {
+                let url = fields
+                    .first()
+                    .and_then(|url| url.as_str())
+                    .map_or(text, String::from);
+                self.urls.insert(id, url);
+                None
+            },
+            LogEvent::Result {
+                id,
+                result_type: RESULT_PROGRESS,
+                fields,
+            } => {
+                let url = self.urls.get(&id)?;
+                let field = |i: usize| fields.get(i).and_then(|n| n.as_u64()).unwrap_or_default();
+                Some(FloxEvent::DownloadProgress {
+                    url: url.clone(),
+                    downloaded: field(0),
+                    total: field(1),
+                })
+            },
+            LogEvent::Stop { id } => {
+                self.urls.remove(&id);
+                None
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Tracks copies between stores in nix' `--log-format internal-json` output
+#[derive(Debug, Default)]
+pub(crate) struct NixStoreCopies {
+    /// Ids of running `actCopyPaths` activities
+    ids: HashSet<u64>,
+}
+
+impl NixStoreCopies {
+    /// Record a line of nix' structured log,
+    /// returning a [FloxEvent::StoreCopyProgress] if it reports progress of a copy
+    pub(crate) fn record_log_line(&mut self, line: &str) -> Option<FloxEvent> {
+        #[derive(Deserialize)]
+        #[serde(tag = "action", rename_all = "camelCase")]
+        enum LogEvent {
+            Start {
+                id: u64,
+                #[serde(rename = "type")]
+                activity_type: u64,
+            },
+            Result {
+                id: u64,
+                #[serde(rename = "type")]
+                result_type: u64,
+                #[serde(default)]
+                fields: Vec<serde_json::Value>,
+            },
+            Stop {
+                id: u64,
+            },
+            #[serde(other)]
+            Other,
+        }
+
+        let event = line
+            .strip_prefix("@nix ")
+            .and_then(|json| serde_json::from_str(json).ok())?;
+
+        match event {
+            LogEvent::Start {
+                id,
+                activity_type: ACTIVITY_COPY_PATHS,
+            } => {
+                self.ids.insert(id);
+                None
+            },
+            LogEvent::Result {
+                id,
+                result_type: RESULT_PROGRESS,
+                fields,
+            } if self.ids.contains(&id) => {
+                let field = |i: usize| fields.get(i).and_then(|n| n.as_u64()).unwrap_or_default();
+                Some(FloxEvent::StoreCopyProgress {
+                    copied: field(0),
+                    total: field(1),
+                })
+            },
+            LogEvent::Stop { id } => {
+                self.ids.remove(&id);
+                None
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_nix_downloads() {
+        let mut downloads = NixDownloads::default();
+        let lines = [
+            r#"@nix {"action":"start","id":1,"level":4,"parent":0,"text":"downloading 'https://example.com/src.tar.gz'","type":101,"fields":["https://example.com/src.tar.gz"]}"#,
+            r#"@nix {"action":"start","id":2,"level":3,"parent":0,"text":"building","type":105}"#,
+            r#"@nix {"action":"result","id":2,"type":105,"fields":[1,2,0,0]}"#,
+            r#"@nix {"action":"result","id":1,"type":105,"fields":[1024,4096,0,0]}"#,
+            r#"@nix {"action":"stop","id":1}"#,
+            r#"@nix {"action":"result","id":1,"type":105,"fields":[4096,4096,0,0]}"#,
+            "plain log line",
+        ];
+
+        let events: Vec<_> = lines
+            .iter()
+            .filter_map(|line| downloads.record_log_line(line))
+            .collect();
+        assert_eq!(events, [FloxEvent::DownloadProgress {
+            url: "https://example.com/src.tar.gz".to_string(),
+            downloaded: 1024,
+            total: 4096,
+        }]);
+    }
+
+    #[test]
+    fn tracks_nix_store_copies() {
+        let mut copies = NixStoreCopies::default();
+        let lines = [
+            r#"@nix {"action":"start","id":1,"level":4,"parent":0,"text":"copying 2 paths","type":103,"fields":[]}"#,
+            r#"@nix {"action":"start","id":2,"level":4,"parent":1,"text":"copying path","type":100,"fields":[]}"#,
+            r#"@nix {"action":"result","id":2,"type":105,"fields":[10,20,0,0]}"#,
+            r#"@nix {"action":"result","id":1,"type":105,"fields":[512,2048,1,0]}"#,
+            r#"@nix {"action":"stop","id":1}"#,
+            r#"@nix {"action":"result","id":1,"type":105,"fields":[2048,2048,0,0]}"#,
+        ];
+
+        let events: Vec<_> = lines
+            .iter()
+            .filter_map(|line| copies.record_log_line(line))
+            .collect();
+        assert_eq!(events, [FloxEvent::StoreCopyProgress {
+            copied: 512,
+            total: 2048,
+        }]);
+    }
+
+    #[test]
+    fn renders_nix_log_lines() {
+        let render = |line| render_nix_log_line(line, Verbosity::default());
+        assert_eq!(
+            render(r#"@nix {"action":"msg","level":0,"msg":"error: HTTP error 403"}"#).as_deref(),
+            Some("error: HTTP error 403")
+        );
+        let build = r#"@nix {"action":"start","id":1,"level":3,"parent":0,"text":"building '/nix/store/x.drv'","type":105,"fields":[]}"#;
+        assert_eq!(
+            render(build).as_deref(),
+            Some("building '/nix/store/x.drv'...")
+        );
+        assert_eq!(render("plain").as_deref(), Some("plain"));
+
+        // not printed by nix
+        let download = r#"@nix {"action":"start","id":2,"level":4,"parent":0,"text":"downloading 'https://example.com/src.tar.gz'","type":101,"fields":[]}"#;
+        assert_eq!(render(download), None);
+        let waiting =
+            r#"@nix {"action":"start","id":3,"level":3,"parent":0,"text":"waiting","type":111}"#;
+        assert_eq!(render(waiting), None);
+        assert_eq!(
+            render(r#"@nix {"action":"result","id":1,"type":105,"fields":[1,2,0,0]}"#),
+            None
+        );
+        assert_eq!(render(r#"@nix {"action":"start","id":4,"type":103}"#), None);
+
+        assert!(render_nix_log_line(download, Verbosity::Verbose(1)).is_some());
+    }
+}
@@ -0,0 +1,120 @@
+//! Fetching flakes ahead of nix commands that would fetch them
+//!
+//! Commands run through a [NixBackend](runix::NixBackend), e.g. `nix flake init`
+//! or evaluations, do not expose the log of nix, so their downloads cannot be reported.
+//! Fetching the flake and its inputs with `nix flake archive` beforehand reports them,
+//! the command then finds the sources in the store.
+
+use std::process::Stdio;
+
+use log::debug;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use super::events::{render_nix_log_line, NixDownloads};
+use crate::flox::{Flox, FloxNixApi};
+
+impl Flox {
+    /// Fetch `flakeref` and its inputs, reporting downloads to [Flox::event_sink]
+    ///
+    /// Does nothing without a sink.
+    /// Failures are only logged, the command fetching the flake afterwards reports them.
+    pub(crate) async fn fetch_flake<Nix: FloxNixApi>(&self, flakeref: &str) {
+        let sink = match &self.event_sink {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        // make sure nix is configured like for any other flox invocation
+        let nix: Nix = self.nix(Default::default());
+
+        let mut command = nix.command(&["flake", "archive"]);
+        command
+            .args(["--log-format", "internal-json", "--json"])
+            .arg(flakeref)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                debug!("Could not fetch {flakeref}: {e}");
+                return;
+            },
+        };
+
+        let mut downloads = NixDownloads::default();
+        if let Some(stderr) = child.stderr.take() {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if let Some(event) = downloads.record_log_line(&line) {
+                    sink.emit(&event);
+                }
+                if let Some(message) = render_nix_log_line(&line, self.verbosity) {
+                    debug!("{message}");
+                }
+            }
+        }
+
+        match child.wait().await {
+            Ok(status) if status.success() => {},
+            Ok(status) => debug!("Could not fetch {flakeref}: nix exited with {status}"),
+            Err(e) => debug!("Could not fetch {flakeref}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use runix::NixBackend;
+
+    use super::*;
+    use crate::models::events::{EventSink, FloxEvent};
+
+    /// Nix backend printing the log of a download
+    #[derive(Debug)]
+    struct DownloadingNix;
+
+    impl NixBackend for DownloadingNix {}
+
+    impl FloxNixApi for DownloadingNix {
+        fn new(_: &Flox, _: runix::default::DefaultArgs) -> Self {
+            DownloadingNix
+        }
+
+        fn command(&self, _: &[&str]) -> tokio::process::Command {
+            let log = [
+                r#"@nix {"action":"start","id":1,"level":4,"parent":0,"text":"downloading 'https://example.com/src.tar.gz'","type":101,"fields":["https://example.com/src.tar.gz"]}"#,
+                r#"@nix {"action":"result","id":1,"type":105,"fields":[1024,4096,0,0]}"#,
+                r#"@nix {"action":"stop","id":1}"#,
+            ];
+            let mut command = tokio::process::Command::new("sh");
+            command
+                .args(["-c", r#"printf '%s\n' "$@" >&2"#, "sh"])
+                .args(log);
+            command
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_downloads_of_fetched_flakes() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink_events = events.clone();
+        let flox = Flox {
+            event_sink: Some(EventSink::new(move |event| {
+                sink_events.lock().unwrap().push(event.clone())
+            })),
+            ..Default::default()
+        };
+
+        flox.fetch_flake::<DownloadingNix>("github:flox/floxpkgs")
+            .await;
+
+        assert_eq!(*events.lock().unwrap(), [FloxEvent::DownloadProgress {
+            url: "https://example.com/src.tar.gz".to_string(),
+            downloaded: 1024,
+            total: 4096,
+        }]);
+    }
+}
@@ -0,0 +1,114 @@
+//! Static lookup of flake registry aliases
+//!
+//! Nix resolves indirect flake references such as `nixpkgs` in `nixpkgs#hello`
+//! through its flake registries.
+//! The functions here read the same registry files,
+//! so that aliases can be validated without invoking nix.
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+
+use super::flake_ref::ToFlakeRef;
+
+/// Matches indirect flake references: `<id>`, `flake:<id>` or `<id>/<ref>`
+static INDIRECT_FLAKEREF_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"^(?:flake:)?([a-zA-Z][a-zA-Z0-9_-]*)(?:/[^:]*)?$"#).unwrap());
+
+#[derive(Deserialize)]
+struct RegistryFile {
+    #[serde(default)]
+    flakes: Vec<RegistryEntry>,
+}
+
+#[derive(Deserialize)]
+struct RegistryEntry {
+    from: RegistryFrom,
+    to: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct RegistryFrom {
+    #[serde(rename = "type")]
+    kind: String,
+    id: Option<String>,
+}
+
+/// Extract the registry alias from a flake reference,
+/// if it is an indirect reference
+pub fn registry_alias(flakeref: &str) -> Option<&str> {
+    INDIRECT_FLAKEREF_RE
+        .captures(flakeref)
+        .and_then(|captures| captures.get(1))
+        .map(|alias| alias.as_str())
+}
+
+/// Look up `alias` in the contents of a registry file
+///
+/// Returns [None] if the registry does not define the alias.
+pub fn lookup(registry: &str, alias: &str) -> Result<Option<ToFlakeRef>, serde_json::Error> {
+    let registry: RegistryFile = serde_json::from_str(registry)?;
+
+    registry
+        .flakes
+        .into_iter()
+        .find(|entry| entry.from.kind == "indirect" && entry.from.id.as_deref() == Some(alias))
+        .map(|entry| serde_json::from_value(entry.to))
+        .transpose()
+}
+
+/// Find the alias under which `registry` defines `flake_ref`
+///
+/// Returns [None] if no indirect entry points to `flake_ref`.
+pub fn reverse_lookup(
+    registry: &str,
+    flake_ref: &ToFlakeRef,
+) -> Result<Option<String>, serde_json::Error> {
+    let registry: RegistryFile = serde_json::from_str(registry)?;
+
+    for entry in registry.flakes {
+        if entry.from.kind != "indirect" {
+            continue;
+        }
+        if &serde_json::from_value::<ToFlakeRef>(entry.to)? == flake_ref {
+            return Ok(entry.from.id);
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_aliases() {
+        assert_eq!(registry_alias("nixpkgs"), Some("nixpkgs"));
+        assert_eq!(registry_alias("flake:nixpkgs"), Some("nixpkgs"));
+        assert_eq!(registry_alias("nixpkgs/nixos-22.11"), Some("nixpkgs"));
+        assert_eq!(registry_alias("github:NixOS/nixpkgs"), None);
+        assert_eq!(registry_alias("./some/path"), None);
+        assert_eq!(registry_alias("."), None);
+    }
+
+    #[test]
+    fn looks_up_indirect_entries() {
+        let registry = r#"{
+            "version": 2,
+            "flakes": [
+                {
+                    "from": { "type": "indirect", "id": "nixpkgs" },
+                    "to": { "type": "github", "owner": "NixOS", "repo": "nixpkgs" }
+                }
+            ]
+        }"#;
+
+        assert!(lookup(registry, "nixpkgs").unwrap().is_some());
+        assert!(lookup(registry, "floxpkgs").unwrap().is_none());
+
+        let nixpkgs = lookup(registry, "nixpkgs").unwrap().unwrap();
+        assert_eq!(
+            reverse_lookup(registry, &nixpkgs).unwrap(),
+            Some("nixpkgs".to_string())
+        );
+    }
+}
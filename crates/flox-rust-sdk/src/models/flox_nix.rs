@@ -0,0 +1,1360 @@
+//! Static reading of `flox.nix` files
+//!
+//! flox.nix files are plain attribute sets.
+//! Instead of evaluating them with nix, the values of simple attributes
+//! (strings, numbers, booleans, lists and attribute sets thereof)
+//! are read directly from the syntax tree using rnix.
+//!
+//! Packages are installed, uninstalled and upgraded by editing the source text
+//! at the positions found in the syntax tree,
+//! so comments and formatting outside of the edited entries are preserved.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::str::FromStr;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use rnix::ast::{self, AstNode, HasEntry};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use thiserror::Error;
+
+use super::flox_package::FloxPackage;
+
+/// Conventional filename of an environment definition
+pub const FLOX_NIX: &str = "flox.nix";
+
+/// Filenames environment definitions are looked up by, in order of preference
+///
+/// New environments are created using the first name.
+/// Existing environments are read from the first name that exists,
+/// so that a project can be migrated while old and new names are both in use.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FloxNixNames(Vec<String>);
+
+impl FloxNixNames {
+    /// Falls back to [FLOX_NIX] if `names` is empty
+    pub fn new(names: impl IntoIterator<Item = impl ToString>) -> Self {
+        let names: Vec<String> = names.into_iter().map(|name| name.to_string()).collect();
+        if names.is_empty() {
+            return Self::default();
+        }
+        FloxNixNames(names)
+    }
+
+    /// The name used for new environments
+    pub fn primary(&self) -> &str {
+        self.0.first().map_or(FLOX_NIX, String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+impl Default for FloxNixNames {
+    fn default() -> Self {
+        FloxNixNames(vec![FLOX_NIX.to_string()])
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FloxNixError {
+    #[error("Error parsing flox.nix: {0}")]
+    Parse(#[from] FloxNixParseError),
+    #[error("flox.nix must contain an attribute set")]
+    NotAnAttrSet,
+    #[error("Attribute '{0}' is defined multiple times")]
+    DuplicateAttr(String),
+    #[error("Attribute names must not contain interpolations (in '{0}')")]
+    DynamicAttr(String),
+    #[error("Attribute '{0}' can not be read without evaluating it")]
+    Unsupported(String),
+    #[error("Attribute '{0}' is not defined")]
+    NotFound(String),
+    #[error("Concurrent changes to '{0}' can not be merged")]
+    Conflict(String),
+    #[error("Invalid value for '{attr}': {err}")]
+    Deserialize {
+        attr: String,
+        err: serde_json::Error,
+    },
+}
+
+#[derive(Error, Debug)]
+#[error("Variable '{0}' is not set")]
+pub struct UndefinedVariable(pub String);
+
+/// A syntax error in a flox.nix file, located so that it can be fixed by hand
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{message} at line {line}, column {column}:\n{snippet}")]
+pub struct FloxNixParseError {
+    pub message: String,
+    /// Line of the error, starting at 1
+    pub line: usize,
+    /// Column of the error in characters, starting at 1
+    pub column: usize,
+    /// The line of the error with a marker below the column
+    pub snippet: String,
+}
+
+impl FloxNixParseError {
+    fn new(contents: &str, error: &rnix::parser::ParseError) -> Self {
+        use rnix::parser::ParseError;
+
+        let (start, message) = match error {
+            ParseError::Unexpected(range) => (Some(range.start()), "unexpected input".to_string()),
+            ParseError::UnexpectedExtra(range) => (
+                Some(range.start()),
+                "unexpected input after the attribute set".to_string(),
+            ),
+            ParseError::UnexpectedWanted(found, range, wanted) => {
+                let wanted: Vec<String> = wanted.iter().map(syntax_kind_name).collect();
+                (
+                    Some(range.start()),
+                    format!(
+                        "unexpected {}, expected {}",
+                        syntax_kind_name(found),
+                        wanted.join(" or ")
+                    ),
+                )
+            },
+            ParseError::UnexpectedDoubleBind(range) => {
+                (Some(range.start()), "unexpected double bind".to_string())
+            },
+            ParseError::DuplicatedArgs(range, name) => (
+                Some(range.start()),
+                format!("argument '{name}' is duplicated"),
+            ),
+            // errors at the end of the file
+            error => (None, error.to_string()),
+        };
+        let offset = start
+            .map(usize::from)
+            .unwrap_or_else(|| contents.trim_end().len())
+            .min(contents.len());
+
+        let line_start = contents[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = contents[offset..]
+            .find('\n')
+            .map_or(contents.len(), |i| offset + i);
+        let column = contents[line_start..offset].chars().count() + 1;
+
+        FloxNixParseError {
+            message,
+            line: contents[..offset].matches('\n').count() + 1,
+            column,
+            snippet: format!(
+                "{}\n{}^",
+                &contents[line_start..line_end],
+                " ".repeat(column - 1)
+            ),
+        }
+    }
+}
+
+/// Readable name of a token or node, e.g. `r brace` for `TOKEN_R_BRACE`
+fn syntax_kind_name(kind: &rnix::SyntaxKind) -> String {
+    format!("{kind:?}")
+        .trim_start_matches("TOKEN_")
+        .trim_start_matches("NODE_")
+        .replace('_', " ")
+        .to_lowercase()
+}
+
+/// An attribute set that may be assembled from several (nested) attrpaths
+///
+/// `a.b = 1; a.c = 2;` and `a = { b = 1; c = 2; };` result in the same tree
+#[derive(Debug)]
+enum Node {
+    Attrs(BTreeMap<String, Node>),
+    Value(ast::Expr),
+}
+
+/// Part of a string that may reference other attributes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StringPart {
+    Literal(String),
+    /// An interpolated attribute path, e.g. `${packages.nixpkgs-flox.hello}`
+    Reference(Vec<String>),
+}
+
+/// A parsed `flox.nix` file
+#[derive(Debug)]
+pub struct FloxNix {
+    attrs: BTreeMap<String, Node>,
+}
+
+impl FromStr for FloxNix {
+    type Err = FloxNixError;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        Ok(FloxNix {
+            attrs: collect_attrs(&root_attrs(contents)?, "")?,
+        })
+    }
+}
+
+impl FloxNix {
+    /// Read the value at `path` as json
+    ///
+    /// Returns `None` if the attribute is not defined.
+    pub fn get(&self, path: &[&str]) -> Result<Option<Value>, FloxNixError> {
+        if path.is_empty() {
+            return attrs_to_value(&self.attrs, "").map(Some);
+        }
+
+        match self.lookup(path)? {
+            Some(node) => node_to_value(node, &path.join(".")).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Read an attribute set of strings at `path`
+    ///
+    /// Unlike [FloxNix::get], interpolations of plain attribute paths
+    /// are kept as [StringPart::Reference] to be resolved by the caller.
+    pub fn get_strings(
+        &self,
+        path: &[&str],
+    ) -> Result<Option<BTreeMap<String, Vec<StringPart>>>, FloxNixError> {
+        let prefix = path.join(".");
+        let attrs = match self.lookup(path)? {
+            Some(Node::Attrs(attrs)) => attrs,
+            Some(Node::Value(_)) => return Err(FloxNixError::Unsupported(prefix)),
+            None => return Ok(None),
+        };
+
+        let mut strings = BTreeMap::new();
+        for (name, node) in attrs {
+            let attr = join_attr(&prefix, name);
+            let parts = match node {
+                Node::Value(ast::Expr::Str(s)) => s
+                    .normalized_parts()
+                    .into_iter()
+                    .map(|part| match part {
+                        ast::InterpolPart::Literal(s) => Ok(StringPart::Literal(s)),
+                        ast::InterpolPart::Interpolation(interpol) => interpol
+                            .expr()
+                            .ok_or_else(|| FloxNixError::Unsupported(attr.clone()))
+                            .and_then(|expr| reference_path(expr, &attr))
+                            .map(StringPart::Reference),
+                    })
+                    .collect::<Result<_, _>>()?,
+                _ => return Err(FloxNixError::Unsupported(attr)),
+            };
+            strings.insert(name.clone(), parts);
+        }
+
+        Ok(Some(strings))
+    }
+
+    /// Read the value at `path` and deserialize it into `T`
+    pub fn get_as<T: DeserializeOwned>(&self, path: &[&str]) -> Result<Option<T>, FloxNixError> {
+        self.get(path)?
+            .map(|value| {
+                serde_json::from_value(value).map_err(|err| FloxNixError::Deserialize {
+                    attr: path.join("."),
+                    err,
+                })
+            })
+            .transpose()
+    }
+
+    /// Packages declared in `packages` as `<channel>.<name>`, with their attributes
+    pub fn packages(&self) -> Result<BTreeMap<FloxPackage, Value>, FloxNixError> {
+        let packages: BTreeMap<String, BTreeMap<String, Value>> =
+            self.get_as(&["packages"])?.unwrap_or_default();
+
+        Ok(packages
+            .into_iter()
+            .flat_map(|(channel, packages)| {
+                packages
+                    .into_iter()
+                    .map(move |(name, attrs)| (format!("{channel}.{name}"), attrs))
+            })
+            .collect())
+    }
+
+    /// Whether a `<channel>.<name>` package is declared in `packages`
+    pub fn has_package(&self, package: &str) -> Result<bool, FloxNixError> {
+        let path = package_path(package);
+        let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+        Ok(self.lookup(&path)?.is_some())
+    }
+
+    /// Paths of the flox.nix files listed in `imports`, as written
+    ///
+    /// Imports are merged into the importing environment like nix modules,
+    /// relative paths are relative to the directory of the importing file.
+    /// Only path literals without interpolations are supported.
+    pub fn imports(&self) -> Result<Vec<String>, FloxNixError> {
+        let unsupported = || FloxNixError::Unsupported("imports".to_string());
+        let list = match self.lookup(&["imports"])? {
+            Some(Node::Value(ast::Expr::List(list))) => list,
+            Some(_) => return Err(unsupported()),
+            None => return Ok(Vec::new()),
+        };
+
+        list.items()
+            .map(|item| match item {
+                ast::Expr::Path(path) => {
+                    let path = path.to_string();
+                    if path.contains("${") {
+                        return Err(unsupported());
+                    }
+                    Ok(path)
+                },
+                _ => Err(unsupported()),
+            })
+            .collect()
+    }
+
+    /// Top level attributes other than `packages` with the source text of their values,
+    /// to compare definitions regardless of formatting
+    fn outline_without_packages(&self) -> BTreeMap<String, Value> {
+        self.attrs
+            .iter()
+            .filter(|(name, _)| *name != "packages")
+            .map(|(name, node)| (name.clone(), outline(node)))
+            .collect()
+    }
+
+    /// Find the node at a non empty `path`
+    fn lookup(&self, path: &[&str]) -> Result<Option<&Node>, FloxNixError> {
+        let mut attrs = &self.attrs;
+        let mut node: Option<&Node> = None;
+
+        for name in path {
+            if let Some(Node::Value(_)) = node {
+                return Err(FloxNixError::Unsupported(path.join(".")));
+            }
+            if let Some(Node::Attrs(nested)) = node {
+                attrs = nested;
+            }
+            node = match attrs.get(*name) {
+                Some(node) => Some(node),
+                None => return Ok(None),
+            };
+        }
+
+        Ok(node)
+    }
+}
+
+/// Add `<channel>.<name>` packages to the `packages` of a flox.nix
+///
+/// New entries are added next to the entries they share the longest attrpath with,
+/// e.g. `nixpkgs-flox.bat` is added into an existing `packages.nixpkgs-flox = { .. };`.
+/// Packages that are already installed are left untouched.
+pub fn install_packages(contents: &str, packages: &[FloxPackage]) -> Result<String, FloxNixError> {
+    packages
+        .iter()
+        .try_fold(contents.to_string(), |contents, package| {
+            // attributes may be spread over several entries,
+            // check the merged attribute set for existing packages
+            if contents.parse::<FloxNix>()?.has_package(package)? {
+                return Ok(contents);
+            }
+
+            let path = package_path(package);
+            let (range, text) = insert_edit(&contents, &root_attrs(&contents)?, &path, "", "{}")?;
+            Ok(splice(&contents, range, &text))
+        })
+}
+
+/// Set the `version` of an installed `<channel>.<name>` package
+///
+/// Any existing `version` entries of the package are replaced.
+pub fn set_package_version(
+    contents: &str,
+    package: &FloxPackage,
+    version: &str,
+) -> Result<String, FloxNixError> {
+    if !contents.parse::<FloxNix>()?.has_package(package)? {
+        return Err(FloxNixError::NotFound(format!("packages.{package}")));
+    }
+
+    let mut path = package_path(package);
+    path.push("version".to_string());
+
+    let mut ranges = Vec::new();
+    remove_ranges(contents, &root_attrs(contents)?, &path, "", &mut ranges)?;
+    ranges.sort_by_key(|range| range.start);
+    let contents = ranges
+        .into_iter()
+        .rev()
+        .fold(contents.to_string(), |contents, range| {
+            splice(&contents, range, "")
+        });
+
+    let (range, text) = insert_edit(
+        &contents,
+        &root_attrs(&contents)?,
+        &path,
+        "",
+        &format!("{version:?}"),
+    )?;
+    Ok(splice(&contents, range, &text))
+}
+
+/// Remove `<channel>.<name>` packages from the `packages` of a flox.nix
+///
+/// All entries defining (parts of) the package are removed,
+/// including comments on the same line.
+/// Attribute sets left empty are kept.
+pub fn uninstall_packages(
+    contents: &str,
+    packages: &[FloxPackage],
+) -> Result<String, FloxNixError> {
+    packages
+        .iter()
+        .try_fold(contents.to_string(), |contents, package| {
+            let set = root_attrs(&contents)?;
+            let mut ranges = Vec::new();
+            remove_ranges(&contents, &set, &package_path(package), "", &mut ranges)?;
+            if ranges.is_empty() {
+                return Err(FloxNixError::NotFound(format!("packages.{package}")));
+            }
+
+            ranges.sort_by_key(|range| range.start);
+            Ok(ranges
+                .into_iter()
+                .rev()
+                .fold(contents, |contents, range| splice(&contents, range, "")))
+        })
+}
+
+/// Merge concurrent changes of `ours` and `theirs` to the `packages` of `base`
+///
+/// Additions of either side are kept,
+/// while a package is only removed if both sides removed it.
+/// The attributes of a package, e.g. its `version`, may be changed by one side only.
+/// Changes outside of `packages` are kept as long as only one side made them.
+/// Anything else fails with [FloxNixError::Conflict].
+pub fn merge_packages(base: &str, ours: &str, theirs: &str) -> Result<String, FloxNixError> {
+    let base_nix: FloxNix = base.parse()?;
+    let our_nix: FloxNix = ours.parse()?;
+    let their_nix: FloxNix = theirs.parse()?;
+
+    // edit the side that changed the rest of the file (if any),
+    // so that only the packages of the other side have to be merged into it
+    let base_rest = base_nix.outline_without_packages();
+    let our_rest = our_nix.outline_without_packages();
+    let their_rest = their_nix.outline_without_packages();
+    let (contents, current) = if our_rest == base_rest {
+        (theirs, &their_nix)
+    } else if their_rest == base_rest {
+        (ours, &our_nix)
+    } else {
+        let changed =
+            |rest: &BTreeMap<String, Value>, name: &str| rest.get(name) != base_rest.get(name);
+        let names: BTreeSet<&str> = base_rest
+            .keys()
+            .chain(our_rest.keys())
+            .chain(their_rest.keys())
+            .map(String::as_str)
+            .collect();
+        let attr = names
+            .iter()
+            .find(|name| changed(&our_rest, name) && changed(&their_rest, name))
+            .or_else(|| names.iter().find(|name| changed(&our_rest, name)))
+            .expect("both sides changed an attribute");
+        return Err(FloxNixError::Conflict(attr.to_string()));
+    };
+
+    let base_packages = base_nix.packages()?;
+    let our_packages = our_nix.packages()?;
+    let their_packages = their_nix.packages()?;
+    let current = current.packages()?;
+
+    let names: BTreeSet<&FloxPackage> = our_packages.keys().chain(their_packages.keys()).collect();
+    let merged = names
+        .into_iter()
+        .try_fold(contents.to_string(), |contents, package| {
+            let base_attrs = base_packages.get(package);
+            let merged = match (our_packages.get(package), their_packages.get(package)) {
+                (Some(our_attrs), Some(their_attrs)) if our_attrs != their_attrs => {
+                    if Some(our_attrs) == base_attrs {
+                        their_attrs
+                    } else if Some(their_attrs) == base_attrs {
+                        our_attrs
+                    } else {
+                        return Err(FloxNixError::Conflict(format!("packages.{package}")));
+                    }
+                },
+                (Some(attrs), _) | (None, Some(attrs)) => attrs,
+                (None, None) => unreachable!("package is declared on either side"),
+            };
+            if current.get(package) == Some(merged) {
+                return Ok(contents);
+            }
+
+            let contents = if current.contains_key(package) {
+                uninstall_packages(&contents, std::slice::from_ref(package))?
+            } else {
+                contents
+            };
+            let (range, text) = insert_edit(
+                &contents,
+                &root_attrs(&contents)?,
+                &package_path(package),
+                "",
+                &nix_value(merged, &format!("packages.{package}"))?,
+            )?;
+            Ok(splice(&contents, range, &text))
+        })?;
+    Ok(merged)
+}
+
+/// Whether `${NAME}` references to the host environment are resolved on activation,
+/// see [interpolate_env]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvInterpolation {
+    /// References are kept as written
+    #[default]
+    Off,
+    /// Undefined variables resolve to an empty string and are reported as warnings
+    Lenient,
+    /// Undefined variables are an error
+    Strict,
+}
+
+/// `${NAME}` references to variables of the host environment, or an escaped `$${`
+static ENV_REFERENCE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\$\$\{|\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap());
+
+/// Resolve `${NAME}` references to variables of the host environment in `value`
+///
+/// References are resolved at activation rather than by nix,
+/// so flox.nix has to escape them as `\${NAME}` (or `''${NAME}` in indented strings).
+/// A literal `${` is written as `$${`.
+/// Undefined variables resolve to an empty string,
+/// their names are returned alongside the interpolated value.
+///
+/// Resolved values are inserted verbatim:
+/// an environment referencing e.g. `${HOME}` or a token
+/// takes whatever value the activating machine and user provide.
+pub fn interpolate_env(
+    value: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> (String, Vec<String>) {
+    let mut interpolated = String::with_capacity(value.len());
+    let mut undefined = Vec::new();
+    let mut last = 0;
+    for captures in ENV_REFERENCE.captures_iter(value) {
+        let reference = captures.get(0).expect("group 0 is the whole match");
+        interpolated.push_str(&value[last..reference.start()]);
+        match captures.get(1) {
+            None => interpolated.push_str("${"),
+            Some(name) => match lookup(name.as_str()) {
+                Some(resolved) => interpolated.push_str(&resolved),
+                None => undefined.push(name.as_str().to_string()),
+            },
+        }
+        last = reference.end();
+    }
+    interpolated.push_str(&value[last..]);
+    (interpolated, undefined)
+}
+
+fn package_path(package: &str) -> Vec<String> {
+    ["packages"]
+        .into_iter()
+        .chain(package.split('.'))
+        .map(String::from)
+        .collect()
+}
+
+/// The top level attribute set of a flox.nix
+fn root_attrs(contents: &str) -> Result<ast::AttrSet, FloxNixError> {
+    let root = rnix::Root::parse(contents)
+        .ok()
+        .map_err(|e| FloxNixParseError::new(contents, &e))?;
+
+    let mut expr = root.expr().ok_or(FloxNixError::NotAnAttrSet)?;
+
+    // allow `{ ... }: { }` style files by looking at the function body
+    if let ast::Expr::Lambda(lambda) = expr {
+        expr = lambda.body().ok_or(FloxNixError::NotAnAttrSet)?;
+    }
+
+    match expr {
+        ast::Expr::AttrSet(set) => Ok(set),
+        _ => Err(FloxNixError::NotAnAttrSet),
+    }
+}
+
+fn entry_path(entry: &ast::AttrpathValue, prefix: &str) -> Result<Vec<String>, FloxNixError> {
+    entry
+        .attrpath()
+        .expect("Failed to get attrpath of entry")
+        .attrs()
+        .map(|attr| attr_name(attr, prefix))
+        .collect()
+}
+
+fn text_range(node: &impl AstNode) -> Range<usize> {
+    let range = node.syntax().text_range();
+    usize::from(range.start())..usize::from(range.end())
+}
+
+fn splice(contents: &str, range: Range<usize>, text: &str) -> String {
+    let mut contents = contents.to_string();
+    contents.replace_range(range, text);
+    contents
+}
+
+/// Find where to insert `path = <value>;` into `set`
+///
+/// `path` must not be defined yet.
+fn insert_edit(
+    contents: &str,
+    set: &ast::AttrSet,
+    path: &[String],
+    prefix: &str,
+    value: &str,
+) -> Result<(Range<usize>, String), FloxNixError> {
+    let mut anchor: Option<(usize, ast::AttrpathValue)> = None;
+
+    for entry in set.attrpath_values() {
+        let entry_path = entry_path(&entry, prefix)?;
+
+        if path.starts_with(&entry_path) {
+            let attr = entry_path.join(".");
+            return match entry.value() {
+                Some(ast::Expr::AttrSet(nested)) => insert_edit(
+                    contents,
+                    &nested,
+                    &path[entry_path.len()..],
+                    &join_attr(prefix, &attr),
+                    value,
+                ),
+                _ => Err(FloxNixError::Unsupported(join_attr(prefix, &attr))),
+            };
+        }
+
+        let common = entry_path
+            .iter()
+            .zip(path)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if anchor
+            .as_ref()
+            .is_none_or(|(longest, _)| common >= *longest)
+        {
+            anchor = Some((common, entry));
+        }
+    }
+
+    let attrpath = path
+        .iter()
+        .map(|name| quote_attr(name))
+        .collect::<Vec<_>>()
+        .join(".");
+    let new_entry = format!("{attrpath} = {value};");
+
+    let set_range = text_range(set);
+    if !contents[set_range.clone()].ends_with('}') {
+        return Err(FloxNixError::Unsupported(prefix.to_string()));
+    }
+    let closing = set_range.end - 1;
+
+    Ok(match anchor {
+        // continue on a new line after the anchor (and its trailing comment)
+        // if it is the last thing on its line
+        Some((_, entry)) => {
+            let range = text_range(&entry);
+            let line_end = contents[range.end..]
+                .find('\n')
+                .map_or(contents.len(), |i| range.end + i);
+            let rest = contents[range.end..line_end].trim();
+
+            match line_indent(contents, range.start) {
+                Some(indent) if rest.is_empty() || rest.starts_with('#') => {
+                    (line_end..line_end, format!("\n{indent}{new_entry}"))
+                },
+                _ => (range.end..range.end, format!(" {new_entry}")),
+            }
+        },
+        None => match line_indent(contents, closing) {
+            Some(indent) => {
+                let line_start = closing - indent.len();
+                (line_start..line_start, format!("{indent}  {new_entry}\n"))
+            },
+            None if contents[..closing].ends_with(char::is_whitespace) => {
+                (closing..closing, format!("{new_entry} "))
+            },
+            None => (closing..closing, format!(" {new_entry} ")),
+        },
+    })
+}
+
+/// Collect the ranges of all entries in `set` defining `path` or attributes within it
+fn remove_ranges(
+    contents: &str,
+    set: &ast::AttrSet,
+    path: &[String],
+    prefix: &str,
+    ranges: &mut Vec<Range<usize>>,
+) -> Result<(), FloxNixError> {
+    for entry in set.attrpath_values() {
+        let entry_path = entry_path(&entry, prefix)?;
+
+        if entry_path.starts_with(path) {
+            ranges.push(removal_range(contents, text_range(&entry)));
+        } else if path.starts_with(&entry_path) {
+            let attr = join_attr(prefix, &entry_path.join("."));
+            match entry.value() {
+                Some(ast::Expr::AttrSet(nested)) => {
+                    remove_ranges(contents, &nested, &path[entry_path.len()..], &attr, ranges)?
+                },
+                _ => return Err(FloxNixError::Unsupported(attr)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extend the range of an entry to its whole line if nothing else is on it
+///
+/// Otherwise only the entry and the whitespace following it are removed.
+fn removal_range(contents: &str, range: Range<usize>) -> Range<usize> {
+    let line_end = contents[range.end..]
+        .find('\n')
+        .map_or(contents.len(), |i| range.end + i);
+    let rest = contents[range.end..line_end].trim();
+
+    match line_indent(contents, range.start) {
+        Some(indent) if rest.is_empty() || rest.starts_with('#') => {
+            range.start - indent.len()..(line_end + 1).min(contents.len())
+        },
+        _ => {
+            let trailing = contents[range.end..line_end].len()
+                - contents[range.end..line_end].trim_start().len();
+            range.start..range.end + trailing
+        },
+    }
+}
+
+/// The whitespace preceding `position` on its line,
+/// or [None] if there is anything else in front of it
+fn line_indent(contents: &str, position: usize) -> Option<&str> {
+    let line_start = contents[..position].rfind('\n').map_or(0, |i| i + 1);
+    let indent = &contents[line_start..position];
+    indent.chars().all(char::is_whitespace).then_some(indent)
+}
+
+/// Quote attribute names that are not valid nix identifiers
+fn quote_attr(name: &str) -> String {
+    const KEYWORDS: [&str; 9] = [
+        "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then",
+    ];
+
+    let mut chars = name.chars();
+    let is_ident = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_'-".contains(c))
+        && !KEYWORDS.contains(&name);
+
+    if is_ident {
+        name.to_string()
+    } else {
+        format!("{name:?}")
+    }
+}
+
+/// Convert an interpolated expression like `packages.nixpkgs-flox.hello` to its attribute path
+fn reference_path(expr: ast::Expr, attr: &str) -> Result<Vec<String>, FloxNixError> {
+    match expr {
+        ast::Expr::Ident(ident) => Ok(vec![ident
+            .ident_token()
+            .expect("Failed to get ident token for ident expression")
+            .text()
+            .to_string()]),
+        ast::Expr::Select(select) => {
+            let mut path = reference_path(
+                select
+                    .expr()
+                    .ok_or_else(|| FloxNixError::Unsupported(attr.to_string()))?,
+                attr,
+            )?;
+            for name in select
+                .attrpath()
+                .ok_or_else(|| FloxNixError::Unsupported(attr.to_string()))?
+                .attrs()
+            {
+                path.push(attr_name(name, attr)?);
+            }
+            Ok(path)
+        },
+        _ => Err(FloxNixError::Unsupported(attr.to_string())),
+    }
+}
+
+fn join_attr(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{prefix}.{name}")
+    }
+}
+
+fn attr_name(attr: ast::Attr, prefix: &str) -> Result<String, FloxNixError> {
+    match attr {
+        ast::Attr::Ident(ident) => Ok(ident
+            .ident_token()
+            .expect("Failed to get ident token for attribute")
+            .text()
+            .to_string()),
+        ast::Attr::Str(s) => match s.normalized_parts().as_slice() {
+            [ast::InterpolPart::Literal(s)] => Ok(s.to_string()),
+            _ => Err(FloxNixError::DynamicAttr(prefix.to_string())),
+        },
+        _ => Err(FloxNixError::DynamicAttr(prefix.to_string())),
+    }
+}
+
+fn collect_attrs(set: &ast::AttrSet, prefix: &str) -> Result<BTreeMap<String, Node>, FloxNixError> {
+    let mut attrs = BTreeMap::new();
+
+    for entry in set.attrpath_values() {
+        let path = entry
+            .attrpath()
+            .expect("Failed to get attrpath of entry")
+            .attrs()
+            .map(|attr| attr_name(attr, prefix))
+            .collect::<Result<Vec<_>, _>>()?;
+        let value = entry.value().expect("Failed to get value of entry");
+
+        insert(&mut attrs, prefix, &path, value)?;
+    }
+
+    Ok(attrs)
+}
+
+fn insert(
+    attrs: &mut BTreeMap<String, Node>,
+    prefix: &str,
+    path: &[String],
+    value: ast::Expr,
+) -> Result<(), FloxNixError> {
+    let (name, rest) = path
+        .split_first()
+        .expect("attrpaths have at least one element");
+    let attr = join_attr(prefix, name);
+
+    if rest.is_empty() {
+        let node = match value {
+            ast::Expr::AttrSet(set) => Node::Attrs(collect_attrs(&set, &attr)?),
+            value => Node::Value(value),
+        };
+
+        match (attrs.get_mut(name), node) {
+            (None, node) => {
+                attrs.insert(name.to_string(), node);
+            },
+            // `a.b = 1; a = { c = 2; };` is valid nix and merges both sets
+            (Some(Node::Attrs(existing)), Node::Attrs(new)) => {
+                for (key, node) in new {
+                    if existing.contains_key(&key) {
+                        return Err(FloxNixError::DuplicateAttr(join_attr(&attr, &key)));
+                    }
+                    existing.insert(key, node);
+                }
+            },
+            (Some(_), _) => return Err(FloxNixError::DuplicateAttr(attr)),
+        }
+        return Ok(());
+    }
+
+    match attrs
+        .entry(name.to_string())
+        .or_insert_with(|| Node::Attrs(BTreeMap::new()))
+    {
+        Node::Attrs(nested) => insert(nested, &attr, rest, value),
+        Node::Value(_) => Err(FloxNixError::DuplicateAttr(attr)),
+    }
+}
+
+fn outline(node: &Node) -> Value {
+    match node {
+        Node::Attrs(attrs) => Value::Object(
+            attrs
+                .iter()
+                .map(|(name, node)| (name.clone(), outline(node)))
+                .collect(),
+        ),
+        Node::Value(expr) => Value::String(expr.to_string()),
+    }
+}
+
+/// Write `value` as a nix expression, the inverse of [expr_to_value]
+fn nix_value(value: &Value, attr: &str) -> Result<String, FloxNixError> {
+    Ok(match value {
+        Value::Null | Value::Bool(_) | Value::Number(_) => value.to_string(),
+        Value::String(s) => format!("{s:?}"),
+        Value::Array(items) => format!(
+            "[ {}]",
+            items
+                .iter()
+                .map(|item| Ok(format!("{} ", nix_value(item, attr)?)))
+                .collect::<Result<String, FloxNixError>>()?
+        ),
+        Value::Object(attrs) if attrs.is_empty() => "{}".to_string(),
+        Value::Object(attrs) => format!(
+            "{{ {}}}",
+            attrs
+                .iter()
+                .map(|(name, value)| {
+                    let value = nix_value(value, &join_attr(attr, name))?;
+                    Ok(format!("{} = {value}; ", quote_attr(name)))
+                })
+                .collect::<Result<String, FloxNixError>>()?
+        ),
+    })
+}
+
+fn attrs_to_value(attrs: &BTreeMap<String, Node>, prefix: &str) -> Result<Value, FloxNixError> {
+    let mut map = Map::new();
+    for (name, node) in attrs {
+        map.insert(name.clone(), node_to_value(node, &join_attr(prefix, name))?);
+    }
+    Ok(Value::Object(map))
+}
+
+fn node_to_value(node: &Node, attr: &str) -> Result<Value, FloxNixError> {
+    match node {
+        Node::Attrs(attrs) => attrs_to_value(attrs, attr),
+        Node::Value(expr) => expr_to_value(expr.clone(), attr),
+    }
+}
+
+fn expr_to_value(expr: ast::Expr, attr: &str) -> Result<Value, FloxNixError> {
+    let unsupported = || FloxNixError::Unsupported(attr.to_string());
+
+    Ok(match expr {
+        ast::Expr::Str(s) => {
+            let mut string = String::new();
+            for part in s.normalized_parts() {
+                match part {
+                    ast::InterpolPart::Literal(s) => string.push_str(&s),
+                    ast::InterpolPart::Interpolation(_) => return Err(unsupported()),
+                }
+            }
+            Value::String(string)
+        },
+        ast::Expr::Literal(literal) => match literal.kind() {
+            ast::LiteralKind::Integer(i) => {
+                Value::Number(i.value().map_err(|_| unsupported())?.into())
+            },
+            ast::LiteralKind::Float(f) => Number::from_f64(f.value().map_err(|_| unsupported())?)
+                .map(Value::Number)
+                .ok_or_else(unsupported)?,
+            ast::LiteralKind::Uri(_) => return Err(unsupported()),
+        },
+        ast::Expr::Ident(ident) => match ident.ident_token().as_ref().map(|t| t.text()) {
+            Some("true") => Value::Bool(true),
+            Some("false") => Value::Bool(false),
+            Some("null") => Value::Null,
+            _ => return Err(unsupported()),
+        },
+        ast::Expr::List(list) => Value::Array(
+            list.items()
+                .map(|item| expr_to_value(item, attr))
+                .collect::<Result<_, _>>()?,
+        ),
+        ast::Expr::AttrSet(set) => attrs_to_value(&collect_attrs(&set, attr)?, attr)?,
+        ast::Expr::Paren(paren) => expr_to_value(paren.expr().ok_or_else(unsupported)?, attr)?,
+        _ => return Err(unsupported()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn reads_nested_attrpaths() {
+        let flox_nix: FloxNix = r#"
+        {
+          packages.nixpkgs-flox.hello = {};
+          packages.nixpkgs-flox.bat = { version = "0.22.1"; };
+          services.postgres = { command = "postgres -D $PGDATA"; };
+          services.redis.command = "redis-server";
+          environmentVariables.LANG = "en_US.UTF-8";
+          shell.hook = ''
+            echo hello
+          '';
+        }
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            flox_nix.get(&["services"]).unwrap(),
+            Some(json!({
+                "postgres": { "command": "postgres -D $PGDATA" },
+                "redis": { "command": "redis-server" },
+            }))
+        );
+        assert_eq!(
+            flox_nix
+                .get(&["packages", "nixpkgs-flox", "bat", "version"])
+                .unwrap(),
+            Some(json!("0.22.1"))
+        );
+        assert_eq!(
+            flox_nix.get(&["shell", "hook"]).unwrap(),
+            Some(json!("echo hello\n"))
+        );
+        assert_eq!(flox_nix.get(&["doesNotExist"]).unwrap(), None);
+    }
+
+    #[test]
+    fn locates_syntax_errors() {
+        let parse_error = |contents: &str| match contents.parse::<FloxNix>() {
+            Err(FloxNixError::Parse(e)) => e,
+            other => panic!("expected a syntax error, got {other:?}"),
+        };
+
+        // trailing input
+        let error = parse_error("{ a = 1; };");
+        assert_eq!((error.line, error.column), (1, 11));
+        assert_eq!(error.snippet, "{ a = 1; };\n          ^");
+
+        // missing semicolon
+        let error = parse_error("{\n  packages.nixpkgs-flox.hello = {}\n}\n");
+        assert_eq!(error.line, 3);
+        assert!(error.message.contains("semicolon"), "{}", error.message);
+
+        // unclosed attribute set
+        let error = parse_error("{\n  a = 1;\n");
+        assert_eq!(error.line, 2);
+        assert!(error.message.contains("end of file"), "{}", error.message);
+
+        // errors are shown with their location
+        let error = parse_error("{\n  a = ;\n}");
+        assert_eq!(error.line, 2);
+        assert!(error
+            .to_string()
+            .contains(&format!("line 2, column {}:\n  a = ;", error.column)));
+    }
+
+    #[test]
+    fn reads_imports() {
+        let flox_nix: FloxNix = r#"
+        {
+          imports = [ ../base/flox.nix ./tools.nix ];
+          packages.nixpkgs-flox.hello = {};
+        }
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(flox_nix.imports().unwrap(), [
+            "../base/flox.nix",
+            "./tools.nix"
+        ]);
+
+        let flox_nix: FloxNix = "{ }".parse().unwrap();
+        assert!(flox_nix.imports().unwrap().is_empty());
+
+        let flox_nix: FloxNix = r#"{ imports = [ "base" ]; }"#.parse().unwrap();
+        assert!(matches!(
+            flox_nix.imports(),
+            Err(FloxNixError::Unsupported(attr)) if attr == "imports"
+        ));
+    }
+
+    #[test]
+    fn reads_interpolated_strings() {
+        let flox_nix: FloxNix = r#"
+        {
+          environmentVariables.LANG = "en_US.UTF-8";
+          environmentVariables.HELLO = "${packages.nixpkgs-flox.hello}/bin/hello";
+        }
+        "#
+        .parse()
+        .unwrap();
+
+        let strings = flox_nix
+            .get_strings(&["environmentVariables"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(strings["LANG"], vec![StringPart::Literal(
+            "en_US.UTF-8".to_string()
+        )]);
+        assert_eq!(strings["HELLO"], vec![
+            StringPart::Reference(vec![
+                "packages".to_string(),
+                "nixpkgs-flox".to_string(),
+                "hello".to_string()
+            ]),
+            StringPart::Literal("/bin/hello".to_string())
+        ]);
+    }
+
+    #[test]
+    fn interpolates_env() {
+        let flox_nix: FloxNix = r#"
+        {
+          environmentVariables.DATABASE_URL = "postgres://\${DB_HOST}:5432/\${DB_NAME}";
+        }
+        "#
+        .parse()
+        .unwrap();
+        let strings = flox_nix
+            .get_strings(&["environmentVariables"])
+            .unwrap()
+            .unwrap();
+        // escaped references are no interpolations for nix
+        let value: String = strings["DATABASE_URL"]
+            .iter()
+            .map(|part| match part {
+                StringPart::Literal(literal) => literal.as_str(),
+                StringPart::Reference(path) => panic!("unexpected reference {path:?}"),
+            })
+            .collect();
+
+        let lookup = |name: &str| (name == "DB_HOST").then(|| "db.internal".to_string());
+        assert_eq!(
+            interpolate_env(&value, lookup),
+            ("postgres://db.internal:5432/".to_string(), vec![
+                "DB_NAME".to_string()
+            ])
+        );
+
+        // anything but plain variable names is kept as is
+        assert_eq!(
+            interpolate_env("${not a name} $HOME", lookup),
+            ("${not a name} $HOME".to_string(), vec![])
+        );
+
+        // `$${` escapes a literal `${`
+        assert_eq!(
+            interpolate_env("$${DB_HOST} ${DB_HOST} $$${DB_HOST}", lookup),
+            ("${DB_HOST} db.internal $${DB_HOST}".to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn has_package() {
+        let flox_nix: FloxNix = r#"
+        {
+          packages.nixpkgs-flox.hello = {};
+          packages = {
+            nixpkgs.bat = { version = "0.22.1"; };
+            "github:flox/etc" = { jq = {}; };
+          };
+        }
+        "#
+        .parse()
+        .unwrap();
+
+        assert!(flox_nix.has_package("nixpkgs-flox.hello").unwrap());
+        assert!(flox_nix.has_package("nixpkgs.bat").unwrap());
+        assert!(!flox_nix.has_package("nixpkgs-flox.ripgrep").unwrap());
+        assert!(!flox_nix.has_package("nixpkgs.hello").unwrap());
+
+        let flox_nix: FloxNix = "{ packages = import ./packages.nix; }".parse().unwrap();
+        assert!(matches!(
+            flox_nix.has_package("nixpkgs-flox.hello"),
+            Err(FloxNixError::Unsupported(_))
+        ));
+
+        let flox_nix: FloxNix = "{ }".parse().unwrap();
+        assert!(!flox_nix.has_package("nixpkgs-flox.hello").unwrap());
+    }
+
+    const COMMENTED: &str = r#"# flox environment
+{
+  # packages from the flox catalog
+  packages.nixpkgs-flox = {
+    hello = {}; # the classic
+    # a better cat
+    bat = { version = "0.22.1"; };
+  };
+
+  packages.nixpkgs-flox.ripgrep = {}; # search
+
+  # shell configuration
+  shell.hook = ''
+    echo hello
+  '';
+}
+"#;
+
+    #[test]
+    fn install_preserves_comments() {
+        let edited = install_packages(COMMENTED, &["nixpkgs-flox.jq".to_string()]).unwrap();
+        assert_eq!(
+            edited,
+            COMMENTED.replace(
+                "    bat = { version = \"0.22.1\"; };\n",
+                "    bat = { version = \"0.22.1\"; };\n    jq = {};\n"
+            )
+        );
+
+        let edited = install_packages(COMMENTED, &[
+            "nixpkgs-flox.hello".to_string(),
+            "nixpkgs-flox.ripgrep".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(edited, COMMENTED);
+
+        let edited = install_packages(COMMENTED, &["nixpkgs.python3".to_string()]).unwrap();
+        assert_eq!(
+            edited,
+            COMMENTED.replace(
+                "  packages.nixpkgs-flox.ripgrep = {}; # search\n",
+                "  packages.nixpkgs-flox.ripgrep = {}; # search\n  packages.nixpkgs.python3 = {};\n"
+            )
+        );
+    }
+
+    #[test]
+    fn install_into_empty_sets() {
+        assert_eq!(
+            install_packages("{ }", &["nixpkgs-flox.hello".to_string()]).unwrap(),
+            "{ packages.nixpkgs-flox.hello = {}; }"
+        );
+        assert_eq!(
+            install_packages("{\n  packages = {\n  };\n}\n", &[
+                "nixpkgs-flox.hello".to_string()
+            ])
+            .unwrap(),
+            "{\n  packages = {\n    nixpkgs-flox.hello = {};\n  };\n}\n"
+        );
+        assert_eq!(
+            install_packages("{}", &["nixpkgs-flox.2048-in-terminal".to_string()]).unwrap(),
+            "{ packages.nixpkgs-flox.\"2048-in-terminal\" = {}; }"
+        );
+    }
+
+    #[test]
+    fn uninstall_preserves_comments() {
+        let edited = uninstall_packages(COMMENTED, &["nixpkgs-flox.hello".to_string()]).unwrap();
+        assert_eq!(
+            edited,
+            COMMENTED.replace("    hello = {}; # the classic\n", "")
+        );
+
+        let edited = uninstall_packages(COMMENTED, &["nixpkgs-flox.ripgrep".to_string()]).unwrap();
+        assert_eq!(
+            edited,
+            COMMENTED.replace("  packages.nixpkgs-flox.ripgrep = {}; # search\n", "")
+        );
+
+        let edited = uninstall_packages("{ packages.a.b = {}; packages.a.c = {}; }", &[
+            "a.b".to_string()
+        ])
+        .unwrap();
+        assert_eq!(edited, "{ packages.a.c = {}; }");
+
+        assert!(matches!(
+            uninstall_packages(COMMENTED, &["nixpkgs-flox.jq".to_string()]),
+            Err(FloxNixError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn sets_package_versions() {
+        let package = "nixpkgs-flox.bat".to_string();
+        assert_eq!(
+            set_package_version("{ packages.nixpkgs-flox.bat = {}; }", &package, "0.23.0").unwrap(),
+            r#"{ packages.nixpkgs-flox.bat = { version = "0.23.0"; }; }"#
+        );
+        assert_eq!(
+            set_package_version(
+                "{\n  packages.nixpkgs-flox.bat = {\n    version = \"0.22.1\"; # pinned\n  };\n}\n",
+                &package,
+                "0.23.0"
+            )
+            .unwrap(),
+            "{\n  packages.nixpkgs-flox.bat = {\n    version = \"0.23.0\";\n  };\n}\n"
+        );
+        assert!(matches!(
+            set_package_version("{ }", &package, "0.23.0"),
+            Err(FloxNixError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn merges_concurrent_package_changes() {
+        let base = "{\n  packages.nixpkgs-flox.hello = {};\n  packages.nixpkgs-flox.bat = {};\n}\n";
+        let packages = |contents: &str| -> Vec<FloxPackage> {
+            contents
+                .parse::<FloxNix>()
+                .unwrap()
+                .packages()
+                .unwrap()
+                .into_keys()
+                .collect()
+        };
+
+        // additions are unioned, removals intersected
+        let ours = install_packages(base, &["nixpkgs-flox.ripgrep".to_string()]).unwrap();
+        let ours = uninstall_packages(&ours, &["nixpkgs-flox.bat".to_string()]).unwrap();
+        let ours = uninstall_packages(&ours, &["nixpkgs-flox.hello".to_string()]).unwrap();
+        let theirs = install_packages(base, &["nixpkgs-flox.jq".to_string()]).unwrap();
+        let theirs = uninstall_packages(&theirs, &["nixpkgs-flox.hello".to_string()]).unwrap();
+        let merged = merge_packages(base, &ours, &theirs).unwrap();
+        assert_eq!(packages(&merged), [
+            "nixpkgs-flox.bat",
+            "nixpkgs-flox.jq",
+            "nixpkgs-flox.ripgrep"
+        ]);
+
+        // attributes changed on one side are kept, as are changes outside of packages
+        let package = "nixpkgs-flox.bat".to_string();
+        let ours = set_package_version(base, &package, "0.23.0").unwrap();
+        let theirs = base.replace("{\n", "{\n  shell.hook = \"echo hi\";\n");
+        let merged = merge_packages(base, &ours, &theirs).unwrap();
+        let flox_nix: FloxNix = merged.parse().unwrap();
+        assert_eq!(
+            flox_nix.get(&["packages", "nixpkgs-flox", "bat"]).unwrap(),
+            Some(json!({ "version": "0.23.0" }))
+        );
+        assert_eq!(
+            flox_nix.get(&["shell", "hook"]).unwrap(),
+            Some(json!("echo hi"))
+        );
+
+        // incompatible changes
+        let theirs = set_package_version(base, &package, "0.24.0").unwrap();
+        assert!(matches!(
+            merge_packages(base, &ours, &theirs),
+            Err(FloxNixError::Conflict(attr)) if attr == "packages.nixpkgs-flox.bat"
+        ));
+        let ours = base.replace("{\n", "{\n  shell.hook = \"echo hello\";\n");
+        let theirs = base.replace("{\n", "{\n  shell.hook = \"echo hi\";\n");
+        assert!(matches!(
+            merge_packages(base, &ours, &theirs),
+            Err(FloxNixError::Conflict(attr)) if attr == "shell"
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicates_and_unsupported() {
+        let flox_nix: FloxNix = r#"{ a = import ./a.nix; b.c = 1; }"#.parse().unwrap();
+        assert!(matches!(
+            flox_nix.get(&["a"]),
+            Err(FloxNixError::Unsupported(_))
+        ));
+        assert_eq!(flox_nix.get(&["b", "c"]).unwrap(), Some(json!(1)));
+
+        assert!(matches!(
+            "{ a.b = 1; a.b = 2; }".parse::<FloxNix>(),
+            Err(FloxNixError::DuplicateAttr(_))
+        ));
+        assert!(matches!(
+            "[ ]".parse::<FloxNix>(),
+            Err(FloxNixError::NotAnAttrSet)
+        ));
+    }
+}
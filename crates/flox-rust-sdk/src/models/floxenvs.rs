@@ -0,0 +1,161 @@
+//! Typed result of evaluating the `floxEnvs` output of a project flake
+//!
+//! Instead of asking nix separately whether an environment exists
+//! and which environments there are, `floxEnvs` is evaluated once
+//! with [apply_expression] into a [FloxEnvsOutput].
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::system::System;
+
+/// Version of the [FloxEnvsOutput] schema produced by [apply_expression]
+///
+/// Bump whenever the expression or the types change incompatibly.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Nix function to apply to `floxEnvs`, producing a [FloxEnvsOutput]
+///
+/// Only the environments of `systems` are looked up and,
+/// unless `metadata` is set, only by their names,
+/// so that environments which fail to evaluate or are foreign to a system are still listed.
+/// Metadata is evaluated with `builtins.tryEval` per environment,
+/// which does not catch every evaluation error, e.g. an `abort`.
+pub fn apply_expression(systems: &[System], metadata: bool) -> String {
+    let env = if metadata {
+        r#"env:
+      let description = builtins.tryEval (env.meta.description or null);
+      in { description = if description.success then description.value else null; }"#
+    } else {
+        "_: { }"
+    };
+    let systems = systems
+        .iter()
+        .map(|system| {
+            format!(
+                r#"    "{system}" = builtins.mapAttrs (_: {env}
+    ) (systems."{system}" or {{ }});
+"#
+            )
+        })
+        .collect::<String>();
+    format!(
+        r#"systems: {{
+  version = {SCHEMA_VERSION};
+  systems = {{
+{systems}  }};
+}}"#
+    )
+}
+
+/// The environments of a project: systems → environment names → metadata
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FloxEnvsOutput {
+    pub version: u32,
+    pub systems: BTreeMap<String, BTreeMap<String, FloxEnvMetadata>>,
+}
+
+/// Metadata of a single environment in [FloxEnvsOutput]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FloxEnvMetadata {
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+impl FloxEnvsOutput {
+    /// Parse the result of evaluating `floxEnvs` with [apply_expression]
+    pub fn parse(value: serde_json::Value) -> Result<Self, FloxEnvsOutputError> {
+        let output: FloxEnvsOutput =
+            serde_json::from_value(value).map_err(FloxEnvsOutputError::Parse)?;
+        if output.version != SCHEMA_VERSION {
+            return Err(FloxEnvsOutputError::UnsupportedVersion(output.version));
+        }
+        Ok(output)
+    }
+
+    /// Whether environment `name` exists for `system`
+    pub fn contains(&self, system: &System, name: &str) -> bool {
+        self.metadata(system, name).is_some()
+    }
+
+    /// Metadata of environment `name` for `system`
+    pub fn metadata(&self, system: &System, name: &str) -> Option<&FloxEnvMetadata> {
+        self.systems.get(system.as_str())?.get(name)
+    }
+
+    /// Names of the environments for `system`, sorted
+    pub fn names(&self, system: &System) -> Vec<String> {
+        self.systems
+            .get(system.as_str())
+            .map(|envs| envs.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FloxEnvsOutputError {
+    #[error("Unexpected output of nix: {0}")]
+    Parse(serde_json::Error),
+    #[error("Unsupported floxEnvs schema version {0}, expected {SCHEMA_VERSION}")]
+    UnsupportedVersion(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parses_output() {
+        let output = FloxEnvsOutput::parse(json!({
+            "version": SCHEMA_VERSION,
+            "systems": {
+                "x86_64-linux": {
+                    "default": { "description": "The default environment" },
+                    "dev": { "description": null },
+                },
+                "aarch64-darwin": {},
+            },
+        }))
+        .unwrap();
+
+        assert!(output.contains(&System::X86_64Linux, "dev"));
+        assert!(!output.contains(&System::Aarch64Darwin, "dev"));
+        assert!(!output.contains(&System::Aarch64Linux, "dev"));
+        assert_eq!(output.names(&System::X86_64Linux), ["default", "dev"]);
+        assert!(output.names(&System::Aarch64Darwin).is_empty());
+        assert_eq!(
+            output
+                .metadata(&System::X86_64Linux, "default")
+                .and_then(|meta| meta.description.as_deref()),
+            Some("The default environment")
+        );
+    }
+
+    #[test]
+    fn evaluates_requested_systems() {
+        let expression = apply_expression(&[System::X86_64Linux], false);
+        assert!(expression.contains(r#"systems."x86_64-linux" or { }"#));
+        assert!(!expression.contains("aarch64-darwin"));
+        assert!(!expression.contains("meta"));
+
+        let expression = apply_expression(&[System::X86_64Linux, System::Aarch64Darwin], true);
+        assert!(expression.contains(r#"systems."aarch64-darwin" or { }"#));
+        assert!(expression.contains("builtins.tryEval (env.meta.description or null)"));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let result = FloxEnvsOutput::parse(json!({
+            "version": SCHEMA_VERSION + 1,
+            "systems": {},
+        }));
+        assert!(matches!(
+            result,
+            Err(FloxEnvsOutputError::UnsupportedVersion(_))
+        ));
+    }
+}
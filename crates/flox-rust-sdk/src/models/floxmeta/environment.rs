@@ -1,6 +1,6 @@
 use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use log::debug;
 use runix::installable::Installable;
@@ -13,6 +13,9 @@ use crate::providers::git::{BranchInfo, GitProvider};
 
 pub const METADATA_JSON: &'_ str = "metadata.json";
 
+/// Message of annotated tags marking a generation, followed by the generation number
+const GENERATION_TAG_MESSAGE: &str = "generation ";
+
 #[derive(Serialize, Debug)]
 pub struct Environment<'flox, Git: GitProvider, A: GitAccess<Git>> {
     name: String,
@@ -92,7 +95,7 @@ impl<'flox, Git: GitProvider, A: GitAccess<Git>> Floxmeta<'flox, Git, A> {
         self.environments()
             .await?
             .into_iter()
-            .find(|env| env.name == name && env.system == self.flox.system)
+            .find(|env| env.name == name && env.system == self.flox.system.as_str())
             .ok_or(GetEnvironmentError::NotFound)
     }
 
@@ -102,7 +105,7 @@ impl<'flox, Git: GitProvider, A: GitAccess<Git>> Floxmeta<'flox, Git, A> {
     ) -> Result<Vec<Environment<'flox, Git, ReadOnly<Git>>>, GetEnvironmentsError<Git>> {
         self.access
             .git()
-            .fetch()
+            .fetch_all()
             .await
             .map_err(GetEnvironmentsError::FetchBranches)?;
 
@@ -287,6 +290,85 @@ impl<'flox, Git: GitProvider> Environment<'flox, Git, ReadOnly<Git>> {
     }
 }
 
+/// Implementations for named generations
+///
+/// Named generations are backed by annotated git tags `flox/<env>/<name>`.
+impl<'flox, Git: GitProvider, A: GitAccess<Git>> Environment<'flox, Git, A> {
+    fn generation_tag_prefix(&self) -> String {
+        format!("flox/{}/", self.name)
+    }
+
+    /// List named generations of this environment and the generation they point to
+    pub async fn generation_tags(&self) -> Result<BTreeMap<String, u32>, GenerationTagsError<Git>> {
+        let prefix = self.generation_tag_prefix();
+        let tags = self
+            .floxmeta
+            .access
+            .git()
+            .list_tags()
+            .await
+            .map_err(GenerationTagsError::ListTags)?
+            .into_iter()
+            .filter_map(|tag| {
+                let name = tag.name.strip_prefix(&prefix)?.to_string();
+                let generation = tag
+                    .message
+                    .strip_prefix(GENERATION_TAG_MESSAGE)
+                    .and_then(|generation| generation.parse().ok())
+                    .or_else(|| {
+                        debug!("Tag '{}' does not name a generation, ignoring", tag.name);
+                        None
+                    })?;
+                Some((name, generation))
+            })
+            .collect();
+        Ok(tags)
+    }
+}
+
+impl<'flox, Git: GitProvider> Environment<'flox, Git, ReadOnly<Git>> {
+    /// Name generation `generation` as `name` for later use with [Environment::rollback_to_tag]
+    ///
+    /// The tag points at the commit of the environment's branch that created the generation.
+    pub async fn tag_generation(
+        &self,
+        generation: u32,
+        name: &str,
+    ) -> Result<(), TagGenerationError<Git>> {
+        let metadata = self.metadata().await?;
+        if !metadata.generations.contains_key(&generation.to_string()) {
+            return Err(TagGenerationError::GenerationNotFound(generation));
+        }
+
+        let branch = format!("{}.{}", self.system, self.name);
+        let branch = if self.local.is_some() {
+            branch
+        } else {
+            format!("origin/{branch}")
+        };
+
+        // later commits only update the metadata, the oldest one adding the generation created it
+        let git = self.floxmeta.access.git();
+        let manifest = Path::new(&generation.to_string()).join("manifest.json");
+        let target = git
+            .log_rev(&branch, Some(&manifest), None)
+            .await
+            .map_err(TagGenerationError::Log)?
+            .pop()
+            .ok_or(TagGenerationError::GenerationNotFound(generation))?
+            .rev;
+
+        git.create_tag(
+            &format!("{}{name}", self.generation_tag_prefix()),
+            &target,
+            Some(&format!("{GENERATION_TAG_MESSAGE}{generation}")),
+        )
+        .await
+        .map_err(TagGenerationError::CreateTag)?;
+        Ok(())
+    }
+}
+
 /// Implementations for R/O only instances
 ///
 /// Mainly transformation into modifiable sandboxed instances
@@ -322,6 +404,51 @@ impl<'flox, Git: GitProvider> Environment<'flox, Git, GitSandBox<Git>> {
             floxmeta,
         })
     }
+
+    /// Make the generation named `name` the current generation
+    ///
+    /// Changes are staged and applied with [Environment::commit_transaction].
+    pub async fn rollback_to_tag(&self, name: &str) -> Result<(), RollbackToTagError<Git>> {
+        let generation = self
+            .generation_tags()
+            .await?
+            .remove(name)
+            .ok_or_else(|| RollbackToTagError::TagNotFound(name.to_string()))?;
+
+        let git = self.floxmeta.access.git();
+        let branch = format!("{}.{}", self.system, self.name);
+        git.checkout(&branch, false)
+            .await
+            .map_err(|e| RollbackToTagError::GitCheckout(branch, e))?;
+
+        let metadata_path = git
+            .workdir()
+            .expect("Workdir should exist during transaction")
+            .join(METADATA_JSON);
+        let metadata_str = tokio::fs::read_to_string(&metadata_path)
+            .await
+            .map_err(RollbackToTagError::ReadMetadata)?;
+        let mut metadata: Metadata =
+            serde_json::from_str(&metadata_str).map_err(RollbackToTagError::ParseMetadata)?;
+
+        if !metadata.generations.contains_key(&generation.to_string()) {
+            return Err(RollbackToTagError::GenerationNotFound(generation));
+        }
+        metadata.current_gen = Some(generation.to_string());
+
+        tokio::fs::write(
+            &metadata_path,
+            serde_json::to_string_pretty(&metadata)
+                .map_err(RollbackToTagError::SerializeMetadata)?,
+        )
+        .await
+        .map_err(RollbackToTagError::WriteMetadata)?;
+
+        git.add(&[Path::new(METADATA_JSON)])
+            .await
+            .map_err(RollbackToTagError::GitAdd)?;
+        Ok(())
+    }
 }
 
 #[derive(Error, Debug)]
@@ -383,3 +510,155 @@ pub enum ManifestError<Git: GitProvider> {
     #[error("Failed parsing 'manifest.json': {0}")]
     ParseManifest(serde_json::Error),
 }
+
+#[derive(Error, Debug)]
+pub enum GenerationTagsError<Git: GitProvider> {
+    #[error("Failed listing tags: {0}")]
+    ListTags(Git::TagError),
+}
+
+#[derive(Error, Debug)]
+pub enum TagGenerationError<Git: GitProvider> {
+    #[error(transparent)]
+    Metadata(#[from] MetadataError<Git>),
+
+    #[error("Generation {0} not found")]
+    GenerationNotFound(u32),
+
+    #[error("Failed finding the commit of the generation: {0}")]
+    Log(Git::LogError),
+
+    #[error("Failed creating tag: {0}")]
+    CreateTag(Git::TagError),
+}
+
+#[derive(Error, Debug)]
+pub enum RollbackToTagError<Git: GitProvider> {
+    #[error(transparent)]
+    GenerationTags(#[from] GenerationTagsError<Git>),
+
+    #[error("No generation named '{0}'")]
+    TagNotFound(String),
+
+    #[error("Generation {0} not found")]
+    GenerationNotFound(u32),
+
+    #[error("Failed checking out branch '{0}': {1}")]
+    GitCheckout(String, Git::CheckoutError),
+
+    #[error("Failed reading 'metadata.json': {0}")]
+    ReadMetadata(std::io::Error),
+
+    #[error("Failed parsing 'metadata.json': {0}")]
+    ParseMetadata(serde_json::Error),
+
+    #[error("Failed serializing 'metadata.json': {0}")]
+    SerializeMetadata(serde_json::Error),
+
+    #[error("Failed writing 'metadata.json': {0}")]
+    WriteMetadata(std::io::Error),
+
+    #[error("Failed staging 'metadata.json': {0}")]
+    GitAdd(Git::AddError),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::marker::PhantomData;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::flox::Flox;
+    use crate::providers::git::GitCommandProvider;
+
+    /// Commit metadata listing `generations`, adding the manifest of the last one
+    async fn commit_generations(
+        git: &GitCommandProvider,
+        current: u32,
+        generations: &[u32],
+    ) -> String {
+        let workdir = git.workdir().unwrap();
+        let metadata = json!({
+            "currentGen": current.to_string(),
+            "generations": generations
+                .iter()
+                .map(|generation| {
+                    (generation.to_string(), json!({
+                        "created": 0,
+                        "lastActive": 0,
+                        "logMessage": [],
+                        "path": "/nix/store/environment",
+                    }))
+                })
+                .collect::<serde_json::Map<_, _>>(),
+        });
+        std::fs::write(workdir.join(METADATA_JSON), metadata.to_string()).unwrap();
+        let last = generations.last().unwrap().to_string();
+        std::fs::create_dir_all(workdir.join(&last)).unwrap();
+        std::fs::write(
+            workdir.join(&last).join("manifest.json"),
+            r#"{ "version": 2, "elements": [] }"#,
+        )
+        .unwrap();
+
+        git.add(&[Path::new(".")]).await.unwrap();
+        git.commit(&format!("generation {current}")).await.unwrap();
+        git.log(None, Some(1)).await.unwrap().remove(0).rev
+    }
+
+    fn tagged_rev(git: &GitCommandProvider, tag: &str) -> String {
+        let output = std::process::Command::new(env!("GIT_BIN"))
+            .arg("-C")
+            .arg(git.path())
+            .args(["rev-parse", &format!("{tag}^{{commit}}")])
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    #[tokio::test]
+    async fn tags_the_commit_of_a_generation() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let git = GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        let branch = format!("{}.default", flox.system.as_str());
+        git.checkout(&branch, true).await.unwrap();
+
+        let first = commit_generations(&git, 1, &[1]).await;
+        commit_generations(&git, 2, &[1, 2]).await;
+        // switching back to generation 1 only changes the metadata
+        let head = commit_generations(&git, 1, &[1, 2]).await;
+
+        let environment = Environment {
+            name: "default".to_string(),
+            system: flox.system.as_str().to_string(),
+            remote: None,
+            local: Some(EnvBranch {
+                description: String::new(),
+                hash: head,
+            }),
+            floxmeta: Floxmeta {
+                flox: &flox,
+                owner: "owner".to_string(),
+                access: ReadOnly::new(git),
+                _git: PhantomData,
+            },
+        };
+
+        environment.tag_generation(1, "known-good").await.unwrap();
+
+        let git = environment.floxmeta.access.git();
+        assert_eq!(tagged_rev(git, "flox/default/known-good"), first);
+        assert_eq!(
+            environment.generation_tags().await.unwrap(),
+            BTreeMap::from([("known-good".to_string(), 1)])
+        );
+        assert!(matches!(
+            environment.tag_generation(3, "missing").await,
+            Err(TagGenerationError::GenerationNotFound(3))
+        ));
+    }
+}
@@ -154,14 +154,22 @@ impl<'flox, Git: GitProvider> Floxmeta<'flox, Git, ReadOnly<Git>> {
         let transaction_temp_dir =
             TempDir::new_in(&self.flox.temp_dir).map_err(TransactionEnterError::CreateTempdir)?;
 
-        let transaction_git =
-            Git::clone(self.access.git().path(), transaction_temp_dir.path(), false)
-                .await
-                .map_err(TransactionEnterError::GitClone)?;
-
-        let sandbox = self
-            .access
-            .to_sandbox_in(transaction_temp_dir, transaction_git);
+        let transaction_git = Git::clone_with(
+            self.access.git().options(),
+            self.access.git().path(),
+            transaction_temp_dir.path(),
+            false,
+        )
+        .await
+        .map_err(TransactionEnterError::GitClone)?;
+
+        let sandbox = if self.flox.keep_sandboxes {
+            self.access
+                .to_kept_sandbox_in(transaction_temp_dir, transaction_git)
+        } else {
+            self.access
+                .to_sandbox_in(transaction_temp_dir, transaction_git)
+        };
 
         Ok(Floxmeta {
             owner: self.owner.clone(),
@@ -361,6 +369,7 @@ mod floxmeta_tests {
     use tempfile::TempDir;
 
     use super::*;
+    use crate::models::system::System;
     use crate::providers::git::GitCommandProvider;
 
     fn flox_instance() -> (Flox, TempDir) {
@@ -373,7 +382,7 @@ mod floxmeta_tests {
         std::fs::create_dir_all(&temp_dir).unwrap();
 
         let flox = Flox {
-            system: "aarch64-darwin".to_string(),
+            system: System::Aarch64Darwin,
             cache_dir,
             temp_dir,
             ..Default::default()
@@ -414,7 +423,7 @@ mod floxmeta_tests {
         git.add_remote("origin", "https://github.com/flox/floxmeta")
             .await
             .expect("Failed adding origin");
-        git.fetch().await.expect("Failed fetching origin");
+        git.fetch_all().await.expect("Failed fetching origin");
 
         let environments = floxmeta
             .environments()
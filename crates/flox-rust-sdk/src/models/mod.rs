@@ -1,12 +1,23 @@
 //# An attempt at defining a domain model for flox
 
+pub mod audit;
 pub mod channels;
 pub mod environment;
 pub mod environment_ref;
+pub mod events;
+pub mod flake_fetch;
+pub mod flake_registry;
 pub mod flox_installable;
+pub mod flox_nix;
 pub mod flox_package;
+pub mod floxenvs;
+pub mod policy;
+pub mod resolver;
 pub mod root;
 pub use runix::{flake_ref, registry};
 pub mod floxmeta;
 pub mod project;
 pub mod stability;
+pub mod store_copy;
+pub mod system;
+pub mod verbosity;
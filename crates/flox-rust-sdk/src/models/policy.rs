@@ -0,0 +1,165 @@
+//! Restrictions on the packages that can be installed into environments
+//!
+//! If [Flox::install_policy](crate::flox::Flox::install_policy) is set,
+//! it is consulted for every package before it is installed,
+//! e.g. to only allow packages from a curated channel.
+
+use std::fmt;
+use std::sync::Arc;
+
+use regex::Regex;
+use runix::installable::Installable;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::flox_package::FloxPackage;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Allow,
+    Deny { reason: String },
+}
+
+/// Decides whether a package may be installed
+///
+/// Clones share the same callback.
+#[derive(Clone)]
+pub struct InstallPolicy(Arc<dyn Fn(&Installable) -> PolicyDecision + Send + Sync>);
+
+impl InstallPolicy {
+    pub fn new(decide: impl Fn(&Installable) -> PolicyDecision + Send + Sync + 'static) -> Self {
+        InstallPolicy(Arc::new(decide))
+    }
+
+    pub fn decide(&self, installable: &Installable) -> PolicyDecision {
+        (self.0)(installable)
+    }
+
+    /// Fail with the first of `packages` that is denied
+    pub fn check(&self, packages: &[FloxPackage]) -> Result<(), PolicyDenied> {
+        for package in packages {
+            if let PolicyDecision::Deny { reason } = self.decide(&package_installable(package)) {
+                return Err(PolicyDenied {
+                    package: package.clone(),
+                    reason,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for InstallPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("InstallPolicy")
+    }
+}
+
+/// Allow and deny lists of package patterns, as read from the flox config
+///
+/// Patterns are matched against `<flakeref>.<attr_path>` of an installable,
+/// for packages of a flox.nix that is `<channel>.<name>`, e.g. `nixpkgs-flox.python3*`.
+/// `*` matches any number of characters and `?` a single character.
+///
+/// A package is denied if it matches a deny pattern,
+/// or if there are allow patterns and it matches none of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackagePolicy {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl PackagePolicy {
+    pub fn decide(&self, installable: &Installable) -> PolicyDecision {
+        let package = format!("{}.{}", installable.flakeref, installable.attr_path);
+        let matching = |patterns: &[String]| {
+            patterns
+                .iter()
+                .find(|pattern| glob_matches(pattern, &package))
+                .cloned()
+        };
+
+        if let Some(pattern) = matching(&self.deny) {
+            return PolicyDecision::Deny {
+                reason: format!("'{package}' matches the denied pattern '{pattern}'"),
+            };
+        }
+        if !self.allow.is_empty() && matching(&self.allow).is_none() {
+            return PolicyDecision::Deny {
+                reason: format!("'{package}' matches none of the allowed patterns"),
+            };
+        }
+        PolicyDecision::Allow
+    }
+
+    /// Policy applying these lists, [None] if both are empty
+    pub fn into_install_policy(self) -> Option<InstallPolicy> {
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return None;
+        }
+        Some(InstallPolicy::new(move |installable| {
+            self.decide(installable)
+        }))
+    }
+}
+
+/// Installable of a package declared as `<channel>.<name>` in a flox.nix
+pub fn package_installable(package: &FloxPackage) -> Installable {
+    let (channel, name) = package.split_once('.').unwrap_or(("", package));
+    Installable::new(channel.to_string(), name.to_string())
+}
+
+fn glob_matches(pattern: &str, subject: &str) -> bool {
+    let regex = pattern
+        .split('*')
+        .map(|part| {
+            part.split('?')
+                .map(regex::escape)
+                .collect::<Vec<_>>()
+                .join(".")
+        })
+        .collect::<Vec<_>>()
+        .join(".*");
+
+    Regex::new(&format!("^{regex}$"))
+        .expect("escaped pattern should be a valid regex")
+        .is_match(subject)
+}
+
+#[derive(Error, Debug)]
+#[error("Installing {package} is not allowed: {reason}")]
+pub struct PolicyDenied {
+    pub package: FloxPackage,
+    pub reason: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_allow_and_deny_lists() {
+        let policy = PackagePolicy {
+            allow: vec!["curated.*".to_string()],
+            deny: vec!["curated.python2?".to_string()],
+        }
+        .into_install_policy()
+        .unwrap();
+
+        assert!(policy.check(&["curated.hello".to_string()]).is_ok());
+
+        let denied = policy
+            .check(&["curated.hello".to_string(), "curated.python27".to_string()])
+            .unwrap_err();
+        assert_eq!(denied.package, "curated.python27");
+
+        let denied = policy
+            .check(&["nixpkgs-flox.hello".to_string()])
+            .unwrap_err();
+        assert_eq!(denied.package, "nixpkgs-flox.hello");
+
+        assert!(PackagePolicy::default().into_install_policy().is_none());
+    }
+}
@@ -0,0 +1,584 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::future::Future;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use log::{debug, info, warn};
+use regex::Regex;
+use runix::command::Eval;
+use runix::command_line::NixCommandLine;
+use runix::installable::Installable;
+use runix::{NixBackend, RunJson};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Semaphore;
+
+use super::environment::{BuildEnvironmentError, Environment, EvalOptions};
+use super::{GetEnvironmentsError, Project, ProjectError};
+use crate::flox::FloxNixApi;
+use crate::models::events::{render_nix_log_line, NixDownloads};
+use crate::models::root::transaction::GitAccess;
+use crate::models::store_copy::{self, NixCopyError};
+use crate::models::system::System;
+use crate::providers::fs::FileSystem;
+use crate::providers::git::GitProvider;
+use crate::utils::errors::FloxErrorCode;
+
+/// Nix activity type of a derivation build (`actBuild`)
+const ACTIVITY_BUILD: u64 = 105;
+/// Nix activity type of a store path substitution (`actSubstitute`)
+const ACTIVITY_SUBSTITUTE: u64 = 108;
+
+/// Metrics collected from a nix build
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildMetrics {
+    pub wall_time: Duration,
+    /// Number of derivations that had to be built locally
+    pub derivations_built: usize,
+    /// Number of store paths fetched from a binary cache
+    pub paths_substituted: usize,
+}
+
+impl BuildMetrics {
+    /// Whether the build was served entirely from caches
+    pub fn cache_hit(&self) -> bool {
+        self.derivations_built == 0
+    }
+
+    /// Record a line of nix's `--log-format internal-json` output
+    ///
+    /// Lines that are not activity starts are ignored.
+    fn record_log_line(&mut self, line: &str) {
+        #[derive(Deserialize)]
+        #[serde(tag = "action", rename_all = "camelCase")]
+        enum LogEvent {
+            Start {
+                #[serde(rename = "type")]
+                activity_type: u64,
+            },
+            #[serde(other)]
+            Other,
+        }
+
+        let event = match line
+            .strip_prefix("@nix ")
+            .and_then(|json| serde_json::from_str(json).ok())
+        {
+            Some(event) => event,
+            None => return,
+        };
+
+        match event {
+            LogEvent::Start {
+                activity_type: ACTIVITY_BUILD,
+            } => self.derivations_built += 1,
+            LogEvent::Start {
+                activity_type: ACTIVITY_SUBSTITUTE,
+            } => self.paths_substituted += 1,
+            _ => {},
+        }
+    }
+}
+
+/// Result of [Project::build]
+#[derive(Debug, Default)]
+pub struct BuildResult {
+    /// Only collected if requested
+    pub metrics: Option<BuildMetrics>,
+    /// File the nix log was written to
+    pub log_file: PathBuf,
+}
+
+/// Outcome of [Project::build_all]
+#[derive(Debug, Default)]
+pub struct BuildAllReport {
+    /// Store paths of the environments that were built, by name
+    pub built: BTreeMap<String, PathBuf>,
+    /// Environments that failed to build, by name
+    pub failed: BTreeMap<String, BuildEnvironmentError>,
+}
+
+impl BuildAllReport {
+    /// Whether all selected environments were built
+    pub fn succeeded(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// A store path in the closure of an environment
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorePath {
+    pub path: PathBuf,
+    /// Size of the path's NAR serialisation in bytes
+    pub nar_size: u64,
+}
+
+/// Runtime closure of an environment, see [Environment::closure]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Closure {
+    /// All store paths the environment references, sorted
+    pub paths: Vec<StorePath>,
+}
+
+impl Closure {
+    /// Sum of the NAR sizes of all paths in bytes
+    pub fn total_size(&self) -> u64 {
+        self.paths.iter().map(|path| path.nar_size).sum()
+    }
+}
+
+impl<Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem> Environment<'_, Git, Access, Fs> {
+    /// Build this environment and list all store paths its outputs reference at runtime
+    ///
+    /// The closure can be copied to another store or binary cache,
+    /// e.g. with `nix copy --to <store>` and the paths listed here.
+    pub async fn closure(&self) -> Result<Closure, ClosureError> {
+        let outputs = self.build_outputs().await?;
+
+        // make sure nix is configured like for any other flox invocation
+        let nix: NixCommandLine = self.project.flox.nix(Default::default());
+
+        let output = nix
+            .command(&["path-info"])
+            .args(["--recursive", "--json"])
+            .args(&outputs)
+            .output()
+            .await
+            .map_err(ClosureError::Spawn)?;
+
+        if !output.status.success() {
+            return Err(ClosureError::BadExit(
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        let paths = parse_path_info(&output.stdout).map_err(ClosureError::Parse)?;
+        Ok(Closure { paths })
+    }
+
+    /// Build this environment and copy it with its closure to the store at `store_uri`,
+    /// e.g. a binary cache or `ssh-ng://<host>`
+    ///
+    /// If [Flox::event_sink](crate::flox::Flox::event_sink) is set, progress is reported as
+    /// [FloxEvent::StoreCopyProgress](crate::models::events::FloxEvent::StoreCopyProgress).
+    pub async fn copy_to(&self, store_uri: &str) -> Result<(), CopyToError> {
+        let outputs = self.build_outputs().await?;
+
+        let mut args = vec![OsString::from("--to"), OsString::from(store_uri)];
+        args.extend(outputs.into_iter().map(OsString::from));
+        store_copy::nix_copy(self.project.flox, args).await?;
+        Ok(())
+    }
+}
+
+/// Parse the output of `nix path-info --json`
+///
+/// Nix 2.19 changed the output from a list of objects with a `path`
+/// to an object keyed by path, both are accepted.
+pub(super) fn parse_path_info(json: &[u8]) -> Result<Vec<StorePath>, serde_json::Error> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct PathInfo {
+        nar_size: u64,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum PathInfos {
+        List(Vec<StorePath>),
+        Map(BTreeMap<PathBuf, PathInfo>),
+    }
+
+    let mut paths = match serde_json::from_slice(json)? {
+        PathInfos::List(paths) => paths,
+        PathInfos::Map(infos) => infos
+            .into_iter()
+            .map(|(path, info)| StorePath {
+                path,
+                nar_size: info.nar_size,
+            })
+            .collect(),
+    };
+    paths.sort_by(|a, b| a.path.cmp(&b.path));
+    paths.dedup_by(|a, b| a.path == b.path);
+    Ok(paths)
+}
+
+impl<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem>
+    Project<'flox, Git, Access, Fs>
+{
+    /// Build packages of this project
+    ///
+    /// Environments are built with [Environment::build](super::environment::Environment::build)
+    /// instead, which takes care of locking, caching and gc roots.
+    ///
+    /// If `collect_metrics` is set, nix' structured log is parsed
+    /// to report how many derivations were built or substituted.
+    /// If [Flox::event_sink](crate::flox::Flox::event_sink) is set,
+    /// downloads are reported as
+    /// [FloxEvent::DownloadProgress](crate::models::events::FloxEvent::DownloadProgress).
+    ///
+    /// The nix log is forwarded to [log] and written to `log_file`,
+    /// or a [new log file](crate::flox::Flox::new_log_file) if none is given.
+    /// Either shows what nix prints, even if its structured log is parsed.
+    /// The log is kept if the build fails.
+    pub async fn build(
+        &self,
+        packages: &[&str],
+        collect_metrics: bool,
+        log_file: Option<PathBuf>,
+    ) -> Result<BuildResult, ProjectBuildError> {
+        // make sure nix is configured like for any other flox invocation
+        let nix: NixCommandLine = self.flox.nix(Default::default());
+
+        let mut command = nix.command(&["build"]);
+        command.arg("--no-link");
+
+        if collect_metrics || self.flox.event_sink.is_some() {
+            command.args(["--log-format", "internal-json"]);
+        }
+        command.stderr(Stdio::piped());
+
+        let flakeref = self.flakeref().await?;
+        for package in packages {
+            command.arg(
+                Installable::new(
+                    flakeref.clone(),
+                    format!(".packages.{}.{package:?}", self.flox.system),
+                )
+                .to_string(),
+            );
+        }
+
+        let log_file = log_file.unwrap_or_else(|| self.flox.new_log_file());
+        if let Some(parent) = log_file.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| ProjectBuildError::WriteLog(log_file.clone(), e))?;
+        }
+        let mut log_writer = tokio::fs::File::create(&log_file)
+            .await
+            .map_err(|e| ProjectBuildError::WriteLog(log_file.clone(), e))?;
+
+        let start = Instant::now();
+        let mut child = command.spawn().map_err(ProjectBuildError::Spawn)?;
+
+        let mut metrics = BuildMetrics::default();
+        let mut downloads = NixDownloads::default();
+        if let Some(stderr) = child.stderr.take() {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Some(line) = lines.next_line().await.map_err(ProjectBuildError::Log)? {
+                metrics.record_log_line(&line);
+                if let Some(sink) = &self.flox.event_sink {
+                    if let Some(event) = downloads.record_log_line(&line) {
+                        sink.emit(&event);
+                    }
+                }
+                let message = match render_nix_log_line(&line, self.flox.verbosity) {
+                    Some(message) => message,
+                    None => continue,
+                };
+                debug!("{message}");
+                log_writer
+                    .write_all(format!("{message}\n").as_bytes())
+                    .await
+                    .map_err(|e| ProjectBuildError::WriteLog(log_file.clone(), e))?;
+            }
+        }
+        log_writer
+            .flush()
+            .await
+            .map_err(|e| ProjectBuildError::WriteLog(log_file.clone(), e))?;
+
+        let status = child.wait().await.map_err(ProjectBuildError::Spawn)?;
+        if !status.success() {
+            return Err(ProjectBuildError::BadExit(
+                status.code().unwrap_or(-1),
+                log_file,
+            ));
+        }
+
+        metrics.wall_time = start.elapsed();
+
+        Ok(BuildResult {
+            metrics: collect_metrics.then_some(metrics),
+            log_file,
+        })
+    }
+}
+
+impl<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem>
+    Project<'flox, Git, Access, Fs>
+{
+    /// Build all environments of this project whose name matches `filter`
+    ///
+    /// `filter` is a glob pattern where `*` matches any sequence of characters
+    /// and `?` any single character; without a filter all environments are built.
+    /// Up to [Flox::max_parallel_builds](crate::flox::Flox::max_parallel_builds)
+    /// environments are built at once.
+    /// A failing environment does not stop the others from being built,
+    /// failures are collected in the report instead.
+    pub async fn build_all<Nix: FloxNixApi>(
+        &'flox self,
+        filter: Option<&str>,
+    ) -> Result<BuildAllReport, BuildAllError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let environments = self
+            .environments::<Nix>()
+            .await
+            .map_err(BuildAllError::Environments)?;
+
+        Ok(build_environments(environments, filter, self.flox.max_parallel_builds).await)
+    }
+
+    /// Like [Self::build_all], but for each of `systems` rather than
+    /// [Flox::system](crate::flox::Flox::system) only
+    ///
+    /// Systems are built one after the other,
+    /// building for a foreign system requires a remote builder for it.
+    pub async fn build_all_for<Nix: FloxNixApi>(
+        &'flox self,
+        filter: Option<&str>,
+        systems: &[System],
+    ) -> Result<BTreeMap<System, BuildAllReport>, BuildAllError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let environments = self
+            .environments_for::<Nix>(systems, EvalOptions::default())
+            .await
+            .map_err(BuildAllError::Environments)?;
+
+        let mut reports = BTreeMap::new();
+        for (system, environments) in environments {
+            let report =
+                build_environments(environments, filter, self.flox.max_parallel_builds).await;
+            reports.insert(system, report);
+        }
+        Ok(reports)
+    }
+}
+
+/// Build `environments` whose name matches the glob `filter`, see [Project::build_all]
+async fn build_environments<Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem>(
+    environments: Vec<Environment<'_, Git, Access, Fs>>,
+    filter: Option<&str>,
+    max_parallel_builds: usize,
+) -> BuildAllReport {
+    let filter = filter.map(glob_regex);
+    let environments = environments
+        .into_iter()
+        .filter(|environment| match &filter {
+            Some(filter) => filter.is_match(&environment.name()),
+            None => true,
+        });
+
+    let results = run_limited(
+        environments,
+        max_parallel_builds,
+        |environment| async move {
+            let result = environment.build().await;
+            (environment.name().to_string(), result)
+        },
+    )
+    .await;
+
+    let mut report = BuildAllReport::default();
+    for (name, result) in results {
+        match result {
+            Ok(store_path) => {
+                report.built.insert(name, store_path);
+            },
+            Err(e) => {
+                warn!("Failed to build environment {name}: {e}");
+                report.failed.insert(name, e);
+            },
+        }
+    }
+
+    info!(
+        "Built {} environments, {} failed",
+        report.built.len(),
+        report.failed.len()
+    );
+    report
+}
+
+/// Run `task` for all `items`, with at most `limit` (at least one) tasks running at a time
+async fn run_limited<T, R, Fut>(
+    items: impl IntoIterator<Item = T>,
+    limit: usize,
+    task: impl Fn(T) -> Fut,
+) -> Vec<R>
+where
+    Fut: Future<Output = R>,
+{
+    let semaphore = Semaphore::new(limit.max(1));
+    let (semaphore, task) = (&semaphore, &task);
+    join_all(items.into_iter().map(|item| async move {
+        let _permit = semaphore
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        task(item).await
+    }))
+    .await
+}
+
+/// Translate a glob `pattern` into a regex matching whole names
+fn glob_regex(pattern: &str) -> Regex {
+    let regex = regex::escape(pattern)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    Regex::new(&format!("^{regex}$")).expect("escaped glob is a valid regex")
+}
+
+#[derive(Error, Debug)]
+pub enum ProjectBuildError {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+    #[error("Failed to read nix build log: {0}")]
+    Log(std::io::Error),
+    #[error("Failed to write nix build log {0:?}: {1}")]
+    WriteLog(PathBuf, std::io::Error),
+    #[error("Nix build failed with exit code {0}, see {1:?} for the full log")]
+    BadExit(i32, PathBuf),
+}
+
+#[derive(Error, Debug)]
+pub enum ClosureError {
+    #[error(transparent)]
+    Build(#[from] BuildEnvironmentError),
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+    #[error("Nix path-info failed with exit code {0}:\n{1}")]
+    BadExit(i32, String),
+    #[error("Failed to parse nix path-info output: {0}")]
+    Parse(serde_json::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum CopyToError {
+    #[error(transparent)]
+    Build(#[from] BuildEnvironmentError),
+    #[error(transparent)]
+    Copy(#[from] NixCopyError),
+}
+
+impl CopyToError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            CopyToError::Build(e) => e.code(),
+            CopyToError::Copy(e) => e.code(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BuildAllError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error("Could not list environments: {0}")]
+    Environments(GetEnvironmentsError<Nix>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_internal_json_log() {
+        let log = [
+            r#"@nix {"action":"start","id":1,"level":0,"type":104,"text":"","fields":[]}"#,
+            r#"@nix {"action":"start","id":2,"level":4,"type":108,"text":"copying path","fields":["/nix/store/a"]}"#,
+            r#"@nix {"action":"start","id":3,"level":4,"type":108,"text":"copying path","fields":["/nix/store/b"]}"#,
+            r#"@nix {"action":"start","id":4,"level":3,"type":105,"text":"building","fields":["/nix/store/c.drv"]}"#,
+            r#"@nix {"action":"result","id":4,"type":101,"fields":["hello"]}"#,
+            r#"@nix {"action":"stop","id":4}"#,
+            "some unstructured output",
+        ];
+
+        let mut metrics = BuildMetrics::default();
+        log.iter().for_each(|line| metrics.record_log_line(line));
+
+        assert_eq!(metrics.derivations_built, 1);
+        assert_eq!(metrics.paths_substituted, 2);
+        assert!(!metrics.cache_hit());
+    }
+
+    #[test]
+    fn parses_path_info() {
+        let expected = vec![
+            StorePath {
+                path: PathBuf::from("/nix/store/a-glibc"),
+                nar_size: 30,
+            },
+            StorePath {
+                path: PathBuf::from("/nix/store/b-hello"),
+                nar_size: 12,
+            },
+        ];
+
+        let list = br#"[
+            {"path": "/nix/store/b-hello", "narSize": 12, "references": ["/nix/store/a-glibc"]},
+            {"path": "/nix/store/a-glibc", "narSize": 30, "references": []}
+        ]"#;
+        let paths = parse_path_info(list).unwrap();
+        assert_eq!(paths, expected);
+        assert_eq!(Closure { paths }.total_size(), 42);
+
+        let map = br#"{
+            "/nix/store/b-hello": {"narSize": 12, "references": ["/nix/store/a-glibc"]},
+            "/nix/store/a-glibc": {"narSize": 30, "references": []}
+        }"#;
+        assert_eq!(parse_path_info(map).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_tasks() {
+        use std::cell::Cell;
+
+        let running = Cell::new(0);
+        let max_running = Cell::new(0);
+
+        let results = run_limited(0..6, 2, |item| {
+            let (running, max_running) = (&running, &max_running);
+            async move {
+                running.set(running.get() + 1);
+                max_running.set(max_running.get().max(running.get()));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                running.set(running.get() - 1);
+                item * 2
+            }
+        })
+        .await;
+
+        assert_eq!(results, [0, 2, 4, 6, 8, 10]);
+        assert_eq!(max_running.get(), 2);
+    }
+
+    #[test]
+    fn matches_globs() {
+        let filter = glob_regex("dev-*");
+        assert!(filter.is_match("dev-"));
+        assert!(filter.is_match("dev-python"));
+        assert!(!filter.is_match("default"));
+        assert!(!filter.is_match("my-dev-python"));
+
+        let filter = glob_regex("ci.?");
+        assert!(filter.is_match("ci.1"));
+        assert!(!filter.is_match("ci-1"));
+        assert!(!filter.is_match("ci.10"));
+    }
+}
@@ -0,0 +1,181 @@
+use runix::arguments::EvalArgs;
+use runix::command::Eval;
+use runix::installable::Installable;
+use runix::RunJson;
+use thiserror::Error;
+
+use super::show::{FlakeOutput, FlakeOutputs, FlakeShowError};
+use super::{Project, ProjectError};
+use crate::flox::FloxNixApi;
+use crate::models::root::transaction::GitAccess;
+use crate::providers::fs::FileSystem;
+use crate::providers::git::GitProvider;
+
+/// Outcome of a single output checked by [Project::validate]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    /// Attribute path of the output, e.g. `checks.x86_64-linux."test"`
+    pub output: String,
+    /// Error output of nix if the check failed
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Result of [Project::validate]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl CheckReport {
+    /// Whether all outputs passed
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(CheckResult::passed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.results.iter().filter(|result| !result.passed())
+    }
+}
+
+impl<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem>
+    Project<'flox, Git, Access, Fs>
+{
+    /// Check that the project's flake evaluates and its checks build
+    ///
+    /// Similar to `nix flake check`, restricted to the current system:
+    /// every flox environment is evaluated and every output in `checks` is built.
+    /// If the flake itself does not evaluate, the report contains a single failed `flake` result.
+    ///
+    /// Failing outputs are recorded in the report,
+    /// errors are only returned if nix could not be run.
+    pub async fn validate<Nix: FloxNixApi>(&self) -> Result<CheckReport, ValidateError>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let outputs = match self.flake_show::<Nix>().await {
+            Ok(outputs) => outputs,
+            Err(FlakeShowError::BadExit(_, stderr)) => {
+                return Ok(CheckReport {
+                    results: vec![CheckResult {
+                        output: "flake".to_string(),
+                        error: Some(stderr),
+                    }],
+                })
+            },
+            Err(e) => return Err(ValidateError::Show(e)),
+        };
+
+        let system = self.flox.system.as_str();
+        let flakeref = self.flakeref().await?;
+        let mut report = CheckReport::default();
+
+        let nix = self.flox.nix::<Nix>(Default::default());
+        for name in system_outputs(&outputs, "floxEnvs", system) {
+            let output = format!("floxEnvs.{system}.{name:?}");
+            let eval = Eval {
+                eval_args: EvalArgs {
+                    apply: Some("env: env.drvPath".to_string().into()),
+                    installable: Some(
+                        Installable::new(flakeref.clone(), format!(".{output}")).into(),
+                    ),
+                },
+                ..Eval::default()
+            };
+
+            let error = eval
+                .run_json(&nix, &Default::default())
+                .await
+                .err()
+                .map(|e| e.to_string());
+            report.results.push(CheckResult { output, error });
+        }
+
+        for name in system_outputs(&outputs, "checks", system) {
+            let output = format!("checks.{system}.{name:?}");
+            let build = nix
+                .command(&["build"])
+                .arg("--no-link")
+                .arg(Installable::new(flakeref.clone(), format!(".{output}")).to_string())
+                .output()
+                .await
+                .map_err(ValidateError::Spawn)?;
+
+            let error = (!build.status.success())
+                .then(|| String::from_utf8_lossy(&build.stderr).to_string());
+            report.results.push(CheckResult { output, error });
+        }
+
+        Ok(report)
+    }
+}
+
+/// Names of the attributes of `outputs.<output>.<system>`
+pub(super) fn system_outputs(outputs: &FlakeOutputs, output: &str, system: &str) -> Vec<String> {
+    match outputs.get(output) {
+        Some(FlakeOutput::Attrs(systems)) => match systems.get(system) {
+            Some(FlakeOutput::Attrs(attrs)) => attrs.keys().cloned().collect(),
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ValidateError {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to list flake outputs: {0}")]
+    Show(FlakeShowError),
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_outputs_of_system() {
+        let outputs: FlakeOutputs = serde_json::from_str(
+            r#"{
+                "checks": {
+                    "x86_64-linux": {
+                        "fmt": { "type": "derivation", "name": "fmt" },
+                        "test": { "type": "derivation", "name": "test" }
+                    },
+                    "aarch64-darwin": {}
+                },
+                "lib": { "type": "unknown" }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(system_outputs(&outputs, "checks", "x86_64-linux"), [
+            "fmt", "test"
+        ]);
+        assert!(system_outputs(&outputs, "checks", "aarch64-darwin").is_empty());
+        assert!(system_outputs(&outputs, "floxEnvs", "x86_64-linux").is_empty());
+        assert!(system_outputs(&outputs, "lib", "x86_64-linux").is_empty());
+
+        let report = CheckReport {
+            results: vec![
+                CheckResult {
+                    output: "checks.x86_64-linux.\"fmt\"".to_string(),
+                    error: None,
+                },
+                CheckResult {
+                    output: "checks.x86_64-linux.\"test\"".to_string(),
+                    error: Some("test failed".to_string()),
+                },
+            ],
+        };
+        assert!(!report.passed());
+        assert_eq!(report.failures().count(), 1);
+    }
+}
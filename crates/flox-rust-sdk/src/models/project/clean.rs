@@ -0,0 +1,495 @@
+//! Selectively removing state flox keeps for a project, see [Project::clean]
+
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use fslock::LockFile;
+use thiserror::Error;
+
+use super::composition::CompositionError;
+use super::environment::{
+    self,
+    Environment,
+    EvalOptions,
+    PruneGcRootsError,
+    PruneReport,
+    ReadFloxNixError,
+};
+use super::{
+    persistent_sandbox,
+    FileAction,
+    Project,
+    ProjectError,
+    TransactionCommitError,
+    TransactionEnterError,
+    TransactionOptions,
+};
+use crate::models::root::transaction::{CommitStrategy, GitAccess, ReadOnly};
+use crate::providers::fs::FileSystem;
+use crate::providers::git::{GitProvider, GitShowError};
+use crate::utils::errors::FloxErrorCode;
+
+/// What [Project::clean] removes, nothing by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CleanOptions {
+    /// Cached builds of the environments for [Flox::system](crate::flox::Flox::system)
+    pub build_results: bool,
+    /// Gc roots keeping built environments alive,
+    /// see [prune_gc_roots](crate::flox::Flox::prune_gc_roots)
+    pub gc_roots: bool,
+    /// The sandbox kept by [TransactionOptions::reuse_sandbox]
+    pub sandboxes: bool,
+    /// Restore flox.nix files with uncommitted changes to their committed contents
+    pub reset_edits: bool,
+}
+
+/// What [Project::clean] removed
+#[derive(Debug, Default)]
+pub struct CleanReport {
+    /// Removed build cache entries
+    pub build_results: Vec<PathBuf>,
+    pub gc_roots: PruneReport,
+    /// Removed sandbox directories
+    pub sandboxes: Vec<PathBuf>,
+    /// Restored flox.nix files, relative to the repository root
+    pub reset: Vec<PathBuf>,
+}
+
+impl<'flox, Git: GitProvider, Fs: FileSystem> Project<'flox, Git, ReadOnly<Git>, Fs> {
+    /// Remove the state flox keeps for this project as selected by `options`
+    ///
+    /// Unlike [Project::cleanup_flox], this keeps the project itself intact.
+    /// Environments are those found in the workdir, without evaluating the project.
+    /// The kept sandbox is removed only once running transactions using it are finished.
+    /// Edits are reset in a transaction that only stages the restored files,
+    /// flox.nix files not yet committed are left as they are.
+    pub async fn clean(&self, options: CleanOptions) -> Result<CleanReport, CleanError<Git>> {
+        let workdir = self.require_workdir()?;
+        let mut report = CleanReport::default();
+
+        if options.build_results {
+            report.build_results = self.remove_build_results().await?;
+        }
+        if options.gc_roots {
+            report.gc_roots = environment::remove_project_gc_roots(
+                &self.flox.cache_dir,
+                &workdir.join(&self.subdir),
+            )
+            .await?;
+        }
+        if options.sandboxes {
+            report.sandboxes = self.remove_sandbox().await?;
+        }
+        if options.reset_edits {
+            report.reset = self.reset_edits().await?;
+        }
+
+        Ok(report)
+    }
+
+    /// Another handle to this project
+    fn reopen(&self) -> Self {
+        Project::new(
+            self.flox,
+            self.git.read_only(),
+            Rc::clone(&self.fs),
+            self.subdir.clone(),
+        )
+    }
+
+    /// Directories of the environments in the workdir by name, see [Environment::dir]
+    async fn environment_dirs(&self) -> Result<Vec<(String, PathBuf)>, CleanError<Git>> {
+        let root = self.require_workdir()?.join(&self.subdir);
+        let mut dirs = vec![("default".to_string(), root.clone())];
+
+        let pkgs = root.join("pkgs");
+        let mut entries = match tokio::fs::read_dir(&pkgs).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(dirs),
+            Err(e) => return Err(CleanError::ListEnvironments(pkgs, e)),
+        };
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| CleanError::ListEnvironments(pkgs.clone(), e))?
+        {
+            let file_type = entry
+                .file_type()
+                .await
+                .map_err(|e| CleanError::ListEnvironments(entry.path(), e))?;
+            if file_type.is_dir() {
+                dirs.push((
+                    entry.file_name().to_string_lossy().to_string(),
+                    entry.path(),
+                ));
+            }
+        }
+        dirs.sort();
+
+        Ok(dirs)
+    }
+
+    /// Remove the build cache entries of the current definitions of all environments
+    async fn remove_build_results(&self) -> Result<Vec<PathBuf>, CleanError<Git>> {
+        let mut removed = Vec::new();
+        for (name, _) in self.environment_dirs().await? {
+            let environment = Environment {
+                name,
+                system: self.flox.system.clone(),
+                project: self.reopen(),
+                compat: false,
+                store_path: None,
+                eval_options: EvalOptions::default(),
+            };
+            let flox_nix = match environment.read_flox_nix().await {
+                Ok(flox_nix) => flox_nix,
+                Err(ReadFloxNixError::Read(_, e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                    continue
+                },
+                Err(e) => return Err(e.into()),
+            };
+
+            let entry = environment.build_cache_entry(&flox_nix).await?;
+            match tokio::fs::remove_file(&entry).await {
+                Ok(()) => removed.push(entry),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(CleanError::Remove(entry, e)),
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove the sandbox kept for this project once no transaction uses it
+    async fn remove_sandbox(&self) -> Result<Vec<PathBuf>, CleanError<Git>> {
+        let (sandbox_dir, lock_path) =
+            persistent_sandbox(&self.flox.cache_dir, self.require_workdir()?);
+        match tokio::fs::symlink_metadata(&sandbox_dir).await {
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(CleanError::Remove(sandbox_dir, e)),
+        }
+
+        // the lock file is kept, transactions may be waiting for it
+        let mut lock =
+            LockFile::open(&lock_path).map_err(|e| CleanError::Lock(lock_path.clone(), e))?;
+        let _lock = tokio::task::spawn_blocking(move || lock.lock().map(|_| lock))
+            .await
+            .expect("lock task panicked")
+            .map_err(|e| CleanError::Lock(lock_path, e))?;
+
+        tokio::fs::remove_dir_all(&sandbox_dir)
+            .await
+            .map_err(|e| CleanError::Remove(sandbox_dir.clone(), e))?;
+
+        Ok(vec![sandbox_dir])
+    }
+
+    /// Restore flox.nix files that differ from `HEAD` in a transaction
+    async fn reset_edits(&self) -> Result<Vec<PathBuf>, CleanError<Git>> {
+        // a fresh repository has nothing to reset to
+        if self.git.git().head_rev().await.is_err() {
+            return Ok(Vec::new());
+        }
+
+        let workdir = self.require_workdir()?;
+        let mut committed = BTreeMap::new();
+        for (_, dir) in self.environment_dirs().await? {
+            for name in self.flox.flox_nix_names.iter() {
+                let path = dir.join(name);
+                // git expects paths relative to the repository root
+                let relative = path
+                    .strip_prefix(workdir)
+                    .expect("environments are in the workdir")
+                    .to_path_buf();
+
+                let contents = match self.git.git().show_file("HEAD", &relative).await {
+                    Ok(contents) => contents.into_vec(),
+                    Err(e) if e.not_found() => continue,
+                    Err(e) => return Err(CleanError::Show(relative, e)),
+                };
+                match self.fs.read(&path).await {
+                    Ok(current) if current == contents => continue,
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(CleanError::Read(path, e))
+                    },
+                    _ => {},
+                }
+                committed.insert(relative, contents);
+            }
+        }
+        if committed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let (sandbox, mut index) = self
+            .reopen()
+            .enter_transaction_with(TransactionOptions {
+                commit_strategy: CommitStrategy::Squashed,
+                ..Default::default()
+            })
+            .await
+            .map_err(CleanError::Enter)?;
+        let sandbox_workdir = sandbox.require_workdir()?.to_path_buf();
+        for (path, contents) in &committed {
+            let destination = sandbox_workdir.join(path);
+            if let Some(parent) = destination.parent() {
+                sandbox
+                    .fs
+                    .create_dir_all(parent)
+                    .await
+                    .map_err(|e| CleanError::Write(path.clone(), e))?;
+            }
+            sandbox
+                .fs
+                .write(&destination, contents)
+                .await
+                .map_err(|e| CleanError::Write(path.clone(), e))?;
+            index.insert(path.clone(), FileAction::Add);
+        }
+        sandbox
+            .write_transaction_state(&index)
+            .await
+            .map_err(|e| CleanError::Write(sandbox_workdir, e))?;
+
+        sandbox
+            .commit_transaction(index, "Reset environment edits", false)
+            .await
+            .map_err(CleanError::Commit)?;
+
+        Ok(committed.into_keys().collect())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CleanError<Git: GitProvider> {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to list environments in {0:?}: {1}")]
+    ListEnvironments(PathBuf, std::io::Error),
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error(transparent)]
+    Composition(#[from] CompositionError),
+    #[error(transparent)]
+    GcRoots(#[from] PruneGcRootsError),
+    #[error("Failed to remove {0:?}: {1}")]
+    Remove(PathBuf, std::io::Error),
+    #[error("Failed to lock {0:?}: {1}")]
+    Lock(PathBuf, std::io::Error),
+    #[error("Failed to read committed {0:?}: {1}")]
+    Show(PathBuf, Git::ShowError),
+    #[error("Failed to read {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to enter transaction: {0}")]
+    Enter(TransactionEnterError<Git>),
+    #[error("Failed to write {0:?}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("Failed to commit transaction: {0}")]
+    Commit(TransactionCommitError<Git>),
+}
+
+impl<Git: GitProvider> CleanError<Git> {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            CleanError::Workdir(e) => e.code(),
+            CleanError::ReadFloxNix(e) => e.code(),
+            CleanError::Composition(e) => e.code(),
+            CleanError::Enter(e) => e.code(),
+            CleanError::Commit(e) => e.code(),
+            CleanError::Show(..) => FloxErrorCode::Git,
+            CleanError::ListEnvironments(..)
+            | CleanError::GcRoots(_)
+            | CleanError::Remove(..)
+            | CleanError::Lock(..)
+            | CleanError::Read(..)
+            | CleanError::Write(..) => FloxErrorCode::Io,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::process::Command;
+
+    use super::*;
+    use crate::flox::Flox;
+    use crate::providers::fs::TokioFs;
+    use crate::providers::git::GitCommandProvider;
+
+    async fn project_in<'flox>(
+        flox: &'flox Flox,
+        dir: &Path,
+    ) -> Project<'flox, GitCommandProvider, ReadOnly<GitCommandProvider>> {
+        std::fs::create_dir_all(dir).unwrap();
+        let git = GitCommandProvider::init(dir, false).await.unwrap();
+        Project::new(flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new())
+    }
+
+    fn flox_in(dir: &Path) -> Flox {
+        let flox = Flox {
+            cache_dir: dir.join("caches"),
+            temp_dir: dir.join("temp"),
+            config_dir: dir.join("config"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&flox.cache_dir).unwrap();
+        std::fs::create_dir_all(&flox.temp_dir).unwrap();
+        flox
+    }
+
+    #[tokio::test]
+    async fn cleans_nothing_by_default() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = flox_in(tempdir.path());
+        let project = project_in(&flox, &tempdir.path().join("project")).await;
+
+        let report = project.clean(CleanOptions::default()).await.unwrap();
+        assert!(report.build_results.is_empty());
+        assert!(report.gc_roots.removed.is_empty());
+        assert!(report.sandboxes.is_empty());
+        assert!(report.reset.is_empty());
+    }
+
+    #[tokio::test]
+    async fn removes_build_results() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = flox_in(tempdir.path());
+        let project = project_in(&flox, &tempdir.path().join("project")).await;
+        let workdir = project.workdir().unwrap().to_path_buf();
+        std::fs::write(workdir.join("flox.nix"), "{ }").unwrap();
+
+        let environment = Environment {
+            name: "default".to_string(),
+            system: flox.system.clone(),
+            project: project.reopen(),
+            compat: false,
+            store_path: None,
+            eval_options: EvalOptions::default(),
+        };
+        let entry = environment.build_cache_entry(b"{ }").await.unwrap();
+        let other = entry.with_file_name("other");
+        std::fs::create_dir_all(entry.parent().unwrap()).unwrap();
+        std::fs::write(&entry, "/nix/store/environment").unwrap();
+        std::fs::write(&other, "/nix/store/other").unwrap();
+
+        let report = project
+            .clean(CleanOptions {
+                build_results: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.build_results, vec![entry.clone()]);
+        assert!(!entry.exists());
+        assert!(other.exists());
+    }
+
+    #[tokio::test]
+    async fn removes_gc_roots_of_project() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = flox_in(tempdir.path());
+        let project = project_in(&flox, &tempdir.path().join("project")).await;
+        let workdir = project.workdir().unwrap().to_path_buf();
+
+        let roots_dir = flox.cache_dir.join(environment::GC_ROOTS_DIR);
+        std::fs::create_dir_all(&roots_dir).unwrap();
+        let elsewhere = tempdir.path().join("elsewhere.nix");
+        std::fs::write(&elsewhere, "{ }").unwrap();
+        std::fs::write(workdir.join("flox.nix"), "{ }").unwrap();
+        for (root, flox_nix) in [("project", workdir.join("flox.nix")), ("other", elsewhere)] {
+            std::os::unix::fs::symlink(Path::new("/nix/store").join(root), roots_dir.join(root))
+                .unwrap();
+            std::fs::write(
+                roots_dir.join(root).with_extension("json"),
+                serde_json::to_vec(&serde_json::json!({ "floxNix": flox_nix })).unwrap(),
+            )
+            .unwrap();
+        }
+
+        let report = project
+            .clean(CleanOptions {
+                gc_roots: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.gc_roots.removed, vec![roots_dir.join("project")]);
+        assert!(roots_dir.join("other").is_symlink());
+    }
+
+    #[tokio::test]
+    async fn removes_kept_sandbox() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = flox_in(tempdir.path());
+        let project = project_in(&flox, &tempdir.path().join("project")).await;
+        std::fs::write(project.workdir().unwrap().join("flake.nix"), "{}").unwrap();
+
+        let (sandbox, _index) = project
+            .reopen()
+            .enter_transaction_with(TransactionOptions {
+                reuse_sandbox: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let sandbox_dir = sandbox.workdir().unwrap().to_path_buf();
+        drop(sandbox);
+
+        let report = project
+            .clean(CleanOptions {
+                sandboxes: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.sandboxes, vec![sandbox_dir.clone()]);
+        assert!(!sandbox_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn resets_uncommitted_edits() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = flox_in(tempdir.path());
+        let project = project_in(&flox, &tempdir.path().join("project")).await;
+        let workdir = project.workdir().unwrap().to_path_buf();
+        std::fs::write(workdir.join("flox.nix"), "{ }").unwrap();
+        std::fs::create_dir_all(workdir.join("pkgs/hello")).unwrap();
+        std::fs::write(workdir.join("pkgs/hello/flox.nix"), "{ }").unwrap();
+        let git = project.git.git();
+        git.add(&[Path::new(".")]).await.unwrap();
+        git.commit("initial").await.unwrap();
+
+        std::fs::write(workdir.join("flox.nix"), "{ edited = true; }").unwrap();
+        std::fs::remove_file(workdir.join("pkgs/hello/flox.nix")).unwrap();
+        std::fs::create_dir_all(workdir.join("pkgs/new")).unwrap();
+        std::fs::write(workdir.join("pkgs/new/flox.nix"), "{ }").unwrap();
+
+        let report = project
+            .clean(CleanOptions {
+                reset_edits: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.reset, [
+            PathBuf::from("flox.nix"),
+            PathBuf::from("pkgs/hello/flox.nix")
+        ]);
+        assert_eq!(
+            std::fs::read_to_string(workdir.join("flox.nix")).unwrap(),
+            "{ }"
+        );
+
+        // only the environment that was never committed remains changed
+        let status = Command::new("git")
+            .args(["status", "--porcelain"])
+            .current_dir(&workdir)
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&status.stdout), "?? pkgs/new/\n");
+    }
+}
@@ -0,0 +1,240 @@
+//! Composition of environments through `imports`
+//!
+//! An environment can extend shared base environments by importing their flox.nix:
+//!
+//! ```nix
+//! {
+//!   imports = [ ../base/flox.nix ];
+//!   packages.nixpkgs-flox.ripgrep = {};
+//! }
+//! ```
+//!
+//! Nix merges imported files like modules when building the environment.
+//! [Environment::resolved] mirrors that merge for reading the declaration,
+//! so that e.g. [Environment::packages] lists inherited packages as well.
+
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use thiserror::Error;
+
+use super::environment::{Environment, ReadFloxNixError};
+use crate::models::flox_nix::{FloxNix, FloxNixError};
+use crate::models::flox_package::FloxPackage;
+use crate::models::root::transaction::GitAccess;
+use crate::providers::fs::FileSystem;
+use crate::providers::git::GitProvider;
+use crate::utils::errors::FloxErrorCode;
+
+/// The declaration of an environment merged with everything it imports
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResolvedEnvironment {
+    /// flox.nix files making up the environment,
+    /// imported files before the files importing them, the environment's own file last
+    pub files: Vec<PathBuf>,
+    /// Packages declared in any of [Self::files] as `<channel>.<name>`, with their attributes
+    ///
+    /// Attributes of a package declared multiple times are taken from the last file.
+    pub packages: BTreeMap<FloxPackage, serde_json::Value>,
+}
+
+/// A flox.nix file read while resolving imports
+pub(super) struct ComposedFile {
+    pub(super) path: PathBuf,
+    pub(super) contents: Vec<u8>,
+    pub(super) flox_nix: FloxNix,
+}
+
+impl<Git: GitProvider, A: GitAccess<Git>, Fs: FileSystem> Environment<'_, Git, A, Fs> {
+    /// Flatten the inheritance chain of this environment
+    ///
+    /// Fails with [CompositionError::Cycle] if files import each other.
+    pub async fn resolved(&self) -> Result<ResolvedEnvironment, CompositionError> {
+        let mut resolved = ResolvedEnvironment::default();
+        for file in self.composition().await? {
+            let packages = file
+                .flox_nix
+                .packages()
+                .map_err(|e| CompositionError::InvalidPackages(file.path.clone(), e))?;
+            resolved.packages.extend(packages);
+            resolved.files.push(file.path);
+        }
+        Ok(resolved)
+    }
+
+    /// Read the flox.nix of this environment and all files it imports, in merge order
+    ///
+    /// Files imported multiple times are only read once.
+    pub(super) async fn composition(&self) -> Result<Vec<ComposedFile>, CompositionError> {
+        let root = self
+            .flox_nix_path()
+            .await
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?;
+
+        let mut files = Vec::new();
+        let mut stack = Vec::new();
+        self.compose(root, &mut stack, &mut files).await?;
+        Ok(files)
+    }
+
+    /// Add `path` to `files` after the files it imports, depth first
+    ///
+    /// `stack` holds the chain of files importing `path`.
+    #[async_recursion::async_recursion(?Send)]
+    async fn compose(
+        &self,
+        path: PathBuf,
+        stack: &mut Vec<PathBuf>,
+        files: &mut Vec<ComposedFile>,
+    ) -> Result<(), CompositionError> {
+        if let Some(start) = stack.iter().position(|importing| importing == &path) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(path);
+            return Err(CompositionError::Cycle(cycle));
+        }
+        if files.iter().any(|file| file.path == path) {
+            return Ok(());
+        }
+
+        let contents = self
+            .project
+            .fs
+            .read(&path)
+            .await
+            .map_err(|e| ReadFloxNixError::Read(path.clone(), e))?;
+        let flox_nix: FloxNix = String::from_utf8_lossy(&contents)
+            .parse()
+            .map_err(|e| ReadFloxNixError::parse(path.clone(), e))?;
+        let imports = flox_nix
+            .imports()
+            .map_err(|e| CompositionError::InvalidImports(path.clone(), e))?;
+
+        let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+        stack.push(path.clone());
+        for import in imports {
+            self.compose(normalize(&dir.join(import)), stack, files)
+                .await?;
+        }
+        stack.pop();
+
+        files.push(ComposedFile {
+            path,
+            contents,
+            flox_nix,
+        });
+        Ok(())
+    }
+}
+
+/// Resolve `.` and `..` components without touching the filesystem
+///
+/// Keeps paths comparable for cycle detection however imports are spelled.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[derive(Error, Debug)]
+pub enum CompositionError {
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error("Invalid imports in {0:?}: {1}")]
+    InvalidImports(PathBuf, FloxNixError),
+    #[error("Invalid packages declaration in {0:?}: {1}")]
+    InvalidPackages(PathBuf, FloxNixError),
+    #[error("Environments import each other: {}", format_cycle(.0))]
+    Cycle(Vec<PathBuf>),
+}
+
+fn format_cycle(cycle: &[PathBuf]) -> String {
+    cycle
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+impl CompositionError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            CompositionError::ReadFloxNix(e) => e.code(),
+            CompositionError::InvalidImports(..)
+            | CompositionError::InvalidPackages(..)
+            | CompositionError::Cycle(_) => FloxErrorCode::Invalid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flox::Flox;
+    use crate::models::project::tests::test_environment;
+    use crate::providers::fs::MemFs;
+
+    #[tokio::test]
+    async fn resolves_imports() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        let base = workdir.join("base/flox.nix");
+        let own = workdir.join("flox.nix");
+        fs.create_dir_all(&workdir.join("base")).await.unwrap();
+        fs.write(&base, b"{ packages.nixpkgs-flox.hello = {}; }")
+            .await
+            .unwrap();
+        fs.write(
+            &own,
+            b"{ imports = [ ./base/flox.nix ]; packages.nixpkgs-flox.ripgrep = {}; }",
+        )
+        .await
+        .unwrap();
+
+        let resolved = environment.resolved().await.unwrap();
+        assert_eq!(resolved.files, [base.clone(), own]);
+        assert_eq!(environment.packages().await.unwrap(), [
+            "nixpkgs-flox.hello",
+            "nixpkgs-flox.ripgrep"
+        ]);
+
+        fs.write(&base, b"{ imports = [ ../flox.nix ]; }")
+            .await
+            .unwrap();
+        assert!(matches!(
+            environment.resolved().await,
+            Err(CompositionError::Cycle(cycle)) if cycle.len() == 3
+        ));
+    }
+
+    #[test]
+    fn normalizes_import_paths() {
+        assert_eq!(
+            normalize(Path::new("/project/pkgs/dev/../base/./flox.nix")),
+            Path::new("/project/pkgs/base/flox.nix")
+        );
+    }
+
+    #[test]
+    fn formats_cycles() {
+        let error = CompositionError::Cycle(vec![
+            PathBuf::from("/a/flox.nix"),
+            PathBuf::from("/b/flox.nix"),
+            PathBuf::from("/a/flox.nix"),
+        ]);
+        assert_eq!(
+            error.to_string(),
+            "Environments import each other: /a/flox.nix -> /b/flox.nix -> /a/flox.nix"
+        );
+    }
+}
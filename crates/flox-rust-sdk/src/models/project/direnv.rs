@@ -0,0 +1,227 @@
+//! Integration with [direnv](https://direnv.net)
+//!
+//! [Environment::write_envrc] writes an [ENVRC] that evaluates the script of
+//! [Environment::print_dev_env], so that entering the directory activates the environment.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use runix::command_line::NixCommandLine;
+use thiserror::Error;
+
+use super::environment::{
+    ActivationHookError,
+    BuildEnvironmentError,
+    Environment,
+    ReadFloxNixError,
+    VariablesError,
+};
+use crate::models::root::transaction::GitAccess;
+use crate::providers::fs::FileSystem;
+use crate::providers::git::GitProvider;
+
+/// Name of the file direnv loads when entering a directory
+pub const ENVRC: &str = ".envrc";
+
+/// Shells [Environment::print_dev_env] can generate scripts for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Shell {
+    #[default]
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl<Git: GitProvider, A: GitAccess<Git>, Fs: FileSystem> Environment<'_, Git, A, Fs> {
+    /// Script activating this environment when `eval`ed by `shell`
+    ///
+    /// The script puts the environment's packages on `PATH`,
+    /// exports its [variables](Self::activation_variables)
+    /// and runs its [activation hook](Self::activation_hook).
+    /// The environment is built if necessary.
+    ///
+    /// Hooks are written for POSIX shells, so [Shell::Fish] runs them in `sh`,
+    /// where they cannot change the environment of the calling shell.
+    pub async fn print_dev_env(&self, shell: Shell) -> Result<String, PrintDevEnvError> {
+        let outputs = self.build_outputs().await?;
+        let variables = self.activation_variables::<NixCommandLine>().await?;
+        let hook = self.activation_hook().await?;
+
+        Ok(dev_env_script(shell, &outputs, &variables, hook.as_deref()))
+    }
+
+    /// Write an [ENVRC] into the directory `at` that activates this environment
+    ///
+    /// An existing [ENVRC] is replaced.
+    /// direnv reloads the environment when its flox.nix changes,
+    /// but like any new `.envrc` it has to be approved with `direnv allow` first.
+    pub async fn write_envrc(&self, at: &Path) -> Result<(), WriteEnvrcError> {
+        let flox_nix = self
+            .flox_nix_path()
+            .await
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?;
+
+        let path = at.join(ENVRC);
+        self.project
+            .fs
+            .write(&path, envrc(&self.name, &flox_nix).as_bytes())
+            .await
+            .map_err(|e| WriteEnvrcError::Write(path, e))
+    }
+}
+
+/// Contents of an [ENVRC] activating the environment `name` defined in `flox_nix`
+fn envrc(name: &str, flox_nix: &Path) -> String {
+    format!(
+        "# Activates the flox environment {name} when entering this directory\n\
+         watch_file {flox_nix}\n\
+         eval \"$(flox print-dev-env --environment {name})\"\n",
+        name = shell_quote(name),
+        flox_nix = shell_quote(&flox_nix.to_string_lossy()),
+    )
+}
+
+fn dev_env_script(
+    shell: Shell,
+    outputs: &[PathBuf],
+    variables: &BTreeMap<String, String>,
+    hook: Option<&str>,
+) -> String {
+    let bins: Vec<_> = outputs
+        .iter()
+        .map(|output| output.join("bin").to_string_lossy().into_owned())
+        .collect();
+    let mut script = match shell {
+        Shell::Bash | Shell::Zsh => format!(
+            "export PATH={}${{PATH:+\":$PATH\"}}\n",
+            shell_quote(&bins.join(":"))
+        ),
+        Shell::Fish => {
+            let bins: Vec<_> = bins.iter().map(|bin| fish_quote(bin)).collect();
+            format!("set -gx PATH {} $PATH\n", bins.join(" "))
+        },
+    };
+
+    for (name, value) in variables {
+        if !is_variable_name(name) {
+            warn!("Not exporting '{name}', it is not a valid shell variable name");
+            continue;
+        }
+        let line = match shell {
+            Shell::Bash | Shell::Zsh => format!("export {name}={}\n", shell_quote(value)),
+            Shell::Fish => format!("set -gx {name} {}\n", fish_quote(value)),
+        };
+        script.push_str(&line);
+    }
+
+    match (shell, hook) {
+        (Shell::Bash | Shell::Zsh, Some(hook)) => {
+            script.push_str(hook);
+            script.push('\n');
+        },
+        (Shell::Fish, Some(hook)) => {
+            script.push_str(&format!("sh -c {}\n", fish_quote(hook)));
+        },
+        (_, None) => {},
+    }
+
+    script
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Quote `value` as a single shell word
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quote `value` as a single word for fish
+fn fish_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+#[derive(Error, Debug)]
+pub enum PrintDevEnvError {
+    #[error(transparent)]
+    Build(#[from] BuildEnvironmentError),
+    #[error(transparent)]
+    Variables(#[from] VariablesError<NixCommandLine>),
+    #[error(transparent)]
+    Hook(#[from] ActivationHookError),
+}
+
+#[derive(Error, Debug)]
+pub enum WriteEnvrcError {
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error("Failed to write {0:?}: {1}")]
+    Write(PathBuf, std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(shell: Shell) -> String {
+        let variables = BTreeMap::from([
+            ("GREETING".to_string(), "it's me".to_string()),
+            ("not-a-name".to_string(), "ignored".to_string()),
+        ]);
+
+        dev_env_script(
+            shell,
+            &[
+                PathBuf::from("/nix/store/abc-env"),
+                PathBuf::from("/nix/store/def-env-man"),
+            ],
+            &variables,
+            Some("echo hello"),
+        )
+    }
+
+    #[test]
+    fn dev_env_script_bash() {
+        assert_eq!(
+            script(Shell::Bash),
+            concat!(
+                "export PATH='/nix/store/abc-env/bin:/nix/store/def-env-man/bin'${PATH:+\":$PATH\"}\n",
+                "export GREETING='it'\\''s me'\n",
+                "echo hello\n",
+            )
+        );
+    }
+
+    #[test]
+    fn dev_env_script_zsh() {
+        assert_eq!(script(Shell::Zsh), script(Shell::Bash));
+    }
+
+    #[test]
+    fn dev_env_script_fish() {
+        assert_eq!(
+            script(Shell::Fish),
+            concat!(
+                "set -gx PATH '/nix/store/abc-env/bin' '/nix/store/def-env-man/bin' $PATH\n",
+                "set -gx GREETING 'it\\'s me'\n",
+                "sh -c 'echo hello'\n",
+            )
+        );
+    }
+
+    #[test]
+    fn envrc_loads_environment() {
+        let envrc = envrc("default", Path::new("/project/pkgs/default/flox.nix"));
+        assert_eq!(
+            envrc,
+            concat!(
+                "# Activates the flox environment 'default' when entering this directory\n",
+                "watch_file '/project/pkgs/default/flox.nix'\n",
+                "eval \"$(flox print-dev-env --environment 'default')\"\n",
+            )
+        );
+    }
+}
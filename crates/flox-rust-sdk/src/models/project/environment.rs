@@ -1,56 +1,1378 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
 
+use log::debug;
+use runix::arguments::eval::EvaluationArgs;
+use runix::arguments::EvalArgs;
+use runix::command::Eval;
+use runix::command_line::NixCommandLine;
 use runix::installable::Installable;
+use runix::{NixBackend, RunJson};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::process::{Child, Command};
 
-use super::{Index, Project, TransactionCommitError, TransactionEnterError};
-use crate::models::root::transaction::{GitAccess, GitSandBox, ReadOnly};
-use crate::providers::git::GitProvider;
+use super::composition::CompositionError;
+use super::lock::ReadLockError;
+use super::migrate::{LegacyLayout, LegacyLayoutError};
+use super::{
+    FileAction,
+    Index,
+    Project,
+    ProjectError,
+    TransactionCommitError,
+    TransactionEnterError,
+    TransactionOutcome,
+};
+use crate::flox::FloxNixApi;
+use crate::models::audit::{AuditEntry, AuditOperation};
+use crate::models::events::FloxWarning;
+use crate::models::flox_nix::{
+    self,
+    EnvInterpolation,
+    FloxNix,
+    FloxNixError,
+    FloxNixParseError,
+    StringPart,
+};
+use crate::models::flox_package::FloxPackage;
+use crate::models::policy::PolicyDenied;
+use crate::models::resolver::ResolvePackageError;
+use crate::models::root::transaction::{CommitStrategy, GitAccess, GitSandBox, ReadOnly};
+use crate::models::stability::Stability;
+use crate::models::system::System;
+use crate::providers::fs::{FileKind, FileSystem, TokioFs};
+use crate::providers::git::{GitProvider, GitShowError};
+use crate::utils::errors::FloxErrorCode;
 
-pub struct Environment<'flox, Git: GitProvider, Access: GitAccess<Git>> {
+/// Directory in [Flox::cache_dir](crate::flox::Flox::cache_dir) mapping environment hashes to built store paths
+const BUILD_CACHE_DIR: &str = "environment-builds";
+
+/// Nix settings that are ignored unless the user is trusted by the nix daemon
+const TRUSTED_NIX_SETTINGS: &[&str] = &[
+    "substituters",
+    "extra-substituters",
+    "trusted-public-keys",
+    "extra-trusted-public-keys",
+    "trusted-substituters",
+    "sandbox",
+    "post-build-hook",
+    "builders",
+];
+
+/// Runs the activation hook passed in [HOOK_ENV] before executing its arguments
+///
+/// The hook runs with `set -e`, so that it fails as soon as any of its commands fails.
+/// If the hook fails its exit code is written to the file in [HOOK_STATUS_ENV].
+const HOOK_WRAPPER: &str = r#"record_status() {
+  status=$?
+  [ "$status" -eq 0 ] || echo "$status" > "$FLOX_HOOK_STATUS"
+}
+trap record_status EXIT
+set -e
+eval "$FLOX_ACTIVATION_HOOK"
+set +e
+trap - EXIT
+exec "$@""#;
+const HOOK_ENV: &str = "FLOX_ACTIVATION_HOOK";
+const HOOK_STATUS_ENV: &str = "FLOX_HOOK_STATUS";
+
+/// File contained in the store path of every built environment
+const ENVIRONMENT_CATALOG: &str = "catalog.json";
+
+/// Directory in [Flox::cache_dir](crate::flox::Flox::cache_dir) holding gc roots of built environments
+pub const GC_ROOTS_DIR: &str = "gcroots";
+
+/// Environment a gc root in [GC_ROOTS_DIR] was created for
+///
+/// Stored next to the root as `<root>.json`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GcRootRecord {
+    flox_nix: PathBuf,
+}
+
+/// Result of [prune_gc_roots]
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    /// Removed gc roots
+    pub removed: Vec<PathBuf>,
+    /// Store paths the removed roots pointed to
+    ///
+    /// These can be reclaimed by `nix-collect-garbage`
+    /// unless they are still referenced by other roots.
+    pub reclaimable: Vec<PathBuf>,
+}
+
+/// A generation of an environment, see [Environment::history]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub generation: usize,
+    pub rev: String,
+    /// Commit time in seconds since the unix epoch
+    pub timestamp: i64,
+    pub message: String,
+    /// Path of flox.nix relative to the repository root
+    path: PathBuf,
+    /// Revision of the preceding generation
+    previous: Option<String>,
+}
+
+/// Packages changed by a generation, as `<channel>.<name>`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvDiff {
+    pub added: Vec<FloxPackage>,
+    pub removed: Vec<FloxPackage>,
+    /// Packages declared before and after with different attributes
+    pub changed: Vec<FloxPackage>,
+}
+
+/// Options controlling how nix evaluates an environment, see [Environment::build_with]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EvalOptions {
+    /// Pass `--impure` to nix, e.g. to let evaluation read environment variables
+    ///
+    /// Environments declaring `impure = true` in flox.nix are always evaluated impurely.
+    pub impure: bool,
+}
+
+pub struct Environment<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem = TokioFs> {
     /// aka. Nix attrpath, undr the assumption that they are not nested!
     pub(super) name: String,
-    pub(super) system: String,
-    pub(super) project: Project<'flox, Git, Access>,
+    pub(super) system: System,
+    pub(super) project: Project<'flox, Git, Access, Fs>,
+    /// Whether this is a `devShells` output of a plain flake, see [Self::is_compat]
+    pub(super) compat: bool,
+    /// Store path the environment was built at out-of-band, see [Self::with_store_path]
+    pub(super) store_path: Option<PathBuf>,
+    /// Options nix evaluates this environment with, see [Self::with_eval_options]
+    pub(super) eval_options: EvalOptions,
+}
+
+/// A long-lived process declared in the `services` attribute of a flox.nix
+///
+/// ```nix
+/// services.postgres.command = "postgres -D $PGDATA";
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceDef {
+    pub name: String,
+    pub command: String,
+}
+
+/// A runnable app exposed by an environment
+///
+/// See [Environment::apps]
+#[derive(Debug, Clone)]
+pub struct AppDef {
+    pub name: String,
+    /// Store path of the executable
+    pub program: PathBuf,
+    /// Installable to pass to `nix run`
+    pub installable: Installable,
+}
+
+/// A `services.<name>` declaration in flox.nix
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub(crate) struct ServiceDecl {
+    /// Command running the service
+    command: String,
+}
+
+/// A `packages.<channel>.<name>` declaration in flox.nix
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub(crate) struct PackageDecl {
+    /// Version the package is pinned to
+    version: Option<String>,
+}
+
+/// A `nixConfig` value in flox.nix
+#[derive(Deserialize)]
+#[serde(untagged)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub(crate) enum NixConfigValue {
+    String(String),
+    Bool(bool),
+    Number(serde_json::Number),
+    List(Vec<NixConfigValue>),
+}
+
+impl NixConfigValue {
+    /// Render the value like nix does for `nix.conf`
+    fn render(&self) -> String {
+        match self {
+            NixConfigValue::String(value) => value.clone(),
+            NixConfigValue::Bool(value) => value.to_string(),
+            NixConfigValue::Number(value) => value.to_string(),
+            NixConfigValue::List(values) => values
+                .iter()
+                .map(NixConfigValue::render)
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
 }
 
 /// Implementations for an environment
-impl<Git: GitProvider, A: GitAccess<Git>> Environment<'_, Git, A> {
+impl<Git: GitProvider, A: GitAccess<Git>, Fs: FileSystem> Environment<'_, Git, A, Fs> {
     pub fn name(&self) -> Cow<str> {
         Cow::from(&self.name)
     }
 
     pub fn system(&self) -> Cow<str> {
-        Cow::from(&self.system)
+        Cow::from(self.system.as_str())
     }
 
     // pub async fn metadata(&self) -> Result<Metadata, MetadataError<Git>> {
     //    todo!("to be replaced by catalog")
     // }
 
+    /// Whether this environment is a `devShells` output of a flake without `floxEnvs`
+    ///
+    /// Such compat environments are only listed if
+    /// [Flox::compat_devshells](crate::flox::Flox::compat_devshells) is set.
+    /// They can be built and activated, but have no flox.nix,
+    /// so that operations on their declaration, e.g. installing packages, fail.
+    pub fn is_compat(&self) -> bool {
+        self.compat
+    }
+
+    /// Use `store_path`, built elsewhere, as the build of this environment
+    ///
+    /// [Building](Self::build) the environment then returns `store_path` without evaluating it,
+    /// so that e.g. [Self::print_dev_env], [Self::closure] and [Self::run_command]
+    /// use the prebuilt environment.
+    /// Variables and the activation hook are still read from flox.nix.
+    /// Fails unless `store_path` is valid in the nix store and contains [ENVIRONMENT_CATALOG].
+    /// The path is not kept alive by a gc root.
+    pub async fn with_store_path(
+        mut self,
+        store_path: impl Into<PathBuf>,
+    ) -> Result<Self, AttachStorePathError> {
+        let store_path = store_path.into();
+        if !self.is_valid_store_path(&store_path).await? {
+            return Err(AttachStorePathError::NotInStore(store_path));
+        }
+        match tokio::fs::metadata(store_path.join(ENVIRONMENT_CATALOG)).await {
+            Ok(metadata) if metadata.is_file() => {},
+            Ok(_) => return Err(AttachStorePathError::NotAnEnvironment(store_path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(AttachStorePathError::NotAnEnvironment(store_path))
+            },
+            Err(e) => return Err(AttachStorePathError::Read(store_path, e)),
+        }
+
+        self.store_path = Some(store_path);
+        Ok(self)
+    }
+
+    /// Evaluate this environment according to `options`
+    ///
+    /// Applies to building, running and evaluating outputs of the environment,
+    /// unless options are passed explicitly, e.g. to [Self::build_with].
+    pub fn with_eval_options(mut self, options: EvalOptions) -> Self {
+        self.eval_options = options;
+        self
+    }
+
     /// get an installable for this environment
     // todo: share with named env
-    pub fn installable(&self) -> Installable {
-        Installable {
-            flakeref: self.project.flakeref(),
-            attr_path: format!(".floxEnvs.{}.{}", self.system, self.name),
+    pub async fn installable(&self) -> Result<Installable, ProjectError> {
+        let output = if self.compat { "devShells" } else { "floxEnvs" };
+        Ok(Installable {
+            flakeref: self.project.flakeref().await?,
+            attr_path: format!(".{output}.{}.{}", self.system, self.name),
+        })
+    }
+
+    /// Directory containing the files of this environment
+    ///
+    /// The project root for the `default` environment, `pkgs/<name>` otherwise.
+    pub(super) fn dir(&self) -> Option<PathBuf> {
+        let root = self.project.workdir()?.join(&self.project.subdir);
+        if self.name == "default" {
+            Some(root)
+        } else {
+            Some(root.join("pkgs").join(&self.name))
+        }
+    }
+
+    /// Path of the flox.nix file defining this environment
+    ///
+    /// The `default` environment is defined at the root of the project,
+    /// other environments in `pkgs/<name>/flox.nix`.
+    /// The file is looked up by each of [Flox::flox_nix_names](crate::flox::Flox::flox_nix_names),
+    /// falling back to the first name if none exists yet.
+    pub(super) async fn flox_nix_path(&self) -> Option<PathBuf> {
+        Some(self.find_flox_nix(&self.dir()?).await)
+    }
+
+    /// Path of [Self::flox_nix_path] in the original project
+    ///
+    /// Within a transaction this is the file the sandbox will be committed to.
+    async fn original_flox_nix_path(&self) -> Option<PathBuf> {
+        let flox_nix = self.flox_nix_path().await?;
+        let relative = flox_nix.strip_prefix(self.project.workdir()?).ok()?;
+        let original = self.project.git.read_only();
+        Some(original.git().workdir()?.join(relative))
+    }
+
+    /// The flox.nix in `dir` by any configured name, the first name if none exists
+    async fn find_flox_nix(&self, dir: &Path) -> PathBuf {
+        let names = &self.project.flox.flox_nix_names;
+        for name in names.iter() {
+            let path = dir.join(name);
+            if let Ok(Some(_)) = self.project.fs.kind(&path).await {
+                return path;
+            }
+        }
+        dir.join(names.primary())
+    }
+
+    /// Absolute path of the existing flox.nix file defining this environment
+    ///
+    /// Resolved like [Self::flox_nix_path] within the project's subdir.
+    /// In projects still using the legacy numbered-generation layout
+    /// (see [migrate](super::migrate)) it is the file of the current generation,
+    /// in `<generation>/pkgs/<name>/`.
+    /// Fails for [compat](Self::is_compat) environments, which have no flox.nix.
+    pub async fn definition_path(&self) -> Result<PathBuf, DefinitionPathError> {
+        if self.compat {
+            return Err(DefinitionPathError::Inline(self.name.clone()));
+        }
+
+        let root = self.project.require_workdir()?.join(&self.project.subdir);
+        let path = match LegacyLayout::read(&*self.project.fs, &root).await? {
+            Some(layout) => {
+                let dir = root
+                    .join(layout.current.to_string())
+                    .join("pkgs")
+                    .join(&self.name);
+                self.find_flox_nix(&dir).await
+            },
+            None => self
+                .flox_nix_path()
+                .await
+                .ok_or(ProjectError::WorkdirNotFound)?,
+        };
+
+        match self.project.fs.kind(&path).await {
+            Ok(Some(FileKind::File)) => Ok(path),
+            Ok(_) => Err(DefinitionPathError::NotFound(path)),
+            Err(e) => Err(DefinitionPathError::Read(path, e)),
+        }
+    }
+
+    /// Read and parse the flox.nix file of this environment
+    async fn flox_nix(&self) -> Result<FloxNix, ReadFloxNixError> {
+        let path = self
+            .flox_nix_path()
+            .await
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?;
+        let contents = self
+            .project
+            .fs
+            .read(&path)
+            .await
+            .map_err(|e| ReadFloxNixError::Read(path.clone(), e))?;
+
+        String::from_utf8_lossy(&contents)
+            .parse()
+            .map_err(|e| ReadFloxNixError::parse(path, e))
+    }
+
+    /// List the services declared in this environment
+    ///
+    /// Environments without a `services` attribute have no services.
+    pub async fn services(&self) -> Result<Vec<ServiceDef>, ServicesError> {
+        let services: BTreeMap<String, ServiceDecl> = self
+            .flox_nix()
+            .await?
+            .get_as(&["services"])
+            .map_err(ServicesError::Invalid)?
+            .unwrap_or_default();
+
+        Ok(services
+            .into_iter()
+            .map(|(name, decl)| ServiceDef {
+                name,
+                command: decl.command,
+            })
+            .collect())
+    }
+
+    /// List the packages declared in this environment and the environments it imports
+    ///
+    /// Packages are returned as `<channel>.<name>`
+    pub async fn packages(&self) -> Result<Vec<FloxPackage>, ListPackagesError> {
+        let packages = self.resolved().await?.packages;
+
+        Ok(packages.into_keys().collect())
+    }
+
+    /// List the latest `limit` generations of this environment, newest first
+    ///
+    /// Every commit changing the environment's flox.nix is a generation,
+    /// numbered from 1 for the commit creating it.
+    /// Use [Self::diff] to summarize the changes of a generation.
+    pub async fn history(&self, limit: usize) -> Result<Vec<HistoryEntry>, HistoryError<Git>> {
+        let workdir = self
+            .project
+            .workdir()
+            .ok_or(HistoryError::WorkdirNotFound)?;
+        let flox_nix_path = self
+            .flox_nix_path()
+            .await
+            .ok_or(HistoryError::WorkdirNotFound)?;
+        // git expects paths relative to the repository root
+        let path = flox_nix_path
+            .strip_prefix(workdir)
+            .unwrap_or(&flox_nix_path)
+            .to_path_buf();
+
+        let commits = self
+            .project
+            .git
+            .git()
+            .log(Some(&path), None)
+            .await
+            .map_err(HistoryError::Log)?;
+
+        let generations = commits.len();
+        let history = commits
+            .iter()
+            .enumerate()
+            .take(limit)
+            .map(|(i, commit)| HistoryEntry {
+                generation: generations - i,
+                rev: commit.rev.clone(),
+                timestamp: commit.timestamp,
+                message: commit.message.clone(),
+                path: path.clone(),
+                previous: commits.get(i + 1).map(|previous| previous.rev.clone()),
+            })
+            .collect();
+
+        Ok(history)
+    }
+
+    /// Summarize the packages changed by a generation from [Self::history]
+    ///
+    /// Compares flox.nix at the generation with the preceding one,
+    /// the first generation adds all of its packages.
+    pub async fn diff(&self, entry: &HistoryEntry) -> Result<EnvDiff, HistoryError<Git>> {
+        let after = self.packages_at(&entry.rev, &entry.path).await?;
+        let before = match entry.previous {
+            Some(ref previous) => self.packages_at(previous, &entry.path).await?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(diff_packages(before, &after))
+    }
+
+    /// Read the packages declared by flox.nix at `path` in `rev`
+    async fn packages_at(
+        &self,
+        rev: &str,
+        path: &Path,
+    ) -> Result<BTreeMap<FloxPackage, serde_json::Value>, HistoryError<Git>> {
+        let contents = self
+            .project
+            .git
+            .git()
+            .show_file(rev, path)
+            .await
+            .map_err(HistoryError::Show)?;
+
+        contents
+            .to_string_lossy()
+            .parse::<FloxNix>()
+            .and_then(|flox_nix| flox_nix.packages())
+            .map_err(|e| HistoryError::Parse(rev.to_string(), e))
+    }
+
+    /// Whether a `<channel>.<name>` package is declared in this environment
+    ///
+    /// Reads flox.nix without evaluating it.
+    /// If the packages can not be read that way, e.g. because they are imported,
+    /// flox.nix is evaluated with nix if `eval_fallback` is set.
+    pub async fn contains(
+        &self,
+        package: &str,
+        eval_fallback: bool,
+    ) -> Result<bool, ContainsPackageError> {
+        let contains = match self.flox_nix().await {
+            Ok(flox_nix) => flox_nix.has_package(package),
+            Err(ReadFloxNixError::Parse(_, e)) => Err(e),
+            Err(e) => return Err(e.into()),
+        };
+
+        match contains {
+            Err(e) if eval_fallback => {
+                debug!("Could not read packages statically, evaluating flox.nix: {e}");
+                self.eval_contains(package).await
+            },
+            contains => contains.map_err(ContainsPackageError::Invalid),
+        }
+    }
+
+    async fn eval_contains(&self, package: &str) -> Result<bool, ContainsPackageError> {
+        let path = self
+            .flox_nix_path()
+            .await
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?;
+        let attr_path = package
+            .split('.')
+            .map(|attr| format!("{attr:?}"))
+            .collect::<Vec<_>>()
+            .join(".");
+
+        let nix: NixCommandLine = self.project.flox.nix(Default::default());
+        let output = nix
+            .command(&["eval"])
+            .args(["--json", "--file"])
+            .arg(&path)
+            .arg("--apply")
+            .arg(format!("env: (env.packages or {{}}) ? {attr_path}"))
+            .output()
+            .await
+            .map_err(ContainsPackageError::Spawn)?;
+
+        if !output.status.success() {
+            return Err(ContainsPackageError::BadExit(
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        serde_json::from_slice(&output.stdout).map_err(ContainsPackageError::ParseEval)
+    }
+
+    /// Channels or flakerefs the packages of this environment are taken from
+    ///
+    /// Evaluates flox.nix with nix, so that imported package sets are included.
+    pub async fn channels(&self) -> Result<Vec<String>, EnvironmentChannelsError> {
+        let path = self
+            .flox_nix_path()
+            .await
+            .ok_or(EnvironmentChannelsError::WorkdirNotFound)?;
+
+        let nix: NixCommandLine = self.project.flox.nix(Default::default());
+        let output = nix
+            .command(&["eval"])
+            .args(["--json", "--file"])
+            .arg(&path)
+            .arg("--apply")
+            .arg("env: builtins.attrNames (env.packages or {})")
+            .output()
+            .await
+            .map_err(EnvironmentChannelsError::Spawn)?;
+
+        if !output.status.success() {
+            return Err(EnvironmentChannelsError::BadExit(
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        serde_json::from_slice(&output.stdout).map_err(EnvironmentChannelsError::ParseEval)
+    }
+
+    /// Environment variables declared in this environment
+    ///
+    /// Variables are read from the `environmentVariables` and `vars` attributes.
+    /// Values may reference the store path of a package,
+    /// e.g. `"${packages.nixpkgs-flox.hello}/bin"`,
+    /// which are resolved by evaluating the package.
+    pub async fn variables<Nix: FloxNixApi>(
+        &self,
+    ) -> Result<BTreeMap<String, String>, VariablesError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let flox_nix = self.flox_nix().await?;
+
+        let mut declared = flox_nix
+            .get_strings(&["vars"])
+            .map_err(VariablesError::Invalid)?
+            .unwrap_or_default();
+        declared.extend(
+            flox_nix
+                .get_strings(&["environmentVariables"])
+                .map_err(VariablesError::Invalid)?
+                .unwrap_or_default(),
+        );
+
+        let nix = self.project.flox.nix::<Nix>(Default::default());
+
+        let mut variables = BTreeMap::new();
+        for (name, parts) in declared {
+            let mut value = String::new();
+            for part in parts {
+                match part {
+                    StringPart::Literal(literal) => value.push_str(&literal),
+                    StringPart::Reference(path) => {
+                        let (channel, attr_path) = match &path[..] {
+                            [packages, channel, attr_path @ ..]
+                                if packages == "packages" && !attr_path.is_empty() =>
+                            {
+                                (channel, attr_path)
+                            },
+                            _ => return Err(VariablesError::UnsupportedReference(path.join("."))),
+                        };
+
+                        let eval = Eval {
+                            eval_args: EvalArgs {
+                                apply: Some("package: package.outPath".to_string().into()),
+                                installable: Some(
+                                    Installable::new(
+                                        channel.to_string(),
+                                        attr_path
+                                            .iter()
+                                            .map(|attr| format!("{attr:?}"))
+                                            .collect::<Vec<_>>()
+                                            .join("."),
+                                    )
+                                    .into(),
+                                ),
+                            },
+                            ..Eval::default()
+                        };
+
+                        let store_path = eval
+                            .run_json(&nix, &Default::default())
+                            .await
+                            .map_err(VariablesError::Eval)?;
+                        let store_path = serde_json::from_value::<String>(store_path)
+                            .map_err(VariablesError::ParseStorePath)?;
+                        value.push_str(&store_path);
+                    },
+                }
+            }
+            variables.insert(name, value);
+        }
+
+        Ok(variables)
+    }
+
+    /// Runnable apps exposed by this environment
+    ///
+    /// Apps are read from the `apps` attribute of the environment's flake output,
+    /// declared like flake apps (`{ type = "app"; program = ...; }`).
+    pub async fn apps<Nix: FloxNixApi>(&self) -> Result<Vec<AppDef>, EnvironmentOutputError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let apps = self
+            .eval_output::<Nix>("env: builtins.mapAttrs (_: app: app.program) (env.apps or {})")
+            .await?;
+        let apps: BTreeMap<String, String> =
+            serde_json::from_value(apps).map_err(EnvironmentOutputError::Parse)?;
+
+        let installable = self.installable().await?;
+        Ok(apps
+            .into_iter()
+            .map(|(name, program)| AppDef {
+                installable: Installable {
+                    flakeref: installable.flakeref.clone(),
+                    attr_path: format!("{}.apps.{name:?}", installable.attr_path),
+                },
+                name,
+                program: PathBuf::from(program),
+            })
+            .collect())
+    }
+
+    /// The development shell exposed by this environment as its `devShell` attribute
+    ///
+    /// Returns an installable to pass to `nix develop`.
+    pub async fn dev_shell<Nix: FloxNixApi>(
+        &self,
+    ) -> Result<Option<Installable>, EnvironmentOutputError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let exists = self.eval_output::<Nix>("env: env ? devShell").await?;
+        let exists: bool = serde_json::from_value(exists).map_err(EnvironmentOutputError::Parse)?;
+        if !exists {
+            return Ok(None);
+        }
+
+        let mut installable = self.installable().await?;
+        installable.attr_path.push_str(".devShell");
+        Ok(Some(installable))
+    }
+
+    /// Evaluate `apply` on the flake output of this environment
+    async fn eval_output<Nix: FloxNixApi>(
+        &self,
+        apply: &str,
+    ) -> Result<serde_json::Value, EnvironmentOutputError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let (options, _) = self.nix_config_args(self.eval_options).await?;
+        let nix = self.project.flox.nix::<Nix>(Default::default());
+        let eval = Eval {
+            eval: EvaluationArgs {
+                impure: options.impure.into(),
+            },
+            eval_args: EvalArgs {
+                apply: Some(apply.to_string().into()),
+                installable: Some(self.installable().await?.into()),
+            },
+            ..Eval::default()
+        };
+
+        eval.run_json(&nix, &Default::default())
+            .await
+            .map_err(EnvironmentOutputError::Eval)
+    }
+
+    /// Shell snippet to run when activating this environment
+    ///
+    /// Read from `shell.hook` and `hook.onActivate`,
+    /// if both are declared they run in that order.
+    /// References to the host environment are resolved like in [Self::activation_variables].
+    pub async fn activation_hook(&self) -> Result<Option<String>, ActivationHookError> {
+        let flox_nix = self.flox_nix().await?;
+
+        let hooks = [&["shell", "hook"][..], &["hook", "onActivate"]]
+            .into_iter()
+            .map(|path| flox_nix.get_as::<String>(path))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(ActivationHookError::Invalid)?;
+
+        let hooks = hooks.into_iter().flatten().collect::<Vec<_>>();
+        if hooks.is_empty() {
+            return Ok(None);
+        }
+        let hook = self
+            .interpolate_env(hooks.join("\n"))
+            .map_err(ActivationHookError::Undefined)?;
+        Ok(Some(hook))
+    }
+
+    /// [Variables](Self::variables) with references to the host environment resolved
+    ///
+    /// References are only resolved if enabled by
+    /// [Flox::env_interpolation](crate::flox::Flox::env_interpolation),
+    /// see [flox_nix::interpolate_env] for the syntax and its implications.
+    pub async fn activation_variables<Nix: FloxNixApi>(
+        &self,
+    ) -> Result<BTreeMap<String, String>, VariablesError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        self.variables::<Nix>()
+            .await?
+            .into_iter()
+            .map(|(name, value)| match self.interpolate_env(value) {
+                Ok(value) => Ok((name, value)),
+                Err(e) => Err(VariablesError::Undefined(name, e)),
+            })
+            .collect()
+    }
+
+    /// Resolve references to host variables in `value`, as configured by
+    /// [Flox::env_interpolation](crate::flox::Flox::env_interpolation)
+    ///
+    /// Undefined variables are reported as warnings unless interpolation is strict.
+    fn interpolate_env(&self, value: String) -> Result<String, flox_nix::UndefinedVariable> {
+        let mode = self.project.flox.env_interpolation;
+        if mode == EnvInterpolation::Off {
+            return Ok(value);
+        }
+
+        let (interpolated, undefined) =
+            flox_nix::interpolate_env(&value, |var| std::env::var(var).ok());
+        for variable in undefined {
+            if mode == EnvInterpolation::Strict {
+                return Err(flox_nix::UndefinedVariable(variable));
+            }
+            self.project
+                .flox
+                .report_warning(FloxWarning::UndefinedVariable {
+                    environment: self.name.clone(),
+                    variable,
+                });
+        }
+        Ok(interpolated)
+    }
+
+    /// Nix settings declared in the `nixConfig` attribute
+    ///
+    /// Values are rendered as in `nix.conf`:
+    /// lists are separated by spaces, booleans are `true` or `false`.
+    pub async fn nix_config(&self) -> Result<BTreeMap<String, String>, NixConfigError> {
+        declared_nix_config(&self.flox_nix().await?)
+    }
+
+    /// Whether flox.nix declares `impure = true`, see [EvalOptions::impure]
+    pub async fn impure(&self) -> Result<bool, NixConfigError> {
+        declares_impure(&self.flox_nix().await?)
+    }
+
+    /// `options` combined with flox.nix, and the flags applying them and [Self::nix_config]
+    ///
+    /// Pass the flags directly after the nix subcommand,
+    /// so that flags given later on the command line take precedence.
+    /// Warnings about the settings are reported once per environment.
+    async fn nix_config_args(
+        &self,
+        options: EvalOptions,
+    ) -> Result<(EvalOptions, Vec<String>), NixConfigError> {
+        let flox_nix = self.flox_nix().await?;
+        let options = EvalOptions {
+            impure: options.impure || declares_impure(&flox_nix)?,
+        };
+
+        let mut args = Vec::new();
+        if options.impure {
+            self.project
+                .flox
+                .report_warning_once(FloxWarning::ImpureEval {
+                    environment: self.name.clone(),
+                });
+            args.push("--impure".to_string());
+        }
+        for (name, value) in declared_nix_config(&flox_nix)? {
+            if TRUSTED_NIX_SETTINGS.contains(&name.as_str()) {
+                self.project
+                    .flox
+                    .report_warning_once(FloxWarning::UntrustedNixSetting {
+                        environment: self.name.clone(),
+                        setting: name.clone(),
+                    });
+            }
+            args.extend(["--option".to_string(), name, value]);
+        }
+        Ok((options, args))
+    }
+
+    /// Start a declared service
+    ///
+    /// Runs the service command in a `nix shell` of this environment,
+    /// so that the environment's packages are on `PATH`,
+    /// with the [variables](Self::variables) of the environment applied.
+    /// The returned process is not awaited.
+    pub async fn run_service<Nix: FloxNixApi>(
+        &self,
+        name: &str,
+    ) -> Result<Child, RunServiceError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let service = self
+            .services()
+            .await?
+            .into_iter()
+            .find(|service| service.name == name)
+            .ok_or_else(|| RunServiceError::NotFound(name.to_string()))?;
+
+        let mut command = self.shell_command::<Nix>().await?;
+        command.args(["--command", "sh", "-c", &service.command]);
+
+        command
+            .spawn()
+            .map_err(|e| RunServiceError::Spawn(service.name, e))
+    }
+
+    /// Run a command in this environment to completion
+    ///
+    /// Like [Self::run_service], the command runs in a `nix shell`
+    /// with the environment's variables applied,
+    /// but `argv` is executed directly rather than through a shell.
+    /// Standard streams are inherited.
+    ///
+    /// The [activation hook](Self::activation_hook) is sourced with `set -e`
+    /// in a shell that then executes `argv`.
+    /// If any command of the hook fails, the command is not run.
+    pub async fn run_command<Nix: FloxNixApi>(
+        &self,
+        argv: &[String],
+    ) -> Result<ExitStatus, RunCommandError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let (program, args) = argv.split_first().ok_or(RunCommandError::EmptyCommand)?;
+
+        let mut command = self.shell_command::<Nix>().await?;
+        let hook_status = match self.activation_hook().await? {
+            Some(hook) => {
+                let hook_status = tempfile::NamedTempFile::new_in(&self.project.flox.temp_dir)
+                    .map_err(RunCommandError::HookStatus)?
+                    .into_temp_path();
+                command
+                    .env(HOOK_ENV, hook)
+                    .env(HOOK_STATUS_ENV, &*hook_status)
+                    .args(["--command", "sh", "-c", HOOK_WRAPPER, "sh"])
+                    .arg(program)
+                    .args(args);
+                Some(hook_status)
+            },
+            None => {
+                command.arg("--command").arg(program).args(args);
+                None
+            },
+        };
+
+        let status = command
+            .status()
+            .await
+            .map_err(|e| RunCommandError::Spawn(program.to_string(), e))?;
+
+        if let Some(hook_status) = hook_status {
+            let hook_status = tokio::fs::read_to_string(&hook_status)
+                .await
+                .map_err(RunCommandError::HookStatus)?;
+            if let Ok(code) = hook_status.trim().parse() {
+                return Err(RunCommandError::HookFailed(code));
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Build this environment and return its store path
+    ///
+    /// Results are cached keyed by the contents of flox.nix, flake.lock and the system.
+    /// A cached store path is only used if nix still knows it to be valid.
+    ///
+    /// [Pinned](Self::pin) environments resolve to the pinned store path,
+    /// as long as flox.nix did not change since pinning.
+    /// A stale pin is ignored with a [FloxWarning::StaleLock],
+    /// the environment is then built from its current flox.nix as if it was not pinned.
+    ///
+    /// The store path is protected from garbage collection by a root in [GC_ROOTS_DIR]
+    /// until the environment is built again or [removed](prune_gc_roots).
+    ///
+    /// Uses the [EvalOptions] of this environment, see [Self::with_eval_options].
+    pub async fn build(&self) -> Result<PathBuf, BuildEnvironmentError> {
+        self.build_with(self.eval_options).await
+    }
+
+    /// Like [build](Self::build), evaluating the environment according to `options`
+    ///
+    /// Impure builds depend on the host, so they are never cached.
+    pub async fn build_with(&self, options: EvalOptions) -> Result<PathBuf, BuildEnvironmentError> {
+        let mut outputs = self.build_outputs_with(options).await?;
+        Ok(outputs.swap_remove(0))
+    }
+
+    /// Build this environment and return the store paths of all its outputs
+    ///
+    /// The first output is the one returned by [build](Self::build).
+    pub async fn build_outputs(&self) -> Result<Vec<PathBuf>, BuildEnvironmentError> {
+        self.build_outputs_with(self.eval_options).await
+    }
+
+    /// Like [build_outputs](Self::build_outputs), evaluating the environment according to `options`
+    pub async fn build_outputs_with(
+        &self,
+        options: EvalOptions,
+    ) -> Result<Vec<PathBuf>, BuildEnvironmentError> {
+        if let Some(store_path) = &self.store_path {
+            return Ok(vec![store_path.clone()]);
+        }
+
+        let flox_nix = self.read_flox_nix().await?;
+        let outputs = self.realise(&flox_nix, options).await?;
+        self.add_gc_root(&outputs[0]).await?;
+        Ok(outputs)
+    }
+
+    /// Find the executable `bin` provided by this environment
+    ///
+    /// Builds the environment and searches the `bin` directory of each of its outputs.
+    /// Returns the absolute path of the executable in the store
+    /// with symlinks into the packages of the environment resolved.
+    pub async fn which(&self, bin: &str) -> Result<Option<PathBuf>, WhichError> {
+        if bin.is_empty() || bin.contains('/') {
+            return Err(WhichError::InvalidName(bin.to_string()));
+        }
+
+        let outputs = self.build_outputs().await?;
+        find_executable(&outputs, bin).await
+    }
+
+    /// Realise the pinned store path of the environment, or build it from `flox_nix`
+    ///
+    /// A lock of a different flox.nix degrades to an unpinned build, see [Self::build].
+    async fn realise(
+        &self,
+        flox_nix: &[u8],
+        options: EvalOptions,
+    ) -> Result<Vec<PathBuf>, BuildEnvironmentError> {
+        if let Some(lock) = self.lock().await? {
+            if lock.flox_nix_hash == content_hash(&[flox_nix]) {
+                return Ok(vec![self.realise_pinned(lock.environment).await?]);
+            }
+            self.project.flox.report_warning(FloxWarning::StaleLock {
+                environment: self.name.clone(),
+            });
+        }
+
+        let (options, nix_config_args) = self.nix_config_args(options).await?;
+        let cache_entry = self.build_cache_entry(flox_nix).await?;
+
+        if options.impure {
+            debug!(
+                "Not using the build cache for impure environment {}",
+                self.name
+            );
+        } else if let Ok(cached) = tokio::fs::read_to_string(&cache_entry).await {
+            let outputs: Vec<PathBuf> = cached.lines().map(PathBuf::from).collect();
+            let mut valid = !outputs.is_empty();
+            for output in &outputs {
+                valid = valid && self.is_valid_store_path(output).await?;
+            }
+            if valid {
+                debug!("Using cached build of environment {}", self.name);
+                return Ok(outputs);
+            }
+        }
+
+        // make sure nix is configured like for any other flox invocation
+        let nix: NixCommandLine = self.project.flox.nix(Default::default());
+
+        let output = nix
+            .command(&["build"])
+            .args(["--no-link", "--print-out-paths"])
+            .args(nix_config_args)
+            .arg(self.installable().await?.to_string())
+            .output()
+            .await
+            .map_err(BuildEnvironmentError::Spawn)?;
+
+        if !output.status.success() {
+            return Err(BuildEnvironmentError::BadExit(
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        // one line per output
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let outputs: Vec<PathBuf> = stdout.lines().map(PathBuf::from).collect();
+        if outputs.is_empty() {
+            return Err(BuildEnvironmentError::NoOutput);
+        }
+        if options.impure {
+            return Ok(outputs);
+        }
+
+        let cache_dir = cache_entry.parent().unwrap();
+        tokio::fs::create_dir_all(cache_dir)
+            .await
+            .map_err(|e| BuildEnvironmentError::WriteCache(cache_dir.to_path_buf(), e))?;
+        tokio::fs::write(&cache_entry, stdout.as_bytes())
+            .await
+            .map_err(|e| BuildEnvironmentError::WriteCache(cache_entry, e))?;
+
+        Ok(outputs)
+    }
+
+    /// Point the gc root of this environment to `store_path`
+    ///
+    /// Each environment has a single root, named after the path of its flox.nix
+    /// in the original project, so builds within transactions replace the same root.
+    async fn add_gc_root(&self, store_path: &Path) -> Result<(), BuildEnvironmentError> {
+        let flox_nix = self
+            .original_flox_nix_path()
+            .await
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?;
+        let roots_dir = self.project.flox.cache_dir.join(GC_ROOTS_DIR);
+        let root = roots_dir.join(content_hash(&[
+            self.system.as_str().as_bytes(),
+            flox_nix.to_string_lossy().as_bytes(),
+        ]));
+
+        if tokio::fs::read_link(&root).await.ok().as_deref() == Some(store_path) {
+            return Ok(());
+        }
+
+        tokio::fs::create_dir_all(&roots_dir)
+            .await
+            .map_err(|e| BuildEnvironmentError::GcRoot(roots_dir.clone(), e))?;
+
+        let nix: NixCommandLine = self.project.flox.nix(Default::default());
+        let output = nix
+            .command(&["build"])
+            .arg("--out-link")
+            .arg(&root)
+            .arg(store_path)
+            .output()
+            .await
+            .map_err(BuildEnvironmentError::Spawn)?;
+
+        if !output.status.success() {
+            return Err(BuildEnvironmentError::BadExit(
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        let record = root.with_extension("json");
+        tokio::fs::write(
+            &record,
+            serde_json::to_vec(&GcRootRecord { flox_nix }).expect("should serialize gc root"),
+        )
+        .await
+        .map_err(|e| BuildEnvironmentError::GcRoot(record, e))
+    }
+
+    pub(super) async fn is_valid_store_path(
+        &self,
+        store_path: &Path,
+    ) -> Result<bool, BuildEnvironmentError> {
+        let nix: NixCommandLine = self.project.flox.nix(Default::default());
+
+        let status = nix
+            .command(&["path-info"])
+            .arg(store_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await
+            .map_err(BuildEnvironmentError::Spawn)?;
+
+        Ok(status.success())
+    }
+
+    pub(super) async fn read_flox_nix(&self) -> Result<Vec<u8>, ReadFloxNixError> {
+        let path = self
+            .flox_nix_path()
+            .await
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?;
+        self.project
+            .fs
+            .read(&path)
+            .await
+            .map_err(|e| ReadFloxNixError::Read(path, e))
+    }
+
+    /// Path of the build cache entry for this environment defined by `flox_nix`
+    ///
+    /// The entry also depends on the files imported by the environment.
+    pub(super) async fn build_cache_entry(
+        &self,
+        flox_nix: &[u8],
+    ) -> Result<PathBuf, CompositionError> {
+        let lock_path = self
+            .project
+            .workdir()
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?
+            .join(&self.project.subdir)
+            .join("flake.lock");
+        let lock = match self.project.fs.read(&lock_path).await {
+            Ok(lock) => lock,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(ReadFloxNixError::Read(lock_path, e).into()),
+        };
+
+        let mut composition = self.composition().await?;
+        // the environment's own file is hashed as passed in
+        composition.pop();
+
+        let mut parts: Vec<&[u8]> = vec![
+            self.system.as_str().as_bytes(),
+            self.name.as_bytes(),
+            flox_nix,
+            &lock,
+        ];
+        parts.extend(composition.iter().map(|file| file.contents.as_slice()));
+        let key = content_hash(&parts);
+
+        Ok(self.project.flox.cache_dir.join(BUILD_CACHE_DIR).join(key))
+    }
+
+    /// Prepare a `nix shell` of this environment, to be completed with `--command`
+    async fn shell_command<Nix: FloxNixApi>(&self) -> Result<Command, ShellCommandError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let installable = match &self.store_path {
+            Some(store_path) => store_path.to_string_lossy().into_owned(),
+            None => self.installable().await?.to_string(),
+        };
+        let environment_variables = self.activation_variables::<Nix>().await?;
+        let (_, nix_config_args) = self.nix_config_args(self.eval_options).await?;
+
+        // make sure nix is configured like for any other flox invocation
+        let nix = self.project.flox.nix::<Nix>(Default::default());
+
+        let mut command = nix.command(&["shell"]);
+        command
+            .envs(&environment_variables)
+            .args(nix_config_args)
+            .arg(installable);
+        Ok(command)
+    }
+}
+
+/// The `nixConfig` declared in `flox_nix`, see [Environment::nix_config]
+fn declared_nix_config(flox_nix: &FloxNix) -> Result<BTreeMap<String, String>, NixConfigError> {
+    let config: BTreeMap<String, serde_json::Value> = flox_nix
+        .get_as(&["nixConfig"])
+        .map_err(NixConfigError::Invalid)?
+        .unwrap_or_default();
+
+    config
+        .into_iter()
+        .map(|(name, value)| match serde_json::from_value(value) {
+            Ok(value) => Ok((name, NixConfigValue::render(&value))),
+            Err(_) => Err(NixConfigError::Value(name)),
+        })
+        .collect()
+}
+
+/// Whether `flox_nix` declares `impure = true`, see [Environment::impure]
+fn declares_impure(flox_nix: &FloxNix) -> Result<bool, NixConfigError> {
+    let impure = flox_nix
+        .get_as(&["impure"])
+        .map_err(NixConfigError::Impure)?;
+    Ok(impure.unwrap_or(false))
+}
+
+/// Changes from the packages declared `before` to those declared `after`
+fn diff_packages(
+    before: BTreeMap<FloxPackage, serde_json::Value>,
+    after: &BTreeMap<FloxPackage, serde_json::Value>,
+) -> EnvDiff {
+    let mut diff = EnvDiff::default();
+    for (package, attrs) in after.iter() {
+        match before.get(package) {
+            None => diff.added.push(package.clone()),
+            Some(previous) if previous != attrs => diff.changed.push(package.clone()),
+            Some(_) => {},
+        }
+    }
+    diff.removed = before
+        .into_keys()
+        .filter(|package| !after.contains_key(package))
+        .collect();
+    diff
+}
+
+impl<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem>
+    Project<'flox, Git, Access, Fs>
+{
+    /// Summarize the packages changed in environment `name` from `other_rev` to `HEAD`
+    ///
+    /// Both versions of flox.nix are read from git without evaluating them,
+    /// uncommitted changes are not considered.
+    /// An environment that only exists at one of the revisions
+    /// is compared with an empty environment.
+    pub async fn diff_against(
+        &self,
+        other_rev: &str,
+        name: &str,
+    ) -> Result<EnvDiff, DiffAgainstError<Git>> {
+        let before = self.environment_packages_at(other_rev, name).await?;
+        let after = self.environment_packages_at("HEAD", name).await?;
+
+        match (before, after) {
+            (None, None) => Err(DiffAgainstError::NotFound(name.to_string())),
+            (before, after) => Ok(diff_packages(
+                before.unwrap_or_default(),
+                &after.unwrap_or_default(),
+            )),
+        }
+    }
+
+    /// Read the packages declared by environment `name` at `rev`,
+    /// [None] if it has no flox.nix at `rev`
+    async fn environment_packages_at(
+        &self,
+        rev: &str,
+        name: &str,
+    ) -> Result<Option<BTreeMap<FloxPackage, serde_json::Value>>, DiffAgainstError<Git>> {
+        // git expects paths relative to the repository root
+        let dir = if name == "default" {
+            self.subdir.clone()
+        } else {
+            self.subdir.join("pkgs").join(name)
+        };
+
+        for flox_nix_name in self.flox.flox_nix_names.iter() {
+            let path = dir.join(flox_nix_name);
+            let contents = match self.git.git().show_file(rev, &path).await {
+                Ok(contents) => contents,
+                Err(e) if e.not_found() => continue,
+                Err(e) => return Err(DiffAgainstError::Show(e)),
+            };
+
+            return contents
+                .to_string_lossy()
+                .parse::<FloxNix>()
+                .and_then(|flox_nix| flox_nix.packages())
+                .map(Some)
+                .map_err(|e| DiffAgainstError::Parse(rev.to_string(), e));
         }
+        Ok(None)
     }
 }
 
 /// Implementations for R/O only instances
 ///
 /// Mainly transformation into modifiable sandboxed instances
-impl<'flox, Git: GitProvider> Environment<'flox, Git, ReadOnly<Git>> {
+impl<'flox, Git: GitProvider, Fs: FileSystem> Environment<'flox, Git, ReadOnly<Git>, Fs> {
+    /// Add operations committed to this environment to the audit log, if enabled
+    ///
+    /// Unless `squashed`, the operations were committed by themselves
+    /// and are attributed to the latest generation.
+    async fn write_audit_log(
+        &self,
+        recorded: Vec<(AuditOperation, Vec<FloxPackage>)>,
+        squashed: bool,
+    ) {
+        let flox = self.project.flox;
+        if !flox.audit || recorded.is_empty() {
+            return;
+        }
+
+        let generation = if squashed {
+            None
+        } else {
+            match self.history(1).await {
+                Ok(history) => history.first().map(|entry| entry.generation),
+                Err(e) => {
+                    debug!("Could not determine the generation for the audit log: {e}");
+                    None
+                },
+            }
+        };
+        let entries: Vec<_> = recorded
+            .into_iter()
+            .map(|(operation, packages)| {
+                AuditEntry::new(operation, &self.name, packages, generation)
+            })
+            .collect();
+
+        flox.append_audit_log(&self.name, &entries).await
+    }
+
     /// Enter into editable mode by creating a git sandbox for the floxmeta
     pub async fn enter_transaction(
         self,
-    ) -> Result<(Environment<'flox, Git, GitSandBox<Git>>, Index), TransactionEnterError> {
+    ) -> Result<(Environment<'flox, Git, GitSandBox<Git>, Fs>, Index), TransactionEnterError<Git>>
+    {
         let (project, index) = self.project.enter_transaction().await?;
         Ok((
             Environment {
                 name: self.name,
                 system: self.system,
                 project,
+                compat: self.compat,
+                store_path: None,
+                eval_options: self.eval_options,
             },
             index,
         ))
@@ -58,18 +1380,2110 @@ impl<'flox, Git: GitProvider> Environment<'flox, Git, ReadOnly<Git>> {
 }
 
 /// Implementations for sandboxed only Environments
-impl<'flox, Git: GitProvider> Environment<'flox, Git, GitSandBox<Git>> {
+impl<'flox, Git: GitProvider, Fs: FileSystem> Environment<'flox, Git, GitSandBox<Git>, Fs> {
     /// Commit changes to environment by closing the underlying transaction
+    ///
+    /// See [Project::commit_transaction] for the semantics of `dry_run`.
     pub async fn commit_transaction(
         self,
         index: Index,
         message: &'flox str,
-    ) -> Result<Environment<'_, Git, ReadOnly<Git>>, TransactionCommitError<Git>> {
-        let project = self.project.commit_transaction(index, message).await?;
-        Ok(Environment {
-            name: self.name,
-            system: self.system,
-            project,
+        dry_run: bool,
+    ) -> Result<
+        TransactionOutcome<Environment<'flox, Git, ReadOnly<Git>, Fs>, Self>,
+        TransactionCommitError<Git>,
+    > {
+        let name = self.name;
+        let system = self.system;
+        let compat = self.compat;
+        let eval_options = self.eval_options;
+        let recorded = self.project.git.take_recorded();
+        let squashed = self.project.git.commit_strategy() == CommitStrategy::Squashed;
+        let outcome = match self
+            .project
+            .commit_transaction(index, message, dry_run)
+            .await?
+        {
+            TransactionOutcome::Committed(project) => {
+                let environment = Environment {
+                    name,
+                    system,
+                    project,
+                    compat,
+                    store_path: None,
+                    eval_options,
+                };
+                environment.write_audit_log(recorded, squashed).await;
+                TransactionOutcome::Committed(environment)
+            },
+            TransactionOutcome::DryRun {
+                sandbox,
+                index,
+                operations,
+            } => {
+                // nothing was committed, keep the operations for the next attempt
+                for (operation, packages) in recorded {
+                    sandbox.git.record(operation, packages);
+                }
+                TransactionOutcome::DryRun {
+                    sandbox: Environment {
+                        name,
+                        system,
+                        project: sandbox,
+                        compat,
+                        store_path: None,
+                        eval_options,
+                    },
+                    index,
+                    operations,
+                }
+            },
+            TransactionOutcome::Rejected {
+                sandbox,
+                index,
+                report,
+            } => {
+                for (operation, packages) in recorded {
+                    sandbox.git.record(operation, packages);
+                }
+                TransactionOutcome::Rejected {
+                    sandbox: Environment {
+                        name,
+                        system,
+                        project: sandbox,
+                        compat,
+                        store_path: None,
+                        eval_options,
+                    },
+                    index,
+                    report,
+                }
+            },
+        };
+        Ok(outcome)
+    }
+
+    /// Add packages to the flox.nix of this environment
+    ///
+    /// Packages naming no channel, e.g. `hello`, are resolved at the default stability
+    /// like in [Self::install_names].
+    pub async fn install(
+        &self,
+        packages: &[FloxPackage],
+        index: &mut Index,
+    ) -> Result<(), EditEnvironmentError> {
+        let mut resolved = Vec::with_capacity(packages.len());
+        for package in packages {
+            if package.contains('.') {
+                resolved.push(package.clone());
+                continue;
+            }
+            let package = self
+                .resolve_name(package, &Stability::default())
+                .await?
+                .ok_or_else(|| EditEnvironmentError::NotResolved(package.clone()))?;
+            resolved.push(package);
+        }
+        let packages = &resolved[..];
+
+        if let Some(policy) = &self.project.flox.install_policy {
+            policy.check(packages)?;
+        }
+        self.edit_flox_nix(index, |contents| {
+            flox_nix::install_packages(&contents, packages)
         })
+        .await?;
+        self.project
+            .git
+            .record(AuditOperation::Install, packages.to_vec());
+        Ok(())
+    }
+
+    /// `<channel>.<name>` of the preferred match of `name`, see [Self::install_names]
+    async fn resolve_name(
+        &self,
+        name: &str,
+        stability: &Stability,
+    ) -> Result<Option<FloxPackage>, ResolvePackageError> {
+        let package = self
+            .project
+            .flox
+            .resolve_package(name, stability)
+            .await?
+            .into_iter()
+            .next();
+        Ok(package.map(|package| format!("{}.{}", package.flakeref, package.key.join("."))))
+    }
+
+    /// Resolve package names, e.g. `hello`, with the
+    /// [PackageResolver](crate::models::resolver::PackageResolver) of flox
+    /// and add the preferred match of each to the flox.nix of this environment
+    ///
+    /// Returns the installed `<channel>.<name>` packages.
+    pub async fn install_names(
+        &self,
+        names: &[&str],
+        stability: &Stability,
+        index: &mut Index,
+    ) -> Result<Vec<FloxPackage>, InstallNamesError> {
+        let mut packages = Vec::with_capacity(names.len());
+        for name in names {
+            let package = self
+                .resolve_name(name, stability)
+                .await?
+                .ok_or_else(|| InstallNamesError::NotFound(name.to_string()))?;
+            packages.push(package);
+        }
+        self.install(&packages, index).await?;
+        Ok(packages)
+    }
+
+    /// Remove packages from the flox.nix of this environment
+    pub async fn uninstall(
+        &self,
+        packages: &[FloxPackage],
+        index: &mut Index,
+    ) -> Result<(), EditEnvironmentError> {
+        self.edit_flox_nix(index, |contents| {
+            flox_nix::uninstall_packages(&contents, packages)
+        })
+        .await?;
+        self.project
+            .git
+            .record(AuditOperation::Uninstall, packages.to_vec());
+        Ok(())
+    }
+
+    /// Upgrade a single `<channel>.<name>` package to the latest version in its channel
+    ///
+    /// The package is resolved at `stability` with the
+    /// [PackageResolver](crate::models::resolver::PackageResolver) of flox
+    /// and has to pass the install policy like a new install.
+    /// Returns the old and new version if the package was upgraded.
+    /// The current version is taken from flox.nix or the lock of a pinned environment;
+    /// a pinned environment drifts from its lock until it is pinned again.
+    /// Packages without either are pinned to the latest version, the old version is then [None].
+    /// Versions are compared with `builtins.compareVersions`,
+    /// a package that is up to date or newer than its channel is left as it is.
+    pub async fn upgrade_package<Nix: FloxNixApi>(
+        &self,
+        package: &FloxPackage,
+        stability: &Stability,
+        index: &mut Index,
+    ) -> Result<Option<(Option<String>, String)>, UpgradePackageError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let (channel, name) = package
+            .split_once('.')
+            .ok_or_else(|| UpgradePackageError::InvalidPackage(package.clone()))?;
+
+        let flox_nix = self.flox_nix().await?;
+        if !flox_nix
+            .has_package(package)
+            .map_err(UpgradePackageError::FloxNix)?
+        {
+            return Err(UpgradePackageError::NotInstalled(package.clone()));
+        }
+        if let Some(policy) = &self.project.flox.install_policy {
+            policy
+                .check(std::slice::from_ref(package))
+                .map_err(EditEnvironmentError::from)?;
+        }
+
+        let declared = flox_nix
+            .get_as::<PackageDecl>(&["packages", channel, name])
+            .map_err(UpgradePackageError::FloxNix)?
+            .and_then(|decl| decl.version);
+        let current = match declared {
+            Some(version) => Some(version),
+            None => self
+                .lock()
+                .await?
+                .and_then(|mut lock| lock.packages.remove(package))
+                .and_then(|locked| locked.version),
+        };
+
+        let resolved = self
+            .project
+            .flox
+            .resolve_package(name, stability)
+            .await?
+            .into_iter()
+            .find(|resolved| resolved.flakeref == channel && resolved.key == [name])
+            .ok_or_else(|| UpgradePackageError::NotResolved(package.clone(), stability.clone()))?;
+
+        let newer = match &current {
+            Some(current) => format!("builtins.compareVersions version {current:?} == 1"),
+            None => "true".to_string(),
+        };
+        let nix = self.project.flox.nix::<Nix>(Default::default());
+        let eval = Eval {
+            eval_args: EvalArgs {
+                apply: Some(
+                    format!(
+                        "package: let version = package.version or null; in \
+                         if version != null && {newer} then version else null"
+                    )
+                    .into(),
+                ),
+                installable: Some(resolved.installable().into()),
+            },
+            ..Eval::default()
+        };
+        let latest = eval
+            .run_json(&nix, &Default::default())
+            .await
+            .map_err(UpgradePackageError::Eval)?;
+        let latest: Option<String> =
+            serde_json::from_value(latest).map_err(UpgradePackageError::ParseEval)?;
+
+        let latest = match latest {
+            Some(latest) => latest,
+            None => return Ok(None),
+        };
+
+        self.edit_flox_nix(index, |contents| {
+            flox_nix::set_package_version(&contents, package, &latest)
+        })
+        .await?;
+        self.project
+            .git
+            .record(AuditOperation::Upgrade, vec![package.clone()]);
+
+        Ok(Some((current, latest)))
+    }
+
+    /// Apply an edit to the flox.nix in the sandbox and record it in the index
+    async fn edit_flox_nix(
+        &self,
+        index: &mut Index,
+        edit: impl FnOnce(String) -> Result<String, FloxNixError>,
+    ) -> Result<(), EditEnvironmentError> {
+        let workdir = self
+            .project
+            .workdir()
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?
+            .to_path_buf();
+        let path = self
+            .flox_nix_path()
+            .await
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?;
+
+        // the build cache is keyed by the contents of the flox.nix,
+        // so the edited definition is not served the build of the current one
+        let contents = self.read_flox_nix().await?;
+
+        let edited = match edit(String::from_utf8_lossy(&contents).into_owned()) {
+            Ok(edited) => edited,
+            Err(FloxNixError::Parse(e)) => {
+                return Err(ReadFloxNixError::MalformedDefinition(path, e).into())
+            },
+            Err(e) => return Err(EditEnvironmentError::ModifyFloxNix(e)),
+        };
+
+        self.project
+            .fs
+            .write(&path, edited.as_bytes())
+            .await
+            .map_err(|e| EditEnvironmentError::WriteFloxNix(path.clone(), e))?;
+
+        index.insert(
+            path.strip_prefix(&workdir)
+                .expect("flox.nix is within the project")
+                .to_path_buf(),
+            FileAction::Add,
+        );
+        self.project
+            .write_transaction_state(index)
+            .await
+            .map_err(EditEnvironmentError::WriteState)?;
+
+        Ok(())
+    }
+}
+
+/// Hex encoded sha256 of length prefixed `parts`
+///
+/// The length prefixes keep the concatenation unambiguous.
+pub(crate) fn content_hash(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Find the first executable named `bin` in the `bin` directories of `outputs`
+///
+/// The returned path is canonicalized.
+async fn find_executable(outputs: &[PathBuf], bin: &str) -> Result<Option<PathBuf>, WhichError> {
+    for output in outputs {
+        let candidate = output.join("bin").join(bin);
+        let path = match tokio::fs::canonicalize(&candidate).await {
+            Ok(path) => path,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(WhichError::Inspect(candidate, e)),
+        };
+        let metadata = tokio::fs::metadata(&path)
+            .await
+            .map_err(|e| WhichError::Inspect(path.clone(), e))?;
+        if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Remove gc roots in [GC_ROOTS_DIR] of environments that no longer exist
+///
+/// An environment no longer exists if its flox.nix was removed.
+pub(crate) async fn prune_gc_roots(cache_dir: &Path) -> Result<PruneReport, PruneGcRootsError> {
+    remove_gc_roots(cache_dir, None).await
+}
+
+/// Remove gc roots in [GC_ROOTS_DIR] of environments defined below `workdir`
+///
+/// Roots of removed environments are removed as well, like by [prune_gc_roots].
+pub(super) async fn remove_project_gc_roots(
+    cache_dir: &Path,
+    workdir: &Path,
+) -> Result<PruneReport, PruneGcRootsError> {
+    remove_gc_roots(cache_dir, Some(workdir)).await
+}
+
+/// Remove gc roots of removed environments and those defined below `workdir`
+async fn remove_gc_roots(
+    cache_dir: &Path,
+    workdir: Option<&Path>,
+) -> Result<PruneReport, PruneGcRootsError> {
+    let roots_dir = cache_dir.join(GC_ROOTS_DIR);
+    let mut entries = match tokio::fs::read_dir(&roots_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => return Err(PruneGcRootsError::Read(roots_dir, e)),
+    };
+
+    let mut report = PruneReport::default();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| PruneGcRootsError::Read(roots_dir.clone(), e))?
+    {
+        let root = entry.path();
+        if root
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            continue;
+        }
+
+        let record = root.with_extension("json");
+        let keep = match tokio::fs::read(&record).await {
+            Ok(contents) => match serde_json::from_slice::<GcRootRecord>(&contents) {
+                Ok(GcRootRecord { flox_nix })
+                    if workdir.is_some_and(|workdir| flox_nix.starts_with(workdir)) =>
+                {
+                    false
+                },
+                Ok(GcRootRecord { flox_nix }) => match tokio::fs::metadata(&flox_nix).await {
+                    Ok(_) => true,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+                    Err(e) => return Err(PruneGcRootsError::Read(flox_nix, e)),
+                },
+                Err(_) => false,
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(PruneGcRootsError::Read(record, e)),
+        };
+        if keep {
+            continue;
+        }
+
+        if let Ok(store_path) = tokio::fs::read_link(&root).await {
+            report.reclaimable.push(store_path);
+        }
+        tokio::fs::remove_file(&root)
+            .await
+            .map_err(|e| PruneGcRootsError::Remove(root.clone(), e))?;
+        match tokio::fs::remove_file(&record).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(PruneGcRootsError::Remove(record, e))
+            },
+            _ => {},
+        }
+        report.removed.push(root);
+    }
+
+    Ok(report)
+}
+
+#[derive(Error, Debug)]
+pub enum AttachStorePathError {
+    #[error(transparent)]
+    Check(#[from] BuildEnvironmentError),
+    #[error("{0:?} is not a valid store path")]
+    NotInStore(PathBuf),
+    #[error("{0:?} is not a flox environment")]
+    NotAnEnvironment(PathBuf),
+    #[error("Failed to read {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+}
+
+impl AttachStorePathError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            AttachStorePathError::Check(e) => e.code(),
+            AttachStorePathError::NotInStore(_) => FloxErrorCode::NotFound,
+            AttachStorePathError::NotAnEnvironment(_) => FloxErrorCode::Invalid,
+            AttachStorePathError::Read(..) => FloxErrorCode::Io,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReadFloxNixError {
+    #[error("Could not determine repository root")]
+    WorkdirNotFound,
+    #[error("Error reading {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Error parsing {0:?}: {1}")]
+    Parse(PathBuf, FloxNixError),
+    #[error("{0:?} is not valid nix: {1}")]
+    MalformedDefinition(PathBuf, FloxNixParseError),
+}
+
+impl ReadFloxNixError {
+    /// Error for `path` failing to parse,
+    /// syntax errors are reported as [ReadFloxNixError::MalformedDefinition]
+    pub(super) fn parse(path: PathBuf, error: FloxNixError) -> Self {
+        match error {
+            FloxNixError::Parse(e) => ReadFloxNixError::MalformedDefinition(path, e),
+            e => ReadFloxNixError::Parse(path, e),
+        }
+    }
+
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            ReadFloxNixError::WorkdirNotFound => FloxErrorCode::NoWorkdir,
+            ReadFloxNixError::Read(_, e) if e.kind() == std::io::ErrorKind::NotFound => {
+                FloxErrorCode::NotFound
+            },
+            ReadFloxNixError::Read(..) => FloxErrorCode::Io,
+            ReadFloxNixError::Parse(..) | ReadFloxNixError::MalformedDefinition(..) => {
+                FloxErrorCode::Invalid
+            },
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DefinitionPathError {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error(transparent)]
+    Layout(#[from] LegacyLayoutError),
+    #[error("Environment '{0}' is defined inline and has no flox.nix")]
+    Inline(String),
+    #[error("Environment definition {0:?} not found")]
+    NotFound(PathBuf),
+    #[error("Failed to inspect {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+}
+
+impl DefinitionPathError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            DefinitionPathError::Workdir(e) => e.code(),
+            DefinitionPathError::Layout(e) => e.code(),
+            DefinitionPathError::Inline(_) => FloxErrorCode::Invalid,
+            DefinitionPathError::NotFound(_) => FloxErrorCode::NotFound,
+            DefinitionPathError::Read(..) => FloxErrorCode::Io,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ListPackagesError {
+    #[error(transparent)]
+    Composition(#[from] CompositionError),
+}
+
+#[derive(Error, Debug)]
+pub enum HistoryError<Git: GitProvider> {
+    #[error("Could not determine repository root")]
+    WorkdirNotFound,
+    #[error("Failed to read history: {0}")]
+    Log(Git::LogError),
+    #[error("Failed to read flox.nix: {0}")]
+    Show(Git::ShowError),
+    #[error("Invalid packages declaration at {0}: {1}")]
+    Parse(String, FloxNixError),
+}
+
+#[derive(Error, Debug)]
+pub enum DiffAgainstError<Git: GitProvider> {
+    #[error("Environment '{0}' exists at neither revision")]
+    NotFound(String),
+    #[error("Failed to read flox.nix: {0}")]
+    Show(Git::ShowError),
+    #[error("Invalid packages declaration at {0}: {1}")]
+    Parse(String, FloxNixError),
+}
+
+#[derive(Error, Debug)]
+pub enum ContainsPackageError {
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error("Invalid packages declaration: {0}")]
+    Invalid(FloxNixError),
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+    #[error("Nix eval failed with exit code {0}: {1}")]
+    BadExit(i32, String),
+    #[error("Failed parsing evaluation result: {0}")]
+    ParseEval(serde_json::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum EnvironmentChannelsError {
+    #[error("Could not determine repository root")]
+    WorkdirNotFound,
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+    #[error("Nix eval failed with exit code {0}: {1}")]
+    BadExit(i32, String),
+    #[error("Failed parsing evaluation result: {0}")]
+    ParseEval(serde_json::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum EditEnvironmentError {
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error("Failed to modify flox.nix: {0}")]
+    ModifyFloxNix(FloxNixError),
+    #[error("Failed to write {0:?}: {1}")]
+    WriteFloxNix(PathBuf, std::io::Error),
+    #[error("Failed to write transaction state: {0}")]
+    WriteState(std::io::Error),
+    #[error(transparent)]
+    Denied(#[from] PolicyDenied),
+    #[error(transparent)]
+    Resolve(#[from] ResolvePackageError),
+    #[error("No package named '{0}' found")]
+    NotResolved(String),
+}
+
+#[derive(Error, Debug)]
+pub enum InstallNamesError {
+    #[error(transparent)]
+    Resolve(#[from] ResolvePackageError),
+    #[error("No package named '{0}' found")]
+    NotFound(String),
+    #[error(transparent)]
+    Edit(#[from] EditEnvironmentError),
+}
+
+#[derive(Error, Debug)]
+pub enum UpgradePackageError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error(transparent)]
+    ReadLock(#[from] ReadLockError),
+    #[error("Package '{0}' is not of the form '<channel>.<name>'")]
+    InvalidPackage(String),
+    #[error("Package '{0}' is not installed")]
+    NotInstalled(String),
+    #[error("Package '{0}' is not available at stability '{1}'")]
+    NotResolved(String, Stability),
+    #[error(transparent)]
+    Resolve(#[from] ResolvePackageError),
+    #[error("Failed to read package from flox.nix: {0}")]
+    FloxNix(FloxNixError),
+    #[error("Failed evaluating package: {0}")]
+    Eval(<Eval as RunJson<Nix>>::JsonError),
+    #[error("Failed parsing package evaluation: {0}")]
+    ParseEval(serde_json::Error),
+    #[error(transparent)]
+    Edit(#[from] EditEnvironmentError),
+}
+
+#[derive(Error, Debug)]
+pub enum BuildEnvironmentError {
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+    #[error("Nix build failed with exit code {0}:\n{1}")]
+    BadExit(i32, String),
+    #[error(transparent)]
+    NixConfig(#[from] NixConfigError),
+    #[error("Nix build did not report a store path")]
+    NoOutput,
+    #[error(transparent)]
+    ReadLock(#[from] ReadLockError),
+    #[error("Pinned environment {0:?} is neither present nor substitutable")]
+    PinnedUnavailable(PathBuf),
+    #[error("Failed to write build cache entry {0:?}: {1}")]
+    WriteCache(PathBuf, std::io::Error),
+    #[error("Failed to create gc root {0:?}: {1}")]
+    GcRoot(PathBuf, std::io::Error),
+    #[error(transparent)]
+    Composition(#[from] CompositionError),
+}
+
+impl BuildEnvironmentError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            BuildEnvironmentError::ReadFloxNix(e) => e.code(),
+            BuildEnvironmentError::Workdir(e) => e.code(),
+            BuildEnvironmentError::ReadLock(e) => e.code(),
+            BuildEnvironmentError::Composition(e) => e.code(),
+            BuildEnvironmentError::NixConfig(NixConfigError::ReadFloxNix(e)) => e.code(),
+            BuildEnvironmentError::NixConfig(_) => FloxErrorCode::Invalid,
+            BuildEnvironmentError::Spawn(_)
+            | BuildEnvironmentError::BadExit(..)
+            | BuildEnvironmentError::NoOutput
+            | BuildEnvironmentError::PinnedUnavailable(_) => FloxErrorCode::Nix,
+            BuildEnvironmentError::WriteCache(..) | BuildEnvironmentError::GcRoot(..) => {
+                FloxErrorCode::Io
+            },
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum WhichError {
+    #[error(transparent)]
+    Build(#[from] BuildEnvironmentError),
+    #[error("Invalid executable name '{0}'")]
+    InvalidName(String),
+    #[error("Failed to inspect {0:?}: {1}")]
+    Inspect(PathBuf, std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum PruneGcRootsError {
+    #[error("Failed to read {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to remove {0:?}: {1}")]
+    Remove(PathBuf, std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ServicesError {
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error("Invalid services declaration: {0}")]
+    Invalid(FloxNixError),
+}
+
+#[derive(Error, Debug)]
+pub enum RunServiceError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    Services(#[from] ServicesError),
+    #[error("Service '{0}' is not declared")]
+    NotFound(String),
+    #[error(transparent)]
+    Shell(#[from] ShellCommandError<Nix>),
+    #[error("Failed to start service '{0}': {1}")]
+    Spawn(String, std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ShellCommandError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Invalid environment variables: {0}")]
+    EnvironmentVariables(#[from] VariablesError<Nix>),
+    #[error(transparent)]
+    NixConfig(#[from] NixConfigError),
+}
+
+#[derive(Error, Debug)]
+pub enum NixConfigError {
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error("Invalid nixConfig declaration: {0}")]
+    Invalid(FloxNixError),
+    #[error("nixConfig.{0} must be a string, number, boolean or a list thereof")]
+    Value(String),
+    #[error("impure must be a boolean: {0}")]
+    Impure(FloxNixError),
+}
+
+#[derive(Error, Debug)]
+pub enum ActivationHookError {
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error("Invalid activation hook: {0}")]
+    Invalid(FloxNixError),
+    #[error("Could not resolve the activation hook: {0}")]
+    Undefined(flox_nix::UndefinedVariable),
+}
+
+#[derive(Error, Debug)]
+pub enum RunCommandError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error("No command given")]
+    EmptyCommand,
+    #[error(transparent)]
+    Shell(#[from] ShellCommandError<Nix>),
+    #[error(transparent)]
+    Hook(#[from] ActivationHookError),
+    #[error("Failed to track activation hook status: {0}")]
+    HookStatus(std::io::Error),
+    #[error("Activation hook failed with exit code {0}")]
+    HookFailed(i32),
+    #[error("Failed to run '{0}': {1}")]
+    Spawn(String, std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum VariablesError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error("Invalid environment variables declaration: {0}")]
+    Invalid(FloxNixError),
+    #[error("Only package references ('packages.<channel>.<name>') are supported, found '{0}'")]
+    UnsupportedReference(String),
+    #[error("Failed evaluating package reference: {0}")]
+    Eval(<Eval as RunJson<Nix>>::JsonError),
+    #[error("Failed parsing store path: {0}")]
+    ParseStorePath(serde_json::Error),
+    #[error("Could not resolve '{0}': {1}")]
+    Undefined(String, flox_nix::UndefinedVariable),
+}
+
+#[derive(Error, Debug)]
+pub enum EnvironmentOutputError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error(transparent)]
+    NixConfig(#[from] NixConfigError),
+    #[error("Failed evaluating environment: {0}")]
+    Eval(<Eval as RunJson<Nix>>::JsonError),
+    #[error("Failed parsing evaluation result: {0}")]
+    Parse(serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use runix::arguments::NixArgs;
+    use runix::Run;
+
+    use super::*;
+    use crate::flox::{Flox, ResolvedInstallableMatch};
+    use crate::models::events::{EventSink, FloxEvent};
+    use crate::models::flox_nix::FloxNixNames;
+    use crate::models::policy::{InstallPolicy, PolicyDecision};
+    use crate::models::project::tests::test_environment;
+    use crate::models::project::TransactionOptions;
+    use crate::models::resolver::PackageResolver;
+    use crate::providers::fs::MemFs;
+    use crate::providers::git::GitCommandProvider;
+
+    #[tokio::test]
+    async fn flox_nix_path_respects_configured_names() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            flox_nix_names: FloxNixNames::new(["env.nix", "flox.nix"]),
+            ..Default::default()
+        };
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        fs.create_dir_all(&workdir).await.unwrap();
+
+        // new environments use the first name
+        assert_eq!(
+            environment.flox_nix_path().await,
+            Some(workdir.join("env.nix"))
+        );
+
+        // existing files are found by any name
+        fs.write(&workdir.join("flox.nix"), b"{}").await.unwrap();
+        assert_eq!(
+            environment.flox_nix_path().await,
+            Some(workdir.join("flox.nix"))
+        );
+
+        // the first name takes precedence if both exist
+        fs.write(&workdir.join("env.nix"), b"{}").await.unwrap();
+        assert_eq!(
+            environment.flox_nix_path().await,
+            Some(workdir.join("env.nix"))
+        );
+    }
+
+    #[tokio::test]
+    async fn definition_path_of_current_and_legacy_layout() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let git = GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        let flox = Flox::default();
+        let project = Project::new(&flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new());
+        let workdir = project.workdir().unwrap().to_path_buf();
+        let environment = |compat| Environment {
+            name: "dev".to_string(),
+            system: System::Aarch64Darwin,
+            project: Project::new(
+                &flox,
+                project.git.read_only(),
+                project.fs.clone(),
+                PathBuf::new(),
+            ),
+            compat,
+            store_path: None,
+            eval_options: EvalOptions::default(),
+        };
+
+        assert!(matches!(
+            environment(false).definition_path().await,
+            Err(DefinitionPathError::NotFound(path)) if path == workdir.join("pkgs/dev/flox.nix")
+        ));
+        assert!(matches!(
+            environment(true).definition_path().await,
+            Err(DefinitionPathError::Inline(name)) if name == "dev"
+        ));
+
+        std::fs::create_dir_all(workdir.join("pkgs/dev")).unwrap();
+        std::fs::write(workdir.join("pkgs/dev/flox.nix"), "{}").unwrap();
+        assert_eq!(
+            environment(false).definition_path().await.unwrap(),
+            workdir.join("pkgs/dev/flox.nix")
+        );
+
+        // legacy projects are defined by the current generation
+        for generation in ["1", "2"] {
+            std::fs::create_dir_all(workdir.join(generation).join("pkgs/dev")).unwrap();
+            std::fs::write(workdir.join(generation).join("flake.nix"), "{}").unwrap();
+            std::fs::write(workdir.join(generation).join("pkgs/dev/flox.nix"), "{}").unwrap();
+        }
+        assert_eq!(
+            environment(false).definition_path().await.unwrap(),
+            workdir.join("2/pkgs/dev/flox.nix")
+        );
+        std::fs::write(
+            workdir.join("metadata.json"),
+            r#"{ "currentGen": "1", "generations": {} }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            environment(false).definition_path().await.unwrap(),
+            workdir.join("1/pkgs/dev/flox.nix")
+        );
+    }
+
+    #[tokio::test]
+    async fn definition_path_of_legacy_subproject() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        let environment = Environment {
+            project: Project {
+                subdir: PathBuf::from("sub"),
+                ..environment.project
+            },
+            ..environment
+        };
+
+        // both the repository and the subproject use the legacy layout
+        for root in [workdir.clone(), workdir.join("sub")] {
+            fs.create_dir_all(&root.join("1/pkgs/default"))
+                .await
+                .unwrap();
+            fs.write(&root.join("1/flake.nix"), b"{}").await.unwrap();
+            fs.write(&root.join("1/pkgs/default/flox.nix"), b"{}")
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            environment.definition_path().await.unwrap(),
+            workdir.join("sub/1/pkgs/default/flox.nix")
+        );
+    }
+
+    #[tokio::test]
+    async fn installs_and_uninstalls_packages() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let environment = test_environment(&flox, &project_dir, TokioFs).await;
+        std::fs::write(project_dir.join("flake.nix"), "{}").unwrap();
+        std::fs::write(
+            project_dir.join("flox.nix"),
+            "{\n  packages.nixpkgs-flox.fd = {};\n}\n",
+        )
+        .unwrap();
+        let (project, mut index) = environment.project.enter_transaction().await.unwrap();
+        let environment = Environment {
+            name: environment.name,
+            system: environment.system,
+            project,
+            compat: false,
+            store_path: None,
+            eval_options: EvalOptions::default(),
+        };
+
+        environment
+            .install(&["nixpkgs-flox.hello".to_string()], &mut index)
+            .await
+            .unwrap();
+        assert_eq!(environment.packages().await.unwrap(), [
+            "nixpkgs-flox.fd",
+            "nixpkgs-flox.hello"
+        ]);
+        assert_eq!(index.get(Path::new("flox.nix")), Some(&FileAction::Add));
+
+        environment
+            .uninstall(&["nixpkgs-flox.fd".to_string()], &mut index)
+            .await
+            .unwrap();
+        assert_eq!(environment.packages().await.unwrap(), [
+            "nixpkgs-flox.hello"
+        ]);
+
+        // the original project is only changed once the transaction is committed
+        assert_eq!(
+            std::fs::read_to_string(project_dir.join("flox.nix")).unwrap(),
+            "{\n  packages.nixpkgs-flox.fd = {};\n}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn interpolates_hooks_if_enabled() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = std::env::var("PATH").unwrap();
+
+        for mode in [
+            EnvInterpolation::Off,
+            EnvInterpolation::Lenient,
+            EnvInterpolation::Strict,
+        ] {
+            let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let sink_warnings = warnings.clone();
+            let flox = Flox {
+                env_interpolation: mode,
+                event_sink: Some(EventSink::new(move |event| {
+                    if let FloxEvent::Warning(warning) = event {
+                        sink_warnings.lock().unwrap().push(warning.clone())
+                    }
+                })),
+                ..Default::default()
+            };
+            let fs = MemFs::new();
+            let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+            let workdir = environment.project.workdir().unwrap().to_path_buf();
+            fs.create_dir_all(&workdir).await.unwrap();
+            fs.write(
+                &workdir.join("flox.nix"),
+                br#"{
+                  shell.hook = "echo \${PATH} \$\${PATH}";
+                  hook.onActivate = "echo \${FLOX_UNSET_TEST_VARIABLE}";
+                }"#,
+            )
+            .await
+            .unwrap();
+
+            let hook = environment.activation_hook().await;
+            let warnings = warnings.lock().unwrap();
+            match mode {
+                EnvInterpolation::Off => {
+                    assert_eq!(
+                        hook.unwrap().unwrap(),
+                        "echo ${PATH} $${PATH}\necho ${FLOX_UNSET_TEST_VARIABLE}"
+                    );
+                    assert!(warnings.is_empty());
+                },
+                EnvInterpolation::Lenient => {
+                    assert_eq!(
+                        hook.unwrap().unwrap(),
+                        format!("echo {path} ${{PATH}}\necho ")
+                    );
+                    assert_eq!(warnings[..], [FloxWarning::UndefinedVariable {
+                        environment: "default".to_string(),
+                        variable: "FLOX_UNSET_TEST_VARIABLE".to_string(),
+                    }]);
+                },
+                EnvInterpolation::Strict => {
+                    assert!(matches!(
+                        hook,
+                        Err(ActivationHookError::Undefined(flox_nix::UndefinedVariable(name)))
+                            if name == "FLOX_UNSET_TEST_VARIABLE"
+                    ));
+                },
+            }
+        }
+    }
+
+    /// Install `package` in a transaction committed with `commit_strategy`
+    async fn install_committed<'flox>(
+        environment: Environment<'flox, GitCommandProvider, ReadOnly<GitCommandProvider>, TokioFs>,
+        package: &str,
+        commit_strategy: CommitStrategy,
+    ) -> Environment<'flox, GitCommandProvider, ReadOnly<GitCommandProvider>, TokioFs> {
+        let (project, mut index) = environment
+            .project
+            .enter_transaction_with(TransactionOptions {
+                commit_strategy,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let sandbox = Environment {
+            name: environment.name,
+            system: environment.system,
+            project,
+            compat: false,
+            store_path: None,
+            eval_options: EvalOptions::default(),
+        };
+        sandbox
+            .install(&[package.to_string()], &mut index)
+            .await
+            .unwrap();
+        sandbox
+            .commit_transaction(index, "install", false)
+            .await
+            .unwrap()
+            .committed()
+            .expect("not a dry run")
+    }
+
+    #[tokio::test]
+    async fn audits_committed_operations() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().to_path_buf(),
+            config_dir: tempdir.path().join("config"),
+            audit: true,
+            ..Default::default()
+        };
+        let environment = test_environment(&flox, &project_dir, TokioFs).await;
+        std::fs::write(project_dir.join("flake.nix"), "{}").unwrap();
+        std::fs::write(project_dir.join("flox.nix"), "{ }\n").unwrap();
+
+        let environment = install_committed(
+            environment,
+            "nixpkgs-flox.hello",
+            CommitStrategy::PerOperation,
+        )
+        .await;
+        install_committed(environment, "nixpkgs-flox.fd", CommitStrategy::Squashed).await;
+
+        // squashed changes are only staged, they have no generation yet
+        let audited = flox
+            .audit_log()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.operation, entry.packages, entry.generation))
+            .collect::<Vec<_>>();
+        assert_eq!(audited, [
+            (
+                AuditOperation::Install,
+                vec!["nixpkgs-flox.hello".to_string()],
+                Some(1)
+            ),
+            (
+                AuditOperation::Install,
+                vec!["nixpkgs-flox.fd".to_string()],
+                None
+            ),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn audit_failures_do_not_fail_commits() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink_warnings = warnings.clone();
+        // the audit log can not be created below a file
+        let config_dir = tempdir.path().join("config");
+        std::fs::write(&config_dir, "").unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().to_path_buf(),
+            config_dir,
+            audit: true,
+            event_sink: Some(EventSink::new(move |event| {
+                if let FloxEvent::Warning(warning) = event {
+                    sink_warnings.lock().unwrap().push(warning.clone())
+                }
+            })),
+            ..Default::default()
+        };
+        let environment = test_environment(&flox, &project_dir, TokioFs).await;
+        std::fs::write(project_dir.join("flake.nix"), "{}").unwrap();
+        std::fs::write(project_dir.join("flox.nix"), "{ }\n").unwrap();
+
+        let environment = install_committed(
+            environment,
+            "nixpkgs-flox.hello",
+            CommitStrategy::PerOperation,
+        )
+        .await;
+
+        assert_eq!(environment.packages().await.unwrap(), [
+            "nixpkgs-flox.hello"
+        ]);
+        assert!(matches!(
+            &warnings.lock().unwrap()[..],
+            [FloxWarning::AuditNotWritten { environment, .. }] if environment == "default"
+        ));
+    }
+
+    /// Resolves every name to `legacyPackages.<system>.<name>` of `channel`
+    #[derive(Debug)]
+    struct ChannelResolver {
+        channel: String,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl PackageResolver for ChannelResolver {
+        async fn resolve(
+            &self,
+            flox: &Flox,
+            name: &str,
+            _stability: &Stability,
+        ) -> Result<Vec<ResolvedInstallableMatch>, ResolvePackageError> {
+            Ok(vec![ResolvedInstallableMatch::new(
+                self.channel.clone(),
+                "legacyPackages".to_string(),
+                Some(flox.system.to_string()),
+                false,
+                vec![name.to_string()],
+                None,
+            )])
+        }
+    }
+
+    /// An environment with `flox_nix` in a transaction on `project_dir`
+    async fn sandboxed_environment<'flox>(
+        flox: &'flox Flox,
+        project_dir: &Path,
+        flox_nix: &str,
+    ) -> (
+        Environment<'flox, GitCommandProvider, GitSandBox<GitCommandProvider>, TokioFs>,
+        Index,
+    ) {
+        let environment = test_environment(flox, project_dir, TokioFs).await;
+        std::fs::write(project_dir.join("flake.nix"), "{}").unwrap();
+        std::fs::write(project_dir.join("flox.nix"), flox_nix).unwrap();
+        let (project, index) = environment.project.enter_transaction().await.unwrap();
+        let environment = Environment {
+            name: environment.name,
+            system: environment.system,
+            project,
+            compat: false,
+            store_path: None,
+            eval_options: EvalOptions::default(),
+        };
+        (environment, index)
+    }
+
+    #[tokio::test]
+    async fn install_resolves_names_without_channel() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().to_path_buf(),
+            package_resolver: Some(Arc::new(ChannelResolver {
+                channel: "curated".to_string(),
+            })),
+            ..Default::default()
+        };
+
+        let (environment, mut index) = sandboxed_environment(&flox, &project_dir, "{}").await;
+        environment
+            .install(
+                &["hello".to_string(), "nixpkgs-flox.fd".to_string()],
+                &mut index,
+            )
+            .await
+            .unwrap();
+        assert_eq!(environment.packages().await.unwrap(), [
+            "curated.hello",
+            "nixpkgs-flox.fd"
+        ]);
+    }
+
+    #[tokio::test]
+    async fn upgrade_package_is_resolved_and_checked() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let mut flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().to_path_buf(),
+            package_resolver: Some(Arc::new(ChannelResolver {
+                channel: "other".to_string(),
+            })),
+            ..Default::default()
+        };
+        let hello = "local.hello".to_string();
+
+        {
+            let (environment, mut index) =
+                sandboxed_environment(&flox, &project_dir, "{ packages.local.hello = {}; }").await;
+            assert!(matches!(
+                environment
+                    .upgrade_package::<NixCommandLine>(&hello, &Stability::Stable, &mut index)
+                    .await,
+                Err(UpgradePackageError::NotResolved(package, Stability::Stable)) if package == hello
+            ));
+        }
+
+        flox.install_policy = Some(InstallPolicy::new(|_| PolicyDecision::Deny {
+            reason: "not allowed".to_string(),
+        }));
+        let (environment, mut index) =
+            sandboxed_environment(&flox, &project_dir, "{ packages.local.hello = {}; }").await;
+        assert!(matches!(
+            environment
+                .upgrade_package::<NixCommandLine>(&hello, &Stability::Stable, &mut index)
+                .await,
+            Err(UpgradePackageError::Edit(EditEnvironmentError::Denied(_)))
+        ));
+        assert!(index.is_empty());
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn upgrades_package_to_latest_version() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let channel_dir = tempdir.path().join("channel");
+        std::fs::create_dir(&channel_dir).unwrap();
+        let mut flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            config_dir: tempdir.path().join("config"),
+            temp_dir: tempdir.path().join("temp"),
+            package_resolver: Some(Arc::new(ChannelResolver {
+                channel: "local".to_string(),
+            })),
+            ..Default::default()
+        };
+        std::fs::create_dir(&flox.config_dir).unwrap();
+        std::fs::create_dir(&flox.temp_dir).unwrap();
+        std::fs::write(
+            channel_dir.join("flake.nix"),
+            format!(
+                r#"{{ outputs = _: {{ legacyPackages."{}".hello = {{ version = "2.12"; }}; }}; }}"#,
+                flox.system
+            ),
+        )
+        .unwrap();
+        flox.channels.register_channel(
+            "local",
+            format!("path:{}", channel_dir.display()).parse().unwrap(),
+        );
+        let hello = "local.hello".to_string();
+
+        for (n, (flox_nix, expected)) in [
+            (
+                r#"{ packages.local.hello = { version = "2.10"; }; }"#,
+                Some((Some("2.10".to_string()), "2.12".to_string())),
+            ),
+            // unversioned packages are pinned to the latest version
+            (
+                "{ packages.local.hello = {}; }",
+                Some((None, "2.12".to_string())),
+            ),
+            // up to date
+            (r#"{ packages.local.hello = { version = "2.12"; }; }"#, None),
+            // never downgraded
+            (r#"{ packages.local.hello = { version = "2.13"; }; }"#, None),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let project_dir = tempdir.path().join(format!("project{n}"));
+            std::fs::create_dir(&project_dir).unwrap();
+            let (environment, mut index) =
+                sandboxed_environment(&flox, &project_dir, flox_nix).await;
+
+            let upgraded = environment
+                .upgrade_package::<NixCommandLine>(&hello, &Stability::Stable, &mut index)
+                .await
+                .unwrap();
+            assert_eq!(upgraded, expected, "{flox_nix}");
+
+            let version: Option<String> = environment
+                .flox_nix()
+                .await
+                .unwrap()
+                .get_as(&["packages", "local", "hello", "version"])
+                .unwrap();
+            match expected {
+                Some((_, latest)) => assert_eq!(version.as_ref(), Some(&latest)),
+                None => assert_eq!(index.get(Path::new("flox.nix")), None),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_malformed_definitions() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        fs.create_dir_all(&workdir).await.unwrap();
+        fs.write(
+            &workdir.join("flox.nix"),
+            b"{\n  packages.nixpkgs-flox.hello = {}\n}",
+        )
+        .await
+        .unwrap();
+
+        match environment.packages().await {
+            Err(ListPackagesError::Composition(CompositionError::ReadFloxNix(
+                ReadFloxNixError::MalformedDefinition(path, e),
+            ))) => {
+                assert_eq!(path, workdir.join("flox.nix"));
+                assert_eq!((e.line, e.column), (3, 1));
+            },
+            other => panic!("expected a malformed definition, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_nix_config() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        fs.create_dir_all(&workdir).await.unwrap();
+        fs.write(
+            &workdir.join("flox.nix"),
+            br#"{
+                nixConfig = {
+                    extra-experimental-features = [ "ca-derivations" "recursive-nix" ];
+                    keep-outputs = true;
+                    max-jobs = 4;
+                };
+            }"#,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            environment.nix_config().await.unwrap(),
+            BTreeMap::from([
+                (
+                    "extra-experimental-features".to_string(),
+                    "ca-derivations recursive-nix".to_string()
+                ),
+                ("keep-outputs".to_string(), "true".to_string()),
+                ("max-jobs".to_string(), "4".to_string()),
+            ])
+        );
+        assert_eq!(
+            environment
+                .nix_config_args(EvalOptions::default())
+                .await
+                .unwrap()
+                .1[..3],
+            [
+                "--option",
+                "extra-experimental-features",
+                "ca-derivations recursive-nix"
+            ]
+        );
+
+        fs.write(&workdir.join("flox.nix"), b"{ nixConfig.x = { }; }")
+            .await
+            .unwrap();
+        assert!(matches!(
+            environment.nix_config().await,
+            Err(NixConfigError::Value(name)) if name == "x"
+        ));
+    }
+
+    #[tokio::test]
+    async fn passes_impure_to_nix() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink_warnings = warnings.clone();
+        let flox = Flox {
+            event_sink: Some(EventSink::new(move |event| {
+                if let FloxEvent::Warning(warning) = event {
+                    sink_warnings.lock().unwrap().push(warning.clone())
+                }
+            })),
+            ..Default::default()
+        };
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        fs.create_dir_all(&workdir).await.unwrap();
+        fs.write(&workdir.join("flox.nix"), b"{ }").await.unwrap();
+
+        let args = |impure| environment.nix_config_args(EvalOptions { impure });
+        assert_eq!(args(false).await.unwrap(), (EvalOptions::default(), vec![]));
+        assert_eq!(
+            args(true).await.unwrap(),
+            (EvalOptions { impure: true }, vec!["--impure".to_string()])
+        );
+
+        fs.write(&workdir.join("flox.nix"), b"{ impure = true; }")
+            .await
+            .unwrap();
+        assert!(environment.impure().await.unwrap());
+        assert_eq!(
+            args(false).await.unwrap(),
+            (EvalOptions { impure: true }, vec!["--impure".to_string()])
+        );
+
+        // warned about once, not on every evaluation
+        assert_eq!(*warnings.lock().unwrap(), [FloxWarning::ImpureEval {
+            environment: "default".to_string()
+        }]);
+    }
+
+    #[tokio::test]
+    async fn keeps_eval_options() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone())
+            .await
+            .with_eval_options(EvalOptions { impure: true });
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        fs.create_dir_all(&workdir).await.unwrap();
+        fs.write(&workdir.join("flox.nix"), b"{ }").await.unwrap();
+
+        let command = environment.shell_command::<NixCommandLine>().await.unwrap();
+        assert!(command.as_std().get_args().any(|arg| arg == "--impure"));
+
+        let (sandbox, _) = environment.enter_transaction().await.unwrap();
+        assert_eq!(sandbox.eval_options, EvalOptions { impure: true });
+    }
+
+    #[tokio::test]
+    async fn diffs_against_revision() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let git = GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        let flox = Flox::default();
+        let project = Project::new(
+            &flox,
+            ReadOnly::new(git.clone()),
+            Rc::new(TokioFs),
+            PathBuf::new(),
+        );
+
+        let flox_nix = tempdir.path().join("flox.nix");
+        std::fs::write(
+            &flox_nix,
+            r#"{
+              packages.nixpkgs-flox.fd = {};
+              packages.nixpkgs-flox.hello = {};
+            }"#,
+        )
+        .unwrap();
+        git.add(&[flox_nix.as_path()]).await.unwrap();
+        git.commit("base").await.unwrap();
+        let base = git.head_rev().await.unwrap();
+
+        std::fs::write(
+            &flox_nix,
+            r#"{
+              packages.nixpkgs-flox.hello = {};
+              packages.nixpkgs-flox.ripgrep = {};
+            }"#,
+        )
+        .unwrap();
+        let dev_flox_nix = tempdir.path().join("pkgs/dev/flox.nix");
+        std::fs::create_dir_all(dev_flox_nix.parent().unwrap()).unwrap();
+        std::fs::write(&dev_flox_nix, "{ packages.nixpkgs-flox.jq = {}; }").unwrap();
+        git.add(&[flox_nix.as_path(), dev_flox_nix.as_path()])
+            .await
+            .unwrap();
+        git.commit("change").await.unwrap();
+
+        assert_eq!(
+            project.diff_against(&base, "default").await.unwrap(),
+            EnvDiff {
+                added: vec!["nixpkgs-flox.ripgrep".to_string()],
+                removed: vec!["nixpkgs-flox.fd".to_string()],
+                changed: vec![],
+            }
+        );
+
+        // environments missing at one revision are compared to an empty environment
+        assert_eq!(project.diff_against(&base, "dev").await.unwrap().added, [
+            "nixpkgs-flox.jq"
+        ]);
+
+        assert!(matches!(
+            project.diff_against(&base, "missing").await,
+            Err(DiffAgainstError::NotFound(name)) if name == "missing"
+        ));
+    }
+
+    #[tokio::test]
+    async fn lists_history() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let environment = test_environment(&flox, tempdir.path(), TokioFs).await;
+        let git = environment.project.git.git();
+
+        assert!(environment.history(10).await.unwrap().is_empty());
+
+        let flox_nix = tempdir.path().join("flox.nix");
+        std::fs::write(
+            &flox_nix,
+            r#"{
+              packages.nixpkgs-flox.hello = {};
+              packages.nixpkgs-flox.jq = {};
+            }"#,
+        )
+        .unwrap();
+        git.add(&[flox_nix.as_path()]).await.unwrap();
+        git.commit("create").await.unwrap();
+
+        // commits not touching flox.nix are not part of the history
+        let readme = tempdir.path().join("README.md");
+        std::fs::write(&readme, "unrelated").unwrap();
+        git.add(&[readme.as_path()]).await.unwrap();
+        git.commit("unrelated").await.unwrap();
+
+        std::fs::write(
+            &flox_nix,
+            r#"{
+              packages.nixpkgs-flox.hello = { version = "2.12"; };
+              packages.nixpkgs-flox.ripgrep = {};
+            }"#,
+        )
+        .unwrap();
+        git.add(&[flox_nix.as_path()]).await.unwrap();
+        git.commit("update").await.unwrap();
+
+        let history = environment.history(10).await.unwrap();
+        let generations: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.generation, entry.message.as_str()))
+            .collect();
+        assert_eq!(generations, [(2, "update"), (1, "create")]);
+
+        assert_eq!(environment.diff(&history[0]).await.unwrap(), EnvDiff {
+            added: vec!["nixpkgs-flox.ripgrep".to_string()],
+            removed: vec!["nixpkgs-flox.jq".to_string()],
+            changed: vec!["nixpkgs-flox.hello".to_string()],
+        });
+        assert_eq!(environment.diff(&history[1]).await.unwrap(), EnvDiff {
+            added: vec![
+                "nixpkgs-flox.hello".to_string(),
+                "nixpkgs-flox.jq".to_string()
+            ],
+            ..Default::default()
+        });
+
+        let latest = environment.history(1).await.unwrap();
+        assert_eq!(latest, history[..1]);
+    }
+
+    #[tokio::test]
+    async fn finds_executables_in_all_outputs() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let package = tempdir.path().join("package/bin/rust-analyzer");
+        let outputs = [tempdir.path().join("out"), tempdir.path().join("dev")];
+
+        std::fs::create_dir_all(package.parent().unwrap()).unwrap();
+        std::fs::write(&package, "").unwrap();
+        std::fs::set_permissions(&package, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::create_dir_all(outputs[0].join("bin")).unwrap();
+        std::fs::write(outputs[0].join("bin/README"), "").unwrap();
+        std::fs::create_dir_all(outputs[1].join("bin")).unwrap();
+        std::os::unix::fs::symlink(&package, outputs[1].join("bin/rust-analyzer")).unwrap();
+
+        assert_eq!(
+            find_executable(&outputs, "rust-analyzer").await.unwrap(),
+            Some(package.canonicalize().unwrap())
+        );
+        assert_eq!(find_executable(&outputs, "README").await.unwrap(), None);
+        assert_eq!(find_executable(&outputs, "python").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn prunes_roots_of_removed_environments() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let roots_dir = tempdir.path().join(GC_ROOTS_DIR);
+        std::fs::create_dir_all(&roots_dir).unwrap();
+
+        let flox_nix = tempdir.path().join("flox.nix");
+        std::fs::write(&flox_nix, "{}").unwrap();
+
+        for (root, flox_nix) in [("live", flox_nix), ("stale", tempdir.path().join("gone"))] {
+            std::os::unix::fs::symlink(Path::new("/nix/store").join(root), roots_dir.join(root))
+                .unwrap();
+            std::fs::write(
+                roots_dir.join(root).with_extension("json"),
+                serde_json::to_vec(&GcRootRecord { flox_nix }).unwrap(),
+            )
+            .unwrap();
+        }
+
+        let report = prune_gc_roots(tempdir.path()).await.unwrap();
+        assert_eq!(report.removed, vec![roots_dir.join("stale")]);
+        assert_eq!(report.reclaimable, vec![PathBuf::from("/nix/store/stale")]);
+        assert!(roots_dir.join("live").is_symlink());
+        assert!(!roots_dir.join("stale.json").exists());
+    }
+
+    #[tokio::test]
+    async fn roots_sandboxes_by_original_flox_nix() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let environment = test_environment(&flox, tempdir.path(), TokioFs).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        std::fs::write(workdir.join("flox.nix"), "{}").unwrap();
+        assert_eq!(
+            environment.original_flox_nix_path().await,
+            Some(workdir.join("flox.nix"))
+        );
+
+        let (sandbox, _) = environment.enter_transaction().await.unwrap();
+        assert_ne!(
+            sandbox.flox_nix_path().await,
+            Some(workdir.join("flox.nix"))
+        );
+        assert_eq!(
+            sandbox.original_flox_nix_path().await,
+            Some(workdir.join("flox.nix"))
+        );
+    }
+
+    #[tokio::test]
+    async fn builds_to_attached_store_path() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        // neither flox.nix nor flake.nix exist
+        let environment = Environment {
+            store_path: Some(PathBuf::from("/nix/store/xyz-floxenv")),
+            ..test_environment(&flox, tempdir.path(), MemFs::new()).await
+        };
+
+        assert_eq!(
+            environment.build().await.unwrap(),
+            Path::new("/nix/store/xyz-floxenv")
+        );
+    }
+
+    #[tokio::test]
+    async fn installable_of_own_fields() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        fs.create_dir_all(&workdir).await.unwrap();
+
+        let installable = environment.installable().await.unwrap();
+        assert_eq!(
+            installable.flakeref,
+            environment.project.flakeref().await.unwrap()
+        );
+        assert_eq!(installable.attr_path, ".floxEnvs.aarch64-darwin.default");
+
+        let environment = Environment {
+            name: "dev".to_string(),
+            system: System::X86_64Linux,
+            compat: true,
+            ..environment
+        };
+        assert_eq!(
+            environment.installable().await.unwrap().attr_path,
+            ".devShells.x86_64-linux.dev"
+        );
+    }
+
+    #[tokio::test]
+    async fn build_cache_entry_depends_on_definition() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            ..Default::default()
+        };
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        fs.create_dir_all(&workdir).await.unwrap();
+        let flox_nix = br#"{ imports = [ ./base.nix ]; }"#;
+        fs.write(&workdir.join("flox.nix"), flox_nix).await.unwrap();
+        fs.write(&workdir.join("base.nix"), b"{ }").await.unwrap();
+
+        let entry = environment.build_cache_entry(flox_nix).await.unwrap();
+        assert!(entry.starts_with(flox.cache_dir.join(BUILD_CACHE_DIR)));
+        assert_eq!(
+            environment.build_cache_entry(flox_nix).await.unwrap(),
+            entry
+        );
+
+        let mut entries = vec![entry];
+        let mut assert_new_entry = |entry: PathBuf| {
+            assert!(!entries.contains(&entry));
+            entries.push(entry);
+        };
+
+        assert_new_entry(environment.build_cache_entry(b"{ }").await.unwrap());
+
+        fs.write(
+            &workdir.join("base.nix"),
+            b"{ packages.nixpkgs-flox.hello = {}; }",
+        )
+        .await
+        .unwrap();
+        assert_new_entry(environment.build_cache_entry(flox_nix).await.unwrap());
+
+        fs.write(&workdir.join("flake.lock"), b"{ }").await.unwrap();
+        assert_new_entry(environment.build_cache_entry(flox_nix).await.unwrap());
+
+        let environment = Environment {
+            system: System::X86_64Linux,
+            ..environment
+        };
+        assert_new_entry(environment.build_cache_entry(flox_nix).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn edits_use_new_build_cache_entry() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            temp_dir: tempdir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let environment = test_environment(&flox, &project_dir, TokioFs).await;
+        std::fs::write(project_dir.join("flox.nix"), "{ }").unwrap();
+
+        let entry = environment.build_cache_entry(b"{ }").await.unwrap();
+        std::fs::create_dir_all(entry.parent().unwrap()).unwrap();
+        std::fs::write(&entry, "/nix/store/environment").unwrap();
+
+        let (sandbox, mut index) = environment.enter_transaction().await.unwrap();
+        sandbox
+            .install(&["nixpkgs-flox.hello".to_string()], &mut index)
+            .await
+            .unwrap();
+        let edited = sandbox.read_flox_nix().await.unwrap();
+        let edited_entry = sandbox.build_cache_entry(&edited).await.unwrap();
+        assert_ne!(edited_entry, entry);
+        assert!(!edited_entry.exists());
+        // still valid for the previous definition
+        assert!(entry.exists());
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn builds_from_cache_until_definition_changes() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("cache"),
+            ..Default::default()
+        };
+        // there is no flake.nix, building fails unless the cache is used
+        let environment = test_environment(&flox, &tempdir.path().join("project"), TokioFs).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        std::fs::write(workdir.join("flox.nix"), "{ }").unwrap();
+
+        let built = tempdir.path().join("floxenv");
+        std::fs::create_dir_all(&built).unwrap();
+        std::fs::write(built.join(ENVIRONMENT_CATALOG), "{}").unwrap();
+        let output = std::process::Command::new("nix-store")
+            .arg("--add")
+            .arg(&built)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let store_path = String::from_utf8(output.stdout).unwrap();
+
+        let entry = environment.build_cache_entry(b"{ }").await.unwrap();
+        std::fs::create_dir_all(entry.parent().unwrap()).unwrap();
+        std::fs::write(&entry, &store_path).unwrap();
+        assert_eq!(
+            environment.build().await.unwrap(),
+            Path::new(store_path.trim())
+        );
+
+        // cached store paths nix does not know are rebuilt
+        std::fs::write(
+            &entry,
+            "/nix/store/00000000000000000000000000000000-missing",
+        )
+        .unwrap();
+        assert!(matches!(
+            environment.build().await,
+            Err(BuildEnvironmentError::BadExit(..))
+        ));
+
+        // so are changed definitions
+        std::fs::write(&entry, &store_path).unwrap();
+        std::fs::write(
+            workdir.join("flox.nix"),
+            "{ packages.nixpkgs-flox.hello = {}; }",
+        )
+        .unwrap();
+        assert!(matches!(
+            environment.build().await,
+            Err(BuildEnvironmentError::BadExit(..))
+        ));
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn attaches_only_environments_in_store() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let environment = || test_environment(&flox, tempdir.path(), TokioFs);
+        let add_to_store = |dir: &Path| {
+            let output = std::process::Command::new("nix-store")
+                .arg("--add")
+                .arg(dir)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            PathBuf::from(String::from_utf8(output.stdout).unwrap().trim())
+        };
+
+        let built = tempdir.path().join("floxenv");
+        std::fs::create_dir_all(built.join("bin")).unwrap();
+        std::fs::write(built.join(ENVIRONMENT_CATALOG), "{}").unwrap();
+        assert!(matches!(
+            environment().await.with_store_path(&built).await,
+            Err(AttachStorePathError::NotInStore(_))
+        ));
+
+        let store_path = add_to_store(&built);
+        let attached = environment()
+            .await
+            .with_store_path(&store_path)
+            .await
+            .unwrap();
+        assert_eq!(attached.build_outputs().await.unwrap(), [store_path]);
+
+        std::fs::remove_file(built.join(ENVIRONMENT_CATALOG)).unwrap();
+        assert!(matches!(
+            environment()
+                .await
+                .with_store_path(add_to_store(&built))
+                .await,
+            Err(AttachStorePathError::NotAnEnvironment(_))
+        ));
+    }
+
+    /// Run [HOOK_WRAPPER] with `hook`, returning the recorded hook status and
+    /// the output of the command, which prints `$HOOKED`
+    fn run_hook_wrapper(hook: &str) -> (String, String) {
+        let tempdir = tempfile::tempdir().unwrap();
+        let status = tempdir.path().join("status");
+        std::fs::write(&status, "").unwrap();
+        let output = std::process::Command::new("sh")
+            .env(HOOK_ENV, hook)
+            .env(HOOK_STATUS_ENV, &status)
+            .args(["-c", HOOK_WRAPPER, "sh", "sh", "-c", "echo \"ran $HOOKED\""])
+            .output()
+            .unwrap();
+        (
+            std::fs::read_to_string(&status).unwrap(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    }
+
+    #[test]
+    fn runs_command_after_hook() {
+        let (status, output) = run_hook_wrapper("export HOOKED=yes; false || true");
+        assert_eq!(status, "");
+        assert_eq!(output, "ran yes\n");
+    }
+
+    #[test]
+    fn stops_at_first_failing_hook_step() {
+        let (status, output) = run_hook_wrapper("export HOOKED=yes\n(exit 3)\necho continued");
+        assert_eq!(status, "3\n");
+        assert_eq!(output, "");
+
+        let (status, output) = run_hook_wrapper("exit 4");
+        assert_eq!(status, "4\n");
+        assert_eq!(output, "");
+    }
+
+    /// Nix backend whose `nix shell` runs the `--command` on the host
+    #[derive(Debug)]
+    struct HostShellNix;
+
+    impl NixBackend for HostShellNix {}
+
+    impl FloxNixApi for HostShellNix {
+        fn new(_: &Flox, _: runix::default::DefaultArgs) -> Self {
+            HostShellNix
+        }
+
+        fn command(&self, subcommand: &[&str]) -> Command {
+            let mut command = Command::new("sh");
+            // skip the arguments of nix up to `--command`
+            let run_command = r#"
+                while [ "$#" -gt 0 ] && [ "$1" != --command ]; do shift; done
+                shift
+                exec "$@"
+            "#;
+            command.args(["-c", run_command, "nix"]).args(subcommand);
+            command
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Run<HostShellNix> for Eval {
+        type Error = std::io::Error;
+
+        async fn run(&self, _: &HostShellNix, _: &NixArgs) -> Result<(), Self::Error> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "services do not evaluate",
+            ))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl RunJson<HostShellNix> for Eval {
+        type JsonError = std::io::Error;
+
+        async fn run_json(
+            &self,
+            _: &HostShellNix,
+            _: &NixArgs,
+        ) -> Result<serde_json::Value, Self::JsonError> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "services do not evaluate",
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_declared_services() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        fs.create_dir_all(&workdir).await.unwrap();
+
+        fs.write(&workdir.join("flox.nix"), b"{ }").await.unwrap();
+        assert_eq!(environment.services().await.unwrap(), []);
+
+        fs.write(
+            &workdir.join("flox.nix"),
+            br#"{
+              services.web.command = "python -m http.server";
+              services.db.command = "postgres -D $PGDATA";
+            }"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(environment.services().await.unwrap(), [
+            ServiceDef {
+                name: "db".to_string(),
+                command: "postgres -D $PGDATA".to_string(),
+            },
+            ServiceDef {
+                name: "web".to_string(),
+                command: "python -m http.server".to_string(),
+            },
+        ]);
+
+        fs.write(&workdir.join("flox.nix"), br#"{ services.web = "serve"; }"#)
+            .await
+            .unwrap();
+        assert!(matches!(
+            environment.services().await,
+            Err(ServicesError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn runs_services_with_environment_variables() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let flox = Flox::default();
+        let environment = test_environment(&flox, &project_dir, TokioFs).await;
+        let output = tempdir.path().join("output");
+        std::fs::write(
+            project_dir.join("flox.nix"),
+            format!(
+                r#"{{
+                  vars.GREETING = "hello";
+                  services.greet.command = "echo $GREETING > {}";
+                }}"#,
+                output.display()
+            ),
+        )
+        .unwrap();
+
+        let status = environment
+            .run_service::<HostShellNix>("greet")
+            .await
+            .unwrap()
+            .wait()
+            .await
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "hello\n");
+
+        assert!(matches!(
+            environment.run_service::<HostShellNix>("missing").await,
+            Err(RunServiceError::NotFound(name)) if name == "missing"
+        ));
     }
 }
@@ -0,0 +1,221 @@
+//! Estimates of the sources nix copies to the store when evaluating a project
+//!
+//! Nix imports the project's flake into the store before evaluating or building it,
+//! large vendored sources make every evaluation after a change pay for that copy.
+
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use super::build::parse_path_info;
+use super::{Project, ProjectError};
+use crate::flox::FloxNixApi;
+use crate::models::root::transaction::GitAccess;
+use crate::providers::fs::{FileKind, FileSystem};
+use crate::providers::git::GitProvider;
+
+/// Number of files listed in [ImportSize::largest]
+pub const LARGEST_FILES: usize = 5;
+
+/// Outcome of [Project::estimate_import_size]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportSize {
+    pub files: u64,
+    pub bytes: u64,
+    /// The largest files and their size in bytes, largest first,
+    /// paths are relative to the project root
+    pub largest: Vec<(PathBuf, u64)>,
+    /// Size of the NAR serialisation of the source nix imported, in bytes
+    pub nar_size: u64,
+}
+
+/// Typed subset of `nix flake metadata --json`
+#[derive(Debug, Deserialize)]
+struct FlakeMetadata {
+    /// Store path of the flake source
+    path: PathBuf,
+}
+
+impl<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem>
+    Project<'flox, Git, Access, Fs>
+{
+    /// Estimate how much nix copies to the store to import the current tree of the project
+    ///
+    /// Nix includes the files tracked by git in the flake of a git repository,
+    /// so untracked and ignored files as well as the contents of submodules are not counted.
+    /// The source is then imported like any evaluation of the project would,
+    /// and [ImportSize::nar_size] reports the size of the imported store path.
+    pub async fn estimate_import_size<Nix: FloxNixApi>(
+        &self,
+    ) -> Result<ImportSize, EstimateImportSizeError<Git>> {
+        let mut size = self.tracked_files_size().await?;
+
+        // make sure nix is configured like for any other flox invocation
+        let nix: Nix = self.flox.nix(Default::default());
+        let output = nix
+            .command(&["flake", "metadata"])
+            .arg("--json")
+            .arg(self.flakeref().await?)
+            .output()
+            .await
+            .map_err(EstimateImportSizeError::Spawn)?;
+        let metadata: FlakeMetadata = serde_json::from_slice(&check_output(output)?)
+            .map_err(EstimateImportSizeError::Parse)?;
+
+        let output = nix
+            .command(&["path-info"])
+            .arg("--json")
+            .arg(&metadata.path)
+            .output()
+            .await
+            .map_err(EstimateImportSizeError::Spawn)?;
+        let paths =
+            parse_path_info(&check_output(output)?).map_err(EstimateImportSizeError::Parse)?;
+        size.nar_size = paths.iter().map(|path| path.nar_size).sum();
+
+        Ok(size)
+    }
+
+    /// Number and size of the files tracked by git, see [Self::estimate_import_size]
+    ///
+    /// Tracked files deleted from the work tree are skipped,
+    /// as are submodules, which git lists as a single entry.
+    async fn tracked_files_size(&self) -> Result<ImportSize, EstimateImportSizeError<Git>> {
+        let root = self.require_workdir()?;
+        let tracked = self
+            .git
+            .git()
+            .ls_files(&[])
+            .await
+            .map_err(EstimateImportSizeError::ListFiles)?;
+
+        let mut size = ImportSize::default();
+        for path in tracked {
+            let full_path = root.join(&path);
+            match self.fs.kind(&full_path).await {
+                Ok(Some(FileKind::File)) => {},
+                Ok(_) => continue,
+                Err(e) => return Err(EstimateImportSizeError::Read(full_path, e)),
+            }
+            let bytes = self
+                .fs
+                .size(&full_path)
+                .await
+                .map_err(|e| EstimateImportSizeError::Read(full_path, e))?;
+
+            size.files += 1;
+            size.bytes += bytes;
+            size.largest.push((path, bytes));
+            size.largest
+                .sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then(a_path.cmp(b_path)));
+            size.largest.truncate(LARGEST_FILES);
+        }
+
+        Ok(size)
+    }
+}
+
+fn check_output<Git: GitProvider>(
+    output: std::process::Output,
+) -> Result<Vec<u8>, EstimateImportSizeError<Git>> {
+    if !output.status.success() {
+        return Err(EstimateImportSizeError::BadExit(
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+#[derive(Error, Debug)]
+pub enum EstimateImportSizeError<Git: GitProvider> {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to list project files: {0}")]
+    ListFiles(Git::ListFilesError),
+    #[error("Failed to read {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+    #[error("Nix failed with exit code {0}:\n{1}")]
+    BadExit(i32, String),
+    #[error("Failed to parse nix output: {0}")]
+    Parse(serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::flox::Flox;
+    use crate::models::root::transaction::ReadOnly;
+    use crate::providers::fs::TokioFs;
+    use crate::providers::git::GitCommandProvider;
+
+    /// A repository with tracked, untracked and ignored files
+    async fn project_repository(root: &Path) -> GitCommandProvider {
+        let git = GitCommandProvider::init(root, false).await.unwrap();
+
+        std::fs::write(root.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+        std::fs::create_dir_all(root.join("vendor/src")).unwrap();
+        std::fs::write(root.join("vendor/src/lib.c"), vec![0; 1000]).unwrap();
+        std::fs::write(root.join(".gitignore"), "/target\n").unwrap();
+        git.add(&[Path::new(".")]).await.unwrap();
+
+        std::fs::create_dir_all(root.join("target")).unwrap();
+        std::fs::write(root.join("target/big"), vec![0; 10_000]).unwrap();
+        std::fs::write(root.join("notes.txt"), vec![0; 5_000]).unwrap();
+        git
+    }
+
+    #[tokio::test]
+    async fn counts_tracked_files() {
+        let flox = Flox::default();
+        let project_dir = tempfile::tempdir().unwrap();
+        let root = project_dir.path();
+        let git = project_repository(root).await;
+
+        let project = Project::new(&flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new());
+        let size = project.tracked_files_size().await.unwrap();
+
+        assert_eq!(size.files, 3);
+        assert_eq!(size.bytes, 21 + 1000 + 8);
+        assert_eq!(size.largest, vec![
+            (PathBuf::from("vendor/src/lib.c"), 1000),
+            (PathBuf::from("flake.nix"), 21),
+            (PathBuf::from(".gitignore"), 8),
+        ]);
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn estimates_import_size() {
+        use runix::command_line::NixCommandLine;
+
+        let project_dir = tempfile::tempdir().unwrap();
+        let root = project_dir.path().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let git = project_repository(&root).await;
+        let flox = Flox {
+            cache_dir: project_dir.path().join("cache"),
+            temp_dir: project_dir.path().to_path_buf(),
+            config_dir: project_dir.path().join("config"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&flox.config_dir).unwrap();
+
+        let project = Project::new(&flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new());
+        let size = project
+            .estimate_import_size::<NixCommandLine>()
+            .await
+            .unwrap();
+
+        // the NAR contains the tracked files only, plus its encoding
+        assert_eq!(size.files, 3);
+        assert!(size.nar_size > size.bytes);
+        assert!(size.nar_size < size.bytes + 5_000);
+    }
+}
@@ -0,0 +1,568 @@
+//! Pinning environments to exact store paths
+//!
+//! A pinned environment has a [FLOX_LOCK] file next to its flox.nix,
+//! recording the store paths the environment was built from.
+//! [Environment::build] uses the pinned build instead of re-evaluating channels,
+//! until the environment is unpinned or its flox.nix changes.
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use runix::arguments::EvalArgs;
+use runix::command::Eval;
+use runix::command_line::NixCommandLine;
+use runix::installable::Installable;
+use runix::{NixBackend, RunJson};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::environment::{
+    content_hash,
+    BuildEnvironmentError,
+    Environment,
+    ListPackagesError,
+    ReadFloxNixError,
+};
+use super::{FileAction, Index};
+use crate::flox::FloxNixApi;
+use crate::models::flox_package::FloxPackage;
+use crate::models::root::transaction::{GitAccess, GitSandBox};
+use crate::providers::fs::FileSystem;
+use crate::providers::git::GitProvider;
+use crate::utils::errors::FloxErrorCode;
+
+/// Name of the lock file written next to a pinned environment's flox.nix
+pub const FLOX_LOCK: &str = "flox.lock";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvironmentLock {
+    /// Hash of the flox.nix the environment was pinned with
+    pub flox_nix_hash: String,
+    /// Store path of the built environment
+    pub environment: PathBuf,
+    /// Installed packages (`<channel>.<name>`) at the time of pinning
+    pub packages: BTreeMap<String, LockedPackage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedPackage {
+    pub store_path: PathBuf,
+    pub version: Option<String>,
+}
+
+/// Difference between an environment's lock and its current flox.nix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockDrift {
+    /// The environment is not pinned
+    Unpinned,
+    /// flox.nix did not change since the environment was pinned
+    Current,
+    /// flox.nix changed since the environment was pinned, so the pinned build is not used
+    ///
+    /// Both lists are empty if only attributes of packages changed.
+    Drifted {
+        /// Packages declared since pinning
+        added: Vec<FloxPackage>,
+        /// Pinned packages that are no longer declared
+        removed: Vec<FloxPackage>,
+    },
+}
+
+impl<Git: GitProvider, A: GitAccess<Git>, Fs: FileSystem> Environment<'_, Git, A, Fs> {
+    async fn lock_path(&self) -> Option<PathBuf> {
+        Some(self.flox_nix_path().await?.with_file_name(FLOX_LOCK))
+    }
+
+    /// Read the lock of a pinned environment
+    ///
+    /// Returns [None] if the environment is not pinned.
+    /// The lock is returned even if flox.nix changed since pinning, see [Self::lock_drift],
+    /// builds then ignore it and build the environment unpinned.
+    pub async fn lock(&self) -> Result<Option<EnvironmentLock>, ReadLockError> {
+        let path = self
+            .lock_path()
+            .await
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?;
+        let contents = match self.project.fs.read(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(ReadLockError::Read(path, e)),
+        };
+
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| ReadLockError::Parse(path, e))
+    }
+
+    /// Compare the lock of a pinned environment with its current flox.nix
+    pub async fn lock_drift(&self) -> Result<LockDrift, LockDriftError> {
+        let lock = match self.lock().await? {
+            Some(lock) => lock,
+            None => return Ok(LockDrift::Unpinned),
+        };
+        if lock.flox_nix_hash == content_hash(&[&self.read_flox_nix().await?]) {
+            return Ok(LockDrift::Current);
+        }
+
+        let declared: BTreeSet<FloxPackage> = self.packages().await?.into_iter().collect();
+        let added = declared
+            .iter()
+            .filter(|package| !lock.packages.contains_key(*package))
+            .cloned()
+            .collect();
+        let removed = lock
+            .packages
+            .into_keys()
+            .filter(|package| !declared.contains(package))
+            .collect();
+
+        Ok(LockDrift::Drifted { added, removed })
+    }
+
+    /// Make sure the pinned environment is present in the store,
+    /// substituting it if necessary
+    pub(super) async fn realise_pinned(
+        &self,
+        store_path: PathBuf,
+    ) -> Result<PathBuf, BuildEnvironmentError> {
+        if self.is_valid_store_path(&store_path).await? {
+            return Ok(store_path);
+        }
+
+        let nix: NixCommandLine = self.project.flox.nix(Default::default());
+        let status = nix
+            .command(&["build"])
+            .arg("--no-link")
+            .arg(&store_path)
+            .status()
+            .await
+            .map_err(BuildEnvironmentError::Spawn)?;
+
+        if !status.success() {
+            return Err(BuildEnvironmentError::PinnedUnavailable(store_path));
+        }
+        Ok(store_path)
+    }
+}
+
+/// Implementations for sandboxed only Environments
+impl<Git: GitProvider, Fs: FileSystem> Environment<'_, Git, GitSandBox<Git>, Fs> {
+    /// Pin the environment to the current versions of its packages
+    ///
+    /// Re-pinning an already pinned environment updates the lock.
+    pub async fn pin<Nix: FloxNixApi>(
+        &self,
+        index: &mut Index,
+    ) -> Result<EnvironmentLock, PinError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let path = self
+            .lock_path()
+            .await
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?;
+
+        // build from the channels rather than the previously pinned paths
+        match self.project.fs.remove(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(PinError::WriteLock(path, e))
+            },
+            _ => {},
+        }
+
+        let nix = self.project.flox.nix::<Nix>(Default::default());
+
+        let mut packages = BTreeMap::new();
+        for package in self.packages().await? {
+            let (channel, name) = package
+                .split_once('.')
+                .ok_or_else(|| PinError::InvalidPackage(package.clone()))?;
+
+            let eval = Eval {
+                eval_args: EvalArgs {
+                    apply: Some(
+                        "package: { storePath = package.outPath; version = package.version or \
+                         null; }"
+                            .to_string()
+                            .into(),
+                    ),
+                    installable: Some(
+                        Installable::new(channel.to_string(), format!("{name:?}")).into(),
+                    ),
+                },
+                ..Eval::default()
+            };
+
+            let locked = eval
+                .run_json(&nix, &Default::default())
+                .await
+                .map_err(PinError::Eval)?;
+            let locked = serde_json::from_value(locked).map_err(PinError::ParseEval)?;
+            packages.insert(package, locked);
+        }
+
+        let lock = EnvironmentLock {
+            flox_nix_hash: content_hash(&[&self.read_flox_nix().await?]),
+            environment: self.build().await?,
+            packages,
+        };
+
+        self.project
+            .fs
+            .write(
+                &path,
+                &serde_json::to_vec_pretty(&lock).expect("should serialize lock"),
+            )
+            .await
+            .map_err(|e| PinError::WriteLock(path.clone(), e))?;
+
+        self.record(index, &path, FileAction::Add)
+            .await
+            .map_err(PinError::WriteState)?;
+
+        Ok(lock)
+    }
+
+    /// Remove the pin of this environment, following channel updates again
+    pub async fn unpin(&self, index: &mut Index) -> Result<(), UnpinError> {
+        let path = self
+            .lock_path()
+            .await
+            .ok_or(ReadFloxNixError::WorkdirNotFound)?;
+
+        match self.project.fs.remove(&path).await {
+            Ok(()) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(UnpinError::NotPinned)
+            },
+            Err(e) => return Err(UnpinError::RemoveLock(path, e)),
+        }
+
+        self.record(index, &path, FileAction::Delete)
+            .await
+            .map_err(UnpinError::WriteState)
+    }
+
+    async fn record(
+        &self,
+        index: &mut Index,
+        path: &Path,
+        action: FileAction,
+    ) -> Result<(), std::io::Error> {
+        let workdir = self
+            .project
+            .workdir()
+            .expect("Workdir should exist during transaction");
+        index.insert(
+            path.strip_prefix(workdir)
+                .expect("flox.lock is within the project")
+                .to_path_buf(),
+            action,
+        );
+        self.project.write_transaction_state(index).await
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReadLockError {
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error("Error reading {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Error parsing {0:?}: {1}")]
+    Parse(PathBuf, serde_json::Error),
+}
+
+impl ReadLockError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            ReadLockError::ReadFloxNix(e) => e.code(),
+            ReadLockError::Read(..) => FloxErrorCode::Io,
+            ReadLockError::Parse(..) => FloxErrorCode::Invalid,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PinError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error(transparent)]
+    ListPackages(#[from] ListPackagesError),
+    #[error("Package '{0}' is not of the form '<channel>.<name>'")]
+    InvalidPackage(String),
+    #[error("Failed evaluating package: {0}")]
+    Eval(<Eval as RunJson<Nix>>::JsonError),
+    #[error("Failed parsing package evaluation: {0}")]
+    ParseEval(serde_json::Error),
+    #[error(transparent)]
+    Build(#[from] BuildEnvironmentError),
+    #[error("Failed to write {0:?}: {1}")]
+    WriteLock(PathBuf, std::io::Error),
+    #[error("Failed to write transaction state: {0}")]
+    WriteState(std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum LockDriftError {
+    #[error(transparent)]
+    ReadLock(#[from] ReadLockError),
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error(transparent)]
+    Packages(#[from] ListPackagesError),
+}
+
+#[derive(Error, Debug)]
+pub enum UnpinError {
+    #[error(transparent)]
+    ReadFloxNix(#[from] ReadFloxNixError),
+    #[error("Environment is not pinned")]
+    NotPinned,
+    #[error("Failed to remove {0:?}: {1}")]
+    RemoveLock(PathBuf, std::io::Error),
+    #[error("Failed to write transaction state: {0}")]
+    WriteState(std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::flox::Flox;
+    use crate::models::events::{EventSink, FloxEvent, FloxWarning};
+    use crate::models::project::tests::test_environment;
+    use crate::providers::fs::TokioFs;
+
+    /// Flox with its caches in `dir`
+    fn flox_in(dir: &Path) -> Flox {
+        Flox {
+            cache_dir: dir.join("cache"),
+            temp_dir: dir.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lock_format() {
+        let lock: EnvironmentLock = serde_json::from_str(
+            r#"{
+                "floxNixHash": "abc",
+                "environment": "/nix/store/xyz-floxenv",
+                "packages": {
+                    "nixpkgs-flox.hello": {
+                        "storePath": "/nix/store/123-hello-2.12.1",
+                        "version": "2.12.1"
+                    },
+                    "nixpkgs-flox.unversioned": {
+                        "storePath": "/nix/store/456-unversioned",
+                        "version": null
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            lock.packages["nixpkgs-flox.hello"].version.as_deref(),
+            Some("2.12.1")
+        );
+        assert_eq!(lock.packages["nixpkgs-flox.unversioned"].version, None);
+    }
+
+    #[tokio::test]
+    async fn reports_drift_and_unpins() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let flox = flox_in(tempdir.path());
+        let environment = test_environment(&flox, &project_dir, TokioFs).await;
+        let flox_nix = "{ packages.nixpkgs-flox.hello = {}; }";
+        std::fs::write(project_dir.join("flox.nix"), flox_nix).unwrap();
+        assert_eq!(environment.lock().await.unwrap(), None);
+        assert_eq!(environment.lock_drift().await.unwrap(), LockDrift::Unpinned);
+
+        let lock = EnvironmentLock {
+            flox_nix_hash: content_hash(&[flox_nix.as_bytes()]),
+            environment: PathBuf::from("/nix/store/xyz-floxenv"),
+            packages: BTreeMap::from([("nixpkgs-flox.hello".to_string(), LockedPackage {
+                store_path: PathBuf::from("/nix/store/123-hello-2.12.1"),
+                version: Some("2.12.1".to_string()),
+            })]),
+        };
+        std::fs::write(
+            project_dir.join(FLOX_LOCK),
+            serde_json::to_vec(&lock).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(environment.lock().await.unwrap(), Some(lock));
+        assert_eq!(environment.lock_drift().await.unwrap(), LockDrift::Current);
+
+        std::fs::write(
+            project_dir.join("flox.nix"),
+            "{ packages.nixpkgs-flox.fd = {}; }",
+        )
+        .unwrap();
+        assert_eq!(
+            environment.lock_drift().await.unwrap(),
+            LockDrift::Drifted {
+                added: vec!["nixpkgs-flox.fd".to_string()],
+                removed: vec!["nixpkgs-flox.hello".to_string()],
+            }
+        );
+
+        let (sandbox, mut index) = environment.enter_transaction().await.unwrap();
+        sandbox.unpin(&mut index).await.unwrap();
+        assert_eq!(sandbox.lock().await.unwrap(), None);
+        assert_eq!(index.get(Path::new(FLOX_LOCK)), Some(&FileAction::Delete));
+        assert!(matches!(
+            sandbox.unpin(&mut index).await,
+            Err(UnpinError::NotPinned)
+        ));
+        // the project stays pinned until the transaction is committed
+        assert!(project_dir.join(FLOX_LOCK).exists());
+    }
+
+    #[tokio::test]
+    async fn warns_about_stale_lock() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink_warnings = warnings.clone();
+        let flox = Flox {
+            event_sink: Some(EventSink::new(move |event| {
+                if let FloxEvent::Warning(warning) = event {
+                    sink_warnings.lock().unwrap().push(warning.clone())
+                }
+            })),
+            ..flox_in(tempdir.path())
+        };
+        let environment = test_environment(&flox, &project_dir, TokioFs).await;
+        std::fs::write(project_dir.join("flox.nix"), "{ }").unwrap();
+        let lock = EnvironmentLock {
+            flox_nix_hash: content_hash(&[b"{ packages.nixpkgs-flox.hello = {}; }"]),
+            environment: PathBuf::from("/nix/store/xyz-floxenv"),
+            packages: BTreeMap::new(),
+        };
+        std::fs::write(
+            project_dir.join(FLOX_LOCK),
+            serde_json::to_vec(&lock).unwrap(),
+        )
+        .unwrap();
+
+        // the unpinned build fails without a flake.nix
+        let _ = environment.build().await;
+        assert_eq!(*warnings.lock().unwrap(), [FloxWarning::StaleLock {
+            environment: environment.name.clone(),
+        }]);
+    }
+
+    /// Nix backend evaluating every package to `/nix/store/123-hello-2.12.1`
+    #[cfg(feature = "impure-unit-tests")]
+    #[derive(Debug)]
+    struct HelloNix;
+
+    #[cfg(feature = "impure-unit-tests")]
+    impl NixBackend for HelloNix {}
+
+    #[cfg(feature = "impure-unit-tests")]
+    impl FloxNixApi for HelloNix {
+        fn new(_: &Flox, _: runix::default::DefaultArgs) -> Self {
+            HelloNix
+        }
+
+        fn command(&self, subcommand: &[&str]) -> tokio::process::Command {
+            let mut command = tokio::process::Command::new("false");
+            command.args(subcommand);
+            command
+        }
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[async_trait::async_trait]
+    impl runix::Run<HelloNix> for Eval {
+        type Error = std::io::Error;
+
+        async fn run(
+            &self,
+            _: &HelloNix,
+            _: &runix::arguments::NixArgs,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[async_trait::async_trait]
+    impl RunJson<HelloNix> for Eval {
+        type JsonError = std::io::Error;
+
+        async fn run_json(
+            &self,
+            _: &HelloNix,
+            _: &runix::arguments::NixArgs,
+        ) -> Result<serde_json::Value, Self::JsonError> {
+            Ok(serde_json::json!({
+                "storePath": "/nix/store/123-hello-2.12.1",
+                "version": "2.12.1",
+            }))
+        }
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn pinned_environments_build_the_pinned_store_path() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let flox = flox_in(tempdir.path());
+        // there is no flake.nix, building fails unless a previous build is used
+        let environment = test_environment(&flox, &project_dir, TokioFs).await;
+        let flox_nix = "{ packages.nixpkgs-flox.hello = {}; }";
+        std::fs::write(project_dir.join("flox.nix"), flox_nix).unwrap();
+
+        let built = tempdir.path().join("floxenv");
+        std::fs::create_dir_all(&built).unwrap();
+        std::fs::write(built.join("catalog.json"), "{}").unwrap();
+        let output = std::process::Command::new("nix-store")
+            .arg("--add")
+            .arg(&built)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let store_path = PathBuf::from(String::from_utf8(output.stdout).unwrap().trim());
+
+        let (sandbox, mut index) = environment.enter_transaction().await.unwrap();
+        let entry = sandbox
+            .build_cache_entry(flox_nix.as_bytes())
+            .await
+            .unwrap();
+        std::fs::create_dir_all(entry.parent().unwrap()).unwrap();
+        std::fs::write(&entry, store_path.to_string_lossy().as_bytes()).unwrap();
+
+        let lock = sandbox.pin::<HelloNix>(&mut index).await.unwrap();
+        assert_eq!(lock.environment, store_path);
+        assert_eq!(
+            lock.packages["nixpkgs-flox.hello"].store_path,
+            Path::new("/nix/store/123-hello-2.12.1")
+        );
+        assert_eq!(index.get(Path::new(FLOX_LOCK)), Some(&FileAction::Add));
+        assert_eq!(sandbox.lock().await.unwrap(), Some(lock));
+
+        // the pinned build is used even without the build the pin was made from
+        std::fs::remove_file(&entry).unwrap();
+        assert_eq!(sandbox.build().await.unwrap(), store_path);
+
+        sandbox.unpin(&mut index).await.unwrap();
+        assert!(matches!(
+            sandbox.build().await,
+            Err(BuildEnvironmentError::BadExit(..))
+        ));
+    }
+}
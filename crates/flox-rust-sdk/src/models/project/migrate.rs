@@ -0,0 +1,510 @@
+//! Migration of the legacy numbered-generation layout
+//!
+//! Generations used to be kept side by side as numbered directories,
+//! each holding a complete flake:
+//!
+//! ```ignore
+//! /
+//! L .git/
+//! L metadata.json
+//! L 1/
+//!   L flake.nix
+//!   L pkgs/
+//!     L default/
+//!       L flox.nix
+//! L 2/
+//!   L flake.nix
+//!   ...
+//! ```
+//!
+//! The current layout keeps a single flake at the project root
+//! and relies on git history (and generation tags) for earlier states.
+
+use std::path::{Path, PathBuf};
+
+use runix::command::Eval;
+use runix::RunJson;
+use thiserror::Error;
+use walkdir::WalkDir;
+
+use super::check::CheckReport;
+use super::{
+    FileAction,
+    Project,
+    ProjectError,
+    TransactionEnterError,
+    TransactionOutcome,
+    ValidatedCommitError,
+};
+use crate::flox::FloxNixApi;
+use crate::models::floxmeta::environment::{Metadata, METADATA_JSON};
+use crate::models::root::transaction::{GitAccess, ReadOnly};
+use crate::providers::fs::{FileKind, FileSystem};
+use crate::providers::git::GitProvider;
+use crate::utils::errors::FloxErrorCode;
+
+/// Result of [Project::migrate_generations]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// The generation that became the project root, [None] if the project was current
+    pub generation: Option<u32>,
+    /// Files moved from the generation directory to the project root,
+    /// relative to the root
+    pub moved: Vec<PathBuf>,
+    /// Generation directories and metadata removed from the project root
+    pub removed: Vec<PathBuf>,
+}
+
+impl MigrationReport {
+    /// Whether the project was already in the current layout
+    pub fn is_empty(&self) -> bool {
+        self.generation.is_none()
+    }
+}
+
+/// Numbers of the generation directories of the legacy layout in `root`, sorted
+///
+/// Only numbered directories containing a `flake.nix` count as generations.
+pub(super) async fn legacy_generations(
+    fs: &impl FileSystem,
+    root: &Path,
+) -> std::io::Result<Vec<u32>> {
+    let mut generations = Vec::new();
+    for name in fs.read_dir(root).await? {
+        let generation = match name.to_str().map(str::parse::<u32>) {
+            Some(Ok(generation)) => generation,
+            _ => continue,
+        };
+        let dir = root.join(&name);
+        if fs.kind(&dir).await? == Some(FileKind::Dir)
+            && fs.kind(&dir.join("flake.nix")).await? == Some(FileKind::File)
+        {
+            generations.push(generation);
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+/// A project in the legacy numbered-generation layout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct LegacyLayout {
+    /// All generations, sorted
+    pub(super) generations: Vec<u32>,
+    /// `currentGen` of `metadata.json`, or the highest numbered generation
+    pub(super) current: u32,
+    /// Whether the project root has a `metadata.json`
+    pub(super) has_metadata: bool,
+}
+
+impl LegacyLayout {
+    /// Read the legacy layout of the project in `root`, [None] if it uses the current layout
+    pub(super) async fn read(
+        fs: &impl FileSystem,
+        root: &Path,
+    ) -> Result<Option<Self>, LegacyLayoutError> {
+        let generations = legacy_generations(fs, root)
+            .await
+            .map_err(|e| LegacyLayoutError::Read(root.to_path_buf(), e))?;
+        if generations.is_empty() {
+            return Ok(None);
+        }
+
+        let metadata_path = root.join(METADATA_JSON);
+        let metadata = match fs.read(&metadata_path).await {
+            Ok(contents) => Some(
+                serde_json::from_slice::<Metadata>(&contents)
+                    .map_err(LegacyLayoutError::ParseMetadata)?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(LegacyLayoutError::Read(metadata_path, e)),
+        };
+        let has_metadata = metadata.is_some();
+        let current = match metadata.and_then(|metadata| metadata.current_gen) {
+            Some(current) => current
+                .parse::<u32>()
+                .ok()
+                .filter(|current| generations.contains(current))
+                .ok_or(LegacyLayoutError::CurrentGeneration(current))?,
+            None => *generations.last().unwrap(),
+        };
+
+        Ok(Some(LegacyLayout {
+            generations,
+            current,
+            has_metadata,
+        }))
+    }
+}
+
+impl<'flox, Git: GitProvider, Fs: FileSystem> Project<'flox, Git, ReadOnly<Git>, Fs> {
+    /// Convert the legacy numbered-generation layout to the current layout
+    ///
+    /// The files of the current generation (`currentGen` of `metadata.json`,
+    /// or the highest numbered one) are moved to the project root,
+    /// all generation directories and `metadata.json` are removed.
+    /// This happens in a single transaction, which is only committed
+    /// if the migrated project [validates](Project::validate).
+    /// Earlier generations remain available in the git history.
+    ///
+    /// Projects already in the current layout are left untouched
+    /// and an [empty](MigrationReport::is_empty) report is returned.
+    pub async fn migrate_generations<Nix: FloxNixApi>(
+        &self,
+    ) -> Result<MigrationReport, MigrateGenerationsError<Git>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let root = self.require_workdir()?;
+        let LegacyLayout {
+            generations,
+            current: generation,
+            has_metadata,
+        } = match LegacyLayout::read(&*self.fs, root).await? {
+            Some(layout) => layout,
+            None => return Ok(MigrationReport::default()),
+        };
+
+        let generation_dir = PathBuf::from(generation.to_string());
+        let source_dir = root.join(&generation_dir);
+        let mut moved = Vec::new();
+        for entry in WalkDir::new(&source_dir).sort_by_file_name() {
+            let entry = entry.map_err(MigrateGenerationsError::Walkdir)?;
+            if !entry.file_type().is_dir() {
+                let relative = entry.path().strip_prefix(&source_dir).unwrap();
+                moved.push(relative.to_path_buf());
+            }
+        }
+
+        let (sandbox, mut index) = Project::new(
+            self.flox,
+            self.git.read_only(),
+            self.fs.clone(),
+            self.subdir.clone(),
+        )
+        .enter_transaction()
+        .await
+        .map_err(MigrateGenerationsError::EnterTransaction)?;
+        let sandbox_root = sandbox.require_workdir()?.to_path_buf();
+
+        for file in &moved {
+            let source_path = sandbox_root.join(&generation_dir).join(file);
+            let target_path = sandbox_root.join(file);
+
+            sandbox
+                .fs
+                .create_dir_all(target_path.parent().unwrap())
+                .await
+                .map_err(|e| MigrateGenerationsError::Write(target_path.clone(), e))?;
+            // moving keeps the mode of the file, e.g. of executable scripts
+            sandbox
+                .fs
+                .rename(&source_path, &target_path)
+                .await
+                .map_err(|e| MigrateGenerationsError::Move(source_path.clone(), e))?;
+            index.insert(file.clone(), FileAction::Add);
+        }
+
+        let mut removed: Vec<PathBuf> = generations
+            .iter()
+            .map(|generation| PathBuf::from(generation.to_string()))
+            .collect();
+        if has_metadata {
+            removed.push(PathBuf::from(METADATA_JSON));
+        }
+        for path in &removed {
+            let sandbox_path = sandbox_root.join(path);
+            if let Ok(Some(_)) = sandbox.fs.kind(&sandbox_path).await {
+                sandbox
+                    .fs
+                    .remove(&sandbox_path)
+                    .await
+                    .map_err(|e| MigrateGenerationsError::Remove(sandbox_path, e))?;
+            }
+        }
+        // unstage the removed files, so that they are not part of the flake nix validates,
+        // `git rm` fails for files that are not tracked, e.g. an ignored metadata.json
+        let removed_paths: Vec<&Path> = removed.iter().map(PathBuf::as_path).collect();
+        let tracked = sandbox
+            .git
+            .git()
+            .ls_files(&removed_paths)
+            .await
+            .map_err(MigrateGenerationsError::ListFiles)?;
+        if !tracked.is_empty() {
+            let tracked: Vec<&Path> = tracked.iter().map(PathBuf::as_path).collect();
+            sandbox
+                .git
+                .git()
+                .rm(&tracked, false, false, true)
+                .await
+                .map_err(MigrateGenerationsError::Unstage)?;
+        }
+        // untracked files cannot be deleted through git and are removed once committed
+        let (deleted, untracked): (Vec<PathBuf>, Vec<PathBuf>) = removed
+            .iter()
+            .cloned()
+            .partition(|path| tracked.iter().any(|file| file.starts_with(path)));
+        for path in deleted {
+            index.insert(path, FileAction::Delete);
+        }
+
+        sandbox
+            .write_transaction_state(&index)
+            .await
+            .map_err(MigrateGenerationsError::WriteState)?;
+
+        let message = format!("Migrate generation {generation} to the project root");
+        match sandbox
+            .commit_transaction_validated::<Nix>(index, &message, false)
+            .await
+            .map_err(MigrateGenerationsError::CommitTransaction)?
+        {
+            TransactionOutcome::Committed(_) => {},
+            TransactionOutcome::Rejected { report, .. } => {
+                return Err(MigrateGenerationsError::Invalid(report))
+            },
+            TransactionOutcome::DryRun { .. } => unreachable!("not a dry run"),
+        }
+
+        for path in untracked {
+            let path = root.join(path);
+            if let Ok(Some(_)) = self.fs.kind(&path).await {
+                self.fs
+                    .remove(&path)
+                    .await
+                    .map_err(|e| MigrateGenerationsError::Remove(path, e))?;
+            }
+        }
+
+        Ok(MigrationReport {
+            generation: Some(generation),
+            moved,
+            removed,
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LegacyLayoutError {
+    #[error("Failed to read {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse {METADATA_JSON}: {0}")]
+    ParseMetadata(serde_json::Error),
+    #[error("Current generation '{0}' not found")]
+    CurrentGeneration(String),
+}
+
+impl LegacyLayoutError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            LegacyLayoutError::Read(..) => FloxErrorCode::Io,
+            LegacyLayoutError::ParseMetadata(_) | LegacyLayoutError::CurrentGeneration(_) => {
+                FloxErrorCode::Invalid
+            },
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MigrateGenerationsError<Git: GitProvider> {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error(transparent)]
+    Layout(#[from] LegacyLayoutError),
+    #[error("Failed to list generation files: {0}")]
+    Walkdir(walkdir::Error),
+    #[error("Failed to move {0:?}: {1}")]
+    Move(PathBuf, std::io::Error),
+    #[error("Failed to write {0:?}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("Failed to remove {0:?}: {1}")]
+    Remove(PathBuf, std::io::Error),
+    #[error("Failed to enter transaction: {0}")]
+    EnterTransaction(TransactionEnterError<Git>),
+    #[error("Failed to list tracked files in sandbox repository: {0}")]
+    ListFiles(Git::ListFilesError),
+    #[error("Failed to unstage removed files in sandbox repository: {0}")]
+    Unstage(Git::RmError),
+    #[error("Failed to write transaction state: {0}")]
+    WriteState(std::io::Error),
+    #[error("Failed to commit transaction: {0}")]
+    CommitTransaction(ValidatedCommitError<Git>),
+    #[error("The migrated project does not evaluate, nothing was changed")]
+    Invalid(CheckReport),
+}
+
+impl<Git: GitProvider> MigrateGenerationsError<Git> {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            MigrateGenerationsError::Workdir(e) => e.code(),
+            MigrateGenerationsError::Layout(e) => e.code(),
+            MigrateGenerationsError::Walkdir(_)
+            | MigrateGenerationsError::Move(..)
+            | MigrateGenerationsError::Write(..)
+            | MigrateGenerationsError::Remove(..)
+            | MigrateGenerationsError::WriteState(_) => FloxErrorCode::Io,
+            MigrateGenerationsError::ListFiles(_) | MigrateGenerationsError::Unstage(_) => {
+                FloxErrorCode::Git
+            },
+            MigrateGenerationsError::EnterTransaction(e) => e.code(),
+            MigrateGenerationsError::CommitTransaction(e) => e.code(),
+            MigrateGenerationsError::Invalid(_) => FloxErrorCode::Nix,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::fs::TokioFs;
+
+    #[tokio::test]
+    async fn detects_legacy_generations() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+        for dir in ["1", "2", "10", "pkgs", "3"] {
+            std::fs::create_dir(root.join(dir)).unwrap();
+        }
+        for generation in ["1", "2", "10", "pkgs"] {
+            std::fs::write(root.join(generation).join("flake.nix"), "{}").unwrap();
+        }
+        std::fs::write(root.join("4"), "").unwrap();
+
+        assert_eq!(legacy_generations(&TokioFs, root).await.unwrap(), [
+            1, 2, 10
+        ]);
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn migrates_legacy_layout() {
+        use runix::command_line::NixCommandLine;
+
+        use crate::flox::Flox;
+        use crate::models::project::tests::open_project;
+        use crate::providers::git::GitCommandProvider;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("caches"),
+            temp_dir: tempdir.path().join("temp"),
+            config_dir: tempdir.path().join("config"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&flox.temp_dir).unwrap();
+
+        // fixture of a repository with two generations, the first being current
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let git = GitCommandProvider::init(&project_dir, false).await.unwrap();
+        for generation in ["1", "2"] {
+            let flox_nix = project_dir.join(generation).join("pkgs/default/flox.nix");
+            std::fs::create_dir_all(flox_nix.parent().unwrap()).unwrap();
+            std::fs::write(&flox_nix, format!("{{ generation = {generation}; }}")).unwrap();
+            std::fs::write(
+                project_dir.join(generation).join("flake.nix"),
+                "{ outputs = _: { }; }",
+            )
+            .unwrap();
+        }
+        std::fs::write(
+            project_dir.join(METADATA_JSON),
+            r#"{ "currentGen": "1", "generations": {} }"#,
+        )
+        .unwrap();
+        git.add(&[Path::new(".")]).await.unwrap();
+        git.commit("legacy").await.unwrap();
+
+        let project = open_project(&flox, &project_dir).await;
+
+        let report = project
+            .migrate_generations::<NixCommandLine>()
+            .await
+            .expect("should migrate");
+        assert_eq!(report.generation, Some(1));
+        assert_eq!(report.moved, [
+            PathBuf::from("flake.nix"),
+            PathBuf::from("pkgs/default/flox.nix")
+        ]);
+        assert_eq!(report.removed, [
+            PathBuf::from("1"),
+            PathBuf::from("2"),
+            PathBuf::from(METADATA_JSON)
+        ]);
+
+        assert_eq!(
+            std::fs::read_to_string(project_dir.join("pkgs/default/flox.nix")).unwrap(),
+            "{ generation = 1; }"
+        );
+        assert!(!project_dir.join("1").exists());
+        assert!(!project_dir.join("2").exists());
+        assert!(!project_dir.join(METADATA_JSON).exists());
+        assert_eq!(git.log(None, None).await.unwrap().len(), 2);
+
+        let report = project
+            .migrate_generations::<NixCommandLine>()
+            .await
+            .expect("should be a no-op");
+        assert!(report.is_empty());
+        assert_eq!(git.log(None, None).await.unwrap().len(), 2);
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn migrates_modes_and_untracked_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use runix::command_line::NixCommandLine;
+
+        use crate::flox::Flox;
+        use crate::models::project::tests::open_project;
+        use crate::providers::git::GitCommandProvider;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("caches"),
+            temp_dir: tempdir.path().join("temp"),
+            config_dir: tempdir.path().join("config"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&flox.temp_dir).unwrap();
+
+        // fixture of a single generation with an executable script and an ignored metadata.json
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir_all(project_dir.join("1")).unwrap();
+        let git = GitCommandProvider::init(&project_dir, false).await.unwrap();
+        std::fs::write(
+            project_dir.join("1").join("flake.nix"),
+            "{ outputs = _: { }; }",
+        )
+        .unwrap();
+        let script = project_dir.join("1").join("hook.sh");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(project_dir.join(".gitignore"), METADATA_JSON).unwrap();
+        std::fs::write(
+            project_dir.join(METADATA_JSON),
+            r#"{ "currentGen": "1", "generations": {} }"#,
+        )
+        .unwrap();
+        git.add(&[Path::new(".")]).await.unwrap();
+        git.commit("legacy").await.unwrap();
+
+        let project = open_project(&flox, &project_dir).await;
+
+        let report = project
+            .migrate_generations::<NixCommandLine>()
+            .await
+            .expect("should migrate");
+        assert_eq!(report.generation, Some(1));
+
+        let mode = std::fs::metadata(project_dir.join("hook.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert!(!project_dir.join("1").exists());
+        assert!(!project_dir.join(METADATA_JSON).exists());
+    }
+}
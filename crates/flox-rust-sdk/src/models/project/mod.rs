@@ -1,40 +1,84 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
 use std::marker::PhantomData;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::rc::Rc;
+use std::str::FromStr;
 
-use log::{debug, info};
+use fslock::LockFile;
+use log::{debug, warn};
 use once_cell::sync::Lazy;
 use regex::Regex;
+use runix::arguments::eval::EvaluationArgs;
 use runix::arguments::{EvalArgs, NixArgs};
 use runix::command::{Eval, FlakeInit};
 use runix::installable::Installable;
 use runix::{NixBackend, Run, RunJson};
+use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
 use thiserror::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::OnceCell;
 use walkdir::WalkDir;
 
-use self::environment::Environment;
-use super::root::transaction::{GitAccess, GitSandBox, ReadOnly};
+use self::check::{CheckReport, ValidateError};
+use self::environment::{Environment, EnvironmentChannelsError, EvalOptions};
+use self::scaffold::{ScaffoldError, ScaffoldStep};
+use self::show::{FlakeOutputs, FlakeShowError};
+use self::template::TemplateCacheError;
+use super::events::{FloxEvent, FloxWarning};
+use super::flake_ref::ToFlakeRef;
+use super::flake_registry;
+use super::flox_nix::{self, FloxNixError, FloxNixNames};
+use super::floxenvs::{self, FloxEnvsOutput, FloxEnvsOutputError};
+use super::root::transaction::{CommitStrategy, GitAccess, GitSandBox, ReadOnly};
 use super::root::{Closed, Root};
+use super::system::{System, UnknownSystemError};
 use crate::flox::{Flox, FloxNixApi};
-use crate::providers::git::GitProvider;
-use crate::utils::errors::IoError;
+use crate::providers::fs::{self, CopyOptions, FileKind, FileSystem, TokioFs};
+use crate::providers::git::{GitProvider, GitStashError};
+use crate::utils::errors::{FloxErrorCode, IoError};
 use crate::utils::guard::Guard;
-use crate::utils::{copy_file_without_permissions, find_and_replace, FindAndReplaceError};
 
+pub mod build;
+pub mod check;
+pub mod clean;
+pub mod composition;
+pub mod direnv;
 pub mod environment;
+pub mod import;
+pub mod lock;
+pub mod migrate;
+pub mod scaffold;
+pub mod show;
+pub mod status;
+pub mod template;
+pub mod vendor;
+pub mod watch;
 
 static PNAME_DECLARATION: Lazy<Regex> = Lazy::new(|| Regex::new(r#"pname = ".*""#).unwrap());
 static PACKAGE_NAME_PLACEHOLDER: &str = "__PACKAGE_NAME__";
+/// File in a transaction sandbox tracking the pending [Index]
+pub const TRANSACTION_JSON: &str = "transaction.json";
+
+/// Directory in [Flox::cache_dir] holding sandboxes kept by [TransactionOptions::reuse_sandbox]
+const SANDBOX_CACHE_DIR: &str = "transaction-sandboxes";
+/// Directory in [Flox::cache_dir] holding the sandboxes of single transactions,
+/// see [pending_transactions]
+const TRANSACTIONS_DIR: &str = "transactions";
 
 #[derive(Debug)]
 /// A representation of a project, i.e. a git repo with a flake.nix
 ///
 /// We assume the flake.nix follows the capacitor output schema
-pub struct Project<'flox, Git: GitProvider, Access: GitAccess<Git>> {
+///
+/// File operations are performed through a [FileSystem],
+/// which defaults to the real filesystem ([TokioFs]).
+pub struct Project<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem = TokioFs> {
     flox: &'flox Flox,
     git: Access,
+    fs: Rc<Fs>,
     /// subdir relative to the git workdir
     ///
     /// Represent setups where the project is not in the git root,
@@ -51,6 +95,8 @@ pub struct Project<'flox, Git: GitProvider, Access: GitAccess<Git>> {
     ///       L flox.nix
     /// ```
     subdir: PathBuf,
+    /// Outputs of the flake, cached by [Project::flake_show]
+    flake_outputs: OnceCell<FlakeOutputs>,
     _marker: PhantomData<Git>,
 }
 
@@ -58,21 +104,38 @@ pub struct Project<'flox, Git: GitProvider, Access: GitAccess<Git>> {
 impl<'flox, Git: GitProvider> Root<'flox, Closed<Git>> {
     /// Guards opening a project
     ///
-    /// - Resolves as initialized if a `flake.nix` is present
+    /// - Resolves as initialized if a `flake.nix` is present,
+    ///   or generations in the legacy layout (see [Project::migrate_generations])
     /// - Resolves as uninitialized if not
+    /// - Fails for bare repositories and if `flake.nix` cannot be accessed
     pub async fn guard(
         self,
     ) -> Result<Guard<Project<'flox, Git, ReadOnly<Git>>, Root<'flox, Closed<Git>>>, OpenProjectError>
     {
         let repo = &self.state.inner;
 
-        let root = repo.workdir().ok_or(OpenProjectError::WorkdirNotFound)?;
+        let root = repo.workdir().ok_or(OpenProjectError::BareRepository)?;
 
         // todo: inset
-        if root.join("flake.nix").exists() {
+        let flake_nix = root.join("flake.nix");
+        // projects opened here use the real filesystem
+        let initialized = match TokioFs.kind(&flake_nix).await {
+            Ok(Some(_)) => true,
+            Ok(None) => !migrate::legacy_generations(&TokioFs, root)
+                .await
+                .map_err(|e| OpenProjectError::Io(root.to_path_buf(), e))?
+                .is_empty(),
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                return Err(OpenProjectError::PermissionDenied(flake_nix))
+            },
+            Err(err) => return Err(OpenProjectError::Io(flake_nix, err)),
+        };
+
+        if initialized {
             Ok(Guard::Initialized(Project::new(
                 self.flox,
                 ReadOnly::new(self.state.inner),
+                Rc::new(TokioFs),
                 PathBuf::new(),
             )))
         } else {
@@ -109,44 +172,71 @@ impl<'flox, Git: GitProvider> Guard<Project<'flox, Git, ReadOnly<Git>>, Root<'fl
 
         let nix = uninit.flox.nix(nix_extra_args);
 
-        FlakeInit {
-            template: Some("flox#templates._init".to_string().into()),
-            ..Default::default()
-        }
-        .run(&nix, &NixArgs {
-            cwd: Some(root.to_path_buf()),
-            ..Default::default()
+        let base = Installable::new("flox".to_string(), "templates._init".to_string());
+        let mut created = Vec::new();
+        template::init_template(uninit.flox, &base, root, &mut created, |dir| async {
+            FlakeInit {
+                template: Some(base.to_string().into()),
+                ..Default::default()
+            }
+            .run(&nix, &NixArgs {
+                cwd: Some(dir),
+                ..Default::default()
+            })
+            .await
+            .map_err(InitProjectError::NixInitBase)
         })
-        .await
-        .map_err(InitProjectError::NixInitBase)?;
+        .await?;
 
-        repo.add(&[Path::new("flake.nix")])
-            .await
-            .map_err(InitProjectError::GitAdd)?;
+        let mut added = vec![Path::new("flake.nix")];
+        added.extend(created.iter().map(PathBuf::as_path));
+        repo.add(&added).await.map_err(InitProjectError::GitAdd)?;
 
         Ok(Project::new(
             uninit.flox,
             ReadOnly::new(repo),
+            Rc::new(TokioFs),
             PathBuf::new(),
         ))
     }
 }
 
 /// Implementations for an opened project (read only)
-impl<'flox, Git: GitProvider, Access: GitAccess<Git>> Project<'flox, Git, Access> {
+impl<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem>
+    Project<'flox, Git, Access, Fs>
+{
     /// Construct a new Project object
     ///
     /// Private in this module, as intialization through git guard is prefered
     /// to provide project guarantees.
-    fn new(flox: &Flox, git: Access, subdir: PathBuf) -> Project<Git, Access> {
+    fn new(flox: &'flox Flox, git: Access, fs: Rc<Fs>, subdir: PathBuf) -> Self {
         Project {
             flox,
             git,
+            fs,
             subdir,
+            flake_outputs: OnceCell::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Replace the [FileSystem] used for file operations of this project
+    pub fn with_fs<F: FileSystem>(self, fs: F) -> Project<'flox, Git, Access, F> {
+        Project {
+            flox: self.flox,
+            git: self.git,
+            fs: Rc::new(fs),
+            subdir: self.subdir,
+            flake_outputs: OnceCell::new(),
             _marker: PhantomData,
         }
     }
 
+    /// The [FileSystem] used for file operations of this project
+    pub fn fs(&self) -> &Fs {
+        &self.fs
+    }
+
     /// Get the root directory of the project flake
     ///
     /// currently the git root but may be a subdir with a flake.nix
@@ -154,15 +244,132 @@ impl<'flox, Git: GitProvider, Access: GitAccess<Git>> Project<'flox, Git, Access
         self.git.git().workdir()
     }
 
+    /// Like [Self::workdir], but fails for projects without a workdir (bare repositories)
+    pub fn require_workdir(&self) -> Result<&Path, ProjectError> {
+        self.workdir().ok_or(ProjectError::WorkdirNotFound)
+    }
+
+    /// Update the refs of a git remote without merging them
+    ///
+    /// Unlike `nix flake update` this does not touch the flake inputs.
+    pub async fn fetch(&self, remote: &str) -> Result<(), Git::FetchError> {
+        self.git.git().fetch(remote).await
+    }
+
     /// flakeref for the project
+    ///
+    /// Fails with [ProjectError::WorkdirMissing] if the project was moved or deleted,
+    /// which nix would only report as a failure to read the path.
     // todo: use typed FlakeRefs
-    pub fn flakeref(&self) -> String {
-        self.workdir().unwrap().to_string_lossy().to_string()
+    pub async fn flakeref(&self) -> Result<String, ProjectError> {
+        let path = self.require_workdir()?.join(&self.subdir);
+        match self.fs.kind(&path).await {
+            Ok(Some(_)) => {},
+            Ok(None) => return Err(ProjectError::WorkdirMissing { path }),
+            Err(e) => return Err(ProjectError::ReadWorkdir(path, e)),
+        }
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    /// Enter a `devShells` output of the project with `nix develop`
+    ///
+    /// Without `shell`, nix picks the default shell of the current system,
+    /// `devShells.<system>.default` or the legacy `devShell.<system>`.
+    /// The shell is looked up before running nix, for a clearer error if it is missing.
+    /// An empty `command` starts an interactive shell,
+    /// otherwise `command` is run in the shell (`nix develop --command`).
+    /// Standard streams are inherited and the exit status of the shell is returned.
+    pub async fn develop<Nix: FloxNixApi>(
+        &self,
+        shell: Option<&str>,
+        command: &[&str],
+    ) -> Result<ExitStatus, DevelopError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let flakeref = self.flakeref().await?;
+        let system = self.flox.system.as_str();
+        let dev_shells = format!("((outputs.devShells or {{ }}).{system:?} or {{ }})");
+        let (apply, installable) = match shell {
+            Some(name) => (
+                format!("outputs: {dev_shells} ? {name:?}"),
+                Installable::new(flakeref.clone(), format!("devShells.{system:?}.{name:?}"))
+                    .to_string(),
+            ),
+            None => (
+                format!(
+                    "outputs: {dev_shells} ? default || (outputs.devShell or {{ }}) ? {system:?}"
+                ),
+                flakeref.clone(),
+            ),
+        };
+
+        // an empty attribute path refers to the flake outputs as a whole
+        let eval = Eval {
+            eval_args: EvalArgs {
+                installable: Some(Installable::new(flakeref, ".".to_string()).into()),
+                apply: Some(apply.into()),
+            },
+            ..Default::default()
+        };
+        let nix = self.flox.nix::<Nix>(Vec::new());
+        let exists = eval
+            .run_json(&nix, &NixArgs::default())
+            .await
+            .map_err(DevelopError::Eval)?;
+        let exists: bool = serde_json::from_value(exists).map_err(DevelopError::Parse)?;
+        if !exists {
+            return Err(DevelopError::NotFound(
+                shell.unwrap_or("default").to_string(),
+            ));
+        }
+
+        let mut develop = nix.command(&["develop"]);
+        develop.arg(installable);
+        if !command.is_empty() {
+            develop.arg("--command").args(command);
+        }
+        develop.status().await.map_err(DevelopError::Spawn)
+    }
+
+    /// Open a nested flake at `rel` (relative to this project) as its own project
+    ///
+    /// The subproject shares the git repository of this project.
+    pub async fn subproject(
+        &self,
+        rel: &Path,
+    ) -> Result<Project<'flox, Git, ReadOnly<Git>, Fs>, SubprojectError> {
+        if !rel
+            .components()
+            .all(|component| matches!(component, std::path::Component::Normal(_)))
+        {
+            return Err(SubprojectError::InvalidPath(rel.to_path_buf()));
+        }
+
+        let subdir = self.subdir.join(rel);
+        let flake_nix = self.require_workdir()?.join(&subdir).join("flake.nix");
+        match self.fs.read(&flake_nix).await {
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(SubprojectError::NoFlake(rel.to_path_buf()))
+            },
+            Err(e) => return Err(SubprojectError::ReadFlake(flake_nix, e)),
+        }
+
+        Ok(Project::new(
+            self.flox,
+            self.git.read_only(),
+            self.fs.clone(),
+            subdir,
+        ))
     }
 
     /// Add a new flox style package from a template.
     /// Uses `nix flake init` to retrieve files
-    /// and postprocesses the generic templates.
+    /// and postprocesses the generic templates, see [scaffold::scaffold_package].
+    /// The created files are then staged.
+    ///
+    /// If any step fails, the steps performed so far are undone.
     //
     // todo: move to mutable state
     pub async fn init_flox_package<Nix: FloxNixApi>(
@@ -174,114 +381,73 @@ impl<'flox, Git: GitProvider, Access: GitAccess<Git>> Project<'flox, Git, Access
     where
         FlakeInit: Run<Nix>,
     {
-        let repo = self.git.git();
-
-        let nix = self.flox.nix(nix_extra_args);
-
-        let root = repo
+        let root = self
             .workdir()
             .ok_or(InitFloxPackageError::WorkdirNotFound)?;
 
-        FlakeInit {
-            template: Some(template.to_string().into()),
-            ..Default::default()
-        }
-        .run(&nix, &NixArgs {
-            cwd: root.to_path_buf().into(),
-            ..NixArgs::default()
-        })
+        let mut steps = Vec::new();
+        let result = match scaffold::scaffold_package::<Nix>(
+            self.flox,
+            nix_extra_args,
+            root,
+            &template,
+            name,
+            &mut steps,
+        )
         .await
-        .map_err(InitFloxPackageError::NixInit)?;
-
-        let old_package_path = root.join("pkgs/default.nix");
-
-        match tokio::fs::File::open(&old_package_path).await {
-            // legacy path. Drop after we merge template changes to floxpkgs
-            Ok(mut file) => {
-                let mut package_contents = String::new();
-                file.read_to_string(&mut package_contents)
-                    .await
-                    .map_err(InitFloxPackageError::ReadTemplateFile)?;
-
-                // Drop handler should clear our file handle in case we want to delete it
-                drop(file);
-
-                let new_contents =
-                    PNAME_DECLARATION.replace(&package_contents, format!(r#"pname = "{name}""#));
-
-                let new_package_dir = root.join("pkgs").join(name);
-                debug!("creating dir: {}", new_package_dir.display());
-                tokio::fs::create_dir_all(&new_package_dir)
-                    .await
-                    .map_err(InitFloxPackageError::MkNamedDir)?;
-
-                let new_package_path = new_package_dir.join("default.nix");
-
-                repo.rm(&[&old_package_path], false, true, false)
-                    .await
-                    .map_err(InitFloxPackageError::RemoveUnnamedFile)?;
-
-                let mut file = tokio::fs::File::create(&new_package_path)
-                    .await
-                    .map_err(InitFloxPackageError::OpenNamed)?;
+        {
+            Ok(()) => self.stage_package(root, &template, &steps).await,
+            Err(e) => Err(e.into()),
+        };
+        if result.is_err() {
+            // a failed `git add` leaves the index untouched
+            scaffold::rollback(&steps).await;
+        }
+        result
+    }
 
-                file.write_all(new_contents.as_bytes())
-                    .await
-                    .map_err(InitFloxPackageError::WriteTemplateFile)?;
+    /// Stage the changes of a successful [scaffold::scaffold_package]
+    async fn stage_package<Nix: FloxNixApi>(
+        &self,
+        root: &Path,
+        template: &Installable,
+        steps: &[ScaffoldStep],
+    ) -> Result<(), InitFloxPackageError<Nix, Git>>
+    where
+        FlakeInit: Run<Nix>,
+    {
+        let repo = self.git.git();
 
-                repo.add(&[&new_package_path])
+        let mut paths = scaffold::created_files(steps);
+        for step in steps {
+            // stage the removal of moved files that were tracked
+            if let ScaffoldStep::Moved { from, .. } = step {
+                let tracked = repo
+                    .ls_files(&[from])
                     .await
-                    .map_err(InitFloxPackageError::GitAdd)?;
-
-                // this might technically be a lie, but it's close enough :)
-                info!("renamed: pkgs/default.nix -> pkgs/{name}/default.nix");
-            },
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 'move_to_pkgs: {
-                let old_proto_pkg_path = root.join("pkgs").join(PACKAGE_NAME_PLACEHOLDER);
-
-                if !old_proto_pkg_path.exists() {
-                    // TODO: really find a better way to not hardcode this
-                    if template.to_string() == "flake:flox#.\"templates\".\"project\"" {
-                        repo.add(&[&root.join("flox.nix")])
-                            .await
-                            .map_err(InitFloxPackageError::GitAdd)?;
-                    }
-
-                    break 'move_to_pkgs;
+                    .map_err(InitFloxPackageError::ListFiles)?;
+                if !tracked.is_empty() {
+                    paths.push(from.clone());
                 }
+            }
+        }
+        // TODO: really find a better way to not hardcode this
+        if template.to_string() == "flake:flox#.\"templates\".\"project\"" {
+            paths.push(root.join("flox.nix"));
+        }
 
-                let new_proto_pkg_path = root.join("pkgs").join(name);
-
-                repo.mv(&old_proto_pkg_path, &new_proto_pkg_path)
-                    .await
-                    .map_err(InitFloxPackageError::GitMv)?;
-                info!(
-                    "moved: {} -> {}",
-                    old_proto_pkg_path.to_string_lossy(),
-                    new_proto_pkg_path.to_string_lossy()
-                );
-
-                // our minimal "templating" - Replace any occurrences of
-                // PACKAGE_NAME_PLACEHOLDER with name
-                find_and_replace(&new_proto_pkg_path, PACKAGE_NAME_PLACEHOLDER, name)
-                    .await
-                    .map_err(InitFloxPackageError::<Nix, Git>::ReplacePackageName)?;
-
-                repo.add(&[&new_proto_pkg_path])
-                    .await
-                    .map_err(InitFloxPackageError::GitAdd)?;
-            },
-            Err(err) => return Err(InitFloxPackageError::OpenTemplateFile(err)),
-        };
-        Ok(())
+        let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        repo.add(&paths).await.map_err(InitFloxPackageError::GitAdd)
     }
 
     /// Delete flox files from repo
     pub async fn cleanup_flox(self) -> Result<(), CleanupInitializerError> {
-        tokio::fs::remove_dir_all("./pkgs")
+        self.fs
+            .remove(Path::new("./pkgs"))
             .await
             .map_err(CleanupInitializerError::RemovePkgs)?;
-        tokio::fs::remove_file("./flake.nix")
+        self.fs
+            .remove(Path::new("./flake.nix"))
             .await
             .map_err(CleanupInitializerError::RemoveFlake)?;
 
@@ -293,323 +459,3799 @@ impl<'flox, Git: GitProvider, Access: GitAccess<Git>> Project<'flox, Git, Access
     pub async fn environment<Nix: FloxNixApi>(
         &self,
         name: &str,
-    ) -> Result<Environment<'flox, Git, ReadOnly<Git>>, ()>
+    ) -> Result<Environment<'flox, Git, ReadOnly<Git>, Fs>, GetEnvironmentError<Nix>>
     where
         Eval: RunJson<Nix>,
     {
-        let nix = self.flox.nix::<Nix>(Default::default());
+        self.environment_with::<Nix>(name, EvalOptions::default())
+            .await
+    }
 
-        let nix_apply_expr = format!(
-            r#"systems: (systems."{}" or {{}}) ? "{name}""#,
-            self.flox.system
-        );
+    /// Like [Self::environment], evaluating the project according to `options`
+    ///
+    /// The environment keeps evaluating according to `options`,
+    /// see [Environment::with_eval_options].
+    pub async fn environment_with<Nix: FloxNixApi>(
+        &self,
+        name: &str,
+        options: EvalOptions,
+    ) -> Result<Environment<'flox, Git, ReadOnly<Git>, Fs>, GetEnvironmentError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        self.environment_on::<Nix>(name, self.flox.system.clone(), options)
+            .await
+    }
 
-        let eval = Eval {
-            eval_args: EvalArgs {
-                apply: Some(nix_apply_expr.into()),
-                installable: Some(Installable::new(self.flakeref(), "floxEnvs".to_string()).into()),
-            },
-            ..Eval::default()
-        };
+    /// Like [Self::environment], but for `system` instead of
+    /// [Flox::system](crate::flox::Flox::system)
+    ///
+    /// Fails with [GetEnvironmentError::UnknownSystem] unless `system` is known to flox.
+    pub async fn environment_for_system<Nix: FloxNixApi>(
+        &self,
+        name: &str,
+        system: &str,
+    ) -> Result<Environment<'flox, Git, ReadOnly<Git>, Fs>, GetEnvironmentError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        self.environment_on::<Nix>(name, system.parse()?, EvalOptions::default())
+            .await
+    }
 
-        let env = eval.run_json(&nix, &Default::default()).await.unwrap();
-        let env = serde_json::from_value::<bool>(env).unwrap();
+    async fn environment_on<Nix: FloxNixApi>(
+        &self,
+        name: &str,
+        system: System,
+        options: EvalOptions,
+    ) -> Result<Environment<'flox, Git, ReadOnly<Git>, Fs>, GetEnvironmentError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        if let Some(names) = self
+            .compat_environment_names::<Nix>(&system)
+            .await
+            .map_err(GetEnvironmentError::FlakeShow)?
+        {
+            if !names.iter().any(|compat_name| compat_name == name) {
+                return Err(GetEnvironmentError::NotFound(name.to_string()));
+            }
+            return Ok(self.read_only_environment(name.to_string(), system, true, options));
+        }
 
-        env.then(|| Environment {
-            name: name.to_string(),
-            system: self.flox.system.clone(),
-            project: Project::new(self.flox, self.git.read_only(), self.subdir.clone()),
-        })
-        .ok_or(())
+        let flox_envs = self
+            .flox_envs::<Nix>(std::slice::from_ref(&system), false, options)
+            .await
+            .map_err(GetEnvironmentError::FloxEnvs)?;
+
+        if !flox_envs.contains(&system, name) {
+            return Err(GetEnvironmentError::NotFound(name.to_string()));
+        }
+        Ok(self.read_only_environment(name.to_string(), system, false, options))
     }
 
-    /// List environments in this project
-    pub async fn environments<Nix: FloxNixApi>(
-        &'flox self,
-    ) -> Result<Vec<Environment<'flox, Git, ReadOnly<Git>>>, GetEnvironmentsError<Nix>>
+    /// Evaluate the `floxEnvs` output of this project for `systems`
+    ///
+    /// Environments are only looked up by name unless `metadata` is requested,
+    /// see [floxenvs::apply_expression].
+    pub async fn flox_envs<Nix: FloxNixApi>(
+        &self,
+        systems: &[System],
+        metadata: bool,
+        options: EvalOptions,
+    ) -> Result<FloxEnvsOutput, FloxEnvsError<Nix>>
     where
         Eval: RunJson<Nix>,
     {
         let nix = self.flox.nix::<Nix>(Default::default());
-
-        let nix_apply_expr = format!(
-            r#"systems: builtins.attrNames (systems."{}" or {{}})"#,
-            self.flox.system
-        );
+        let flakeref = self.flakeref().await.map_err(FloxEnvsError::Workdir)?;
+        self.flox.fetch_flake::<Nix>(&flakeref).await;
 
         let eval = Eval {
+            eval: EvaluationArgs {
+                impure: options.impure.into(),
+            },
             eval_args: EvalArgs {
-                apply: Some(nix_apply_expr.into()),
-                installable: Some(Installable::new(self.flakeref(), "floxEnvs".to_string()).into()),
+                apply: Some(floxenvs::apply_expression(systems, metadata).into()),
+                installable: Some(Installable::new(flakeref, "floxEnvs".to_string()).into()),
             },
             ..Eval::default()
         };
 
-        let names = eval.run_json(&nix, &Default::default()).await.unwrap();
-        let names = serde_json::from_value::<Vec<String>>(names).unwrap();
-
-        let envs = names
-            .into_iter()
-            .map(|name| Environment {
-                name,
-                system: self.flox.system.clone(),
-                project: Project::new(self.flox, self.git.read_only(), self.subdir.clone()),
-            })
-            .collect();
+        let output = eval
+            .run_json(&nix, &Default::default())
+            .await
+            .map_err(FloxEnvsError::Eval)?;
+        FloxEnvsOutput::parse(output).map_err(FloxEnvsError::Output)
+    }
 
-        Ok(envs)
+    /// List environments in this project, sorted by name ignoring case
+    pub async fn environments<Nix: FloxNixApi>(
+        &'flox self,
+    ) -> Result<Vec<Environment<'flox, Git, ReadOnly<Git>, Fs>>, GetEnvironmentsError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        self.environments_with::<Nix>(EvalOptions::default()).await
     }
-}
 
-/// Implementations exclusively for [ReadOnly] instances
-impl<'flox, Git: GitProvider> Project<'flox, Git, ReadOnly<Git>> {
-    pub async fn enter_transaction(
-        self,
-    ) -> Result<(Project<'flox, Git, GitSandBox<Git>>, Index), TransactionEnterError> {
-        let transaction_temp_dir =
-            TempDir::new_in(&self.flox.temp_dir).map_err(TransactionEnterError::CreateTempdir)?;
+    /// Like [Self::environments], evaluating the project according to `options`
+    ///
+    /// The environments keep evaluating according to `options`,
+    /// see [Environment::with_eval_options].
+    pub async fn environments_with<Nix: FloxNixApi>(
+        &'flox self,
+        options: EvalOptions,
+    ) -> Result<Vec<Environment<'flox, Git, ReadOnly<Git>, Fs>>, GetEnvironmentsError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let system = &self.flox.system;
+        let mut environments = self
+            .environments_for::<Nix>(std::slice::from_ref(system), options)
+            .await?;
+        Ok(environments.remove(system).unwrap_or_default())
+    }
 
-        let current_root = self.workdir().expect("only supports projects on FS");
+    /// Like [Self::environments], but for `system` instead of
+    /// [Flox::system](crate::flox::Flox::system)
+    ///
+    /// Fails with [GetEnvironmentsError::UnknownSystem] unless `system` is known to flox.
+    pub async fn environments_for_system<Nix: FloxNixApi>(
+        &'flox self,
+        system: &str,
+    ) -> Result<Vec<Environment<'flox, Git, ReadOnly<Git>, Fs>>, GetEnvironmentsError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let system: System = system.parse()?;
+        let mut environments = self
+            .environments_for::<Nix>(std::slice::from_ref(&system), EvalOptions::default())
+            .await?;
+        Ok(environments.remove(&system).unwrap_or_default())
+    }
 
-        for entry in WalkDir::new(current_root).into_iter().skip(1) {
-            let entry = entry.map_err(TransactionEnterError::Walkdir)?;
-            let new_path = transaction_temp_dir
-                .path()
-                .join(entry.path().strip_prefix(current_root).unwrap());
-            if entry.file_type().is_dir() {
-                tokio::fs::create_dir(new_path)
-                    .await
-                    .map_err(TransactionEnterError::CopyDir)?;
-            } else {
-                copy_file_without_permissions(entry.path(), &new_path)
-                    .await
-                    .map_err(TransactionEnterError::CopyFile)?;
+    /// List environments in this project for each of `systems`
+    ///
+    /// Unlike [Self::environments] this is not limited to
+    /// [Flox::system](crate::flox::Flox::system), e.g. to build for several systems in one run.
+    /// `floxEnvs` is evaluated at most once for all systems, according to `options`.
+    #[allow(clippy::type_complexity)]
+    pub async fn environments_for<Nix: FloxNixApi>(
+        &'flox self,
+        systems: &[System],
+        options: EvalOptions,
+    ) -> Result<
+        BTreeMap<System, Vec<Environment<'flox, Git, ReadOnly<Git>, Fs>>>,
+        GetEnvironmentsError<Nix>,
+    >
+    where
+        Eval: RunJson<Nix>,
+    {
+        let mut environments = BTreeMap::new();
+        let mut flox_systems = Vec::new();
+        for system in systems {
+            match self
+                .compat_environment_names::<Nix>(system)
+                .await
+                .map_err(GetEnvironmentsError::FlakeShow)?
+            {
+                Some(mut names) => {
+                    sort_environment_names(&mut names);
+                    let envs = names
+                        .into_iter()
+                        .map(|name| self.read_only_environment(name, system.clone(), true, options))
+                        .collect();
+                    environments.insert(system.clone(), envs);
+                },
+                None => flox_systems.push(system.clone()),
             }
         }
+        if flox_systems.is_empty() {
+            return Ok(environments);
+        }
+
+        let flox_envs = self
+            .flox_envs::<Nix>(&flox_systems, false, options)
+            .await
+            .map_err(GetEnvironmentsError::FloxEnvs)?;
+        for system in flox_systems {
+            let mut names = flox_envs.names(&system);
+            sort_environment_names(&mut names);
+            let envs = names
+                .into_iter()
+                .map(|name| self.read_only_environment(name, system.clone(), false, options))
+                .collect();
+            environments.insert(system, envs);
+        }
+        Ok(environments)
+    }
 
-        let git = Git::discover(transaction_temp_dir.path()).await.unwrap();
+    /// Names of the `devShells` of `system` to list as compat environments
+    ///
+    /// [None] unless [Flox::compat_devshells] is set and the flake has no `floxEnvs`.
+    async fn compat_environment_names<Nix: FloxNixApi>(
+        &self,
+        system: &System,
+    ) -> Result<Option<Vec<String>>, FlakeShowError> {
+        if !self.flox.compat_devshells {
+            return Ok(None);
+        }
 
-        let sandbox = self.git.to_sandbox_in(transaction_temp_dir, git);
+        let outputs = self.flake_show::<Nix>().await?;
+        if outputs.contains_key("floxEnvs") {
+            return Ok(None);
+        }
+        Ok(Some(check::system_outputs(
+            &outputs,
+            "devShells",
+            system.as_str(),
+        )))
+    }
 
-        Ok((
-            Project {
-                flox: self.flox,
-                git: sandbox,
-                subdir: self.subdir,
-                _marker: PhantomData,
-            },
-            Index::default(),
-        ))
+    fn read_only_environment(
+        &self,
+        name: String,
+        system: System,
+        compat: bool,
+        eval_options: EvalOptions,
+    ) -> Environment<'flox, Git, ReadOnly<Git>, Fs> {
+        Environment {
+            name,
+            system,
+            project: Project::new(
+                self.flox,
+                self.git.read_only(),
+                self.fs.clone(),
+                self.subdir.clone(),
+            ),
+            compat,
+            store_path: None,
+            eval_options,
+        }
     }
-}
 
-type Index = BTreeMap<PathBuf, FileAction>;
-pub enum FileAction {
-    Add,
-    Delete,
-}
+    /// Get the environment to operate on if none was named explicitly
+    ///
+    /// Picks the `default` environment if it exists,
+    /// otherwise the only environment of the project.
+    pub async fn active_environment<Nix: FloxNixApi>(
+        &'flox self,
+    ) -> Result<Environment<'flox, Git, ReadOnly<Git>, Fs>, ActiveEnvironmentError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let mut envs = self
+            .environments::<Nix>()
+            .await
+            .map_err(ActiveEnvironmentError::GetEnvironments)?;
 
-/// Implementations exclusively for [GitSandBox]ed instances
-impl<'flox, Git: GitProvider> Project<'flox, Git, GitSandBox<Git>> {
-    pub async fn commit_transaction(
-        self,
-        index: Index,
-        _message: &str,
-    ) -> Result<Project<'flox, Git, ReadOnly<Git>>, TransactionCommitError<Git>> {
-        let original = self.git.read_only();
+        let index = select_active_environment(envs.iter().map(|env| env.name.as_str()))?;
 
-        for (file, action) in index {
-            match action {
-                FileAction::Add => {
-                    if let Some(parent) = file.parent() {
-                        tokio::fs::create_dir_all(original.git().workdir().unwrap().join(parent))
-                            .await
-                            .unwrap();
-                    }
-                    tokio::fs::rename(
-                        self.git.git().workdir().unwrap().join(&file),
-                        original.git().workdir().unwrap().join(&file),
-                    )
-                    .await
-                    .unwrap();
+        Ok(envs.swap_remove(index))
+    }
 
-                    original.git().add(&[&file]).await.expect("should add file")
-                },
-                FileAction::Delete => {
-                    original
-                        .git()
-                        .rm(
-                            &[&file],
-                            original.git().workdir().unwrap().join(&file).is_dir(),
-                            false,
-                            false,
-                        )
-                        .await
-                        .expect("should remove path");
-                },
-            }
+    /// Registered channels the packages of environment `name` are taken from
+    ///
+    /// Packages from a flakeref that is not registered as a channel
+    /// are reported as [UNKNOWN_CHANNEL].
+    pub async fn channels_used<Nix: FloxNixApi>(
+        &self,
+        name: &str,
+    ) -> Result<Vec<String>, ChannelsUsedError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let environment = match self.environment::<Nix>(name).await {
+            Ok(environment) => environment,
+            Err(GetEnvironmentError::NotFound(name)) => {
+                return Err(ChannelsUsedError::NotFound(name))
+            },
+            Err(e) => return Err(ChannelsUsedError::GetEnvironment(e)),
+        };
+        let inputs = environment
+            .channels()
+            .await
+            .map_err(ChannelsUsedError::Channels)?;
+
+        let registry =
+            serde_json::to_string(&self.flox.channels).map_err(ChannelsUsedError::Registry)?;
+
+        let mut channels = BTreeSet::new();
+        for input in inputs {
+            channels.insert(channel_name(&registry, &input).map_err(ChannelsUsedError::Registry)?);
         }
 
-        Ok(Project {
-            flox: self.flox,
-            git: original,
-            subdir: self.subdir,
-            _marker: PhantomData,
-        })
+        Ok(channels.into_iter().collect())
     }
+}
 
-    /// create a new root
-    pub async fn create_default_env(&self, index: &mut Index) {
-        let path = Path::new("flox.nix").to_path_buf();
-        tokio::fs::write(
-            self.workdir().expect("only works with workdir").join(&path),
-            include_str!("./flox.nix.in"),
-        )
-        .await
-        .unwrap();
-        index.insert(path, FileAction::Add);
-    }
-}
+/// Reported by [Project::channels_used] for flakerefs that are not a registered channel
+pub const UNKNOWN_CHANNEL: &str = "unknown";
 
-#[derive(Error, Debug)]
-pub enum TransactionEnterError {
-    #[error("Failed to create tempdir for transaction")]
-    CreateTempdir(std::io::Error),
-    #[error("Failed to walk over file: {0}")]
-    Walkdir(walkdir::Error),
-    #[error("Failed to copy dir")]
-    CopyDir(std::io::Error),
-    #[error("Failed to copy file")]
-    CopyFile(IoError),
+/// Content of the flox.nix of environments created by [Project::create_default_env]
+pub fn default_env_template() -> &'static str {
+    include_str!("./flox.nix.in")
 }
-#[derive(Error, Debug)]
-pub enum TransactionCommitError<Git: GitProvider> {
-    GitCommit(Git::CommitError),
-    GitPush(Git::PushError),
+
+/// Map an input of an environment to the name of the channel in `registry` it refers to
+///
+/// Inputs are either channel names or flakerefs.
+fn channel_name(registry: &str, input: &str) -> Result<String, serde_json::Error> {
+    if flake_registry::lookup(registry, input)?.is_some() {
+        return Ok(input.to_string());
+    }
+
+    let name = match ToFlakeRef::from_str(input) {
+        Ok(flake_ref) => flake_registry::reverse_lookup(registry, &flake_ref)?,
+        Err(_) => None,
+    };
+    Ok(name.unwrap_or_else(|| UNKNOWN_CHANNEL.to_string()))
 }
 
-/// Errors occurring while trying to upgrade to an [`Open<Git>`] [Root]
-#[derive(Error, Debug)]
-pub enum OpenProjectError {
-    #[error("Could not determine repository root")]
-    WorkdirNotFound,
+/// Sort environment names ignoring case,
+/// names only differing in case keep their order
+fn sort_environment_names(names: &mut [String]) {
+    names.sort_by_cached_key(|name| name.to_lowercase());
 }
 
-#[derive(Error, Debug)]
-pub enum InitProjectError<Nix: NixBackend, Git: GitProvider>
-where
-    FlakeInit: Run<Nix>,
-{
-    #[error("Could not determine repository root")]
-    WorkdirNotFound,
+/// Select the index of the active environment from a list of environment names
+fn select_active_environment<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<usize, SelectEnvironmentError> {
+    let names: Vec<&str> = names.into_iter().collect();
 
-    #[error("Error initializing base template with Nix")]
-    NixInitBase(<FlakeInit as Run<Nix>>::Error),
-    #[error("Error reading template file contents")]
-    ReadTemplateFile(std::io::Error),
-    #[error("Error truncating template file")]
-    TruncateTemplateFile(std::io::Error),
-    #[error("Error writing to template file")]
-    WriteTemplateFile(std::io::Error),
-    #[error("Error new template file in Git")]
-    GitAdd(Git::AddError),
-}
+    if let Some(index) = names.iter().position(|name| *name == "default") {
+        return Ok(index);
+    }
 
-#[derive(Error, Debug)]
-pub enum InitFloxPackageError<Nix: NixBackend, Git: GitProvider>
-where
-    FlakeInit: Run<Nix>,
-{
-    #[error("Could not determine repository root")]
-    WorkdirNotFound,
-    #[error("Error initializing template with Nix")]
-    NixInit(<FlakeInit as Run<Nix>>::Error),
-    #[error("Error moving template file to named location using Git")]
-    MvNamed(Git::MvError),
-    #[error("Error opening template file")]
-    OpenTemplateFile(std::io::Error),
-    #[error("Error reading template file contents")]
-    ReadTemplateFile(std::io::Error),
-    #[error("Error truncating template file")]
-    TruncateTemplateFile(std::io::Error),
-    #[error("Error writing to template file")]
-    WriteTemplateFile(std::io::Error),
-    #[error("Error making named directory")]
-    MkNamedDir(std::io::Error),
-    #[error("Error opening new renamed file for writing")]
-    OpenNamed(std::io::Error),
-    #[error("Error removing old unnamed file using Git")]
-    RemoveUnnamedFile(Git::RmError),
-    #[error("Error staging new renamed file in Git")]
-    GitAdd(Git::AddError),
-    #[error("Error moving file in Git")]
-    GitMv(Git::MvError),
-    #[error("Error replacing {}: {0}", PACKAGE_NAME_PLACEHOLDER)]
-    ReplacePackageName(FindAndReplaceError),
+    match names.len() {
+        0 => Err(SelectEnvironmentError::NoEnvironment),
+        1 => Ok(0),
+        _ => {
+            let mut choices: Vec<String> = names.into_iter().map(String::from).collect();
+            choices.sort();
+            Err(SelectEnvironmentError::AmbiguousEnvironment { choices })
+        },
+    }
 }
 
-#[derive(Error, Debug)]
-pub enum CleanupInitializerError {
-    #[error("Error removing pkgs")]
-    RemovePkgs(std::io::Error),
-    #[error("Error removing flake.nix")]
-    RemoveFlake(std::io::Error),
+/// Options controlling how a transaction sandbox is created
+#[derive(Debug, Clone, Copy)]
+pub struct TransactionOptions {
+    /// Keep access and modification times of copied files,
+    /// so that timestamp based tools (e.g. make) do not consider them changed
+    pub preserve_times: bool,
+    /// Clone the original repository into the sandbox with `git clone --shared`
+    ///
+    /// Instead of starting a fresh repository,
+    /// the sandbox shares the history and objects of the original repository
+    /// through git alternates, without copying them.
+    /// [Project::commit_transaction] then commits in the sandbox
+    /// and moves the branch of the original to the fetched commit.
+    pub share_objects: bool,
+    /// Whether [Project::commit_transaction] creates a commit
+    pub commit_strategy: CommitStrategy,
+    /// Keep the sandbox of this project in [Flox::cache_dir] and reuse it for later transactions
+    ///
+    /// Entering a transaction then only copies files whose size or modification time changed
+    /// and removes files that no longer exist in the original.
+    /// File times are always preserved in reused sandboxes.
+    pub reuse_sandbox: bool,
+    /// [Stash](GitProvider::stash) uncommitted changes of the original before copying it
+    ///
+    /// The sandbox then starts from the committed state of the project.
+    /// The changes are restored when the transaction is committed
+    /// or aborted with [Project::abort_transaction],
+    /// and when the sandbox is dropped, e.g. after an error.
+    /// If they conflict with the restored worktree, they remain in `git stash list`.
+    pub auto_stash: bool,
 }
 
-#[derive(Error, Debug)]
-pub enum GetEnvironmentsError<Nix: NixBackend>
-where
-    Eval: RunJson<Nix>,
-{
-    ListEnvironments(<Eval as RunJson<Nix>>::JsonError),
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        Self {
+            preserve_times: true,
+            share_objects: false,
+            commit_strategy: CommitStrategy::default(),
+            reuse_sandbox: false,
+            auto_stash: false,
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::env;
+/// Implementations exclusively for [ReadOnly] instances
+impl<'flox, Git: GitProvider, Fs: FileSystem> Project<'flox, Git, ReadOnly<Git>, Fs> {
+    /// Copy the project into a sandbox to perform modifications in
+    ///
+    /// Uses the default [TransactionOptions].
+    pub async fn enter_transaction(
+        self,
+    ) -> Result<(Project<'flox, Git, GitSandBox<Git>, Fs>, Index), TransactionEnterError<Git>> {
+        self.enter_transaction_with(TransactionOptions::default())
+            .await
+    }
+
+    /// Copy the project into a sandbox to perform modifications in
+    ///
+    /// The sandbox is always created on disk, as it is backed by a git repository.
+    ///
+    /// The git history is not copied, `.git` directories are skipped.
+    /// Instead the sandbox is a fresh repository, or a clone sharing the objects
+    /// of the original if [TransactionOptions::share_objects] is set.
+    /// Submodules declared in `.gitmodules` are skipped as well,
+    /// as nix does not include them in flakes by default.
+    /// Transactions can not change files inside submodules.
+    /// All copied files are staged, including files untracked in the original workdir.
+    /// Nix therefore sees them in the sandbox,
+    /// while it ignores them when evaluating the original repository as a flake.
+    /// Changes are applied to the original repository by [Project::commit_transaction].
+    ///
+    /// If [Flox::event_sink] is set, copying reports [FloxEvent::CopyProgress]
+    /// after each copied entry.
+    pub async fn enter_transaction_with(
+        self,
+        options: TransactionOptions,
+    ) -> Result<(Project<'flox, Git, GitSandBox<Git>, Fs>, Index), TransactionEnterError<Git>> {
+        let stash = if options.auto_stash {
+            match self.git.git().stash().await {
+                Ok(id) => Some(id),
+                Err(e) if e.nothing_to_stash() => None,
+                Err(e) => return Err(TransactionEnterError::Stash(e)),
+            }
+        } else {
+            None
+        };
+
+        let original = self.git.read_only();
+        match self.create_sandbox(options).await {
+            Ok((mut project, index)) => {
+                project.git = project.git.with_stash(stash);
+                Ok((project, index))
+            },
+            Err(e) => {
+                if let Some(id) = stash {
+                    if let Err(unstash_error) = original.git().unstash(&id).await {
+                        warn!("Failed to restore stashed changes: {unstash_error}");
+                    }
+                }
+                Err(e)
+            },
+        }
+    }
+
+    /// Create the sandbox for [Project::enter_transaction_with]
+    async fn create_sandbox(
+        self,
+        options: TransactionOptions,
+    ) -> Result<(Project<'flox, Git, GitSandBox<Git>, Fs>, Index), TransactionEnterError<Git>> {
+        if options.reuse_sandbox {
+            return self.enter_persistent_transaction(options).await;
+        }
+
+        // in a known location, so that sandboxes of crashed processes can be found
+        let transactions_dir = self.flox.cache_dir.join(TRANSACTIONS_DIR);
+        tokio::fs::create_dir_all(&transactions_dir)
+            .await
+            .map_err(TransactionEnterError::CreateTempdir)?;
+        let transaction_temp_dir =
+            TempDir::new_in(&transactions_dir).map_err(TransactionEnterError::CreateTempdir)?;
+
+        let current_root = self
+            .require_workdir()
+            .map_err(TransactionEnterError::Workdir)?;
+
+        // clone before copying files, git only clones into empty directories
+        let shared_git = if options.share_objects {
+            Some(
+                Git::clone_shared_with(
+                    self.git.git().options(),
+                    current_root,
+                    transaction_temp_dir.path(),
+                )
+                .await
+                .map_err(TransactionEnterError::CloneGit)?,
+            )
+        } else {
+            None
+        };
+
+        let submodules = self
+            .git
+            .git()
+            .submodule_paths()
+            .await
+            .map_err(TransactionEnterError::ReadSubmodules)?;
+        let entries = fs::walk(self.fs.as_ref(), current_root, |path| {
+            path.ends_with(".git") || submodules.iter().any(|submodule| submodule == path)
+        })
+        .await
+        .map_err(TransactionEnterError::ListFiles)?;
+
+        // only report progress if anyone is interested in it
+        let mut progress = self
+            .flox
+            .event_sink
+            .as_ref()
+            .map(|sink| (sink, entries.len() as u64, 0, 0));
+
+        self.fs
+            .create_dir_all(transaction_temp_dir.path())
+            .await
+            .map_err(TransactionEnterError::CopyDir)?;
+        let copy_options = CopyOptions {
+            mode: self.flox.permissions.project_file_mode,
+            preserve_times: options.preserve_times,
+        };
+        for (path, kind) in entries {
+            let new_path = transaction_temp_dir.path().join(&path);
+            let bytes = match kind {
+                FileKind::Dir => {
+                    self.fs
+                        .create_dir_all(&new_path)
+                        .await
+                        .map_err(TransactionEnterError::CopyDir)?;
+                    0
+                },
+                FileKind::File => self
+                    .fs
+                    .copy(&current_root.join(&path), &new_path, copy_options)
+                    .await
+                    .map_err(|e| TransactionEnterError::Copy(path, e))?,
+            };
+
+            if let Some((sink, total, copied_entries, copied_bytes)) = &mut progress {
+                *copied_entries += 1;
+                *copied_bytes += bytes;
+                sink.emit(&FloxEvent::CopyProgress {
+                    copied: *copied_entries,
+                    total: *total,
+                    bytes: *copied_bytes,
+                });
+            }
+        }
+
+        let git = match shared_git {
+            Some(git) => git,
+            None => Git::init_with(self.git.git().options(), transaction_temp_dir.path(), false)
+                .await
+                .map_err(TransactionEnterError::InitGit)?,
+        };
+        git.add(&[Path::new(".")])
+            .await
+            .map_err(TransactionEnterError::StageFiles)?;
+        let base = read_flox_nix_base(
+            self.fs.as_ref(),
+            transaction_temp_dir.path(),
+            &self.subdir,
+            &self.flox.flox_nix_names,
+        )
+        .await
+        .map_err(TransactionEnterError::ReadBase)?;
+
+        let sandbox = if self.flox.keep_sandboxes {
+            self.git.to_kept_sandbox_in(transaction_temp_dir, git)
+        } else {
+            self.git.to_sandbox_in(transaction_temp_dir, git)
+        }
+        .with_commit_strategy(options.commit_strategy)
+        .with_shared_objects(options.share_objects)
+        .with_base(base);
+
+        let project = Project {
+            flox: self.flox,
+            git: sandbox,
+            fs: self.fs,
+            subdir: self.subdir,
+            flake_outputs: OnceCell::new(),
+            _marker: PhantomData,
+        };
+        let index = Index::default();
+
+        project
+            .write_transaction_state(&index)
+            .await
+            .map_err(TransactionEnterError::WriteState)?;
+
+        Ok((project, index))
+    }
+
+    /// Make `sandbox` a copy of `original`, copying only files that changed
+    ///
+    /// Files are considered unchanged if their size and modification time match.
+    /// `.git` directories, the `submodules` of `original`
+    /// and the [TRANSACTION_JSON] of the sandbox are left alone.
+    async fn sync_sandbox(
+        &self,
+        original: &Path,
+        submodules: &[PathBuf],
+        sandbox: &Path,
+    ) -> Result<(), TransactionEnterError<Git>> {
+        let fs = self.fs.as_ref();
+        let submodules: BTreeSet<&Path> = submodules.iter().map(PathBuf::as_path).collect();
+        let entries = fs::walk(fs, original, |path| {
+            path.ends_with(".git") || submodules.contains(path)
+        })
+        .await
+        .map_err(TransactionEnterError::ListFiles)?;
+
+        let mut progress = self
+            .flox
+            .event_sink
+            .as_ref()
+            .map(|sink| (sink, entries.len() as u64, 0, 0));
+
+        let copy_options = CopyOptions {
+            mode: self.flox.permissions.project_file_mode,
+            preserve_times: true,
+        };
+        for (path, kind) in entries {
+            let copy = sandbox.join(&path);
+            let existing = fs.kind(&copy).await.map_err(TransactionEnterError::Sync)?;
+
+            let bytes = match (kind, existing) {
+                (FileKind::Dir, Some(FileKind::Dir)) => 0,
+                (FileKind::Dir, existing) => {
+                    if existing.is_some() {
+                        fs.remove(&copy)
+                            .await
+                            .map_err(TransactionEnterError::Sync)?;
+                    }
+                    fs.create_dir_all(&copy)
+                        .await
+                        .map_err(TransactionEnterError::CopyDir)?;
+                    0
+                },
+                (FileKind::File, Some(FileKind::File))
+                    if unchanged(fs, &original.join(&path), &copy)
+                        .await
+                        .map_err(TransactionEnterError::Sync)? =>
+                {
+                    0
+                },
+                (FileKind::File, existing) => {
+                    if existing == Some(FileKind::Dir) {
+                        fs.remove(&copy)
+                            .await
+                            .map_err(TransactionEnterError::Sync)?;
+                    }
+                    fs.copy(&original.join(&path), &copy, copy_options)
+                        .await
+                        .map_err(|e| TransactionEnterError::Copy(path, e))?
+                },
+            };
+
+            if let Some((sink, total, copied_entries, copied_bytes)) = &mut progress {
+                *copied_entries += 1;
+                *copied_bytes += bytes;
+                sink.emit(&FloxEvent::CopyProgress {
+                    copied: *copied_entries,
+                    total: *total,
+                    bytes: *copied_bytes,
+                });
+            }
+        }
+
+        // remove what was deleted in the original since the last transaction
+        let copies = fs::walk(fs, sandbox, |path| path.ends_with(".git"))
+            .await
+            .map_err(TransactionEnterError::Sync)?;
+        for (path, _) in copies {
+            if path == Path::new(TRANSACTION_JSON) {
+                continue;
+            }
+            // submodules may have been copied before they were skipped
+            let stale = submodules.contains(path.as_path())
+                || fs
+                    .kind(&original.join(&path))
+                    .await
+                    .map_err(TransactionEnterError::Sync)?
+                    .is_none();
+            if !stale {
+                continue;
+            }
+            match fs.remove(&sandbox.join(&path)).await {
+                // contained in a directory removed before
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                removed => removed.map_err(TransactionEnterError::Sync)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Enter a transaction in the sandbox kept for this project,
+    /// see [TransactionOptions::reuse_sandbox]
+    ///
+    /// Waits for other transactions using the same sandbox to finish.
+    async fn enter_persistent_transaction(
+        self,
+        options: TransactionOptions,
+    ) -> Result<(Project<'flox, Git, GitSandBox<Git>, Fs>, Index), TransactionEnterError<Git>> {
+        let current_root = self
+            .require_workdir()
+            .map_err(TransactionEnterError::Workdir)?;
+
+        let (sandbox_dir, lock_path) = persistent_sandbox(&self.flox.cache_dir, current_root);
+        tokio::fs::create_dir_all(self.flox.cache_dir.join(SANDBOX_CACHE_DIR))
+            .await
+            .map_err(TransactionEnterError::CreateTempdir)?;
+
+        let mut lock = LockFile::open(&lock_path).map_err(TransactionEnterError::Lock)?;
+        let lock = tokio::task::spawn_blocking(move || lock.lock().map(|_| lock))
+            .await
+            .expect("lock task panicked")
+            .map_err(TransactionEnterError::Lock)?;
+
+        // git works on the sandbox on disk, whatever the filesystem of the project
+        let reused = sandbox_dir.join(".git").exists();
+        // a reused sandbox keeps sharing objects if it was cloned to do so
+        let shares_objects = if reused {
+            sandbox_dir.join(".git/objects/info/alternates").exists()
+        } else {
+            options.share_objects
+        };
+        if !reused {
+            // leftovers of a sandbox whose creation was interrupted
+            match tokio::fs::remove_dir_all(&sandbox_dir).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(TransactionEnterError::Sync(e))
+                },
+                _ => {},
+            }
+        }
+
+        let git = if reused {
+            Some(
+                Git::discover_with(self.git.git().options(), &sandbox_dir)
+                    .await
+                    .map_err(TransactionEnterError::DiscoverSandbox)?,
+            )
+        } else if options.share_objects {
+            tokio::fs::create_dir(&sandbox_dir)
+                .await
+                .map_err(TransactionEnterError::CreateTempdir)?;
+            Some(
+                Git::clone_shared_with(self.git.git().options(), current_root, &sandbox_dir)
+                    .await
+                    .map_err(TransactionEnterError::CloneGit)?,
+            )
+        } else {
+            tokio::fs::create_dir(&sandbox_dir)
+                .await
+                .map_err(TransactionEnterError::CreateTempdir)?;
+            None
+        };
+
+        let submodules = self
+            .git
+            .git()
+            .submodule_paths()
+            .await
+            .map_err(TransactionEnterError::ReadSubmodules)?;
+        self.fs
+            .create_dir_all(&sandbox_dir)
+            .await
+            .map_err(TransactionEnterError::CopyDir)?;
+        self.sync_sandbox(current_root, &submodules, &sandbox_dir)
+            .await?;
+
+        let git = match git {
+            Some(git) => git,
+            None => Git::init_with(self.git.git().options(), &sandbox_dir, false)
+                .await
+                .map_err(TransactionEnterError::InitGit)?,
+        };
+        git.add(&[Path::new(".")])
+            .await
+            .map_err(TransactionEnterError::StageFiles)?;
+        let base = read_flox_nix_base(
+            self.fs.as_ref(),
+            &sandbox_dir,
+            &self.subdir,
+            &self.flox.flox_nix_names,
+        )
+        .await
+        .map_err(TransactionEnterError::ReadBase)?;
+
+        let sandbox = self
+            .git
+            .to_persistent_sandbox(lock, git)
+            .with_commit_strategy(options.commit_strategy)
+            .with_shared_objects(shares_objects)
+            .with_base(base);
+
+        let project = Project {
+            flox: self.flox,
+            git: sandbox,
+            fs: self.fs,
+            subdir: self.subdir,
+            flake_outputs: OnceCell::new(),
+            _marker: PhantomData,
+        };
+        let index = Index::default();
+
+        project
+            .write_transaction_state(&index)
+            .await
+            .map_err(TransactionEnterError::WriteState)?;
+
+        Ok((project, index))
+    }
+
+    /// Commit the changes staged by transactions using [CommitStrategy::Squashed]
+    pub async fn commit_staged(&self, message: &str) -> Result<(), TransactionCommitError<Git>> {
+        self.git
+            .git()
+            .commit(message)
+            .await
+            .map_err(TransactionCommitError::GitCommit)
+    }
+
+    /// Create environment `to` as a copy of environment `from`
+    ///
+    /// The files of `from` are duplicated in a transaction
+    /// with any `pname` declared in them set to `to`, and committed.
+    /// Of the `default` environment only the flox.nix and lock file are copied,
+    /// as its directory is the project root.
+    pub async fn copy_environment<Nix: FloxNixApi>(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Environment<'flox, Git, ReadOnly<Git>, Fs>, CopyEnvironmentError<Nix, Git>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let project = self.duplicate_environment::<Nix>(from, to, false).await?;
+
+        Ok(Environment {
+            name: to.to_string(),
+            system: self.flox.system.clone(),
+            project,
+            compat: false,
+            store_path: None,
+            eval_options: EvalOptions::default(),
+        })
+    }
+
+    /// Rename environment `from` to `to`
+    ///
+    /// The files of `from` are moved in a transaction
+    /// with any `pname` declared in them set to `to`, and committed.
+    /// Generation tags `flox/<from>/<tag>` are moved to `flox/<to>/<tag>`.
+    /// Of the `default` environment only the flox.nix and lock file are moved,
+    /// as its directory is the project root.
+    pub async fn rename_environment<Nix: FloxNixApi>(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Environment<'flox, Git, ReadOnly<Git>, Fs>, RenameEnvironmentError<Nix, Git>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let project = self.duplicate_environment::<Nix>(from, to, true).await?;
+
+        let git = project.git.git();
+        let tags = git
+            .list_tags()
+            .await
+            .map_err(RenameEnvironmentError::MoveTag)?;
+        for tag in tags {
+            let name = match tag.name.strip_prefix(&generation_tag_prefix(from)) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let message = (!tag.message.is_empty()).then_some(tag.message.as_str());
+            git.create_tag(
+                &format!("{}{name}", generation_tag_prefix(to)),
+                &tag.rev,
+                message,
+            )
+            .await
+            .map_err(RenameEnvironmentError::MoveTag)?;
+            git.delete_tag(&tag.name)
+                .await
+                .map_err(RenameEnvironmentError::MoveTag)?;
+        }
+
+        Ok(Environment {
+            name: to.to_string(),
+            system: self.flox.system.clone(),
+            project,
+            compat: false,
+            store_path: None,
+            eval_options: EvalOptions::default(),
+        })
+    }
+
+    /// Write the files of environment `from` as environment `to` in a transaction
+    ///
+    /// Any `pname` declared in them is set to `to`.
+    /// With `remove_source` the files of `from` are removed in the same transaction.
+    async fn duplicate_environment<Nix: FloxNixApi>(
+        &self,
+        from: &str,
+        to: &str,
+        remove_source: bool,
+    ) -> Result<Project<'flox, Git, ReadOnly<Git>, Fs>, CopyEnvironmentError<Nix, Git>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let source = self
+            .environment::<Nix>(from)
+            .await
+            .map_err(CopyEnvironmentError::Source)?;
+        let target = Environment {
+            name: to.to_string(),
+            system: self.flox.system.clone(),
+            project: Project::new(
+                self.flox,
+                self.git.read_only(),
+                self.fs.clone(),
+                self.subdir.clone(),
+            ),
+            compat: false,
+            store_path: None,
+            eval_options: EvalOptions::default(),
+        };
+
+        let root = self.require_workdir()?;
+        let source_dir = source.dir().ok_or(ProjectError::WorkdirNotFound)?;
+        let target_dir = target.dir().ok_or(ProjectError::WorkdirNotFound)?;
+        let target_flox_nix = target
+            .flox_nix_path()
+            .await
+            .ok_or(ProjectError::WorkdirNotFound)?;
+        for existing in [&target_dir, &target_flox_nix] {
+            // the project root always exists
+            if existing == root {
+                continue;
+            }
+            match self.fs.kind(existing).await {
+                Ok(None) => {},
+                Ok(Some(_)) => return Err(CopyEnvironmentError::AlreadyExists(to.to_string())),
+                Err(e) => return Err(CopyEnvironmentError::Read(existing.clone(), e)),
+            }
+        }
+
+        let source_flox_nix = source
+            .flox_nix_path()
+            .await
+            .ok_or(ProjectError::WorkdirNotFound)?;
+        let files = self
+            .environment_files(from, &source_dir, &source_flox_nix)
+            .await
+            .map_err(CopyEnvironmentError::Walkdir)?;
+
+        let source_dir = source_dir.strip_prefix(root).unwrap().to_path_buf();
+        let target_dir = target_dir.strip_prefix(root).unwrap().to_path_buf();
+
+        let (sandbox, mut index) = Project::new(
+            self.flox,
+            self.git.read_only(),
+            self.fs.clone(),
+            self.subdir.clone(),
+        )
+        .enter_transaction()
+        .await
+        .map_err(CopyEnvironmentError::EnterTransaction)?;
+        let sandbox_root = sandbox.require_workdir()?.to_path_buf();
+
+        for file in &files {
+            let source_path = sandbox_root.join(&source_dir).join(file);
+            let target_path = sandbox_root.join(&target_dir).join(file);
+
+            let mut contents = sandbox
+                .fs
+                .read(&source_path)
+                .await
+                .map_err(|e| CopyEnvironmentError::Read(source_path.clone(), e))?;
+            if file.extension() == Some(OsStr::new("nix")) {
+                let pname = format!(r#"pname = "{to}""#);
+                contents = PNAME_DECLARATION
+                    .replace_all(&String::from_utf8_lossy(&contents), pname)
+                    .into_owned()
+                    .into_bytes();
+            }
+
+            if remove_source {
+                sandbox
+                    .fs
+                    .remove(&source_path)
+                    .await
+                    .map_err(|e| CopyEnvironmentError::Remove(source_path.clone(), e))?;
+            }
+            sandbox
+                .fs
+                .create_dir_all(target_path.parent().unwrap())
+                .await
+                .map_err(|e| CopyEnvironmentError::Write(target_path.clone(), e))?;
+            sandbox
+                .fs
+                .write(&target_path, &contents)
+                .await
+                .map_err(|e| CopyEnvironmentError::Write(target_path.clone(), e))?;
+            index.insert(target_dir.join(file), FileAction::Add);
+        }
+
+        // the directory of any other environment goes as a whole
+        if remove_source && from == "default" {
+            for file in &files {
+                index.insert(source_dir.join(file), FileAction::Delete);
+            }
+        } else if remove_source {
+            let sandbox_source_dir = sandbox_root.join(&source_dir);
+            if let Ok(Some(_)) = sandbox.fs.kind(&sandbox_source_dir).await {
+                sandbox
+                    .fs
+                    .remove(&sandbox_source_dir)
+                    .await
+                    .map_err(|e| CopyEnvironmentError::Remove(sandbox_source_dir, e))?;
+            }
+            index.insert(source_dir, FileAction::Delete);
+        }
+
+        sandbox
+            .write_transaction_state(&index)
+            .await
+            .map_err(CopyEnvironmentError::WriteState)?;
+
+        let message = if remove_source {
+            format!("Rename environment {from} to {to}")
+        } else {
+            format!("Copy environment {from} to {to}")
+        };
+        let project = sandbox
+            .commit_transaction(index, &message, false)
+            .await
+            .map_err(CopyEnvironmentError::CommitTransaction)?
+            .committed()
+            .expect("not a dry run");
+        Ok(project)
+    }
+
+    /// Paths of the files of environment `name`, relative to its directory `dir`
+    ///
+    /// Of the `default` environment only `flox_nix` and the lock file belong to it,
+    /// as its directory is the project root.
+    async fn environment_files(
+        &self,
+        name: &str,
+        dir: &Path,
+        flox_nix: &Path,
+    ) -> Result<Vec<PathBuf>, walkdir::Error> {
+        if name == "default" {
+            let mut files = vec![PathBuf::from(flox_nix.file_name().unwrap())];
+            if let Ok(Some(_)) = self.fs.kind(&dir.join(lock::FLOX_LOCK)).await {
+                files.push(PathBuf::from(lock::FLOX_LOCK));
+            }
+            return Ok(files);
+        }
+
+        let mut files = Vec::new();
+        for entry in WalkDir::new(dir) {
+            let entry = entry?;
+            if !entry.file_type().is_dir() {
+                let relative = entry.path().strip_prefix(dir).unwrap();
+                files.push(relative.to_path_buf());
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Prefix of the tags naming generations of environment `name`
+///
+/// Follows the naming of generation tags in floxmeta.
+fn generation_tag_prefix(name: &str) -> String {
+    format!("flox/{name}/")
+}
+
+/// Whether `copy` is unchanged from `original`, going by their size and modification time
+///
+/// Files of a filesystem that keeps no modification times are always considered changed.
+async fn unchanged<Fs: FileSystem + ?Sized>(
+    fs: &Fs,
+    original: &Path,
+    copy: &Path,
+) -> std::io::Result<bool> {
+    if fs.size(original).await? != fs.size(copy).await? {
+        return Ok(false);
+    }
+    match (fs.modified(original).await?, fs.modified(copy).await?) {
+        (Some(original), Some(copy)) => Ok(original == copy),
+        _ => Ok(false),
+    }
+}
+
+/// Directory and lock file of the sandbox kept for the project at `workdir`,
+/// see [TransactionOptions::reuse_sandbox]
+fn persistent_sandbox(cache_dir: &Path, workdir: &Path) -> (PathBuf, PathBuf) {
+    let sandboxes = cache_dir.join(SANDBOX_CACHE_DIR);
+    let key = environment::content_hash(&[workdir.as_os_str().as_bytes()]);
+    let lock = sandboxes.join(format!("{key}.lock"));
+    (sandboxes.join(key), lock)
+}
+
+/// Contents of the environment definitions of the project at `subdir` of `root`,
+/// by their path relative to `root`
+///
+/// These are the base [Project::commit_transaction] merges concurrent changes against.
+async fn read_flox_nix_base<Fs: FileSystem + ?Sized>(
+    fs: &Fs,
+    root: &Path,
+    subdir: &Path,
+    names: &FloxNixNames,
+) -> std::io::Result<BTreeMap<PathBuf, String>> {
+    let mut dirs = vec![subdir.to_path_buf()];
+    let pkgs = subdir.join("pkgs");
+    match fs.read_dir(&root.join(&pkgs)).await {
+        Ok(entries) => {
+            for name in entries {
+                if fs.kind(&root.join(&pkgs).join(&name)).await? == Some(FileKind::Dir) {
+                    dirs.push(pkgs.join(name));
+                }
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+
+    let mut base = BTreeMap::new();
+    for dir in dirs {
+        for name in names.iter() {
+            let path = dir.join(name);
+            match fs.read(&root.join(&path)).await {
+                Ok(contents) => {
+                    base.insert(path, String::from_utf8_lossy(&contents).into_owned());
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(base)
+}
+
+pub type Index = BTreeMap<PathBuf, FileAction>;
+
+/// A single step of committing a transaction, see [Project::commit_transaction]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommitOperation {
+    /// Move a file from the sandbox into the original project and stage it
+    Add {
+        path: PathBuf,
+        /// whether an existing file is overwritten
+        replaces: bool,
+    },
+    /// Remove a file or directory from the original project
+    Delete { path: PathBuf, dir: bool },
+}
+
+impl CommitOperation {
+    /// Path of the file or directory, relative to the project root
+    pub fn path(&self) -> &Path {
+        match self {
+            CommitOperation::Add { path, .. } | CommitOperation::Delete { path, .. } => path,
+        }
+    }
+}
+
+/// Result of [Project::commit_transaction]
+#[derive(Debug)]
+pub enum TransactionOutcome<Committed, Sandbox> {
+    /// The changes were applied to the original project
+    Committed(Committed),
+    /// Nothing was changed, the transaction can be continued
+    DryRun {
+        sandbox: Sandbox,
+        index: Index,
+        operations: Vec<CommitOperation>,
+    },
+    /// Validation of the sandbox failed, nothing was changed
+    ///
+    /// See [Project::commit_transaction_validated]
+    Rejected {
+        sandbox: Sandbox,
+        index: Index,
+        report: CheckReport,
+    },
+}
+
+impl<Committed, Sandbox> TransactionOutcome<Committed, Sandbox> {
+    /// The committed result, [None] if nothing was committed
+    pub fn committed(self) -> Option<Committed> {
+        match self {
+            TransactionOutcome::Committed(committed) => Some(committed),
+            TransactionOutcome::DryRun { .. } | TransactionOutcome::Rejected { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileAction {
+    Add,
+    Delete,
+}
+
+/// Persisted state of a transaction
+///
+/// Written to [TRANSACTION_JSON] in the sandbox,
+/// so that a transaction can be recovered if the process dies before committing.
+#[derive(Debug, Serialize, Deserialize)]
+struct TransactionState {
+    /// workdir of the project the sandbox was created from
+    original: PathBuf,
+    subdir: PathBuf,
+    index: Index,
+    #[serde(default)]
+    commit_strategy: CommitStrategy,
+    /// see [GitSandBox::base]
+    #[serde(default)]
+    base: BTreeMap<PathBuf, String>,
+}
+
+/// Sandboxes of transactions that were neither committed nor aborted,
+/// to be re-opened with [Project::recover_transaction]
+///
+/// Lists the sandboxes in [Flox::cache_dir] with a [TRANSACTION_JSON],
+/// which includes the transactions of processes that are still running
+/// and sandboxes kept by [Flox::keep_sandboxes].
+pub async fn pending_transactions(flox: &Flox) -> Result<Vec<PathBuf>, std::io::Error> {
+    let transactions_dir = flox.cache_dir.join(TRANSACTIONS_DIR);
+    let mut entries = match tokio::fs::read_dir(&transactions_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut pending = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.path().join(TRANSACTION_JSON).exists() {
+            pending.push(entry.path());
+        }
+    }
+    pending.sort();
+    Ok(pending)
+}
+
+impl<'flox, Git: GitProvider> Project<'flox, Git, GitSandBox<Git>> {
+    /// Re-open a transaction sandbox left behind by an interrupted process
+    ///
+    /// Returns the sandboxed project and the pending index
+    /// that can then be committed or discarded.
+    pub async fn recover_transaction(
+        flox: &'flox Flox,
+        sandbox_path: &Path,
+    ) -> Result<(Self, Index), RecoverTransactionError<Git>> {
+        let state = tokio::fs::read(sandbox_path.join(TRANSACTION_JSON))
+            .await
+            .map_err(RecoverTransactionError::ReadState)?;
+        let state: TransactionState =
+            serde_json::from_slice(&state).map_err(RecoverTransactionError::ParseState)?;
+
+        let original = Git::discover(&state.original)
+            .await
+            .map_err(RecoverTransactionError::DiscoverOriginal)?;
+        let sandboxed = Git::discover_with(original.options(), sandbox_path)
+            .await
+            .map_err(RecoverTransactionError::DiscoverSandbox)?;
+
+        let sandbox = ReadOnly::new(original)
+            .recover_sandbox_in(sandbox_path.to_path_buf(), sandboxed)
+            .with_commit_strategy(state.commit_strategy)
+            .with_base(state.base);
+
+        Ok((
+            Project::new(flox, sandbox, Rc::new(TokioFs), state.subdir),
+            state.index,
+        ))
+    }
+}
+
+/// Implementations exclusively for [GitSandBox]ed instances
+impl<'flox, Git: GitProvider, Fs: FileSystem> Project<'flox, Git, GitSandBox<Git>, Fs> {
+    /// Discard the sandbox and restore changes stashed by [TransactionOptions::auto_stash]
+    pub async fn abort_transaction(
+        self,
+    ) -> Result<Project<'flox, Git, ReadOnly<Git>, Fs>, Git::StashError> {
+        let mut sandbox = self.git;
+        let stash = sandbox.take_stash();
+        let original = sandbox.abort();
+        if let Some(id) = stash {
+            original.git().unstash(&id).await?;
+        }
+
+        Ok(Project {
+            flox: self.flox,
+            git: original,
+            fs: self.fs,
+            subdir: self.subdir,
+            flake_outputs: OnceCell::new(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Apply the changes recorded in `index` to the original project
+    ///
+    /// The changes are committed with `message`,
+    /// or only staged if the transaction uses [CommitStrategy::Squashed].
+    /// Changes stashed by [TransactionOptions::auto_stash] are restored afterwards.
+    /// All operations are planned and checked for conflicts before any file is moved.
+    /// Environment definitions changed in the original project since entering the transaction
+    /// are merged with the changes of the transaction, see [flox_nix::merge_packages].
+    /// If the sandbox [shares the objects](TransactionOptions::share_objects) of the original,
+    /// the commit is created in the sandbox and the branch of the original is moved to it,
+    /// see [Self::transfer_commit].
+    /// With `dry_run` set, the planned operations are returned together with
+    /// the untouched sandbox and index, so the transaction can still be continued
+    /// or committed for real.
+    pub async fn commit_transaction(
+        self,
+        index: Index,
+        message: &str,
+        dry_run: bool,
+    ) -> Result<
+        TransactionOutcome<Project<'flox, Git, ReadOnly<Git>, Fs>, Self>,
+        TransactionCommitError<Git>,
+    > {
+        let operations = self.plan_commit(&index).await?;
+        let merged = self.merge_concurrent_edits(&operations).await?;
+
+        if dry_run {
+            return Ok(TransactionOutcome::DryRun {
+                sandbox: self,
+                index,
+                operations,
+            });
+        }
+
+        let original = self.git.read_only();
+        let original_workdir = original
+            .git()
+            .workdir()
+            .ok_or(ProjectError::WorkdirNotFound)?;
+        let sandbox_workdir = self
+            .git
+            .git()
+            .workdir()
+            .ok_or(ProjectError::WorkdirNotFound)?;
+        let commit =
+            !operations.is_empty() && self.git.commit_strategy() == CommitStrategy::PerOperation;
+
+        let mut written = BTreeMap::new();
+        for operation in &operations {
+            let fingerprint = match operation {
+                CommitOperation::Add { path, .. } => self
+                    .fs
+                    .read(&sandbox_workdir.join(path))
+                    .await
+                    .ok()
+                    .map(|contents| environment::content_hash(&[&contents])),
+                CommitOperation::Delete { .. } => None,
+            };
+            written.insert(original_workdir.join(operation.path()), fingerprint);
+        }
+        if !written.is_empty() {
+            let marked = watch::mark_commit(&self.flox.cache_dir, original_workdir, written).await;
+            if let Err(e) = marked {
+                warn!("Failed to mark the commit for watches: {e}");
+            }
+        }
+
+        for (path, contents) in merged {
+            self.fs
+                .write(&sandbox_workdir.join(&path), contents.as_bytes())
+                .await
+                .map_err(|e| TransactionCommitError::WriteMerged(path, e))?;
+        }
+
+        let transferred = if commit && self.git.shares_objects() {
+            self.transfer_commit(&operations, message).await?
+        } else {
+            None
+        };
+
+        for operation in &operations {
+            match operation {
+                CommitOperation::Add { path, .. } => {
+                    if let Some(parent) = path.parent() {
+                        self.fs
+                            .create_dir_all(&original_workdir.join(parent))
+                            .await
+                            .map_err(|e| TransactionCommitError::MoveFile(path.clone(), e))?;
+                    }
+                    self.fs
+                        .rename(&sandbox_workdir.join(path), &original_workdir.join(path))
+                        .await
+                        .map_err(|e| TransactionCommitError::MoveFile(path.clone(), e))?;
+
+                    if transferred.is_none() {
+                        original
+                            .git()
+                            .add(&[path])
+                            .await
+                            .map_err(TransactionCommitError::GitAdd)?;
+                    }
+                },
+                CommitOperation::Delete { path, .. } if transferred.is_some() => {
+                    self.fs
+                        .remove(&original_workdir.join(path))
+                        .await
+                        .map_err(|e| TransactionCommitError::MoveFile(path.clone(), e))?;
+                },
+                CommitOperation::Delete { path, dir } => {
+                    original
+                        .git()
+                        .rm(&[path], *dir, false, false)
+                        .await
+                        .map_err(TransactionCommitError::GitRm)?;
+                },
+            }
+        }
+
+        if let Some(rev) = &transferred {
+            // the index of the original still has the entries of the previous commit
+            let paths: Vec<&Path> = operations.iter().map(CommitOperation::path).collect();
+            original
+                .git()
+                .reset_paths(rev, &paths)
+                .await
+                .map_err(TransactionCommitError::GitReset)?;
+        } else if commit {
+            original
+                .git()
+                .commit(message)
+                .await
+                .map_err(TransactionCommitError::GitCommit)?;
+        }
+
+        // the transaction is committed already, failing to restore the stash is only a caveat
+        let mut sandbox = self.git;
+        if let Some(id) = sandbox.take_stash() {
+            if let Err(e) = original.git().unstash(&id).await {
+                self.flox.report_warning(FloxWarning::StashNotRestored {
+                    stash: id.0,
+                    error: e.to_string(),
+                });
+            }
+        }
+
+        Ok(TransactionOutcome::Committed(Project {
+            flox: self.flox,
+            git: original,
+            fs: self.fs,
+            subdir: self.subdir,
+            flake_outputs: OnceCell::new(),
+            _marker: PhantomData,
+        }))
+    }
+
+    /// Commit `operations` in the sandbox on top of `HEAD` of the original
+    /// and move the branch of the original to the commit
+    ///
+    /// The sandbox shares the objects of the original,
+    /// so only the objects of the new commit are fetched.
+    /// Fails if a commit was made in the original concurrently.
+    /// Returns [None] without committing if the original has no commit to build on yet.
+    async fn transfer_commit(
+        &self,
+        operations: &[CommitOperation],
+        message: &str,
+    ) -> Result<Option<String>, TransactionCommitError<Git>> {
+        let original = self.git.read_only();
+        let head = match original.git().head_rev().await {
+            Ok(head) => head,
+            Err(e) => {
+                debug!("Not transferring the commit, the project has no HEAD: {e}");
+                return Ok(None);
+            },
+        };
+
+        // only the changes of the transaction are committed,
+        // not the uncommitted changes of the original copied into the sandbox
+        let sandbox = self.git.git();
+        sandbox
+            .reset(&head)
+            .await
+            .map_err(TransactionCommitError::GitReset)?;
+
+        let mut added = Vec::new();
+        let mut deleted = Vec::new();
+        for operation in operations {
+            match operation {
+                CommitOperation::Add { path, .. } => added.push(path.as_path()),
+                CommitOperation::Delete { path, .. } => deleted.push(path.as_path()),
+            }
+        }
+        sandbox
+            .add(&added)
+            .await
+            .map_err(TransactionCommitError::GitAdd)?;
+        if !deleted.is_empty() {
+            let tracked = sandbox
+                .ls_files(&deleted)
+                .await
+                .map_err(TransactionCommitError::ListFiles)?;
+            let tracked: Vec<&Path> = tracked.iter().map(PathBuf::as_path).collect();
+            if !tracked.is_empty() {
+                sandbox
+                    .rm(&tracked, false, true, true)
+                    .await
+                    .map_err(TransactionCommitError::GitRm)?;
+            }
+        }
+        sandbox
+            .commit(message)
+            .await
+            .map_err(TransactionCommitError::GitCommit)?;
+
+        let sandbox_dir = sandbox.workdir().unwrap_or_else(|| sandbox.path());
+        let rev = original
+            .git()
+            .fetch_head(sandbox_dir)
+            .await
+            .map_err(TransactionCommitError::Fetch)?;
+        original
+            .git()
+            .update_head(&rev, &head, message)
+            .await
+            .map_err(TransactionCommitError::UpdateHead)?;
+
+        Ok(Some(rev))
+    }
+
+    /// [Validate](Project::validate) the sandbox before committing the transaction
+    ///
+    /// Files added in the transaction are staged in the sandbox first, so that nix sees them.
+    /// If any output fails, nothing is committed
+    /// and the sandbox is handed back as [TransactionOutcome::Rejected].
+    pub async fn commit_transaction_validated<Nix: FloxNixApi>(
+        self,
+        index: Index,
+        message: &str,
+        dry_run: bool,
+    ) -> Result<
+        TransactionOutcome<Project<'flox, Git, ReadOnly<Git>, Fs>, Self>,
+        ValidatedCommitError<Git>,
+    >
+    where
+        Eval: RunJson<Nix>,
+    {
+        let added: Vec<&Path> = index
+            .iter()
+            .filter(|(_, action)| **action == FileAction::Add)
+            .map(|(path, _)| path.as_path())
+            .collect();
+        if !added.is_empty() {
+            self.git
+                .git()
+                .add(&added)
+                .await
+                .map_err(ValidatedCommitError::Stage)?;
+        }
+
+        let report = self
+            .validate::<Nix>()
+            .await
+            .map_err(ValidatedCommitError::Validate)?;
+        if !report.passed() {
+            return Ok(TransactionOutcome::Rejected {
+                sandbox: self,
+                index,
+                report,
+            });
+        }
+
+        self.commit_transaction(index, message, dry_run)
+            .await
+            .map_err(ValidatedCommitError::Commit)
+    }
+
+    /// Turn `index` into the operations [Project::commit_transaction] performs
+    ///
+    /// Fails if a file to add is missing from the sandbox,
+    /// if its destination or one of its parents is occupied by a different kind of entry,
+    /// or if a path to delete does not exist in the original project.
+    async fn plan_commit(
+        &self,
+        index: &Index,
+    ) -> Result<Vec<CommitOperation>, TransactionCommitError<Git>> {
+        let original = self.git.read_only();
+        let original_workdir = original
+            .git()
+            .workdir()
+            .ok_or(ProjectError::WorkdirNotFound)?;
+        let sandbox_workdir = self
+            .git
+            .git()
+            .workdir()
+            .ok_or(ProjectError::WorkdirNotFound)?;
+
+        let submodules = original
+            .git()
+            .submodule_paths()
+            .await
+            .map_err(TransactionCommitError::ReadSubmodules)?;
+
+        let mut operations = Vec::with_capacity(index.len());
+        for (file, action) in index {
+            if submodules
+                .iter()
+                .any(|submodule| file.starts_with(submodule))
+            {
+                return Err(TransactionCommitError::Submodule(file.clone()));
+            }
+
+            let operation = match action {
+                FileAction::Add => {
+                    let source = self
+                        .entry_kind(sandbox_workdir.join(file))
+                        .await?
+                        .ok_or_else(|| TransactionCommitError::MissingSource(file.clone()))?;
+
+                    for parent in file.ancestors().skip(1) {
+                        if parent.as_os_str().is_empty() {
+                            continue;
+                        }
+                        if self.entry_kind(original_workdir.join(parent)).await?
+                            == Some(FileKind::File)
+                        {
+                            return Err(TransactionCommitError::Conflict(file.clone()));
+                        }
+                    }
+
+                    let destination = self.entry_kind(original_workdir.join(file)).await?;
+                    if destination.is_some() && destination != Some(source) {
+                        return Err(TransactionCommitError::Conflict(file.clone()));
+                    }
+
+                    CommitOperation::Add {
+                        path: file.clone(),
+                        replaces: destination.is_some(),
+                    }
+                },
+                FileAction::Delete => {
+                    let target = self
+                        .entry_kind(original_workdir.join(file))
+                        .await?
+                        .ok_or_else(|| TransactionCommitError::MissingTarget(file.clone()))?;
+
+                    CommitOperation::Delete {
+                        path: file.clone(),
+                        dir: target == FileKind::Dir,
+                    }
+                },
+            };
+            operations.push(operation);
+        }
+
+        Ok(operations)
+    }
+
+    /// Merge changes to environment definitions made in the original project
+    /// since entering the transaction with the ones made in the sandbox
+    ///
+    /// Returns the merged contents of the files to add that were changed on both sides.
+    async fn merge_concurrent_edits(
+        &self,
+        operations: &[CommitOperation],
+    ) -> Result<Vec<(PathBuf, String)>, TransactionCommitError<Git>> {
+        let original = self.git.read_only();
+        let original_workdir = original
+            .git()
+            .workdir()
+            .ok_or(ProjectError::WorkdirNotFound)?;
+        let sandbox_workdir = self
+            .git
+            .git()
+            .workdir()
+            .ok_or(ProjectError::WorkdirNotFound)?;
+
+        let mut merged = Vec::new();
+        for operation in operations {
+            let path = match operation {
+                CommitOperation::Add {
+                    path,
+                    replaces: true,
+                } => path,
+                _ => continue,
+            };
+            let base = match self.git.base().get(path) {
+                Some(base) => base,
+                None => continue,
+            };
+
+            let theirs = self.read_text(original_workdir.join(path)).await?;
+            if theirs == *base {
+                continue;
+            }
+            let ours = self.read_text(sandbox_workdir.join(path)).await?;
+            let contents = if ours == *base {
+                theirs
+            } else {
+                flox_nix::merge_packages(base, &ours, &theirs)
+                    .map_err(|e| TransactionCommitError::ConcurrentEdit(path.clone(), e))?
+            };
+            merged.push((path.clone(), contents));
+        }
+
+        Ok(merged)
+    }
+
+    async fn read_text(&self, path: PathBuf) -> Result<String, TransactionCommitError<Git>> {
+        match self.fs.read(&path).await {
+            Ok(contents) => Ok(String::from_utf8_lossy(&contents).into_owned()),
+            Err(e) => Err(TransactionCommitError::Read(path, e)),
+        }
+    }
+
+    async fn entry_kind(
+        &self,
+        path: PathBuf,
+    ) -> Result<Option<FileKind>, TransactionCommitError<Git>> {
+        let kind = self.fs.kind(&path).await;
+        kind.map_err(|e| TransactionCommitError::Inspect(path, e))
+    }
+
+    /// create a new root
+    ///
+    /// Uses [default_env_template] as the flox.nix.
+    pub async fn create_default_env(&self, index: &mut Index) -> Result<(), CreateDefaultEnvError> {
+        self.create_default_env_with(default_env_template(), index)
+            .await
+    }
+
+    /// Like [Self::create_default_env], with `template` as the content of the flox.nix
+    pub async fn create_default_env_with(
+        &self,
+        template: &str,
+        index: &mut Index,
+    ) -> Result<(), CreateDefaultEnvError> {
+        let path = PathBuf::from(self.flox.flox_nix_names.primary());
+        let full_path = self.require_workdir()?.join(&path);
+        self.fs
+            .write(&full_path, template.as_bytes())
+            .await
+            .map_err(|e| CreateDefaultEnvError::Write(full_path, e))?;
+        index.insert(path, FileAction::Add);
+        self.write_transaction_state(index)
+            .await
+            .map_err(CreateDefaultEnvError::WriteState)
+    }
+
+    /// Persist the pending index to [TRANSACTION_JSON] in the sandbox
+    async fn write_transaction_state(&self, index: &Index) -> Result<(), std::io::Error> {
+        let no_workdir = || {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "transactions require projects with a workdir",
+            )
+        };
+        let state = TransactionState {
+            original: self
+                .git
+                .read_only()
+                .git()
+                .workdir()
+                .ok_or_else(no_workdir)?
+                .to_path_buf(),
+            subdir: self.subdir.clone(),
+            index: index.clone(),
+            commit_strategy: self.git.commit_strategy(),
+            base: self.git.base().clone(),
+        };
+
+        self.fs
+            .write(
+                &self
+                    .workdir()
+                    .ok_or_else(no_workdir)?
+                    .join(TRANSACTION_JSON),
+                &serde_json::to_vec_pretty(&state).expect("should serialize transaction state"),
+            )
+            .await
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CreateDefaultEnvError {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to write {0:?}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("Failed to write transaction state: {0}")]
+    WriteState(std::io::Error),
+}
+
+impl CreateDefaultEnvError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            CreateDefaultEnvError::Workdir(e) => e.code(),
+            CreateDefaultEnvError::Write(..) | CreateDefaultEnvError::WriteState(_) => {
+                FloxErrorCode::Io
+            },
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TransactionEnterError<Git: GitProvider> {
+    #[error(transparent)]
+    Workdir(ProjectError),
+    #[error("Failed to create tempdir for transaction")]
+    CreateTempdir(std::io::Error),
+    #[error("Failed to walk over file: {0}")]
+    Walkdir(walkdir::Error),
+    #[error("Failed to copy dir")]
+    CopyDir(std::io::Error),
+    #[error("Failed to copy file")]
+    CopyFile(IoError),
+    #[error("Failed to preserve file times: {0}")]
+    PreserveTimes(std::io::Error),
+    #[error("Failed to list the files of the project: {0}")]
+    ListFiles(std::io::Error),
+    #[error("Failed to copy {0:?} into the sandbox: {1}")]
+    Copy(PathBuf, std::io::Error),
+    #[error("Failed to initialize sandbox repository: {0}")]
+    InitGit(Git::InitError),
+    #[error("Failed to clone original repository into sandbox: {0}")]
+    CloneGit(Git::CloneError),
+    #[error("Failed to stage files in sandbox repository: {0}")]
+    StageFiles(Git::AddError),
+    #[error("Failed to write transaction state")]
+    WriteState(std::io::Error),
+    #[error("Failed to lock transaction sandbox: {0}")]
+    Lock(std::io::Error),
+    #[error("Failed to open transaction sandbox repository: {0}")]
+    DiscoverSandbox(Git::DiscoverError),
+    #[error("Failed to update transaction sandbox: {0}")]
+    Sync(std::io::Error),
+    #[error("Failed to read .gitmodules: {0}")]
+    ReadSubmodules(Git::ConfigError),
+    #[error("Failed to stash uncommitted changes: {0}")]
+    Stash(Git::StashError),
+    #[error("Failed to read environment definitions: {0}")]
+    ReadBase(std::io::Error),
+}
+
+impl<Git: GitProvider> TransactionEnterError<Git> {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            TransactionEnterError::Workdir(e) => e.code(),
+            TransactionEnterError::InitGit(_)
+            | TransactionEnterError::CloneGit(_)
+            | TransactionEnterError::StageFiles(_)
+            | TransactionEnterError::DiscoverSandbox(_)
+            | TransactionEnterError::ReadSubmodules(_)
+            | TransactionEnterError::Stash(_) => FloxErrorCode::Git,
+            TransactionEnterError::CreateTempdir(_)
+            | TransactionEnterError::Walkdir(_)
+            | TransactionEnterError::CopyDir(_)
+            | TransactionEnterError::CopyFile(_)
+            | TransactionEnterError::PreserveTimes(_)
+            | TransactionEnterError::ListFiles(_)
+            | TransactionEnterError::Copy(..)
+            | TransactionEnterError::WriteState(_)
+            | TransactionEnterError::Lock(_)
+            | TransactionEnterError::Sync(_)
+            | TransactionEnterError::ReadBase(_) => FloxErrorCode::Io,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RecoverTransactionError<Git: GitProvider> {
+    #[error("Failed to read transaction state: {0}")]
+    ReadState(std::io::Error),
+    #[error("Failed to parse transaction state: {0}")]
+    ParseState(serde_json::Error),
+    #[error("Failed to open original repository: {0}")]
+    DiscoverOriginal(Git::DiscoverError),
+    #[error("Failed to open sandbox repository: {0}")]
+    DiscoverSandbox(Git::DiscoverError),
+}
+
+impl<Git: GitProvider> RecoverTransactionError<Git> {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            RecoverTransactionError::ReadState(_) => FloxErrorCode::Io,
+            RecoverTransactionError::ParseState(_) => FloxErrorCode::Invalid,
+            RecoverTransactionError::DiscoverOriginal(_)
+            | RecoverTransactionError::DiscoverSandbox(_) => FloxErrorCode::Git,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TransactionCommitError<Git: GitProvider> {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to commit changes: {0}")]
+    GitCommit(Git::CommitError),
+    #[error("Failed to push changes: {0}")]
+    GitPush(Git::PushError),
+    #[error("Could not inspect {0:?}: {1}")]
+    Inspect(PathBuf, std::io::Error),
+    #[error("File to add does not exist in the sandbox: {0:?}")]
+    MissingSource(PathBuf),
+    #[error("File to delete does not exist in the project: {0:?}")]
+    MissingTarget(PathBuf),
+    #[error("Cannot add {0:?}, it conflicts with an existing entry in the project")]
+    Conflict(PathBuf),
+    #[error("Cannot change {0:?}, it is part of a git submodule")]
+    Submodule(PathBuf),
+    #[error("Failed to read .gitmodules: {0}")]
+    ReadSubmodules(Git::ConfigError),
+    #[error("Failed to move {0:?} into the project: {1}")]
+    MoveFile(PathBuf, std::io::Error),
+    #[error("Failed to read {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Cannot merge {0:?} with changes made since entering the transaction: {1}")]
+    ConcurrentEdit(PathBuf, FloxNixError),
+    #[error("Failed to write merged {0:?}: {1}")]
+    WriteMerged(PathBuf, std::io::Error),
+    #[error("Failed to stage files: {0}")]
+    GitAdd(Git::AddError),
+    #[error("Failed to remove files: {0}")]
+    GitRm(Git::RmError),
+    #[error("Failed to list tracked files: {0}")]
+    ListFiles(Git::ListFilesError),
+    #[error("Failed to reset the index: {0}")]
+    GitReset(Git::ResetError),
+    #[error("Failed to fetch the commit of the sandbox: {0}")]
+    Fetch(Git::FetchError),
+    #[error("Failed to update HEAD, was a commit made concurrently? {0}")]
+    UpdateHead(Git::HeadError),
+}
+
+impl<Git: GitProvider> TransactionCommitError<Git> {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            TransactionCommitError::Workdir(e) => e.code(),
+            TransactionCommitError::GitCommit(_)
+            | TransactionCommitError::GitPush(_)
+            | TransactionCommitError::GitAdd(_)
+            | TransactionCommitError::GitRm(_)
+            | TransactionCommitError::ListFiles(_)
+            | TransactionCommitError::GitReset(_)
+            | TransactionCommitError::Fetch(_)
+            | TransactionCommitError::ReadSubmodules(_)
+            | TransactionCommitError::UpdateHead(_) => FloxErrorCode::Git,
+            TransactionCommitError::Inspect(..)
+            | TransactionCommitError::MoveFile(..)
+            | TransactionCommitError::Read(..)
+            | TransactionCommitError::WriteMerged(..) => FloxErrorCode::Io,
+            TransactionCommitError::MissingSource(_) | TransactionCommitError::MissingTarget(_) => {
+                FloxErrorCode::NotFound
+            },
+            TransactionCommitError::Conflict(_)
+            | TransactionCommitError::ConcurrentEdit(..)
+            | TransactionCommitError::Submodule(_) => FloxErrorCode::Conflict,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ValidatedCommitError<Git: GitProvider> {
+    #[error("Failed to stage files in sandbox repository: {0}")]
+    Stage(Git::AddError),
+    #[error("Failed to validate project: {0}")]
+    Validate(ValidateError),
+    #[error(transparent)]
+    Commit(TransactionCommitError<Git>),
+}
+
+impl<Git: GitProvider> ValidatedCommitError<Git> {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            ValidatedCommitError::Stage(_) => FloxErrorCode::Git,
+            ValidatedCommitError::Validate(ValidateError::Workdir(e)) => e.code(),
+            ValidatedCommitError::Validate(_) => FloxErrorCode::Nix,
+            ValidatedCommitError::Commit(e) => e.code(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ProjectError {
+    #[error("Project has no working directory")]
+    WorkdirNotFound,
+    #[error("Project directory {path:?} does not exist anymore, was it moved or deleted?")]
+    WorkdirMissing { path: PathBuf },
+    #[error("Could not access project directory {0:?}: {1}")]
+    ReadWorkdir(PathBuf, std::io::Error),
+}
+
+impl ProjectError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            ProjectError::WorkdirNotFound => FloxErrorCode::NoWorkdir,
+            ProjectError::WorkdirMissing { .. } => FloxErrorCode::NotFound,
+            ProjectError::ReadWorkdir(..) => FloxErrorCode::Io,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SubprojectError {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Subproject path must be relative and within the project: {0:?}")]
+    InvalidPath(PathBuf),
+    #[error("No flake.nix found in {0:?}")]
+    NoFlake(PathBuf),
+    #[error("Could not read {0:?}: {1}")]
+    ReadFlake(PathBuf, std::io::Error),
+}
+
+/// Errors occurring while trying to upgrade to an [`Open<Git>`] [Root]
+#[derive(Error, Debug)]
+pub enum OpenProjectError {
+    #[error("Repository is bare and has no working directory")]
+    BareRepository,
+    #[error("Permission denied accessing {0}")]
+    PermissionDenied(PathBuf),
+    #[error("Could not access {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+}
+
+impl OpenProjectError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            OpenProjectError::BareRepository => FloxErrorCode::NoWorkdir,
+            OpenProjectError::PermissionDenied(_) => FloxErrorCode::PermissionDenied,
+            OpenProjectError::Io(..) => FloxErrorCode::Io,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum InitProjectError<Nix: NixBackend, Git: GitProvider>
+where
+    FlakeInit: Run<Nix>,
+{
+    #[error("Could not determine repository root")]
+    WorkdirNotFound,
+
+    #[error("Error initializing base template with Nix")]
+    NixInitBase(<FlakeInit as Run<Nix>>::Error),
+    #[error("Error reading template file contents")]
+    ReadTemplateFile(std::io::Error),
+    #[error("Error truncating template file")]
+    TruncateTemplateFile(std::io::Error),
+    #[error("Error writing to template file")]
+    WriteTemplateFile(std::io::Error),
+    #[error("Error new template file in Git")]
+    GitAdd(Git::AddError),
+    #[error(transparent)]
+    TemplateCache(#[from] TemplateCacheError),
+}
+
+impl<Nix: NixBackend, Git: GitProvider> InitProjectError<Nix, Git>
+where
+    FlakeInit: Run<Nix>,
+{
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            InitProjectError::WorkdirNotFound => FloxErrorCode::NoWorkdir,
+            InitProjectError::NixInitBase(_) => FloxErrorCode::Nix,
+            InitProjectError::ReadTemplateFile(_)
+            | InitProjectError::TruncateTemplateFile(_)
+            | InitProjectError::WriteTemplateFile(_)
+            | InitProjectError::TemplateCache(_) => FloxErrorCode::Template,
+            InitProjectError::GitAdd(_) => FloxErrorCode::Git,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum InitFloxPackageError<Nix: NixBackend, Git: GitProvider>
+where
+    FlakeInit: Run<Nix>,
+{
+    #[error("Could not determine repository root")]
+    WorkdirNotFound,
+    #[error(transparent)]
+    Scaffold(#[from] ScaffoldError<Nix>),
+    #[error("Error listing tracked files using Git")]
+    ListFiles(Git::ListFilesError),
+    #[error("Error staging new renamed file in Git")]
+    GitAdd(Git::AddError),
+}
+
+impl<Nix: NixBackend, Git: GitProvider> InitFloxPackageError<Nix, Git>
+where
+    FlakeInit: Run<Nix>,
+{
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            InitFloxPackageError::WorkdirNotFound => FloxErrorCode::NoWorkdir,
+            InitFloxPackageError::Scaffold(e) => e.code(),
+            InitFloxPackageError::ListFiles(_) | InitFloxPackageError::GitAdd(_) => {
+                FloxErrorCode::Git
+            },
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CleanupInitializerError {
+    #[error("Error removing pkgs")]
+    RemovePkgs(std::io::Error),
+    #[error("Error removing flake.nix")]
+    RemoveFlake(std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum GetEnvironmentError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    FloxEnvs(FloxEnvsError<Nix>),
+    #[error("Environment '{0}' not found")]
+    NotFound(String),
+    #[error("Failed to list flake outputs: {0}")]
+    FlakeShow(FlakeShowError),
+    #[error(transparent)]
+    UnknownSystem(#[from] UnknownSystemError),
+}
+
+impl<Nix: NixBackend> GetEnvironmentError<Nix>
+where
+    Eval: RunJson<Nix>,
+{
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            GetEnvironmentError::FloxEnvs(e) => e.code(),
+            GetEnvironmentError::FlakeShow(_) => FloxErrorCode::Nix,
+            GetEnvironmentError::NotFound(_) => FloxErrorCode::NotFound,
+            GetEnvironmentError::UnknownSystem(_) => FloxErrorCode::Invalid,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FloxEnvsError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    Workdir(ProjectError),
+    #[error("Failed to evaluate environments: {0}")]
+    Eval(<Eval as RunJson<Nix>>::JsonError),
+    #[error(transparent)]
+    Output(FloxEnvsOutputError),
+}
+
+impl<Nix: NixBackend> FloxEnvsError<Nix>
+where
+    Eval: RunJson<Nix>,
+{
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            FloxEnvsError::Workdir(e) => e.code(),
+            FloxEnvsError::Eval(_) | FloxEnvsError::Output(_) => FloxErrorCode::Nix,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DevelopError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to look up the devShell: {0}")]
+    Eval(<Eval as RunJson<Nix>>::JsonError),
+    #[error("Failed parsing evaluation result: {0}")]
+    Parse(serde_json::Error),
+    #[error("Project has no devShell '{0}'")]
+    NotFound(String),
+    #[error("Failed to run nix develop: {0}")]
+    Spawn(std::io::Error),
+}
+
+impl<Nix: NixBackend> DevelopError<Nix>
+where
+    Eval: RunJson<Nix>,
+{
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            DevelopError::Workdir(e) => e.code(),
+            DevelopError::Eval(_) | DevelopError::Parse(_) => FloxErrorCode::Nix,
+            DevelopError::NotFound(_) => FloxErrorCode::NotFound,
+            DevelopError::Spawn(_) => FloxErrorCode::Io,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CopyEnvironmentError<Nix: NixBackend, Git: GitProvider>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Could not find environment: {0}")]
+    Source(GetEnvironmentError<Nix>),
+    #[error("Environment '{0}' already exists")]
+    AlreadyExists(String),
+    #[error("Failed to list environment files: {0}")]
+    Walkdir(walkdir::Error),
+    #[error("Failed to read {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to write {0:?}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("Failed to remove {0:?}: {1}")]
+    Remove(PathBuf, std::io::Error),
+    #[error("Failed to enter transaction: {0}")]
+    EnterTransaction(TransactionEnterError<Git>),
+    #[error("Failed to write transaction state: {0}")]
+    WriteState(std::io::Error),
+    #[error("Failed to commit transaction: {0}")]
+    CommitTransaction(TransactionCommitError<Git>),
+}
+
+impl<Nix: NixBackend, Git: GitProvider> CopyEnvironmentError<Nix, Git>
+where
+    Eval: RunJson<Nix>,
+{
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            CopyEnvironmentError::Workdir(e) => e.code(),
+            CopyEnvironmentError::Source(e) => e.code(),
+            CopyEnvironmentError::AlreadyExists(_) => FloxErrorCode::AlreadyExists,
+            CopyEnvironmentError::Walkdir(_)
+            | CopyEnvironmentError::Read(..)
+            | CopyEnvironmentError::Write(..)
+            | CopyEnvironmentError::Remove(..)
+            | CopyEnvironmentError::WriteState(_) => FloxErrorCode::Io,
+            CopyEnvironmentError::EnterTransaction(e) => e.code(),
+            CopyEnvironmentError::CommitTransaction(e) => e.code(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RenameEnvironmentError<Nix: NixBackend, Git: GitProvider>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    Move(#[from] CopyEnvironmentError<Nix, Git>),
+    #[error("Failed to move generation tags: {0}")]
+    MoveTag(Git::TagError),
+}
+
+impl<Nix: NixBackend, Git: GitProvider> RenameEnvironmentError<Nix, Git>
+where
+    Eval: RunJson<Nix>,
+{
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            RenameEnvironmentError::Move(e) => e.code(),
+            RenameEnvironmentError::MoveTag(_) => FloxErrorCode::Git,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum GetEnvironmentsError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error("Failed to list environments: {0}")]
+    FloxEnvs(FloxEnvsError<Nix>),
+    #[error("Failed to list flake outputs: {0}")]
+    FlakeShow(FlakeShowError),
+    #[error(transparent)]
+    UnknownSystem(#[from] UnknownSystemError),
+}
+
+#[derive(Error, Debug)]
+pub enum ChannelsUsedError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error("Environment '{0}' not found")]
+    NotFound(String),
+    #[error(transparent)]
+    GetEnvironment(GetEnvironmentError<Nix>),
+    #[error("Could not determine channels of environment: {0}")]
+    Channels(EnvironmentChannelsError),
+    #[error("Could not read channel registry: {0}")]
+    Registry(serde_json::Error),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SelectEnvironmentError {
+    #[error("No environments found")]
+    NoEnvironment,
+    #[error("Multiple environments found, choose one of: {}", choices.join(", "))]
+    AmbiguousEnvironment { choices: Vec<String> },
+}
+
+#[derive(Error, Debug)]
+pub enum ActiveEnvironmentError<Nix: NixBackend>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error("Could not list environments")]
+    GetEnvironments(GetEnvironmentsError<Nix>),
+    #[error(transparent)]
+    Select(#[from] SelectEnvironmentError),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::{Arc, Mutex};
+
+    use filetime::FileTime;
+
+    use super::*;
+    use crate::models::events::EventSink;
+    use crate::models::root::reference::ProjectDiscoverGitError;
+    use crate::prelude::ChannelRegistry;
+    use crate::providers::fs::MemFs;
+    use crate::providers::git::{CommitInfo, GitCommandProvider, GitShowError};
+
+    /// Open the project in the git repository at `dir`, which has a flake.nix
+    pub(super) async fn open_project<'flox>(
+        flox: &'flox Flox,
+        dir: &Path,
+    ) -> Project<'flox, GitCommandProvider, ReadOnly<GitCommandProvider>> {
+        flox.resource(dir.to_path_buf())
+            .guard::<GitCommandProvider>()
+            .await
+            .expect("Finding dir should succeed")
+            .open()
+            .expect("should find git repo")
+            .guard()
+            .await
+            .expect("Opening project dir should succeed")
+            .open()
+            .unwrap_or_else(|_| panic!("should find flake.nix"))
+    }
+
+    /// The environment `default` of a project in a new git repository at `dir`
+    ///
+    /// Files of the project are accessed through `fs`.
+    pub(super) async fn test_environment<'flox, Fs: FileSystem>(
+        flox: &'flox Flox,
+        dir: &Path,
+        fs: Fs,
+    ) -> Environment<'flox, GitCommandProvider, ReadOnly<GitCommandProvider>, Fs> {
+        let git = GitCommandProvider::init(dir, false)
+            .await
+            .expect("should create git repo");
+        Environment {
+            name: "default".to_string(),
+            system: System::Aarch64Darwin,
+            project: Project::new(flox, ReadOnly::new(git), Rc::new(fs), PathBuf::new()),
+            compat: false,
+            store_path: None,
+            eval_options: EvalOptions::default(),
+        }
+    }
+
+    fn flox_instance() -> (Flox, TempDir) {
+        let tempdir_handle = tempfile::tempdir_in(std::env::temp_dir()).unwrap();
+
+        let cache_dir = tempdir_handle.path().join("caches");
+        let temp_dir = tempdir_handle.path().join("temp");
+        let config_dir = tempdir_handle.path().join("config");
+
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        std::fs::create_dir_all(&config_dir).unwrap();
+
+        let mut channels = ChannelRegistry::default();
+        channels.register_channel("flox", "github:flox/floxpkgs/master".parse().unwrap());
+
+        let flox = Flox {
+            system: System::Aarch64Darwin,
+            cache_dir,
+            temp_dir,
+            config_dir,
+            channels,
+            ..Default::default()
+        };
+
+        (flox, tempdir_handle)
+    }
+
+    #[test]
+    fn select_active_environment_without_environments() {
+        assert_eq!(
+            select_active_environment([]),
+            Err(SelectEnvironmentError::NoEnvironment)
+        );
+    }
+
+    #[test]
+    fn select_active_environment_single() {
+        assert_eq!(select_active_environment(["dev"]), Ok(0));
+        assert_eq!(select_active_environment(["default"]), Ok(0));
+    }
+
+    #[test]
+    fn select_active_environment_many() {
+        assert_eq!(select_active_environment(["dev", "default", "ci"]), Ok(1));
+        assert_eq!(
+            select_active_environment(["dev", "ci"]),
+            Err(SelectEnvironmentError::AmbiguousEnvironment {
+                choices: vec!["ci".to_string(), "dev".to_string()]
+            })
+        );
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(
+            OpenProjectError::BareRepository.code(),
+            FloxErrorCode::NoWorkdir
+        );
+
+        let conflict =
+            TransactionCommitError::<GitCommandProvider>::Conflict(PathBuf::from("flox.nix"));
+        assert_eq!(conflict.code(), FloxErrorCode::Conflict);
+
+        let missing = TransactionCommitError::MissingSource(PathBuf::from("flox.nix"));
+        let validated = ValidatedCommitError::<GitCommandProvider>::Commit(missing);
+        assert_eq!(validated.code(), FloxErrorCode::NotFound);
+
+        assert_eq!(FloxErrorCode::NoWorkdir.as_str(), "no-workdir");
+    }
+
+    #[test]
+    fn channel_name_maps_flakerefs_to_channels() {
+        let (flox, _tempdir_handle) = flox_instance();
+        let registry = serde_json::to_string(&flox.channels).unwrap();
+
+        assert_eq!(channel_name(&registry, "flox").unwrap(), "flox");
+        assert_eq!(
+            channel_name(&registry, "github:flox/floxpkgs/master").unwrap(),
+            "flox"
+        );
+        assert_eq!(
+            channel_name(&registry, "github:NixOS/nixpkgs").unwrap(),
+            UNKNOWN_CHANNEL
+        );
+        assert_eq!(channel_name(&registry, "nixpkgs").unwrap(), UNKNOWN_CHANNEL);
+    }
+
+    #[tokio::test]
+    async fn fail_without_git() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+
+        flox.resource(project_dir.path().to_path_buf())
+            .guard::<GitCommandProvider>()
+            .await
+            .expect("Finding dir should succeed")
+            .open()
+            .expect_err("should find empty dir");
+    }
+
+    #[tokio::test]
+    async fn fail_on_missing_path() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let missing = tempdir_handle.path().join("missing");
+
+        let err = flox
+            .resource(missing.clone())
+            .guard::<GitCommandProvider>()
+            .await
+            .expect_err("Should not discover a missing path");
+
+        assert!(matches!(err, ProjectDiscoverGitError::PathNotFound(path) if path == missing));
+    }
+
+    #[tokio::test]
+    async fn fail_on_bare_repo() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), true)
+            .await
+            .expect("should create bare git repo");
+
+        let guard = flox
+            .resource(project_dir.path().to_path_buf())
+            .guard::<GitCommandProvider>()
+            .await
+            .expect("Finding dir should succeed")
+            .open()
+            .expect("should find git repo")
+            .guard()
+            .await;
+
+        assert!(matches!(guard, Err(OpenProjectError::BareRepository)));
+    }
+
+    #[tokio::test]
+    async fn enter_transaction_preserves_mtime() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+
+        let flake_nix = project_dir.path().join("flake.nix");
+        std::fs::write(&flake_nix, "{}").unwrap();
+        let mtime = FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(&flake_nix, mtime).unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, _index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+
+        let copied = std::fs::metadata(sandbox.workdir().unwrap().join("flake.nix")).unwrap();
+        let copied_mtime = FileTime::from_last_modification_time(&copied);
+        assert!((copied_mtime.unix_seconds() - mtime.unix_seconds()).abs() <= 1);
+    }
+
+    #[tokio::test]
+    async fn enter_transaction_skips_submodules() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        std::fs::write(
+            project_dir.path().join(".gitmodules"),
+            "[submodule \"lib\"]\n\tpath = vendor/lib\n\turl = https://example.com/lib.git\n",
+        )
+        .unwrap();
+        let submodule = project_dir.path().join("vendor/lib");
+        std::fs::create_dir_all(&submodule).unwrap();
+        std::fs::write(submodule.join(".git"), "gitdir: ../../.git/modules/lib").unwrap();
+        std::fs::write(submodule.join("default.nix"), "{}").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, mut index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+        let sandbox_dir = sandbox.workdir().unwrap().to_path_buf();
+        assert!(sandbox_dir.join("vendor").exists());
+        assert!(!sandbox_dir.join("vendor/lib").exists());
+
+        std::fs::create_dir_all(sandbox_dir.join("vendor/lib")).unwrap();
+        std::fs::write(sandbox_dir.join("vendor/lib/default.nix"), "{ }").unwrap();
+        index.insert(PathBuf::from("vendor/lib/default.nix"), FileAction::Add);
+
+        let err = sandbox
+            .commit_transaction(index, "unused", true)
+            .await
+            .expect_err("should not change files in submodules");
+        assert!(matches!(
+            err,
+            TransactionCommitError::Submodule(path) if path == Path::new("vendor/lib/default.nix")
+        ));
+    }
+
+    #[tokio::test]
+    async fn create_default_env_from_template() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, mut index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+
+        let template = "{ packages.nixpkgs-flox.hello = { }; }";
+        sandbox
+            .create_default_env_with(template, &mut index)
+            .await
+            .unwrap();
+
+        let flox_nix = sandbox.workdir().unwrap().join("flox.nix");
+        assert_eq!(std::fs::read_to_string(flox_nix).unwrap(), template);
+        assert_eq!(index.get(Path::new("flox.nix")), Some(&FileAction::Add));
+    }
+
+    #[tokio::test]
+    async fn recover_transaction_after_crash() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, mut index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+        sandbox.create_default_env(&mut index).await.unwrap();
+        let sandbox_dir = sandbox.workdir().unwrap().to_path_buf();
+
+        // the process dies without dropping the sandbox
+        std::mem::forget(sandbox);
+
+        let pending = flox.pending_transactions().await.unwrap();
+        assert_eq!(pending, vec![sandbox_dir.clone()]);
+
+        let (recovered, recovered_index) =
+            Project::<GitCommandProvider, _>::recover_transaction(&flox, &sandbox_dir)
+                .await
+                .expect("should recover transaction");
+        assert_eq!(recovered_index, index);
+
+        recovered
+            .commit_transaction(recovered_index, "recovered", false)
+            .await
+            .expect("should commit recovered transaction");
+
+        assert!(project_dir.path().join("flox.nix").exists());
+        assert!(flox.pending_transactions().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn commit_transaction_dry_run() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        std::fs::write(project_dir.path().join("obsolete.nix"), "{}").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, mut index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+        let sandbox_dir = sandbox.workdir().unwrap().to_path_buf();
+
+        std::fs::write(sandbox_dir.join("flox.nix"), "{}").unwrap();
+        index.insert(PathBuf::from("flox.nix"), FileAction::Add);
+        index.insert(PathBuf::from("obsolete.nix"), FileAction::Delete);
+
+        let outcome = sandbox
+            .commit_transaction(index.clone(), "unused", true)
+            .await
+            .expect("Should plan transaction");
+
+        let (sandbox, returned, operations) = match outcome {
+            TransactionOutcome::DryRun {
+                sandbox,
+                index,
+                operations,
+            } => (sandbox, index, operations),
+            _ => panic!("should not commit in a dry run"),
+        };
+        assert_eq!(returned, index);
+        assert_eq!(operations, vec![
+            CommitOperation::Add {
+                path: PathBuf::from("flox.nix"),
+                replaces: false,
+            },
+            CommitOperation::Delete {
+                path: PathBuf::from("obsolete.nix"),
+                dir: false,
+            },
+        ]);
+
+        assert!(sandbox_dir.join("flox.nix").exists());
+        assert!(!project_dir.path().join("flox.nix").exists());
+        assert!(project_dir.path().join("obsolete.nix").exists());
+
+        index.insert(PathBuf::from("missing.nix"), FileAction::Add);
+        let err = sandbox
+            .commit_transaction(index, "unused", true)
+            .await
+            .expect_err("should not plan adding a missing file");
+        assert!(matches!(
+            err,
+            TransactionCommitError::MissingSource(path) if path == Path::new("missing.nix")
+        ));
+    }
+
+    #[tokio::test]
+    async fn enter_transaction_reports_progress() {
+        let (mut flox, tempdir_handle) = flox_instance();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink_events = events.clone();
+        flox.event_sink = Some(EventSink::new(move |event| {
+            sink_events.lock().unwrap().push(event.clone())
+        }));
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        std::fs::create_dir(project_dir.path().join("pkgs")).unwrap();
+        std::fs::write(project_dir.path().join("pkgs/flox.nix"), "{ }").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events.last(),
+            Some(&FloxEvent::CopyProgress {
+                copied: 3,
+                total: 3,
+                bytes: 5,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn enter_transaction_keeps_sandbox() {
+        let (mut flox, tempdir_handle) = flox_instance();
+        flox.keep_sandboxes = true;
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, _index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+        let sandbox_dir = sandbox.workdir().unwrap().to_path_buf();
+        drop(sandbox);
+
+        assert!(sandbox_dir.join("flake.nix").exists());
+    }
+
+    #[tokio::test]
+    async fn enter_transaction_uses_git_options() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::providers::git::GitCommandOptions;
+
+        let (flox, tempdir_handle) = flox_instance();
+
+        let log = tempdir_handle.path().join("log");
+        let wrapper = tempdir_handle.path().join("git");
+        std::fs::write(
+            &wrapper,
+            format!(
+                "#!/bin/sh\necho \"$*\" >> {log}\nexec {git} \"$@\"\n",
+                log = log.display(),
+                git = env!("GIT_BIN"),
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let options = GitCommandOptions::default().with_binary(&wrapper);
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+
+        let project = flox
+            .resource(project_dir.path().to_path_buf())
+            .guard_with::<GitCommandProvider>(&options)
+            .await
+            .expect("Finding dir should succeed")
+            .open()
+            .expect("should find git repo")
+            .guard()
+            .await
+            .expect("Opening project dir should succeed")
+            .open()
+            .unwrap_or_else(|_| panic!("should find flake.nix"));
+
+        let (sandbox, _index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+        let sandbox_dir = sandbox.workdir().unwrap().to_path_buf();
+
+        let log = std::fs::read_to_string(&log).unwrap();
+        assert!(log.contains(&format!("-C {} init", sandbox_dir.display())));
+        assert!(log.contains(&format!("-C {} add .", sandbox_dir.display())));
+    }
+
+    #[tokio::test]
+    async fn enter_transaction_reuses_sandbox() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        std::fs::write(project_dir.path().join("kept.nix"), "kept").unwrap();
+        std::fs::write(project_dir.path().join("deleted.nix"), "deleted").unwrap();
+
+        let options = TransactionOptions {
+            reuse_sandbox: true,
+            ..Default::default()
+        };
+
+        let (sandbox, _index) = open_project(&flox, project_dir.path())
+            .await
+            .enter_transaction_with(options)
+            .await
+            .expect("Should be able to make sandbox");
+        let sandbox_dir = sandbox.workdir().unwrap().to_path_buf();
+        assert!(sandbox_dir.starts_with(flox.cache_dir.join(SANDBOX_CACHE_DIR)));
+
+        // edits of an aborted transaction
+        std::fs::write(sandbox_dir.join("flake.nix"), "{ aborted = true; }").unwrap();
+        std::fs::write(sandbox_dir.join("stray.nix"), "").unwrap();
+        drop(sandbox);
+
+        std::fs::remove_file(project_dir.path().join("deleted.nix")).unwrap();
+        std::fs::create_dir(project_dir.path().join("pkgs")).unwrap();
+        std::fs::write(project_dir.path().join("pkgs/added.nix"), "added").unwrap();
+
+        let (sandbox, _index) = open_project(&flox, project_dir.path())
+            .await
+            .enter_transaction_with(options)
+            .await
+            .expect("Should be able to reuse sandbox");
+        assert_eq!(sandbox.workdir().unwrap(), sandbox_dir);
+
+        let read = |path: &str| std::fs::read_to_string(sandbox_dir.join(path)).unwrap();
+        assert_eq!(read("flake.nix"), "{}");
+        assert_eq!(read("kept.nix"), "kept");
+        assert_eq!(read("pkgs/added.nix"), "added");
+        assert!(!sandbox_dir.join("deleted.nix").exists());
+        assert!(!sandbox_dir.join("stray.nix").exists());
+        assert!(sandbox_dir.join(TRANSACTION_JSON).exists());
+    }
+
+    #[tokio::test]
+    async fn enter_transaction_creates_fresh_repository() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        std::fs::write(project_dir.path().join(".git/marker"), "").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, _index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+        let sandbox_dir = sandbox.workdir().unwrap();
+
+        assert!(sandbox_dir.join(".git").is_dir());
+        assert!(!sandbox_dir.join(".git/marker").exists());
+
+        let staged = std::process::Command::new("git")
+            .arg("-C")
+            .arg(sandbox_dir)
+            .args(["ls-files", "--cached"])
+            .output()
+            .unwrap();
+        let staged = String::from_utf8_lossy(&staged.stdout);
+        assert!(staged.lines().any(|file| file == "flake.nix"));
+    }
+
+    #[tokio::test]
+    async fn enter_transaction_auto_stashes() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        project_git.add(&[Path::new("flake.nix")]).await.unwrap();
+        project_git.commit("initial").await.unwrap();
+        std::fs::write(project_dir.path().join("flake.nix"), "{ dirty = true; }").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, _index) = project
+            .enter_transaction_with(TransactionOptions {
+                auto_stash: true,
+                ..Default::default()
+            })
+            .await
+            .expect("Should be able to make sandbox");
+        assert!(sandbox.git.stash().is_some());
+        assert_eq!(
+            std::fs::read_to_string(sandbox.workdir().unwrap().join("flake.nix")).unwrap(),
+            "{}"
+        );
+        assert_eq!(
+            std::fs::read_to_string(project_dir.path().join("flake.nix")).unwrap(),
+            "{}"
+        );
+
+        sandbox.abort_transaction().await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(project_dir.path().join("flake.nix")).unwrap(),
+            "{ dirty = true; }"
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_sandbox_restores_stash() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        project_git.add(&[Path::new("flake.nix")]).await.unwrap();
+        project_git.commit("initial").await.unwrap();
+        std::fs::write(project_dir.path().join("flake.nix"), "{ dirty = true; }").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, _index) = project
+            .enter_transaction_with(TransactionOptions {
+                auto_stash: true,
+                ..Default::default()
+            })
+            .await
+            .expect("Should be able to make sandbox");
+        // e.g. a failed operation
+        drop(sandbox);
+
+        assert_eq!(
+            std::fs::read_to_string(project_dir.path().join("flake.nix")).unwrap(),
+            "{ dirty = true; }"
+        );
+        let stashes = std::process::Command::new(env!("GIT_BIN"))
+            .arg("-C")
+            .arg(project_dir.path())
+            .args(["stash", "list"])
+            .output()
+            .unwrap();
+        assert!(stashes.stdout.is_empty());
+    }
+
+    #[tokio::test]
+    async fn commit_transaction_keeps_conflicting_stash() {
+        let (mut flox, tempdir_handle) = flox_instance();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink_events = events.clone();
+        flox.event_sink = Some(EventSink::new(move |event| {
+            sink_events.lock().unwrap().push(event.clone())
+        }));
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        project_git.add(&[Path::new("flake.nix")]).await.unwrap();
+        project_git.commit("initial").await.unwrap();
+        std::fs::write(project_dir.path().join("flake.nix"), "{ dirty = true; }").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, mut index) = project
+            .enter_transaction_with(TransactionOptions {
+                auto_stash: true,
+                ..Default::default()
+            })
+            .await
+            .expect("Should be able to make sandbox");
+        std::fs::write(
+            sandbox.workdir().unwrap().join("flake.nix"),
+            "{ changed = true; }",
+        )
+        .unwrap();
+        index.insert(PathBuf::from("flake.nix"), FileAction::Add);
+
+        sandbox
+            .commit_transaction(index, "change flake.nix", false)
+            .await
+            .expect("should report the committed transaction")
+            .committed()
+            .expect("not a dry run");
+
+        assert_eq!(project_git.log(None, None).await.unwrap().len(), 2);
+        assert!(events.lock().unwrap().iter().any(|event| matches!(
+            event,
+            FloxEvent::Warning(FloxWarning::StashNotRestored { .. })
+        )));
+    }
+
+    #[tokio::test]
+    async fn enter_transaction_shares_objects() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let (sandbox, _index) = project
+            .enter_transaction_with(TransactionOptions {
+                share_objects: true,
+                ..Default::default()
+            })
+            .await
+            .expect("Should be able to make sandbox");
+        let sandbox_dir = sandbox.workdir().unwrap();
+
+        let alternates =
+            std::fs::read_to_string(sandbox_dir.join(".git/objects/info/alternates")).unwrap();
+        assert!(alternates.trim_end().ends_with(".git/objects"));
+        assert!(sandbox_dir.join("flake.nix").exists());
+    }
+
+    #[tokio::test]
+    async fn commit_transaction_transfers_shared_commit() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::create_dir(project_dir.path().join("sub")).unwrap();
+        for file in ["flake.nix", "sub/flake.nix", "sub/old.nix"] {
+            std::fs::write(project_dir.path().join(file), "{}").unwrap();
+        }
+        project_git.add(&[Path::new(".")]).await.unwrap();
+        project_git.commit("initial").await.unwrap();
+        // uncommitted changes of the original are not part of the transaction
+        std::fs::write(project_dir.path().join("notes.txt"), "notes").unwrap();
+
+        let subproject = open_project(&flox, project_dir.path())
+            .await
+            .subproject(Path::new("sub"))
+            .await
+            .unwrap();
+        let options = TransactionOptions {
+            share_objects: true,
+            ..Default::default()
+        };
+
+        let (sandbox, mut index) = subproject.enter_transaction_with(options).await.unwrap();
+        let sandbox_dir = sandbox.workdir().unwrap().to_path_buf();
+        std::fs::write(sandbox_dir.join("sub/new.nix"), "{ new = true; }").unwrap();
+        std::fs::remove_file(sandbox_dir.join("sub/old.nix")).unwrap();
+        index.insert(PathBuf::from("sub/new.nix"), FileAction::Add);
+        index.insert(PathBuf::from("sub/old.nix"), FileAction::Delete);
+
+        let subproject = sandbox
+            .commit_transaction(index, "replace old.nix", false)
+            .await
+            .expect("should commit transaction")
+            .committed()
+            .expect("not a dry run");
+        // the sandbox is removed, the commit was fetched into the project
+        assert!(!sandbox_dir.exists());
+
+        let log = project_git.log(None, None).await.unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].message, "replace old.nix");
+        assert_eq!(
+            project_git
+                .show_file("HEAD", Path::new("sub/new.nix"))
+                .await
+                .unwrap(),
+            "{ new = true; }"
+        );
+        for missing in ["sub/old.nix", "notes.txt"] {
+            assert!(project_git
+                .show_file("HEAD", Path::new(missing))
+                .await
+                .unwrap_err()
+                .not_found());
+        }
+
+        // worktree and index of the project match the new commit
+        assert!(project_dir.path().join("sub/new.nix").exists());
+        assert!(!project_dir.path().join("sub/old.nix").exists());
+        let status = std::process::Command::new("git")
+            .arg("-C")
+            .arg(project_dir.path())
+            .args(["status", "--porcelain"])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&status.stdout), "?? notes.txt\n");
+
+        // the transaction builds on commits made in the project meanwhile
+        let (sandbox, mut index) = subproject.enter_transaction_with(options).await.unwrap();
+        std::fs::write(sandbox.workdir().unwrap().join("sub/other.nix"), "{}").unwrap();
+        index.insert(PathBuf::from("sub/other.nix"), FileAction::Add);
+        project_git.add(&[Path::new("notes.txt")]).await.unwrap();
+        project_git.commit("notes").await.unwrap();
+        sandbox
+            .commit_transaction(index, "add other.nix", false)
+            .await
+            .expect("should commit transaction");
+        let messages: Vec<_> = project_git
+            .log(None, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|commit| commit.message)
+            .collect();
+        assert_eq!(messages, [
+            "add other.nix",
+            "notes",
+            "replace old.nix",
+            "initial"
+        ]);
+        assert_eq!(
+            project_git
+                .show_file("HEAD", Path::new("notes.txt"))
+                .await
+                .unwrap(),
+            "notes"
+        );
+    }
+
+    /// Commit two transactions adding a file each and count the commits of the project
+    async fn commit_count_with(commit_strategy: CommitStrategy) -> usize {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        project_git.add(&[Path::new("flake.nix")]).await.unwrap();
+        project_git.commit("initial").await.unwrap();
+
+        let mut project = open_project(&flox, project_dir.path()).await;
+
+        for file in ["a.nix", "b.nix"] {
+            let (sandbox, mut index) = project
+                .enter_transaction_with(TransactionOptions {
+                    commit_strategy,
+                    ..Default::default()
+                })
+                .await
+                .expect("Should be able to make sandbox");
+            std::fs::write(sandbox.workdir().unwrap().join(file), "{}").unwrap();
+            index.insert(PathBuf::from(file), FileAction::Add);
+
+            project = sandbox
+                .commit_transaction(index, &format!("add {file}"), false)
+                .await
+                .expect("Should commit transaction")
+                .committed()
+                .expect("not a dry run");
+        }
+
+        if commit_strategy == CommitStrategy::Squashed {
+            project
+                .commit_staged("add a.nix and b.nix")
+                .await
+                .expect("Should commit staged changes");
+        }
+
+        let output = std::process::Command::new(env!("GIT_BIN"))
+            .arg("-C")
+            .arg(project_dir.path())
+            .args(["rev-list", "--count", "HEAD"])
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn commit_transaction_per_operation() {
+        assert_eq!(commit_count_with(CommitStrategy::PerOperation).await, 3);
+    }
+
+    #[tokio::test]
+    async fn commit_transaction_squashed() {
+        assert_eq!(commit_count_with(CommitStrategy::Squashed).await, 2);
+    }
+
+    #[tokio::test]
+    async fn commit_transaction_merges_concurrent_package_changes() {
+        let (flox, tempdir_handle) = flox_instance();
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .unwrap();
+        let flox_nix_path = project_dir.path().join("flox.nix");
+        let base = "{\n  packages.nixpkgs-flox.hello = {};\n}\n";
+        std::fs::write(&flox_nix_path, base).unwrap();
+        git.add(&[Path::new("flox.nix")]).await.unwrap();
+        git.commit("initial").await.unwrap();
+        let project = Project::new(&flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new());
+
+        let packages = |contents: &str| -> Vec<String> {
+            contents
+                .parse::<flox_nix::FloxNix>()
+                .unwrap()
+                .packages()
+                .unwrap()
+                .into_keys()
+                .collect()
+        };
+
+        let (sandbox, mut index) = project.enter_transaction().await.unwrap();
+        let ours = flox_nix::install_packages(base, &["nixpkgs-flox.ripgrep".to_string()]).unwrap();
+        std::fs::write(sandbox.workdir().unwrap().join("flox.nix"), ours).unwrap();
+        index.insert(PathBuf::from("flox.nix"), FileAction::Add);
+        let theirs = flox_nix::install_packages(base, &["nixpkgs-flox.jq".to_string()]).unwrap();
+        std::fs::write(&flox_nix_path, theirs).unwrap();
+
+        let project = sandbox
+            .commit_transaction(index, "install ripgrep", false)
+            .await
+            .unwrap()
+            .committed()
+            .unwrap();
+        assert_eq!(
+            packages(&std::fs::read_to_string(&flox_nix_path).unwrap()),
+            [
+                "nixpkgs-flox.hello",
+                "nixpkgs-flox.jq",
+                "nixpkgs-flox.ripgrep"
+            ]
+        );
+
+        // both sides pin a different version
+        let base = std::fs::read_to_string(&flox_nix_path).unwrap();
+        let hello = "nixpkgs-flox.hello".to_string();
+        let (sandbox, mut index) = project.enter_transaction().await.unwrap();
+        let ours = flox_nix::set_package_version(&base, &hello, "2.12").unwrap();
+        std::fs::write(sandbox.workdir().unwrap().join("flox.nix"), ours).unwrap();
+        index.insert(PathBuf::from("flox.nix"), FileAction::Add);
+        let theirs = flox_nix::set_package_version(&base, &hello, "2.10").unwrap();
+        std::fs::write(&flox_nix_path, &theirs).unwrap();
+
+        assert!(matches!(
+            sandbox.commit_transaction(index, "pin hello", false).await,
+            Err(TransactionCommitError::ConcurrentEdit(path, FloxNixError::Conflict(_)))
+                if path == Path::new("flox.nix")
+        ));
+        assert_eq!(std::fs::read_to_string(&flox_nix_path).unwrap(), theirs);
+    }
+
+    #[tokio::test]
+    async fn system_override_must_be_known() {
+        use runix::command_line::NixCommandLine;
+
+        let (flox, tempdir_handle) = flox_instance();
+        let git = GitCommandProvider::init(tempdir_handle.path(), false)
+            .await
+            .unwrap();
+        let project = Project::new(&flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new());
+
+        assert!(matches!(
+            project
+                .environments_for_system::<NixCommandLine>("aarch64-darwn")
+                .await,
+            Err(GetEnvironmentsError::UnknownSystem(_))
+        ));
+        let error = match project
+            .environment_for_system::<NixCommandLine>("default", "x86-64_linux")
+            .await
+        {
+            Err(error) => error,
+            Ok(_) => panic!("environment of an unknown system should not be found"),
+        };
+        assert_eq!(error.code(), FloxErrorCode::Invalid);
+    }
+
+    #[tokio::test]
+    async fn flakeref_of_deleted_workdir() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+        assert!(project.flakeref().await.is_ok());
+
+        let workdir = project_dir.path().to_path_buf();
+        project_dir.close().unwrap();
+
+        assert!(matches!(
+            project.flakeref().await,
+            Err(ProjectError::WorkdirMissing { path }) if path == workdir
+        ));
+    }
+
+    #[tokio::test]
+    async fn flakeref_checks_workdir_through_fs() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox::default();
+        let fs = MemFs::new();
+        let environment = test_environment(&flox, tempdir.path(), fs.clone()).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+
+        // the directory exists on disk, but not in the filesystem of the project
+        assert!(matches!(
+            environment.project.flakeref().await,
+            Err(ProjectError::WorkdirMissing { path }) if path == workdir
+        ));
+
+        fs.create_dir_all(&workdir).await.unwrap();
+        assert_eq!(
+            Path::new(&environment.project.flakeref().await.unwrap()),
+            workdir
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_moves_remote_refs() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let remote_dir = tempdir_handle.path().join("remote.git");
+        std::fs::create_dir(&remote_dir).unwrap();
+        GitCommandProvider::init(&remote_dir, true).await.unwrap();
+
+        // pushes to the remote behind the back of the project
+        let upstream_dir = tempdir_handle.path().join("upstream");
+        std::fs::create_dir(&upstream_dir).unwrap();
+        let upstream = GitCommandProvider::init(&upstream_dir, false)
+            .await
+            .unwrap();
+        std::fs::write(upstream_dir.join("flake.nix"), "{}").unwrap();
+        upstream.add(&[Path::new("flake.nix")]).await.unwrap();
+        upstream.commit("initial").await.unwrap();
+        upstream
+            .add_remote("origin", &remote_dir.to_string_lossy())
+            .await
+            .unwrap();
+        upstream.push("origin").await.unwrap();
+        let branch = upstream.current_branch().await.unwrap().unwrap();
+
+        let project_dir = tempdir_handle.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        <GitCommandProvider as GitProvider>::clone(&remote_dir, &project_dir, false)
+            .await
+            .unwrap();
+        let project = open_project(&flox, &project_dir).await;
+        let remote_branch = format!("origin/{branch}");
+        let remote_rev = |commits: Vec<CommitInfo>| commits[0].rev.clone();
+
+        std::fs::write(upstream_dir.join("flox.nix"), "{}").unwrap();
+        upstream.add(&[Path::new("flox.nix")]).await.unwrap();
+        upstream.commit("update").await.unwrap();
+        upstream.push("origin").await.unwrap();
+        let updated = upstream.head_rev().await.unwrap();
+
+        let git = project.git.git();
+        let before = git.log_rev(&remote_branch, None, Some(1)).await.unwrap();
+        assert_ne!(remote_rev(before), updated);
+
+        project.fetch("origin").await.expect("should fetch");
+        let after = git.log_rev(&remote_branch, None, Some(1)).await.unwrap();
+        assert_eq!(remote_rev(after), updated);
+    }
+
+    #[tokio::test]
+    async fn open_subproject() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        std::fs::create_dir_all(project_dir.path().join("nested/sub")).unwrap();
+        std::fs::write(project_dir.path().join("nested/sub/flake.nix"), "{}").unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let subproject = project
+            .subproject(Path::new("nested/sub"))
+            .await
+            .expect("should open nested flake");
+        assert_eq!(
+            subproject.flakeref().await.unwrap(),
+            project_dir.path().join("nested/sub").to_string_lossy()
+        );
+
+        assert!(matches!(
+            project.subproject(Path::new("nested")).await,
+            Err(SubprojectError::NoFlake(_))
+        ));
+        assert!(matches!(
+            project.subproject(Path::new("../elsewhere")).await,
+            Err(SubprojectError::InvalidPath(_))
+        ));
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn list_subproject_environments() {
+        use runix::command_line::NixCommandLine;
+
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(
+            project_dir.path().join("flake.nix"),
+            "{ outputs = _: { }; }",
+        )
+        .unwrap();
+        std::fs::create_dir_all(project_dir.path().join("sub")).unwrap();
+        std::fs::write(
+            project_dir.path().join("sub/flake.nix"),
+            format!(
+                r#"{{ outputs = _: {{ floxEnvs."{}".nested = {{ }}; }}; }}"#,
+                flox.system
+            ),
+        )
+        .unwrap();
+        project_git
+            .add(&[Path::new("flake.nix"), Path::new("sub/flake.nix")])
+            .await
+            .unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let subproject = project
+            .subproject(Path::new("sub"))
+            .await
+            .expect("should open nested flake");
+
+        let envs = subproject
+            .environments::<NixCommandLine>()
+            .await
+            .expect("should list environments");
+        let names: Vec<_> = envs.iter().map(|env| env.name()).collect();
+        assert_eq!(names, ["nested"]);
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn lists_environments_sorted_ignoring_case() {
+        use runix::command_line::NixCommandLine;
+
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(
+            project_dir.path().join("flake.nix"),
+            format!(
+                r#"{{ outputs = _: {{ floxEnvs."{}" = {{
+                    gamma = {{ }}; Delta = {{ }}; beta = {{ }}; Alpha = {{ }};
+                }}; }}; }}"#,
+                flox.system
+            ),
+        )
+        .unwrap();
+        project_git.add(&[Path::new("flake.nix")]).await.unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let envs = project
+            .environments::<NixCommandLine>()
+            .await
+            .expect("should list environments");
+        let names: Vec<_> = envs.iter().map(|env| env.name()).collect();
+        assert_eq!(names, ["Alpha", "beta", "Delta", "gamma"]);
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn lists_environments_that_fail_to_evaluate() {
+        use runix::command_line::NixCommandLine;
+
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(
+            project_dir.path().join("flake.nix"),
+            format!(
+                r#"{{ outputs = _: {{
+                    floxEnvs."{}" = {{
+                        broken = abort "broken";
+                        good.meta.description = "good";
+                    }};
+                    floxEnvs.other-system = abort "foreign";
+                }}; }}"#,
+                flox.system
+            ),
+        )
+        .unwrap();
+        project_git.add(&[Path::new("flake.nix")]).await.unwrap();
+
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let envs = project
+            .environments::<NixCommandLine>()
+            .await
+            .expect("should list environments");
+        let names: Vec<_> = envs.iter().map(|env| env.name()).collect();
+        assert_eq!(names, ["broken", "good"]);
+        project
+            .environment::<NixCommandLine>("broken")
+            .await
+            .expect("should find environment without evaluating it");
+
+        let output = project
+            .flox_envs::<NixCommandLine>(
+                std::slice::from_ref(&flox.system),
+                false,
+                EvalOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output
+                .metadata(&flox.system, "good")
+                .and_then(|meta| meta.description.as_deref()),
+            None
+        );
+    }
+
+    #[test]
+    fn sorts_environment_names_stably() {
+        let mut names = ["b", "B", "a", "A"].map(String::from);
+        sort_environment_names(&mut names);
+        assert_eq!(names, ["a", "A", "b", "B"]);
+    }
+
+    /// Nix backend showing a `dev` devShell and evaluating a `default` floxEnv
+    ///
+    /// Every `nix flake show` is recorded as a line in `flake-show` in the temp dir.
+    #[derive(Debug)]
+    struct OutputsNix {
+        system: System,
+        shown: PathBuf,
+    }
+
+    impl NixBackend for OutputsNix {}
+
+    impl FloxNixApi for OutputsNix {
+        fn new(flox: &Flox, _: runix::default::DefaultArgs) -> Self {
+            OutputsNix {
+                system: flox.system.clone(),
+                shown: flox.temp_dir.join("flake-show"),
+            }
+        }
+
+        fn command(&self, subcommand: &[&str]) -> tokio::process::Command {
+            if subcommand != ["flake", "show"] {
+                let mut command = tokio::process::Command::new("false");
+                command.args(subcommand);
+                return command;
+            }
+            let outputs = serde_json::json!({
+                "devShells": { self.system.as_str(): { "dev": { "type": "derivation" } } }
+            });
+            let mut command = tokio::process::Command::new("sh");
+            command.arg("-c").arg(format!(
+                "echo >> '{}'; printf '%s' '{outputs}'",
+                self.shown.display()
+            ));
+            command
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Run<OutputsNix> for Eval {
+        type Error = std::io::Error;
+
+        async fn run(&self, _: &OutputsNix, _: &NixArgs) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl RunJson<OutputsNix> for Eval {
+        type JsonError = std::io::Error;
+
+        async fn run_json(
+            &self,
+            nix: &OutputsNix,
+            _: &NixArgs,
+        ) -> Result<serde_json::Value, Self::JsonError> {
+            Ok(serde_json::json!({
+                "version": floxenvs::SCHEMA_VERSION,
+                "systems": { nix.system.as_str(): { "default": {} } },
+            }))
+        }
+    }
+
+    /// Open a project whose flake is only ever inspected through [OutputsNix]
+    async fn outputs_project<'flox>(
+        flox: &'flox Flox,
+        tempdir_handle: &TempDir,
+    ) -> (
+        TempDir,
+        Project<'flox, GitCommandProvider, ReadOnly<GitCommandProvider>>,
+    ) {
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .unwrap();
+        std::fs::write(project_dir.path().join("flake.nix"), "{}").unwrap();
+        git.add(&[Path::new("flake.nix")]).await.unwrap();
+        let project = Project::new(flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new());
+        (project_dir, project)
+    }
+
+    #[tokio::test]
+    async fn caches_compat_devshells() {
+        let (mut flox, tempdir_handle) = flox_instance();
+        flox.compat_devshells = true;
+        let (_project_dir, project) = outputs_project(&flox, &tempdir_handle).await;
+
+        for _ in 0..2 {
+            let envs = project.environments::<OutputsNix>().await.unwrap();
+            let names: Vec<_> = envs.iter().map(|env| env.name()).collect();
+            assert_eq!(names, ["dev"]);
+            assert!(envs[0].is_compat());
+        }
+        let env = project.environment::<OutputsNix>("dev").await.unwrap();
+        assert!(env.is_compat());
+
+        let shown = std::fs::read_to_string(flox.temp_dir.join("flake-show")).unwrap();
+        assert_eq!(shown.lines().count(), 1);
+    }
 
-    use super::*;
-    use crate::prelude::ChannelRegistry;
-    use crate::providers::git::GitCommandProvider;
+    #[tokio::test]
+    async fn lists_flox_envs_without_compat() {
+        let (flox, tempdir_handle) = flox_instance();
+        let (_project_dir, project) = outputs_project(&flox, &tempdir_handle).await;
 
-    fn flox_instance() -> (Flox, TempDir) {
-        let tempdir_handle = tempfile::tempdir_in(std::env::temp_dir()).unwrap();
+        let envs = project.environments::<OutputsNix>().await.unwrap();
+        let names: Vec<_> = envs.iter().map(|env| env.name()).collect();
+        assert_eq!(names, ["default"]);
+        assert!(!envs[0].is_compat());
+        assert!(matches!(
+            project.environment::<OutputsNix>("dev").await,
+            Err(GetEnvironmentError::NotFound(_))
+        ));
 
-        let cache_dir = tempdir_handle.path().join("caches");
-        let temp_dir = tempdir_handle.path().join("temp");
-        let config_dir = tempdir_handle.path().join("config");
+        assert!(!flox.temp_dir.join("flake-show").exists());
+    }
 
-        std::fs::create_dir_all(&cache_dir).unwrap();
-        std::fs::create_dir_all(&temp_dir).unwrap();
-        std::fs::create_dir_all(&config_dir).unwrap();
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn list_compat_devshells() {
+        use runix::command_line::NixCommandLine;
 
-        let mut channels = ChannelRegistry::default();
-        channels.register_channel("flox", "github:flox/floxpkgs/master".parse().unwrap());
+        let (mut flox, tempdir_handle) = flox_instance();
+        flox.compat_devshells = true;
 
-        let flox = Flox {
-            system: "aarch64-darwin".to_string(),
-            cache_dir,
-            temp_dir,
-            config_dir,
-            channels,
-            ..Default::default()
-        };
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(
+            project_dir.path().join("flake.nix"),
+            format!(
+                r#"{{ outputs = _: {{ devShells."{system}".dev = derivation {{
+                    name = "dev"; system = "{system}"; builder = "/bin/sh";
+                }}; }}; }}"#,
+                system = flox.system
+            ),
+        )
+        .unwrap();
+        project_git.add(&[Path::new("flake.nix")]).await.unwrap();
 
-        (flox, tempdir_handle)
+        let project = open_project(&flox, project_dir.path()).await;
+
+        let envs = project
+            .environments::<NixCommandLine>()
+            .await
+            .expect("should list devShells");
+        let names: Vec<_> = envs.iter().map(|env| env.name()).collect();
+        assert_eq!(names, ["dev"]);
+        assert!(envs[0].is_compat());
+
+        let env = project
+            .environment::<NixCommandLine>("dev")
+            .await
+            .expect("should find devShell");
+        assert!(env.is_compat());
+        assert_eq!(
+            env.installable().await.unwrap().attr_path,
+            format!(".devShells.{}.dev", flox.system)
+        );
     }
 
+    #[cfg(feature = "impure-unit-tests")]
     #[tokio::test]
-    async fn fail_without_git() {
+    async fn develop_requires_existing_devshell() {
+        use runix::command_line::NixCommandLine;
+
         let (flox, tempdir_handle) = flox_instance();
 
         let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+        std::fs::write(
+            project_dir.path().join("flake.nix"),
+            format!(
+                r#"{{
+                    inputs.nixpkgs.url = "github:flox/nixpkgs/stable";
+                    outputs = {{ nixpkgs, ... }}: {{
+                        devShells."{system}".dev = nixpkgs.legacyPackages."{system}".mkShell {{
+                            DEV_SHELL = "dev";
+                        }};
+                    }};
+                }}"#,
+                system = flox.system
+            ),
+        )
+        .unwrap();
+        git.add(&[Path::new("flake.nix")]).await.unwrap();
+        let project = Project::new(&flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new());
 
-        flox.resource(project_dir.path().to_path_buf())
-            .guard::<GitCommandProvider>()
+        assert!(matches!(
+            project.develop::<NixCommandLine>(None, &["true"]).await,
+            Err(DevelopError::NotFound(name)) if name == "default"
+        ));
+        assert!(matches!(
+            project.develop::<NixCommandLine>(Some("missing"), &["true"]).await,
+            Err(DevelopError::NotFound(name)) if name == "missing"
+        ));
+
+        // the command runs in the environment of the shell
+        let status = project
+            .develop::<NixCommandLine>(Some("dev"), &["sh", "-c", r#"test "$DEV_SHELL" = dev"#])
             .await
-            .expect("Finding dir should succeed")
-            .open()
-            .expect_err("should find empty dir");
+            .expect("should run nix develop");
+        assert!(status.success());
+        let status = project
+            .develop::<NixCommandLine>(Some("dev"), &["false"])
+            .await
+            .expect("should run nix develop");
+        assert!(!status.success());
     }
 
     #[tokio::test]
@@ -637,6 +4279,8 @@ mod tests {
     #[cfg(feature = "impure-unit-tests")]
     #[tokio::test]
     async fn create_project() {
+        use runix::command_line::NixCommandLine;
+
         let temp_home = tempfile::tempdir().unwrap();
         env::set_var("HOME", temp_home.path());
 
@@ -657,12 +4301,12 @@ mod tests {
             .guard()
             .await
             .expect("Openeing project dir should succeed")
-            .init_project(Vec::new())
+            .init_project::<NixCommandLine>(Vec::new())
             .await
             .expect("Should init a new project");
 
         let envs = project
-            .environments()
+            .environments::<NixCommandLine>()
             .await
             .expect("should find empty floxEnvs");
         assert!(envs.is_empty());
@@ -672,16 +4316,510 @@ mod tests {
             .await
             .expect("Should be able to make sandbox");
 
-        project.create_default_env(&mut index).await;
+        project.create_default_env(&mut index).await.unwrap();
 
         let project = project
-            .commit_transaction(index, "unused")
+            .commit_transaction(index, "unused", false)
             .await
-            .expect("Should commit transaction");
+            .expect("Should commit transaction")
+            .committed()
+            .expect("not a dry run");
 
         project
-            .environment("default")
+            .environment::<NixCommandLine>("default")
             .await
             .expect("should find new environment");
     }
+
+    /// Nix backend whose `nix flake init` creates a package template in the working directory
+    #[derive(Debug)]
+    struct TemplateNix;
+
+    impl NixBackend for TemplateNix {}
+
+    impl FloxNixApi for TemplateNix {
+        fn new(_: &Flox, _: runix::default::DefaultArgs) -> Self {
+            TemplateNix
+        }
+
+        fn command(&self, subcommand: &[&str]) -> tokio::process::Command {
+            let mut command = tokio::process::Command::new("false");
+            command.args(subcommand);
+            command
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Run<TemplateNix> for FlakeInit {
+        type Error = std::io::Error;
+
+        async fn run(&self, _: &TemplateNix, nix_args: &NixArgs) -> Result<(), Self::Error> {
+            let cwd = nix_args
+                .cwd
+                .clone()
+                .expect("init runs in a staging directory");
+            let package = cwd.join("pkgs").join(PACKAGE_NAME_PLACEHOLDER);
+            std::fs::create_dir_all(&package)?;
+            std::fs::write(
+                package.join("default.nix"),
+                format!(r#"{{ pname = "{PACKAGE_NAME_PLACEHOLDER}"; }}"#),
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn init_flox_package_names_template_package() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .unwrap();
+        let project = Project::new(&flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new());
+
+        let template = Installable::new("flake:flox".to_string(), "templates.package".to_string());
+        project
+            .init_flox_package::<TemplateNix>(Vec::new(), template, "hello")
+            .await
+            .expect("should init package");
+
+        assert_eq!(
+            std::fs::read_to_string(project_dir.path().join("pkgs/hello/default.nix")).unwrap(),
+            r#"{ pname = "hello"; }"#
+        );
+        assert!(!project_dir.path().join("pkgs/__PACKAGE_NAME__").exists());
+
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(project_dir.path())
+            .args(["status", "--porcelain", "--untracked-files=all"])
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "A  pkgs/hello/default.nix\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn init_flox_package_undoes_only_its_changes() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .unwrap();
+        // a tracked file that is not UTF-8 makes replacing the placeholder fail after the move
+        let placeholder = project_dir
+            .path()
+            .join("pkgs")
+            .join(PACKAGE_NAME_PLACEHOLDER);
+        std::fs::create_dir_all(&placeholder).unwrap();
+        std::fs::write(placeholder.join("logo.png"), [0xff, 0xfe]).unwrap();
+        git.add(&[Path::new(".")]).await.unwrap();
+        git.commit("add logo").await.unwrap();
+        std::fs::create_dir_all(project_dir.path().join("target/debug")).unwrap();
+        std::fs::write(project_dir.path().join("target/debug/build.log"), "built").unwrap();
+
+        let project = Project::new(&flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new());
+        let template = Installable::new("flake:flox".to_string(), "templates.package".to_string());
+        let result = project
+            .init_flox_package::<TemplateNix>(Vec::new(), template, "hello")
+            .await;
+        assert!(matches!(
+            result,
+            Err(InitFloxPackageError::Scaffold(
+                ScaffoldError::ReplacePackageName(_)
+            ))
+        ));
+
+        assert!(!project_dir.path().join("pkgs/hello").exists());
+        assert!(!placeholder.join("default.nix").exists());
+        assert_eq!(std::fs::read(placeholder.join("logo.png")).unwrap(), [
+            0xff, 0xfe
+        ]);
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(project_dir.path())
+            .args(["status", "--porcelain", "--untracked-files=all"])
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "?? target/debug/build.log\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn transaction_in_mem_fs() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        // git needs a repository, the files of the project only exist in memory
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .unwrap();
+        let fs = MemFs::new();
+        fs.create_dir_all(&project_dir.path().join("pkgs/hello"))
+            .await
+            .unwrap();
+        fs.write(&project_dir.path().join("flake.nix"), b"{}")
+            .await
+            .unwrap();
+        fs.write(&project_dir.path().join("obsolete.nix"), b"{}")
+            .await
+            .unwrap();
+        let project = Project::new(
+            &flox,
+            ReadOnly::new(git),
+            Rc::new(fs.clone()),
+            PathBuf::new(),
+        );
+
+        let (sandbox, mut index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+        let sandbox_dir = sandbox.workdir().unwrap().to_path_buf();
+        assert!(fs.exists(&sandbox_dir.join("flake.nix")));
+        assert!(fs.exists(&sandbox_dir.join("pkgs/hello")));
+        assert!(!sandbox_dir.join("flake.nix").exists());
+
+        sandbox.create_default_env(&mut index).await.unwrap();
+        assert!(fs.exists(&sandbox_dir.join("flox.nix")));
+        index.insert(PathBuf::from("obsolete.nix"), FileAction::Delete);
+
+        let outcome = sandbox
+            .commit_transaction(index, "unused", true)
+            .await
+            .expect("should plan the commit");
+        match outcome {
+            TransactionOutcome::DryRun { operations, .. } => {
+                assert_eq!(operations, vec![
+                    CommitOperation::Add {
+                        path: PathBuf::from("flox.nix"),
+                        replaces: false,
+                    },
+                    CommitOperation::Delete {
+                        path: PathBuf::from("obsolete.nix"),
+                        dir: false,
+                    },
+                ]);
+            },
+            _ => panic!("expected a dry run"),
+        }
+        assert!(!fs.exists(&project_dir.path().join("flox.nix")));
+        assert!(fs.exists(&project_dir.path().join("obsolete.nix")));
+    }
+
+    #[tokio::test]
+    async fn reused_sandbox_in_mem_fs() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .unwrap();
+        let fs = MemFs::new();
+        fs.create_dir_all(&project_dir.path().join("pkgs/hello"))
+            .await
+            .unwrap();
+        fs.write(&project_dir.path().join("flake.nix"), b"{}")
+            .await
+            .unwrap();
+        fs.write(&project_dir.path().join("deleted.nix"), b"{}")
+            .await
+            .unwrap();
+        let project = || {
+            Project::new(
+                &flox,
+                ReadOnly::new(git.clone()),
+                Rc::new(fs.clone()),
+                PathBuf::new(),
+            )
+        };
+        let options = TransactionOptions {
+            reuse_sandbox: true,
+            ..Default::default()
+        };
+
+        let (sandbox, _index) = project()
+            .enter_transaction_with(options)
+            .await
+            .expect("Should be able to make sandbox");
+        let sandbox_dir = sandbox.workdir().unwrap().to_path_buf();
+        assert!(fs.exists(&sandbox_dir.join("flake.nix")));
+        assert!(fs.exists(&sandbox_dir.join("deleted.nix")));
+        assert!(fs.exists(&sandbox_dir.join("pkgs/hello")));
+        assert!(!sandbox_dir.join("flake.nix").exists());
+        drop(sandbox);
+
+        fs.remove(&project_dir.path().join("deleted.nix"))
+            .await
+            .unwrap();
+        fs.write(&project_dir.path().join("flake.nix"), b"{ edited = true; }")
+            .await
+            .unwrap();
+
+        let (sandbox, _index) = project()
+            .enter_transaction_with(options)
+            .await
+            .expect("Should be able to reuse sandbox");
+        assert_eq!(sandbox.workdir().unwrap(), sandbox_dir);
+        assert_eq!(
+            fs.read(&sandbox_dir.join("flake.nix")).await.unwrap(),
+            b"{ edited = true; }"
+        );
+        assert!(!fs.exists(&sandbox_dir.join("deleted.nix")));
+    }
+
+    #[tokio::test]
+    async fn commit_transaction_in_mem_fs_detects_conflicts() {
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .unwrap();
+        let fs = MemFs::new();
+        fs.create_dir_all(project_dir.path()).await.unwrap();
+        fs.write(&project_dir.path().join("flake.nix"), b"{}")
+            .await
+            .unwrap();
+        let project = Project::new(
+            &flox,
+            ReadOnly::new(git),
+            Rc::new(fs.clone()),
+            PathBuf::new(),
+        );
+
+        let (sandbox, mut index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+        sandbox.create_default_env(&mut index).await.unwrap();
+
+        // a directory takes the place of the flox.nix in the meantime
+        fs.create_dir_all(&project_dir.path().join("flox.nix"))
+            .await
+            .unwrap();
+
+        let err = sandbox
+            .commit_transaction(index, "unused", false)
+            .await
+            .expect_err("should detect the conflict");
+        assert!(matches!(
+            err,
+            TransactionCommitError::Conflict(path) if path == Path::new("flox.nix")
+        ));
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn init_flox_package_rolls_back_on_failure() {
+        use runix::command_line::NixCommandLine;
+
+        let (flox, tempdir_handle) = flox_instance();
+
+        let template_dir = tempdir_handle.path().join("template");
+        std::fs::create_dir_all(template_dir.join("package/pkgs/__PACKAGE_NAME__")).unwrap();
+        std::fs::write(
+            template_dir.join("flake.nix"),
+            r#"{ outputs = _: { templates.package = { path = ./package; description = "package"; }; }; }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            template_dir.join("package/pkgs/__PACKAGE_NAME__/default.nix"),
+            r#"{ pname = "__PACKAGE_NAME__"; }"#,
+        )
+        .unwrap();
+        let template_git = GitCommandProvider::init(&template_dir, false)
+            .await
+            .unwrap();
+        template_git.add(&[Path::new(".")]).await.unwrap();
+        template_git.commit("add template").await.unwrap();
+        let template = Installable::new(
+            format!("git+file://{}", template_dir.display()),
+            "templates.package".to_string(),
+        );
+
+        // a file in place of the package directory makes moving the template fail
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .unwrap();
+        std::fs::create_dir_all(project_dir.path().join("pkgs")).unwrap();
+        std::fs::write(project_dir.path().join("pkgs/hello"), "occupied").unwrap();
+        git.add(&[Path::new(".")]).await.unwrap();
+        git.commit("occupy pkgs/hello").await.unwrap();
+
+        let status = || {
+            let output = std::process::Command::new("git")
+                .arg("-C")
+                .arg(project_dir.path())
+                .args(["status", "--porcelain", "--untracked-files=all"])
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        let project = Project::new(&flox, ReadOnly::new(git), Rc::new(TokioFs), PathBuf::new());
+        let result = project
+            .init_flox_package::<NixCommandLine>(Vec::new(), template, "hello")
+            .await;
+        assert!(matches!(
+            result,
+            Err(InitFloxPackageError::Scaffold(ScaffoldError::Exists(_)))
+        ));
+
+        assert_eq!(status(), "");
+        assert!(!project_dir.path().join("pkgs/__PACKAGE_NAME__").exists());
+        assert_eq!(
+            std::fs::read_to_string(project_dir.path().join("pkgs/hello")).unwrap(),
+            "occupied"
+        );
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn copy_environment() {
+        use runix::command_line::NixCommandLine;
+
+        let temp_home = tempfile::tempdir().unwrap();
+        env::set_var("HOME", temp_home.path());
+
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let _project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+
+        let project = flox
+            .resource(project_dir.path().to_path_buf())
+            .guard::<GitCommandProvider>()
+            .await
+            .expect("Finding dir should succeed")
+            .open()
+            .expect("should find git repo")
+            .guard()
+            .await
+            .expect("Openeing project dir should succeed")
+            .init_project::<NixCommandLine>(Vec::new())
+            .await
+            .expect("Should init a new project");
+
+        let (project, mut index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+        project.create_default_env(&mut index).await.unwrap();
+        let project = project
+            .commit_transaction(index, "unused", false)
+            .await
+            .expect("Should commit transaction")
+            .committed()
+            .expect("not a dry run");
+
+        let copy = project
+            .copy_environment::<NixCommandLine>("default", "dev")
+            .await
+            .expect("should copy environment");
+        assert_eq!(copy.name(), "dev");
+        assert!(project_dir.path().join("pkgs/dev/flox.nix").exists());
+
+        project
+            .environment::<NixCommandLine>("dev")
+            .await
+            .expect("should find copied environment");
+
+        assert!(matches!(
+            project
+                .copy_environment::<NixCommandLine>("dev", "default")
+                .await,
+            Err(CopyEnvironmentError::AlreadyExists(name)) if name == "default"
+        ));
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn rename_environment() {
+        use runix::command_line::NixCommandLine;
+
+        let temp_home = tempfile::tempdir().unwrap();
+        env::set_var("HOME", temp_home.path());
+
+        let (flox, tempdir_handle) = flox_instance();
+
+        let project_dir = tempfile::tempdir_in(tempdir_handle.path()).unwrap();
+        let project_git = GitCommandProvider::init(project_dir.path(), false)
+            .await
+            .expect("should create git repo");
+
+        let project = flox
+            .resource(project_dir.path().to_path_buf())
+            .guard::<GitCommandProvider>()
+            .await
+            .expect("Finding dir should succeed")
+            .open()
+            .expect("should find git repo")
+            .guard()
+            .await
+            .expect("Openeing project dir should succeed")
+            .init_project::<NixCommandLine>(Vec::new())
+            .await
+            .expect("Should init a new project");
+
+        let (project, mut index) = project
+            .enter_transaction()
+            .await
+            .expect("Should be able to make sandbox");
+        project.create_default_env(&mut index).await.unwrap();
+        let project = project
+            .commit_transaction(index, "unused", false)
+            .await
+            .expect("Should commit transaction")
+            .committed()
+            .expect("not a dry run");
+        project
+            .copy_environment::<NixCommandLine>("default", "dev")
+            .await
+            .expect("should copy environment");
+        project_git
+            .create_tag("flox/dev/stable", "HEAD", Some("generation 1"))
+            .await
+            .unwrap();
+
+        let renamed = project
+            .rename_environment::<NixCommandLine>("dev", "staging")
+            .await
+            .expect("should rename environment");
+        assert_eq!(renamed.name(), "staging");
+        assert!(project_dir.path().join("pkgs/staging/flox.nix").exists());
+        assert!(!project_dir.path().join("pkgs/dev").exists());
+
+        let tags: Vec<_> = project_git
+            .list_tags()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|tag| tag.name)
+            .collect();
+        assert_eq!(tags, ["flox/staging/stable"]);
+
+        assert!(matches!(
+            project
+                .rename_environment::<NixCommandLine>("dev", "other")
+                .await,
+            Err(RenameEnvironmentError::Move(CopyEnvironmentError::Source(
+                GetEnvironmentError::NotFound(name)
+            ))) if name == "dev"
+        ));
+        assert!(matches!(
+            project
+                .rename_environment::<NixCommandLine>("staging", "default")
+                .await,
+            Err(RenameEnvironmentError::Move(CopyEnvironmentError::AlreadyExists(name))) if name == "default"
+        ));
+    }
 }
@@ -0,0 +1,359 @@
+//! Package templates materialized into a plain directory
+//!
+//! Unlike [Project::init_flox_package](super::Project::init_flox_package),
+//! scaffolding does not need a git repository.
+//! Created files are left unstaged, so a repository can be initialized later.
+//! [Project::init_flox_package](super::Project::init_flox_package) stages them
+//! after the same [scaffold_package] step.
+
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+
+use log::{debug, info, warn};
+use runix::arguments::NixArgs;
+use runix::command::FlakeInit;
+use runix::installable::Installable;
+use runix::{NixBackend, Run};
+use thiserror::Error;
+
+use super::template::{self, TemplateCacheError};
+use super::{PACKAGE_NAME_PLACEHOLDER, PNAME_DECLARATION};
+use crate::flox::{Flox, FloxNixApi};
+use crate::models::events::FloxWarning;
+use crate::utils::errors::FloxErrorCode;
+use crate::utils::{find_and_replace, FindAndReplaceError};
+
+/// Materialize `template` in `dir` as package `name`, see [Flox::scaffold]
+///
+/// Returns the created files relative to `dir`.
+/// If any step fails, the steps performed so far are undone, see [rollback].
+pub(crate) async fn scaffold<Nix: FloxNixApi>(
+    flox: &Flox,
+    dir: &Path,
+    template: Installable,
+    name: &str,
+) -> Result<Vec<PathBuf>, ScaffoldError<Nix>>
+where
+    FlakeInit: Run<Nix>,
+{
+    let mut steps = Vec::new();
+    if let Err(e) =
+        scaffold_package::<Nix>(flox, Vec::new(), dir, &template, name, &mut steps).await
+    {
+        rollback(&steps).await;
+        return Err(e);
+    }
+
+    Ok(created_files(&steps)
+        .into_iter()
+        .filter_map(|path| path.strip_prefix(dir).ok().map(Path::to_path_buf))
+        .collect())
+}
+
+/// A change to the directory made by [scaffold_package], undone on failure
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum ScaffoldStep {
+    /// A file or directory that did not exist before
+    Created(PathBuf),
+    /// A moved file or directory
+    Moved { from: PathBuf, to: PathBuf },
+}
+
+/// Materialize `template` in `dir` as package `name`
+///
+/// Runs `nix flake init` with `nix_extra_args` unless the template is cached,
+/// then moves the package of the template to `pkgs/<name>` and fills in its name.
+/// Every change to `dir` is recorded in `steps` as it is made.
+pub(super) async fn scaffold_package<Nix: FloxNixApi>(
+    flox: &Flox,
+    nix_extra_args: Vec<String>,
+    dir: &Path,
+    template: &Installable,
+    name: &str,
+    steps: &mut Vec<ScaffoldStep>,
+) -> Result<(), ScaffoldError<Nix>>
+where
+    FlakeInit: Run<Nix>,
+{
+    create_dir_all(dir, steps).await?;
+
+    let nix = flox.nix::<Nix>(nix_extra_args);
+    let flake_init = FlakeInit {
+        template: Some(template.to_string().into()),
+        ..Default::default()
+    };
+    let mut created = Vec::new();
+    let init = template::init_template(flox, template, dir, &mut created, |staging| async move {
+        flox.fetch_flake::<Nix>(&template.flakeref).await;
+        flake_init
+            .run(&nix, &NixArgs {
+                cwd: staging.into(),
+                ..NixArgs::default()
+            })
+            .await
+            .map_err(ScaffoldError::NixInit)
+    })
+    .await;
+    steps.extend(created.into_iter().map(ScaffoldStep::Created));
+    init?;
+
+    let old_package_path = dir.join("pkgs/default.nix");
+    match tokio::fs::read_to_string(&old_package_path).await {
+        // legacy path. Drop after we merge template changes to floxpkgs
+        Ok(package_contents) => {
+            if flox.reject_legacy_layout {
+                return Err(ScaffoldError::LegacyLayout);
+            }
+
+            // only log the warning once, a sink receives it for every init
+            static LEGACY_LAYOUT_WARNING: Once = Once::new();
+            match flox.event_sink {
+                Some(_) => flox.report_warning(FloxWarning::LegacyLayout),
+                None => LEGACY_LAYOUT_WARNING
+                    .call_once(|| flox.report_warning(FloxWarning::LegacyLayout)),
+            }
+
+            let new_package_dir = dir.join("pkgs").join(name);
+            create_dir_all(&new_package_dir, steps).await?;
+            let new_package_path = new_package_dir.join("default.nix");
+            rename(&old_package_path, &new_package_path, steps).await?;
+
+            let new_contents =
+                PNAME_DECLARATION.replace(&package_contents, format!(r#"pname = "{name}""#));
+            tokio::fs::write(&new_package_path, new_contents.as_bytes())
+                .await
+                .map_err(|e| ScaffoldError::Write(new_package_path, e))?;
+
+            // this might technically be a lie, but it's close enough :)
+            info!("renamed: pkgs/default.nix -> pkgs/{name}/default.nix");
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let old_proto_pkg_path = dir.join("pkgs").join(PACKAGE_NAME_PLACEHOLDER);
+            if !old_proto_pkg_path.exists() {
+                return Ok(());
+            }
+
+            let new_proto_pkg_path = dir.join("pkgs").join(name);
+            rename(&old_proto_pkg_path, &new_proto_pkg_path, steps).await?;
+            info!(
+                "moved: {} -> {}",
+                old_proto_pkg_path.to_string_lossy(),
+                new_proto_pkg_path.to_string_lossy()
+            );
+
+            // our minimal "templating" - Replace any occurrences of
+            // PACKAGE_NAME_PLACEHOLDER with name
+            find_and_replace(&new_proto_pkg_path, PACKAGE_NAME_PLACEHOLDER, name)
+                .await
+                .map_err(ScaffoldError::ReplacePackageName)?;
+        },
+        Err(e) => return Err(ScaffoldError::Read(old_package_path, e)),
+    }
+    Ok(())
+}
+
+/// Create `dir` and its missing parents, recording them in `steps`
+async fn create_dir_all<Nix: NixBackend>(
+    dir: &Path,
+    steps: &mut Vec<ScaffoldStep>,
+) -> Result<(), ScaffoldError<Nix>>
+where
+    FlakeInit: Run<Nix>,
+{
+    let missing: Vec<PathBuf> = dir
+        .ancestors()
+        .take_while(|dir| !dir.exists())
+        .map(Path::to_path_buf)
+        .collect();
+    debug!("creating dir: {}", dir.display());
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|e| ScaffoldError::CreateDir(dir.to_path_buf(), e))?;
+    steps.extend(missing.into_iter().rev().map(ScaffoldStep::Created));
+    Ok(())
+}
+
+/// Move `from` to `to` unless it exists, recording the move in `steps`
+async fn rename<Nix: NixBackend>(
+    from: &Path,
+    to: &Path,
+    steps: &mut Vec<ScaffoldStep>,
+) -> Result<(), ScaffoldError<Nix>>
+where
+    FlakeInit: Run<Nix>,
+{
+    if tokio::fs::symlink_metadata(to).await.is_ok() {
+        return Err(ScaffoldError::Exists(to.to_path_buf()));
+    }
+    tokio::fs::rename(from, to)
+        .await
+        .map_err(|e| ScaffoldError::Rename(from.to_path_buf(), e))?;
+    steps.push(ScaffoldStep::Moved {
+        from: from.to_path_buf(),
+        to: to.to_path_buf(),
+    });
+    Ok(())
+}
+
+/// Files the `steps` of a successful [scaffold_package] left behind, sorted
+///
+/// That is the created files and everything moved into place,
+/// files created in created directories by other tools are not included.
+pub(super) fn created_files(steps: &[ScaffoldStep]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for step in steps {
+        match step {
+            ScaffoldStep::Created(path) if path.is_file() => files.push(path.clone()),
+            ScaffoldStep::Created(_) => {},
+            ScaffoldStep::Moved { to, .. } => files.extend(
+                walkdir::WalkDir::new(to)
+                    .into_iter()
+                    .filter_map(Result::ok)
+                    .filter(|entry| !entry.file_type().is_dir())
+                    .map(walkdir::DirEntry::into_path),
+            ),
+        }
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Undo the `steps` of a failed [scaffold_package], latest first
+///
+/// Moves are reverted and created files removed.
+/// Created directories are only removed if they are empty,
+/// keeping files other tools created meanwhile.
+/// Failures are only logged, as the error of the scaffold is more relevant.
+pub(super) async fn rollback(steps: &[ScaffoldStep]) {
+    for step in steps.iter().rev() {
+        let result = match step {
+            ScaffoldStep::Moved { from, to } => tokio::fs::rename(to, from).await,
+            ScaffoldStep::Created(path) => match tokio::fs::symlink_metadata(path).await {
+                Ok(metadata) if metadata.is_dir() => match tokio::fs::remove_dir(path).await {
+                    Err(e) if e.kind() == std::io::ErrorKind::DirectoryNotEmpty => {
+                        debug!("Keeping {}, it is not empty", path.display());
+                        Ok(())
+                    },
+                    result => result,
+                },
+                Ok(_) => tokio::fs::remove_file(path).await,
+                // already moved or removed by a later step
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            warn!("Failed to undo {step:?}: {e}");
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ScaffoldError<Nix: NixBackend>
+where
+    FlakeInit: Run<Nix>,
+{
+    #[error("Error initializing template with Nix")]
+    NixInit(<FlakeInit as Run<Nix>>::Error),
+    #[error(transparent)]
+    TemplateCache(#[from] TemplateCacheError),
+    #[error("The template uses the unsupported 'pkgs/default.nix' layout")]
+    LegacyLayout,
+    #[error("Failed to create {0:?}: {1}")]
+    CreateDir(PathBuf, std::io::Error),
+    #[error("Failed to read {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to write {0:?}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("Failed to move {0:?} to the package directory: {1}")]
+    Rename(PathBuf, std::io::Error),
+    #[error("Package directory {0:?} already exists")]
+    Exists(PathBuf),
+    #[error("Error replacing {}: {0}", PACKAGE_NAME_PLACEHOLDER)]
+    ReplacePackageName(FindAndReplaceError),
+}
+
+impl<Nix: NixBackend> ScaffoldError<Nix>
+where
+    FlakeInit: Run<Nix>,
+{
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            ScaffoldError::NixInit(_) => FloxErrorCode::Nix,
+            ScaffoldError::TemplateCache(_)
+            | ScaffoldError::LegacyLayout
+            | ScaffoldError::ReplacePackageName(_) => FloxErrorCode::Template,
+            ScaffoldError::Exists(_) => FloxErrorCode::Conflict,
+            ScaffoldError::CreateDir(..)
+            | ScaffoldError::Read(..)
+            | ScaffoldError::Write(..)
+            | ScaffoldError::Rename(..) => FloxErrorCode::Io,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "impure-unit-tests"))]
+mod tests {
+    use runix::command_line::NixCommandLine;
+
+    use super::*;
+    use crate::providers::git::{GitCommandProvider, GitProvider};
+
+    #[tokio::test]
+    async fn scaffolds_without_git() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("caches"),
+            temp_dir: tempdir.path().join("temp"),
+            config_dir: tempdir.path().join("config"),
+            ..Default::default()
+        };
+
+        let template_dir = tempdir.path().join("template");
+        std::fs::create_dir_all(template_dir.join("package/pkgs/__PACKAGE_NAME__")).unwrap();
+        std::fs::write(
+            template_dir.join("flake.nix"),
+            r#"{ outputs = _: { templates.package = { path = ./package; description = "package"; }; }; }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            template_dir.join("package/pkgs/__PACKAGE_NAME__/default.nix"),
+            r#"{ pname = "__PACKAGE_NAME__"; }"#,
+        )
+        .unwrap();
+        let template_git = GitCommandProvider::init(&template_dir, false)
+            .await
+            .unwrap();
+        template_git.add(&[Path::new(".")]).await.unwrap();
+        template_git.commit("add template").await.unwrap();
+        let template = Installable::new(
+            format!("git+file://{}", template_dir.display()),
+            "templates.package".to_string(),
+        );
+
+        let dir = tempdir.path().join("scaffold");
+        let created = flox
+            .scaffold::<NixCommandLine>(&dir, template.clone(), "hello")
+            .await
+            .unwrap();
+        assert_eq!(created, [PathBuf::from("pkgs/hello/default.nix")]);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("pkgs/hello/default.nix")).unwrap(),
+            r#"{ pname = "hello"; }"#
+        );
+        assert!(!dir.join(".git").exists());
+
+        // an existing package is not overwritten, and the template files are removed again
+        assert!(matches!(
+            flox.scaffold::<NixCommandLine>(&dir, template, "hello")
+                .await,
+            Err(ScaffoldError::Exists(_))
+        ));
+        assert!(!dir.join("pkgs/__PACKAGE_NAME__").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("pkgs/hello/default.nix")).unwrap(),
+            r#"{ pname = "hello"; }"#
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use super::{Project, ProjectError};
+use crate::flox::FloxNixApi;
+use crate::models::root::transaction::GitAccess;
+use crate::providers::fs::FileSystem;
+use crate::providers::git::GitProvider;
+
+/// Typed output of `nix flake show --json`
+///
+/// Maps output names (`packages`, `apps`, `devShells`, `floxEnvs`, ...)
+/// to their attribute trees.
+pub type FlakeOutputs = BTreeMap<String, FlakeOutput>;
+
+/// A node in the output tree of a flake
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum FlakeOutput {
+    /// An evaluated output such as a derivation or an app
+    Leaf(FlakeOutputLeaf),
+    /// A nested attribute set, e.g. the systems of `packages`
+    Attrs(BTreeMap<String, FlakeOutput>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FlakeOutputLeaf {
+    /// Kind of output as reported by nix (`derivation`, `app`, `unknown`, ...)
+    #[serde(rename = "type")]
+    pub output_type: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+impl FlakeOutput {
+    /// Collect all leaves below this node together with their attribute path
+    pub fn leaves(&self) -> Vec<(Vec<String>, &FlakeOutputLeaf)> {
+        fn collect<'a>(
+            node: &'a FlakeOutput,
+            path: &mut Vec<String>,
+            leaves: &mut Vec<(Vec<String>, &'a FlakeOutputLeaf)>,
+        ) {
+            match node {
+                FlakeOutput::Leaf(leaf) => leaves.push((path.clone(), leaf)),
+                FlakeOutput::Attrs(attrs) => {
+                    for (name, child) in attrs {
+                        path.push(name.clone());
+                        collect(child, path, leaves);
+                        path.pop();
+                    }
+                },
+            }
+        }
+
+        let mut leaves = Vec::new();
+        collect(self, &mut Vec::new(), &mut leaves);
+        leaves
+    }
+}
+
+/// Typed output of `nix flake metadata --json`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectMetadata {
+    pub description: Option<String>,
+    /// Git revision of the project, unset if the working tree is dirty
+    pub revision: Option<String>,
+    /// Time of the last modification in seconds since the unix epoch
+    pub last_modified: Option<u64>,
+    pub resolved_url: String,
+}
+
+impl<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem>
+    Project<'flox, Git, Access, Fs>
+{
+    /// List all outputs of the project's flake
+    ///
+    /// Nix runs only once per [Project], later calls return the cached outputs.
+    pub async fn flake_show<Nix: FloxNixApi>(&self) -> Result<FlakeOutputs, FlakeShowError> {
+        self.flake_outputs
+            .get_or_try_init(|| self.run_flake_show::<Nix>())
+            .await
+            .cloned()
+    }
+
+    async fn run_flake_show<Nix: FloxNixApi>(&self) -> Result<FlakeOutputs, FlakeShowError> {
+        let output = self
+            .flox
+            .nix::<Nix>(Default::default())
+            .command(&["flake", "show"])
+            .arg("--json")
+            .arg(self.flakeref().await?)
+            .output()
+            .await
+            .map_err(FlakeShowError::Spawn)?;
+
+        if !output.status.success() {
+            return Err(FlakeShowError::BadExit(
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        serde_json::from_slice(&output.stdout).map_err(FlakeShowError::Parse)
+    }
+
+    /// Read the description and source information of the project's flake
+    pub async fn metadata<Nix: FloxNixApi>(&self) -> Result<ProjectMetadata, FlakeMetadataError> {
+        let output = self
+            .flox
+            .nix::<Nix>(Default::default())
+            .command(&["flake", "metadata"])
+            .arg("--json")
+            .arg(self.flakeref().await?)
+            .output()
+            .await
+            .map_err(FlakeMetadataError::Spawn)?;
+
+        if !output.status.success() {
+            return Err(FlakeMetadataError::BadExit(
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        serde_json::from_slice(&output.stdout).map_err(FlakeMetadataError::Parse)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FlakeShowError {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+    #[error("Nix flake show failed with exit code {0}:\n{1}")]
+    BadExit(i32, String),
+    #[error("Failed to parse flake outputs: {0}")]
+    Parse(serde_json::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum FlakeMetadataError {
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+    #[error("Nix flake metadata failed with exit code {0}:\n{1}")]
+    BadExit(i32, String),
+    #[error("Failed to parse flake metadata: {0}")]
+    Parse(serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_flake_show_output() {
+        let json = r#"{
+            "packages": {
+                "x86_64-linux": {
+                    "hello": {
+                        "type": "derivation",
+                        "name": "hello-2.12.1",
+                        "description": "A program that produces a familiar, friendly greeting"
+                    }
+                },
+                "aarch64-darwin": {}
+            },
+            "floxEnvs": {
+                "x86_64-linux": {
+                    "default": { "type": "derivation", "name": "floxenv" }
+                }
+            },
+            "lib": { "type": "unknown" }
+        }"#;
+
+        let outputs: FlakeOutputs = serde_json::from_str(json).unwrap();
+
+        assert_eq!(
+            outputs["lib"],
+            FlakeOutput::Leaf(FlakeOutputLeaf {
+                output_type: "unknown".to_string(),
+                name: None,
+                description: None,
+            })
+        );
+
+        let packages = outputs["packages"].leaves();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].0, ["x86_64-linux", "hello"]);
+        assert_eq!(packages[0].1.name.as_deref(), Some("hello-2.12.1"));
+
+        let envs = outputs["floxEnvs"].leaves();
+        assert_eq!(envs[0].0, ["x86_64-linux", "default"]);
+        assert_eq!(envs[0].1.description, None);
+    }
+
+    #[test]
+    fn parses_flake_metadata_output() {
+        let json = r#"{
+            "description": "A flox project",
+            "lastModified": 1680000000,
+            "locked": { "lastModified": 1680000000, "type": "git", "url": "file:///home/user/project" },
+            "locks": { "nodes": { "root": {} }, "root": "root", "version": 7 },
+            "original": { "type": "git", "url": "file:///home/user/project" },
+            "originalUrl": "git+file:///home/user/project",
+            "path": "/nix/store/00000000000000000000000000000000-source",
+            "resolved": { "type": "git", "url": "file:///home/user/project" },
+            "resolvedUrl": "git+file:///home/user/project",
+            "revision": "0f6d1b6b1d0e4a7f1b1a3c7e2c1d9e8f7a6b5c4d",
+            "url": "git+file:///home/user/project?rev=0f6d1b6b1d0e4a7f1b1a3c7e2c1d9e8f7a6b5c4d"
+        }"#;
+
+        let metadata: ProjectMetadata = serde_json::from_str(json).unwrap();
+        assert_eq!(metadata, ProjectMetadata {
+            description: Some("A flox project".to_string()),
+            revision: Some("0f6d1b6b1d0e4a7f1b1a3c7e2c1d9e8f7a6b5c4d".to_string()),
+            last_modified: Some(1680000000),
+            resolved_url: "git+file:///home/user/project".to_string(),
+        });
+
+        // dirty working trees have no revision
+        let metadata: ProjectMetadata =
+            serde_json::from_str(r#"{ "resolvedUrl": "git+file:///home/user/project" }"#).unwrap();
+        assert_eq!(metadata.revision, None);
+        assert_eq!(metadata.description, None);
+    }
+}
@@ -0,0 +1,156 @@
+//! Overview of the state of a project, e.g. for a status dashboard
+
+use std::path::Path;
+
+use futures::future::join_all;
+use runix::command::Eval;
+use runix::{NixBackend, RunJson};
+use serde::Deserialize;
+
+use super::environment::HistoryError;
+use super::lock::{LockDrift, LockDriftError};
+use super::show::FlakeMetadataError;
+use super::{GetEnvironmentsError, Project, ProjectError};
+use crate::flox::FloxNixApi;
+use crate::models::root::transaction::GitAccess;
+use crate::providers::fs::{FileKind, FileSystem};
+use crate::providers::git::GitProvider;
+
+/// Version of the flake.lock format written by supported versions of nix
+const FLAKE_LOCK_VERSION: u64 = 7;
+
+/// Outcome of [Project::status]
+///
+/// Each section is queried on its own, so a failing section does not hide the others.
+#[derive(Debug)]
+pub struct ProjectStatus<Nix: NixBackend, Git: GitProvider>
+where
+    Eval: RunJson<Nix>,
+{
+    /// Whether all changes to the project are committed
+    pub clean: Result<bool, FlakeMetadataError>,
+    pub environments: Result<Vec<EnvironmentStatus<Git>>, GetEnvironmentsError<Nix>>,
+    /// Outdated or unsupported formats of the project's files
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct EnvironmentStatus<Git: GitProvider> {
+    pub name: String,
+    pub system: String,
+    /// Latest generation, [None] if the environment was never committed
+    pub generation: Result<Option<usize>, HistoryError<Git>>,
+    pub lock: Result<LockDrift, LockDriftError>,
+}
+
+impl<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem>
+    Project<'flox, Git, Access, Fs>
+{
+    /// Summarize git state, environments, their locks and format warnings of this project
+    ///
+    /// Sections are queried concurrently and fail independently,
+    /// only a missing workdir fails the status as a whole.
+    pub async fn status<Nix: FloxNixApi>(
+        &'flox self,
+    ) -> Result<ProjectStatus<Nix, Git>, ProjectError>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let root = self.flakeref().await?;
+
+        let (clean, environments, warnings) = futures::join!(
+            async {
+                // nix reports no revision for dirty working trees
+                self.metadata::<Nix>()
+                    .await
+                    .map(|metadata| metadata.revision.is_some())
+            },
+            self.environment_statuses::<Nix>(),
+            self.format_warnings(Path::new(&root)),
+        );
+
+        Ok(ProjectStatus {
+            clean,
+            environments,
+            warnings,
+        })
+    }
+
+    async fn environment_statuses<Nix: FloxNixApi>(
+        &'flox self,
+    ) -> Result<Vec<EnvironmentStatus<Git>>, GetEnvironmentsError<Nix>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let environments = self.environments::<Nix>().await?;
+
+        Ok(join_all(environments.iter().map(|environment| async move {
+            let (history, lock) = futures::join!(environment.history(1), environment.lock_drift());
+            EnvironmentStatus {
+                name: environment.name().into_owned(),
+                system: environment.system().into_owned(),
+                generation: history.map(|history| history.first().map(|entry| entry.generation)),
+                lock,
+            }
+        }))
+        .await)
+    }
+
+    async fn format_warnings(&self, root: &Path) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        let flake_lock = root.join("flake.lock");
+        match self.fs.read(&flake_lock).await {
+            Ok(contents) => warnings.extend(flake_lock_warning(&contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => warnings.push(format!("Could not read {flake_lock:?}: {e}")),
+        }
+
+        if let Ok(Some(FileKind::File)) = self.fs.kind(&root.join("pkgs/default.nix")).await {
+            warnings.push(
+                "The project uses the deprecated 'pkgs/default.nix' layout, \
+                 move the package to 'pkgs/<name>/default.nix'"
+                    .to_string(),
+            );
+        }
+
+        warnings
+    }
+}
+
+/// Warn about flake.lock files nix may not read as expected
+fn flake_lock_warning(contents: &[u8]) -> Option<String> {
+    #[derive(Deserialize)]
+    struct FlakeLock {
+        version: u64,
+    }
+
+    match serde_json::from_slice::<FlakeLock>(contents) {
+        Ok(FlakeLock {
+            version: FLAKE_LOCK_VERSION,
+        }) => None,
+        Ok(FlakeLock { version }) => Some(format!(
+            "flake.lock has version {version}, expected version {FLAKE_LOCK_VERSION}"
+        )),
+        Err(e) => Some(format!("flake.lock is invalid: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_about_flake_lock_versions() {
+        let lock = |version: u64| {
+            format!(r#"{{ "nodes": {{ "root": {{}} }}, "root": "root", "version": {version} }}"#)
+        };
+
+        assert_eq!(flake_lock_warning(lock(7).as_bytes()), None);
+        assert_eq!(
+            flake_lock_warning(lock(5).as_bytes()).as_deref(),
+            Some("flake.lock has version 5, expected version 7")
+        );
+        assert!(flake_lock_warning(b"not json").is_some());
+    }
+}
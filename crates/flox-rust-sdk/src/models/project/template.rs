@@ -0,0 +1,746 @@
+//! Cache of fetched project templates
+//!
+//! `nix flake init` fetches a template each time it is used.
+//! Templates of flakes with a locked revision are kept in
+//! `<cache_dir>/templates/<rev>/` after their first use instead,
+//! later inits with the same revision copy the cached files.
+//! The revision a remote flakeref resolves to is recorded in `<cache_dir>/templates/refs/`,
+//! so that cached templates can be used without network access.
+
+use std::future::Future;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, warn};
+use runix::command_line::NixCommandLine;
+use runix::installable::Installable;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::process::Command;
+
+use super::environment::content_hash;
+use crate::flox::{Flox, FloxNixApi};
+use crate::utils::errors::IoError;
+use crate::utils::{copy_file_with_mode, copy_file_without_permissions};
+
+/// Directory in [Flox::cache_dir] holding cached templates
+pub const TEMPLATE_CACHE_DIR: &str = "templates";
+
+/// How long a recorded resolution of a remote flakeref is used without asking nix,
+/// like nix's default `tarball-ttl`
+const RESOLUTION_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Typed subset of `nix flake metadata --json`
+#[derive(Debug, Deserialize)]
+struct FlakeMetadata {
+    /// Locked flakeref of the flake
+    url: String,
+    /// Store path of the flake source
+    path: PathBuf,
+    locked: LockedFlake,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LockedFlake {
+    rev: Option<String>,
+    nar_hash: Option<String>,
+}
+
+/// What a flakeref resolved to, recorded to resolve it again without nix
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Resolution {
+    /// Locked flakeref of the flake
+    url: String,
+    /// Store path of the flake source
+    path: PathBuf,
+    rev: String,
+    nar_hash: String,
+}
+
+/// Metadata stored next to a filled cache entry, see [CachedTemplate::is_filled]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    /// Nar hash of the flake the template was taken from
+    nar_hash: String,
+    /// [tree_hash] of the cached files
+    tree_hash: String,
+}
+
+/// A template in the cache, which may not have been filled yet
+#[derive(Debug)]
+struct CachedTemplate {
+    /// Template with its flakeref locked to [Self::dir]'s revision
+    locked: String,
+    /// Source of the flake in the nix store
+    source: PathBuf,
+    nar_hash: String,
+    dir: PathBuf,
+}
+
+impl CachedTemplate {
+    /// Locate `template` in the cache
+    ///
+    /// Remote flakerefs resolved within the last [RESOLUTION_TTL] are not resolved again.
+    /// If nix fails to resolve a remote flakeref, e.g. without network access,
+    /// its last recorded resolution is used regardless of its age.
+    /// Local flakerefs are always resolved by nix, which needs no network for them.
+    /// Templates without a locked revision (e.g. of a dirty local flake) are not cached.
+    async fn resolve(
+        flox: &Flox,
+        template: &Installable,
+    ) -> Result<Option<Self>, TemplateCacheError> {
+        let record =
+            (!is_local(&template.flakeref)).then(|| resolution_file(flox, &template.flakeref));
+        let recorded = match &record {
+            Some(record) => read_resolution(record).await,
+            None => None,
+        };
+
+        let resolution = match recorded {
+            Some((resolution, age)) if age < RESOLUTION_TTL => resolution,
+            recorded => match lock_flake(flox, &template.flakeref).await {
+                Ok(Some(resolution)) => {
+                    if let Some(record) = &record {
+                        if let Err(e) = write_resolution(record, &resolution).await {
+                            debug!("Failed to record the resolution of a template: {e}");
+                        }
+                    }
+                    resolution
+                },
+                Ok(None) => return Ok(None),
+                Err(e) => match recorded {
+                    Some((resolution, _)) => {
+                        debug!(
+                            "Failed to resolve {}, using its last resolution: {e}",
+                            template.flakeref
+                        );
+                        resolution
+                    },
+                    None => return Err(e),
+                },
+            },
+        };
+
+        let dir = flox
+            .cache_dir
+            .join(TEMPLATE_CACHE_DIR)
+            .join(&resolution.rev)
+            .join(content_hash(&[template.attr_path.as_bytes()]));
+
+        Ok(Some(CachedTemplate {
+            locked: format!("{}#{}", resolution.url, template.attr_path),
+            source: resolution.path,
+            nar_hash: resolution.nar_hash,
+            dir,
+        }))
+    }
+
+    /// File recording the [CacheEntry] of the cached template
+    fn entry_file(&self) -> PathBuf {
+        self.dir.with_extension("json")
+    }
+
+    /// Whether the template was cached from a flake with the expected nar hash
+    /// and the cached files are unchanged since
+    async fn is_filled(&self) -> bool {
+        let entry: CacheEntry = match tokio::fs::read(self.entry_file()).await {
+            Ok(entry) => match serde_json::from_slice(&entry) {
+                Ok(entry) => entry,
+                Err(_) => return false,
+            },
+            Err(_) => return false,
+        };
+        if entry.nar_hash != self.nar_hash {
+            return false;
+        }
+        match tree_hash(&self.dir).await {
+            Ok(tree_hash) if tree_hash == entry.tree_hash => true,
+            Ok(_) => {
+                warn!(
+                    "Cached template {:?} was modified, fetching it again",
+                    self.dir
+                );
+                false
+            },
+            Err(e) => {
+                debug!("Failed to verify cached template {:?}: {e}", self.dir);
+                false
+            },
+        }
+    }
+
+    /// Copy the template from the nix store into the cache
+    ///
+    /// The flake source is verified against the nar hash nix locked it with.
+    async fn fill(&self, flox: &Flox) -> Result<(), TemplateCacheError> {
+        let output = nix_command(flox, &["hash", "path"])
+            .arg(&self.source)
+            .output()
+            .await
+            .map_err(TemplateCacheError::Spawn)?;
+        let actual = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || actual != self.nar_hash {
+            return Err(TemplateCacheError::HashMismatch {
+                expected: self.nar_hash.clone(),
+                actual,
+            });
+        }
+
+        let output = nix_command(flox, &["eval"])
+            .arg("--raw")
+            .arg(format!("{}.path", self.locked))
+            .output()
+            .await
+            .map_err(TemplateCacheError::Spawn)?;
+        if !output.status.success() {
+            return Err(TemplateCacheError::BadExit(
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+        let template_path = PathBuf::from(String::from_utf8_lossy(&output.stdout).into_owned());
+
+        // copy next to the final location first, so that no partial template is ever used
+        let parent = self
+            .dir
+            .parent()
+            .expect("cache entries are within the cache dir");
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| TemplateCacheError::Write(parent.to_path_buf(), e))?;
+        let staging = tempfile::tempdir_in(parent)
+            .map_err(|e| TemplateCacheError::Write(parent.to_path_buf(), e))?;
+        copy_tree(&template_path, staging.path()).await?;
+
+        if tokio::fs::metadata(&self.dir).await.is_ok() {
+            tokio::fs::remove_dir_all(&self.dir)
+                .await
+                .map_err(|e| TemplateCacheError::Write(self.dir.clone(), e))?;
+        }
+        tokio::fs::rename(staging.into_path(), &self.dir)
+            .await
+            .map_err(|e| TemplateCacheError::Write(self.dir.clone(), e))?;
+
+        let entry = CacheEntry {
+            nar_hash: self.nar_hash.clone(),
+            tree_hash: tree_hash(&self.dir).await?,
+        };
+        let entry = serde_json::to_vec(&entry).expect("cache entries serialize");
+        tokio::fs::write(self.entry_file(), entry)
+            .await
+            .map_err(|e| TemplateCacheError::Write(self.entry_file(), e))
+    }
+}
+
+/// Initialize `dir` from `template`, using the template cache if possible
+///
+/// Runs `flake_init` to fetch the template with nix into the given empty directory
+/// if it is not cached yet.
+/// The template is then copied into `dir`, recording the created files and directories,
+/// parents first, in `created`, also if copying fails.
+/// Created files are not staged.
+/// Failures to use the cache only fall back to `flake_init`,
+/// conflicts with existing files are reported.
+pub(super) async fn init_template<E, Fut>(
+    flox: &Flox,
+    template: &Installable,
+    dir: &Path,
+    created: &mut Vec<PathBuf>,
+    flake_init: impl FnOnce(PathBuf) -> Fut,
+) -> Result<(), E>
+where
+    Fut: Future<Output = Result<(), E>>,
+    E: From<TemplateCacheError>,
+{
+    let cached = CachedTemplate::resolve(flox, template)
+        .await
+        .unwrap_or_else(|e| {
+            warn!(
+                "Not using the template cache for {}: {e}",
+                template.to_nix()
+            );
+            None
+        });
+
+    match cached {
+        Some(cached) if cached.is_filled().await => {
+            debug!(
+                "Using cached template {} from {:?}",
+                template.to_nix(),
+                cached.dir
+            );
+            copy_template(
+                &cached.dir,
+                dir,
+                flox.permissions.project_file_mode,
+                created,
+            )
+            .await?;
+        },
+        cached => {
+            tokio::fs::create_dir_all(&flox.temp_dir)
+                .await
+                .map_err(|e| TemplateCacheError::Write(flox.temp_dir.clone(), e))?;
+            let staging = tempfile::tempdir_in(&flox.temp_dir)
+                .map_err(|e| TemplateCacheError::Write(flox.temp_dir.clone(), e))?;
+            flake_init(staging.path().to_path_buf()).await?;
+            copy_template(
+                staging.path(),
+                dir,
+                flox.permissions.project_file_mode,
+                created,
+            )
+            .await?;
+
+            if let Some(cached) = cached {
+                if let Err(e) = cached.fill(flox).await {
+                    warn!("Failed to cache template {}: {e}", template.to_nix());
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Copy the template files in `from` into `dir`, recording created entries in `created`
+///
+/// Like `nix flake init`, existing files are kept if identical and a conflict otherwise.
+/// Conflicts are detected before anything is copied.
+/// Created files get `file_mode` if set.
+async fn copy_template(
+    from: &Path,
+    dir: &Path,
+    file_mode: Option<u32>,
+    created: &mut Vec<PathBuf>,
+) -> Result<(), TemplateCacheError> {
+    let mut entries = Vec::new();
+    for entry in walkdir::WalkDir::new(from).min_depth(1).sort_by_file_name() {
+        let entry = entry.map_err(TemplateCacheError::Walkdir)?;
+        let target = dir.join(
+            entry
+                .path()
+                .strip_prefix(from)
+                .expect("walkdir only yields entries below the root"),
+        );
+        let existing = match tokio::fs::symlink_metadata(&target).await {
+            Ok(metadata) => Some(metadata),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(TemplateCacheError::Read(target, e)),
+        };
+
+        match existing {
+            Some(existing) if existing.is_dir() != entry.file_type().is_dir() => {
+                return Err(TemplateCacheError::Conflict(target));
+            },
+            Some(existing) if !existing.is_dir() => {
+                let existing = tokio::fs::read(&target)
+                    .await
+                    .map_err(|e| TemplateCacheError::Read(target.clone(), e))?;
+                let template = tokio::fs::read(entry.path())
+                    .await
+                    .map_err(|e| TemplateCacheError::Read(entry.path().to_path_buf(), e))?;
+                if existing != template {
+                    return Err(TemplateCacheError::Conflict(target));
+                }
+            },
+            Some(_) => {},
+            None => entries.push((entry, target)),
+        }
+    }
+
+    for (entry, target) in entries {
+        if entry.file_type().is_dir() {
+            tokio::fs::create_dir(&target)
+                .await
+                .map_err(|e| TemplateCacheError::Write(target.clone(), e))?;
+        } else {
+            copy_file_with_mode(entry.path(), &target, file_mode)
+                .await
+                .map_err(TemplateCacheError::Copy)?;
+        }
+        created.push(target);
+    }
+    Ok(())
+}
+
+/// Lock `flakeref` with `nix flake metadata`
+///
+/// Returns [None] if the flake has no locked revision.
+async fn lock_flake(flox: &Flox, flakeref: &str) -> Result<Option<Resolution>, TemplateCacheError> {
+    let output = nix_command(flox, &["flake", "metadata"])
+        .arg("--json")
+        .arg(flakeref)
+        .output()
+        .await
+        .map_err(TemplateCacheError::Spawn)?;
+    if !output.status.success() {
+        return Err(TemplateCacheError::BadExit(
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    let metadata: FlakeMetadata =
+        serde_json::from_slice(&output.stdout).map_err(TemplateCacheError::Parse)?;
+
+    match metadata.locked {
+        LockedFlake {
+            rev: Some(rev),
+            nar_hash: Some(nar_hash),
+        } => Ok(Some(Resolution {
+            url: metadata.url,
+            path: metadata.path,
+            rev,
+            nar_hash,
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// Whether `flakeref` refers to a flake on the local file system
+fn is_local(flakeref: &str) -> bool {
+    ["path:", "git+file:", "file:", "/", "."]
+        .iter()
+        .any(|prefix| flakeref.starts_with(prefix))
+}
+
+/// File recording the [Resolution] of `flakeref`
+fn resolution_file(flox: &Flox, flakeref: &str) -> PathBuf {
+    flox.cache_dir
+        .join(TEMPLATE_CACHE_DIR)
+        .join("refs")
+        .join(content_hash(&[flakeref.as_bytes()]))
+        .with_extension("json")
+}
+
+/// The recorded resolution in `file` and its age, [None] if there is no valid one
+async fn read_resolution(file: &Path) -> Option<(Resolution, Duration)> {
+    let modified = tokio::fs::metadata(file).await.ok()?.modified().ok()?;
+    let resolution = serde_json::from_slice(&tokio::fs::read(file).await.ok()?).ok()?;
+    // modification times in the future count as fresh
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    Some((resolution, age))
+}
+
+async fn write_resolution(file: &Path, resolution: &Resolution) -> std::io::Result<()> {
+    if let Some(parent) = file.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let contents = serde_json::to_vec(resolution).expect("resolutions serialize");
+    tokio::fs::write(file, contents).await
+}
+
+/// Hash of the names, kinds and contents of the entries in `dir`
+async fn tree_hash(dir: &Path) -> Result<String, TemplateCacheError> {
+    let mut parts = Vec::new();
+    for entry in walkdir::WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+        let entry = entry.map_err(TemplateCacheError::Walkdir)?;
+        let relative = entry
+            .path()
+            .strip_prefix(dir)
+            .expect("walkdir only yields entries below the root");
+        parts.push(relative.as_os_str().as_bytes().to_vec());
+        if entry.file_type().is_dir() {
+            parts.push(b"dir".to_vec());
+        } else {
+            parts.push(b"file".to_vec());
+            let contents = tokio::fs::read(entry.path())
+                .await
+                .map_err(|e| TemplateCacheError::Read(entry.path().to_path_buf(), e))?;
+            parts.push(contents);
+        }
+    }
+    let parts: Vec<&[u8]> = parts.iter().map(Vec::as_slice).collect();
+    Ok(content_hash(&parts))
+}
+
+/// Recursively copy the files of `from` into the existing directory `to`
+async fn copy_tree(from: &Path, to: &Path) -> Result<(), TemplateCacheError> {
+    for entry in walkdir::WalkDir::new(from).min_depth(1) {
+        let entry = entry.map_err(TemplateCacheError::Walkdir)?;
+        let target = to.join(
+            entry
+                .path()
+                .strip_prefix(from)
+                .expect("walkdir only yields entries below the root"),
+        );
+        if entry.file_type().is_dir() {
+            tokio::fs::create_dir_all(&target)
+                .await
+                .map_err(|e| TemplateCacheError::Write(target, e))?;
+        } else {
+            copy_file_without_permissions(entry.path(), &target)
+                .await
+                .map_err(TemplateCacheError::Copy)?;
+        }
+    }
+    Ok(())
+}
+
+/// A nix command configured like any other flox invocation
+fn nix_command(flox: &Flox, subcommand: &[&str]) -> Command {
+    let nix: NixCommandLine = flox.nix(Default::default());
+    nix.command(subcommand)
+}
+
+#[derive(Error, Debug)]
+pub enum TemplateCacheError {
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+    #[error("Nix failed with exit code {0}:\n{1}")]
+    BadExit(i32, String),
+    #[error("Failed to parse flake metadata: {0}")]
+    Parse(serde_json::Error),
+    #[error("Template source does not match its nar hash {expected}, found '{actual}'")]
+    HashMismatch { expected: String, actual: String },
+    #[error("Failed to list template files: {0}")]
+    Walkdir(walkdir::Error),
+    #[error("Failed to read {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to write {0:?}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error(transparent)]
+    Copy(IoError),
+    #[error("Template file {0:?} conflicts with an existing file")]
+    Conflict(PathBuf),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    fn flox_in(dir: &Path) -> Flox {
+        let flox = Flox {
+            cache_dir: dir.join("caches"),
+            temp_dir: dir.join("temp"),
+            config_dir: dir.join("config"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&flox.temp_dir).unwrap();
+        std::fs::create_dir_all(&flox.config_dir).unwrap();
+        flox
+    }
+
+    /// Record a resolution of `template` and fill its cache entry with a `hello.txt`,
+    /// without nix
+    async fn cache_template(flox: &Flox, template: &Installable) -> CachedTemplate {
+        let resolution = Resolution {
+            url: "github:flox/templates/0000000000000000000000000000000000000000".to_string(),
+            path: PathBuf::from("/nix/store/00000000000000000000000000000000-source"),
+            rev: "0000000000000000000000000000000000000000".to_string(),
+            nar_hash: "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string(),
+        };
+        write_resolution(&resolution_file(flox, &template.flakeref), &resolution)
+            .await
+            .unwrap();
+
+        let cached = CachedTemplate::resolve(flox, template)
+            .await
+            .unwrap()
+            .expect("should resolve from the record");
+        std::fs::create_dir_all(&cached.dir).unwrap();
+        std::fs::write(cached.dir.join("hello.txt"), "hello").unwrap();
+        let entry = CacheEntry {
+            nar_hash: cached.nar_hash.clone(),
+            tree_hash: tree_hash(&cached.dir).await.unwrap(),
+        };
+        std::fs::write(cached.entry_file(), serde_json::to_vec(&entry).unwrap()).unwrap();
+        cached
+    }
+
+    fn unexpected_init(_dir: PathBuf) -> std::future::Ready<Result<(), TemplateCacheError>> {
+        std::future::ready(Err(TemplateCacheError::BadExit(
+            1,
+            "should use the cached template".to_string(),
+        )))
+    }
+
+    #[tokio::test]
+    async fn uses_recorded_resolution_without_nix() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = flox_in(tempdir.path());
+        let template = Installable::new(
+            "github:flox/templates".to_string(),
+            "templates.demo".to_string(),
+        );
+        cache_template(&flox, &template).await;
+
+        let project = tempdir.path().join("project");
+        std::fs::create_dir_all(&project).unwrap();
+        let mut created = Vec::new();
+        init_template(&flox, &template, &project, &mut created, unexpected_init)
+            .await
+            .unwrap();
+        assert_eq!(created, [project.join("hello.txt")]);
+    }
+
+    #[tokio::test]
+    async fn uses_stale_resolution_without_network() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = flox_in(tempdir.path());
+        // nothing listens on the discard port, resolving fails like without network
+        let template = Installable::new(
+            "git+https://127.0.0.1:9/templates".to_string(),
+            "templates.demo".to_string(),
+        );
+        cache_template(&flox, &template).await;
+
+        let record = resolution_file(&flox, &template.flakeref);
+        let expired = SystemTime::now() - RESOLUTION_TTL * 2;
+        filetime::set_file_mtime(&record, filetime::FileTime::from_system_time(expired)).unwrap();
+
+        let project = tempdir.path().join("project");
+        std::fs::create_dir_all(&project).unwrap();
+        let mut created = Vec::new();
+        init_template(&flox, &template, &project, &mut created, unexpected_init)
+            .await
+            .unwrap();
+        assert_eq!(created, [project.join("hello.txt")]);
+    }
+
+    #[tokio::test]
+    async fn refetches_modified_template() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = flox_in(tempdir.path());
+        let template = Installable::new(
+            "github:flox/templates".to_string(),
+            "templates.demo".to_string(),
+        );
+        let cached = cache_template(&flox, &template).await;
+        std::fs::write(cached.dir.join("hello.txt"), "tampered").unwrap();
+
+        let project = tempdir.path().join("project");
+        std::fs::create_dir_all(&project).unwrap();
+        let initialized = AtomicBool::new(false);
+        let initialized_ref = &initialized;
+        let mut created = Vec::new();
+        init_template(&flox, &template, &project, &mut created, |dir| async move {
+            initialized_ref.store(true, Ordering::SeqCst);
+            std::fs::create_dir(dir.join("src")).unwrap();
+            std::fs::write(dir.join("src/hello.txt"), "fetched").unwrap();
+            Ok::<_, TemplateCacheError>(())
+        })
+        .await
+        .unwrap();
+        assert!(initialized.load(Ordering::SeqCst));
+        assert_eq!(created, [
+            project.join("src"),
+            project.join("src/hello.txt")
+        ]);
+        assert_eq!(
+            std::fs::read_to_string(project.join("src/hello.txt")).unwrap(),
+            "fetched"
+        );
+        assert!(!project.join("hello.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn reports_conflicts_before_copying() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = flox_in(tempdir.path());
+        let template = Installable::new(
+            "github:flox/templates".to_string(),
+            "templates.demo".to_string(),
+        );
+
+        let project = tempdir.path().join("project");
+        std::fs::create_dir_all(project.join("src")).unwrap();
+        std::fs::write(project.join("src/b.txt"), "mine").unwrap();
+        let mut created = Vec::new();
+        let result = init_template(&flox, &template, &project, &mut created, |dir| async move {
+            std::fs::write(dir.join("a.txt"), "template").unwrap();
+            std::fs::create_dir(dir.join("src")).unwrap();
+            std::fs::write(dir.join("src/b.txt"), "template").unwrap();
+            Ok::<_, TemplateCacheError>(())
+        })
+        .await;
+        assert!(matches!(
+            result,
+            Err(TemplateCacheError::Conflict(path)) if path == project.join("src/b.txt")
+        ));
+        assert!(created.is_empty());
+        assert!(!project.join("a.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(project.join("src/b.txt")).unwrap(),
+            "mine"
+        );
+    }
+
+    #[cfg(feature = "impure-unit-tests")]
+    #[tokio::test]
+    async fn second_init_uses_cache() {
+        use crate::providers::git::{GitCommandProvider, GitProvider};
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let flox = Flox {
+            cache_dir: tempdir.path().join("caches"),
+            temp_dir: tempdir.path().join("temp"),
+            config_dir: tempdir.path().join("config"),
+            ..Default::default()
+        };
+
+        let flake_dir = tempdir.path().join("flake");
+        std::fs::create_dir_all(flake_dir.join("demo")).unwrap();
+        std::fs::write(
+            flake_dir.join("flake.nix"),
+            r#"{ outputs = _: { templates.demo = { path = ./demo; description = "demo"; }; }; }"#,
+        )
+        .unwrap();
+        std::fs::write(flake_dir.join("demo/hello.txt"), "hello").unwrap();
+        let git = GitCommandProvider::init(&flake_dir, false).await.unwrap();
+        git.add(&[Path::new(".")]).await.unwrap();
+        git.commit("add template").await.unwrap();
+
+        let template = Installable::new(
+            format!("git+file://{}", flake_dir.display()),
+            "templates.demo".to_string(),
+        );
+
+        let first = tempdir.path().join("first");
+        std::fs::create_dir_all(&first).unwrap();
+        let mut created = Vec::new();
+        init_template(&flox, &template, &first, &mut created, |dir| async {
+            let status = Command::new("nix")
+                .args(["flake", "init", "--template"])
+                .arg(template.to_string())
+                .current_dir(dir)
+                .status()
+                .await
+                .map_err(TemplateCacheError::Spawn)?;
+            assert!(status.success());
+            Ok::<_, TemplateCacheError>(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(created, [first.join("hello.txt")]);
+        assert_eq!(
+            std::fs::read_to_string(first.join("hello.txt")).unwrap(),
+            "hello"
+        );
+
+        // a second init must neither run `nix flake init` nor fetch the template
+        let second = tempdir.path().join("second");
+        std::fs::create_dir_all(&second).unwrap();
+        let mut created = Vec::new();
+        init_template(&flox, &template, &second, &mut created, |_| async {
+            Err(TemplateCacheError::Spawn(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "should use the cached template",
+            )))
+        })
+        .await
+        .unwrap();
+        assert_eq!(created, [second.join("hello.txt")]);
+        assert_eq!(
+            std::fs::read_to_string(second.join("hello.txt")).unwrap(),
+            "hello"
+        );
+    }
+}
@@ -0,0 +1,327 @@
+//! Copies of package definitions from other flakes
+//!
+//! [Project::import_package] locates the file defining a package through its `meta.position`
+//! and copies the directory of that file into the project,
+//! like [Project::init_flox_package] does for templates.
+//! Definitions referring to files outside of that directory are not copied,
+//! the references would break.
+
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+use rnix::SyntaxKind;
+use runix::arguments::EvalArgs;
+use runix::command::Eval;
+use runix::installable::Installable;
+use runix::{NixBackend, RunJson};
+use thiserror::Error;
+use walkdir::WalkDir;
+
+use super::{
+    FileAction,
+    Project,
+    ProjectError,
+    TransactionCommitError,
+    TransactionEnterError,
+    PNAME_DECLARATION,
+};
+use crate::flox::FloxNixApi;
+use crate::models::root::transaction::GitAccess;
+use crate::providers::fs::FileSystem;
+use crate::providers::git::GitProvider;
+use crate::utils::errors::FloxErrorCode;
+
+/// Files that define a single package, so that their directory can be copied
+const PACKAGE_FILES: [&str; 2] = ["default.nix", "package.nix"];
+
+impl<'flox, Git: GitProvider, Access: GitAccess<Git>, Fs: FileSystem>
+    Project<'flox, Git, Access, Fs>
+{
+    /// Copy the definition of the package `source` into this project as package `name`
+    ///
+    /// The definition has to be a `default.nix` or `package.nix`,
+    /// as reported by the package's `meta.position`.
+    /// Its directory is copied to `pkgs/<name>/` in a transaction,
+    /// with the definition renamed to `default.nix`
+    /// and any `pname` declared in it set to `name`, and committed.
+    ///
+    /// Packages defined inline, e.g. in nixpkgs' `all-packages.nix`,
+    /// without a position, or next to a flake.nix cannot be copied.
+    /// Neither can definitions whose nix files refer to paths outside of their directory,
+    /// e.g. `../../build-support/setup-hook.sh`.
+    pub async fn import_package<Nix: FloxNixApi>(
+        &self,
+        source: Installable,
+        name: &str,
+    ) -> Result<(), ImportPackageError<Nix, Git>>
+    where
+        Eval: RunJson<Nix>,
+    {
+        let root = self.require_workdir()?;
+        let target_dir = Path::new("pkgs").join(name);
+        match self.fs.kind(&root.join(&target_dir)).await {
+            Ok(None) => {},
+            Ok(Some(_)) => return Err(ImportPackageError::AlreadyExists(name.to_string())),
+            Err(e) => return Err(ImportPackageError::Read(root.join(&target_dir), e)),
+        }
+
+        let nix: Nix = self.flox.nix(Default::default());
+        let position = Eval {
+            eval_args: EvalArgs {
+                apply: Some("package: package.meta.position or null".to_string().into()),
+                installable: Some(source.clone().into()),
+            },
+            ..Eval::default()
+        }
+        .run_json(&nix, &Default::default())
+        .await
+        .map_err(ImportPackageError::Eval)?;
+        let position: Option<String> =
+            serde_json::from_value(position).map_err(ImportPackageError::ParsePosition)?;
+
+        let definition = position
+            .as_deref()
+            .and_then(package_definition)
+            .ok_or_else(|| ImportPackageError::NotCopyable(source.to_string(), position.clone()))?;
+        let source_dir = definition.parent().unwrap();
+        if let Ok(Some(_)) = self.fs.kind(&source_dir.join("flake.nix")).await {
+            return Err(ImportPackageError::NotCopyable(
+                source.to_string(),
+                position,
+            ));
+        }
+
+        let mut files = Vec::new();
+        for entry in WalkDir::new(source_dir) {
+            let entry = entry.map_err(ImportPackageError::Walkdir)?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let file = entry.path().strip_prefix(source_dir).unwrap().to_path_buf();
+            let source_path = source_dir.join(&file);
+            let contents = self
+                .fs
+                .read(&source_path)
+                .await
+                .map_err(|e| ImportPackageError::Read(source_path.clone(), e))?;
+            if file.extension() == Some(OsStr::new("nix")) {
+                if let Some(path) = outside_paths(&String::from_utf8_lossy(&contents), &file)
+                    .into_iter()
+                    .next()
+                {
+                    return Err(ImportPackageError::OutsideReference(source_path, path));
+                }
+            }
+            files.push((file, contents));
+        }
+
+        let (sandbox, mut index) = Project::new(
+            self.flox,
+            self.git.read_only(),
+            self.fs.clone(),
+            self.subdir.clone(),
+        )
+        .enter_transaction()
+        .await
+        .map_err(ImportPackageError::EnterTransaction)?;
+        let sandbox_root = sandbox.require_workdir()?.to_path_buf();
+
+        for (file, mut contents) in files {
+            let target_file = if source_dir.join(&file) == definition {
+                PathBuf::from("default.nix")
+            } else {
+                file
+            };
+            let target_path = sandbox_root.join(&target_dir).join(&target_file);
+
+            if target_file.extension() == Some(OsStr::new("nix")) {
+                let pname = format!(r#"pname = "{name}""#);
+                contents = PNAME_DECLARATION
+                    .replace_all(&String::from_utf8_lossy(&contents), pname)
+                    .into_owned()
+                    .into_bytes();
+            }
+
+            sandbox
+                .fs
+                .create_dir_all(target_path.parent().unwrap())
+                .await
+                .map_err(|e| ImportPackageError::Write(target_path.clone(), e))?;
+            sandbox
+                .fs
+                .write(&target_path, &contents)
+                .await
+                .map_err(|e| ImportPackageError::Write(target_path.clone(), e))?;
+            index.insert(target_dir.join(&target_file), FileAction::Add);
+        }
+        sandbox
+            .write_transaction_state(&index)
+            .await
+            .map_err(ImportPackageError::WriteState)?;
+
+        sandbox
+            .commit_transaction(
+                index,
+                &format!("Import package {name} from {}", source.to_nix()),
+                false,
+            )
+            .await
+            .map_err(ImportPackageError::CommitTransaction)?;
+        Ok(())
+    }
+}
+
+/// The file defining a package at `position` (`<file>:<line>`),
+/// if it is one of [PACKAGE_FILES]
+fn package_definition(position: &str) -> Option<PathBuf> {
+    let (file, _line) = position.rsplit_once(':')?;
+    let file = Path::new(file);
+
+    let is_package_file = file
+        .file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|file_name| PACKAGE_FILES.contains(&file_name));
+    (file.is_absolute() && is_package_file).then(|| file.to_path_buf())
+}
+
+/// Relative path literals in `contents` of `file` that point outside of the directory of the
+/// package, `file` being relative to that directory
+///
+/// Only the part of a path before its first interpolation is considered.
+/// Absolute paths and lookup paths like `<nixpkgs>` resolve the same from anywhere.
+fn outside_paths(contents: &str, file: &Path) -> Vec<String> {
+    let dir = file.parent().unwrap_or(Path::new(""));
+    rnix::Root::parse(contents)
+        .syntax()
+        .descendants()
+        .filter(|node| node.kind() == SyntaxKind::NODE_PATH)
+        .map(|node| node.text().to_string())
+        .filter(|path| {
+            let literal = path.split("${").next().unwrap_or_default();
+            if !literal.starts_with('.') {
+                return false;
+            }
+
+            // components below the directory of the package
+            let mut depth: usize = 0;
+            for component in dir.join(literal).components() {
+                match component {
+                    Component::Normal(_) => depth += 1,
+                    Component::ParentDir => match depth.checked_sub(1) {
+                        Some(parent) => depth = parent,
+                        None => return true,
+                    },
+                    _ => {},
+                }
+            }
+            false
+        })
+        .collect()
+}
+
+#[derive(Error, Debug)]
+pub enum ImportPackageError<Nix: NixBackend, Git: GitProvider>
+where
+    Eval: RunJson<Nix>,
+{
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Package '{0}' already exists")]
+    AlreadyExists(String),
+    #[error("Failed to evaluate the position of the package: {0}")]
+    Eval(<Eval as RunJson<Nix>>::JsonError),
+    #[error("Failed to parse the position of the package: {0}")]
+    ParsePosition(serde_json::Error),
+    #[error(
+        "Cannot copy the definition of {0} (defined at {}), \
+         only packages defined on their own in a default.nix or package.nix can be imported",
+        .1.as_deref().unwrap_or("unknown position")
+    )]
+    NotCopyable(String, Option<String>),
+    #[error("Cannot import {0:?}, it refers to {1} outside of the directory of the package")]
+    OutsideReference(PathBuf, String),
+    #[error("Failed to list package files: {0}")]
+    Walkdir(walkdir::Error),
+    #[error("Failed to read {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to write {0:?}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("Failed to enter transaction: {0}")]
+    EnterTransaction(TransactionEnterError<Git>),
+    #[error("Failed to write transaction state: {0}")]
+    WriteState(std::io::Error),
+    #[error("Failed to commit transaction: {0}")]
+    CommitTransaction(TransactionCommitError<Git>),
+}
+
+impl<Nix: NixBackend, Git: GitProvider> ImportPackageError<Nix, Git>
+where
+    Eval: RunJson<Nix>,
+{
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            ImportPackageError::Workdir(e) => e.code(),
+            ImportPackageError::AlreadyExists(_) => FloxErrorCode::AlreadyExists,
+            ImportPackageError::Eval(_) => FloxErrorCode::Nix,
+            ImportPackageError::ParsePosition(_)
+            | ImportPackageError::NotCopyable(..)
+            | ImportPackageError::OutsideReference(..) => FloxErrorCode::Invalid,
+            ImportPackageError::Walkdir(_)
+            | ImportPackageError::Read(..)
+            | ImportPackageError::Write(..)
+            | ImportPackageError::WriteState(_) => FloxErrorCode::Io,
+            ImportPackageError::EnterTransaction(e) => e.code(),
+            ImportPackageError::CommitTransaction(e) => e.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locates_package_definitions() {
+        assert_eq!(
+            package_definition("/nix/store/abc-source/pkgs/hello/default.nix:12"),
+            Some(PathBuf::from(
+                "/nix/store/abc-source/pkgs/hello/default.nix"
+            ))
+        );
+        assert_eq!(
+            package_definition("/nix/store/abc-source/pkgs/by-name/he/hello/package.nix:3"),
+            Some(PathBuf::from(
+                "/nix/store/abc-source/pkgs/by-name/he/hello/package.nix"
+            ))
+        );
+        assert_eq!(
+            package_definition("/nix/store/abc-source/pkgs/top-level/all-packages.nix:100"),
+            None
+        );
+        assert_eq!(package_definition("default.nix"), None);
+    }
+
+    #[test]
+    fn finds_references_outside_of_the_package() {
+        let contents = r#"{ callPackage }: {
+            patches = [ ./fix.patch ./patches/../other.patch ];
+            src = ./src/${version};
+            lib = import ../../lib;
+            hook = ../setup-hook.sh;
+            nixpkgs = import <nixpkgs> { };
+            store = /nix/store/abc-source;
+        }"#;
+        assert_eq!(outside_paths(contents, Path::new("default.nix")), [
+            "../../lib",
+            "../setup-hook.sh"
+        ]);
+        assert_eq!(
+            outside_paths("import ../common.nix", Path::new("sub/default.nix")),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            outside_paths("import ./../../common.nix", Path::new("sub/default.nix")),
+            ["./../../common.nix"]
+        );
+    }
+}
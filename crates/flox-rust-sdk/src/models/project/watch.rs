@@ -0,0 +1,385 @@
+//! Watching the files of an environment to rebuild it when they change
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::Stream;
+use log::{debug, warn};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use super::composition::CompositionError;
+use super::environment::{content_hash, Environment};
+use super::ProjectError;
+use crate::models::root::transaction::GitAccess;
+use crate::providers::fs::FileSystem;
+use crate::providers::git::GitProvider;
+use crate::utils::errors::FloxErrorCode;
+
+/// Time without further changes after which changes are reported
+pub const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Directory in [Flox::cache_dir](crate::flox::Flox::cache_dir) holding the [CommitRecord]s
+const COMMITS_DIR: &str = "commits";
+
+/// Files written by the last transaction committed to a project, see [mark_commit]
+#[derive(Debug, Serialize, Deserialize)]
+struct CommitRecord {
+    /// Tells apart commits writing the same contents
+    id: String,
+    /// Content hashes of the written files, [None] for removed files
+    files: BTreeMap<PathBuf, Option<String>>,
+}
+
+/// A change reported by [Environment::watch]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The contents of `paths` changed, the environment should be rebuilt
+    Changed { paths: Vec<PathBuf> },
+}
+
+impl<Git: GitProvider, A: GitAccess<Git>, Fs: FileSystem> Environment<'_, Git, A, Fs> {
+    /// Watch the files defining this environment
+    ///
+    /// Watched are the flox.nix of the environment, the files it [imports](super::composition)
+    /// and the `flake.nix` and `flake.lock` of the project.
+    /// Changes are reported once no further changes happened for [WATCH_DEBOUNCE],
+    /// and only if the contents of a watched file differ from the last report.
+    /// Thus writes leaving a file as it was do not trigger spurious rebuilds.
+    /// Files written by committing a transaction are not reported either,
+    /// as marked by the commit, see [mark_commit].
+    ///
+    /// The watched files are determined when the watch starts,
+    /// newly imported files are only watched by a new watch.
+    pub async fn watch(&self) -> Result<impl Stream<Item = WatchEvent>, WatchError> {
+        let mut files: Vec<PathBuf> = self
+            .composition()
+            .await?
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        let workdir = self.project.require_workdir()?;
+        let flake_dir = workdir.join(&self.project.subdir);
+        files.push(flake_dir.join("flake.nix"));
+        files.push(flake_dir.join("flake.lock"));
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<_>| {
+            // fails only once the stream was dropped
+            let _ = sender.send(event);
+        })
+        .map_err(WatchError::Watch)?;
+        // transactions replace files by renaming, which ends watches of the files themselves
+        let dirs: BTreeSet<&Path> = files.iter().filter_map(|file| file.parent()).collect();
+        for dir in dirs {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .map_err(WatchError::Watch)?;
+        }
+
+        let state = WatchState {
+            _watcher: watcher,
+            receiver,
+            files: WatchedFiles::new(files, &self.project.flox.cache_dir, workdir).await,
+        };
+        Ok(futures::stream::unfold(state, WatchState::next))
+    }
+}
+
+/// Record that a transaction is about to write `files` to the project in `workdir`
+///
+/// `files` map the absolute paths to the hashes of their new contents, see [content_hash],
+/// or [None] for removed files.
+/// [Environment::watch] ignores changes resulting in these contents.
+pub(super) async fn mark_commit(
+    cache_dir: &Path,
+    workdir: &Path,
+    files: BTreeMap<PathBuf, Option<String>>,
+) -> std::io::Result<()> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let record = CommitRecord {
+        id: format!("{}-{nanos}", std::process::id()),
+        files,
+    };
+
+    let path = commit_record_path(cache_dir, workdir);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(
+        &path,
+        serde_json::to_vec(&record).expect("should serialize commit record"),
+    )
+    .await
+}
+
+fn commit_record_path(cache_dir: &Path, workdir: &Path) -> PathBuf {
+    cache_dir
+        .join(COMMITS_DIR)
+        .join(content_hash(&[workdir.as_os_str().as_bytes()]))
+        .with_extension("json")
+}
+
+/// The [CommitRecord] at `path`, [None] if there is none or it can not be read
+async fn read_commit_record(path: &Path) -> Option<CommitRecord> {
+    let contents = tokio::fs::read(path).await.ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+struct WatchState {
+    /// Stops watching when dropped
+    _watcher: RecommendedWatcher,
+    receiver: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+    files: WatchedFiles,
+}
+
+/// The files of a watch and their contents at the last report
+struct WatchedFiles {
+    files: Vec<PathBuf>,
+    /// Content hashes of [Self::files] at the last report, [None] for missing files
+    fingerprints: BTreeMap<PathBuf, Option<String>>,
+    /// Where commits to the project are recorded, see [mark_commit]
+    commit_record: PathBuf,
+    /// Id of the last commit whose writes were ignored
+    commit_id: Option<String>,
+}
+
+impl WatchedFiles {
+    async fn new(files: Vec<PathBuf>, cache_dir: &Path, workdir: &Path) -> Self {
+        let commit_record = commit_record_path(cache_dir, workdir);
+        WatchedFiles {
+            fingerprints: fingerprints(&files).await,
+            commit_id: read_commit_record(&commit_record)
+                .await
+                .map(|record| record.id),
+            files,
+            commit_record,
+        }
+    }
+
+    /// Files changed since the last call, except for those written by a new commit
+    async fn changes(&mut self) -> Option<WatchEvent> {
+        let fingerprints = fingerprints(&self.files).await;
+        let commit = read_commit_record(&self.commit_record)
+            .await
+            .filter(|record| Some(&record.id) != self.commit_id.as_ref());
+
+        let mut paths = Vec::new();
+        for file in &self.files {
+            let fingerprint = fingerprints.get(file);
+            if fingerprint == self.fingerprints.get(file) {
+                continue;
+            }
+            if commit
+                .as_ref()
+                .is_some_and(|commit| commit.files.get(file) == fingerprint)
+            {
+                debug!("Ignoring {} as written by a transaction", file.display());
+                continue;
+            }
+            paths.push(file.clone());
+        }
+
+        self.fingerprints = fingerprints;
+        if let Some(commit) = commit {
+            self.commit_id = Some(commit.id);
+        }
+
+        if paths.is_empty() {
+            debug!("Ignoring changes leaving the environment's files as they were");
+            return None;
+        }
+        Some(WatchEvent::Changed { paths })
+    }
+}
+
+impl WatchState {
+    async fn next(mut self) -> Option<(WatchEvent, Self)> {
+        loop {
+            loop {
+                let event = self.receiver.recv().await?;
+                if self.concerns_files(event) {
+                    break;
+                }
+            }
+            // wait for the changes to settle
+            loop {
+                match tokio::time::timeout(WATCH_DEBOUNCE, self.receiver.recv()).await {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => return None,
+                    Err(_) => break,
+                }
+            }
+
+            if let Some(event) = self.files.changes().await {
+                return Some((event, self));
+            }
+        }
+    }
+
+    /// Whether `event` may have changed a watched file
+    fn concerns_files(&self, event: notify::Result<notify::Event>) -> bool {
+        match event {
+            Ok(event) => {
+                !event.kind.is_access()
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| self.files.files.contains(path))
+            },
+            Err(e) => {
+                warn!("Failed to watch environment files: {e}");
+                false
+            },
+        }
+    }
+}
+
+/// Content hashes of `files`, [None] for files that can not be read
+async fn fingerprints(files: &[PathBuf]) -> BTreeMap<PathBuf, Option<String>> {
+    let mut fingerprints = BTreeMap::new();
+    for file in files {
+        let fingerprint = tokio::fs::read(file)
+            .await
+            .ok()
+            .map(|contents| content_hash(&[&contents]));
+        fingerprints.insert(file.clone(), fingerprint);
+    }
+    fingerprints
+}
+
+#[derive(Error, Debug)]
+pub enum WatchError {
+    #[error(transparent)]
+    Composition(#[from] CompositionError),
+    #[error(transparent)]
+    Workdir(#[from] ProjectError),
+    #[error("Failed to watch environment files: {0}")]
+    Watch(notify::Error),
+}
+
+impl WatchError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            WatchError::Composition(e) => e.code(),
+            WatchError::Workdir(e) => e.code(),
+            WatchError::Watch(_) => FloxErrorCode::Io,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::flox::Flox;
+    use crate::models::project::tests::test_environment;
+    use crate::providers::fs::TokioFs;
+
+    /// Flox with its caches in `dir`
+    fn flox_in(dir: &Path) -> Flox {
+        Flox {
+            cache_dir: dir.join("cache"),
+            temp_dir: dir.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_writes_of_transactions() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let flox = flox_in(tempdir.path());
+        let environment = test_environment(&flox, &project_dir, TokioFs).await;
+        let workdir = environment.project.workdir().unwrap().to_path_buf();
+        let flox_nix = workdir.join("flox.nix");
+        std::fs::write(workdir.join("flake.nix"), "{}").unwrap();
+        std::fs::write(&flox_nix, "{ }").unwrap();
+
+        let mut files = WatchedFiles::new(vec![flox_nix.clone()], &flox.cache_dir, &workdir).await;
+
+        // rewriting the same contents is not a change
+        std::fs::write(&flox_nix, "{ }").unwrap();
+        assert_eq!(files.changes().await, None);
+
+        let (sandbox, mut index) = environment.enter_transaction().await.unwrap();
+        sandbox
+            .install(&["nixpkgs-flox.hello".to_string()], &mut index)
+            .await
+            .unwrap();
+        sandbox
+            .commit_transaction(index, "install hello", false)
+            .await
+            .unwrap()
+            .committed()
+            .expect("not a dry run");
+        let committed = std::fs::read_to_string(&flox_nix).unwrap();
+        assert_ne!(committed, "{ }");
+        assert_eq!(files.changes().await, None);
+
+        // edits are reported, even if they restore contents written by the transaction
+        std::fs::write(&flox_nix, "{ }").unwrap();
+        assert_eq!(
+            files.changes().await,
+            Some(WatchEvent::Changed {
+                paths: vec![flox_nix.clone()]
+            })
+        );
+        std::fs::write(&flox_nix, committed).unwrap();
+        assert_eq!(
+            files.changes().await,
+            Some(WatchEvent::Changed {
+                paths: vec![flox_nix]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_edits_after_transactions() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let project_dir = tempdir.path().join("project");
+        std::fs::create_dir(&project_dir).unwrap();
+        let flox = flox_in(tempdir.path());
+        let environment = test_environment(&flox, &project_dir, TokioFs).await;
+        let flox_nix = environment.project.workdir().unwrap().join("flox.nix");
+        std::fs::write(project_dir.join("flake.nix"), "{}").unwrap();
+        std::fs::write(&flox_nix, "{ }").unwrap();
+
+        let events = environment.watch().await.unwrap();
+        futures::pin_mut!(events);
+
+        let (sandbox, mut index) = test_environment(&flox, &project_dir, TokioFs)
+            .await
+            .enter_transaction()
+            .await
+            .unwrap();
+        sandbox
+            .install(&["nixpkgs-flox.hello".to_string()], &mut index)
+            .await
+            .unwrap();
+        sandbox
+            .commit_transaction(index, "install hello", false)
+            .await
+            .unwrap()
+            .committed()
+            .expect("not a dry run");
+        std::fs::write(&flox_nix, "{ packages.nixpkgs-flox.fd = {}; }").unwrap();
+
+        // the first event is caused by the edit, the commit is not reported
+        assert_eq!(
+            events.next().await,
+            Some(WatchEvent::Changed {
+                paths: vec![flox_nix]
+            })
+        );
+    }
+}
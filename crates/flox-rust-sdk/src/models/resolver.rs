@@ -0,0 +1,241 @@
+//! Resolution of short package names, e.g. `hello`, to packages of a catalog
+//!
+//! [Flox::search], the installs and upgrades of
+//! [Environment](crate::models::project::environment::Environment)
+//! and package names of the CLI go through the [PackageResolver] of [Flox],
+//! see [Flox::package_resolver].
+//! Downstreams with other package sources attach their own resolver with
+//! [FloxBuilder::package_resolver](crate::flox::FloxBuilder::package_resolver),
+//! the flox catalog is resolved by [FloxCatalogResolver] otherwise.
+//! Attach a [FloxCatalogResolver] of other nix and git backends the same way.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use runix::command::Eval;
+use runix::command_line::NixCommandLine;
+use runix::RunJson;
+use thiserror::Error;
+
+use super::flake_ref::FlakeRefError;
+use super::stability::Stability;
+use crate::flox::{
+    Flox,
+    FloxNixApi,
+    ResolveFloxInstallableError,
+    ResolveRegistryError,
+    ResolvedInstallableMatch,
+};
+use crate::models::flox_installable::FloxInstallable;
+use crate::providers::git::{GitCommandProvider, GitProvider};
+use crate::utils::errors::FloxErrorCode;
+
+/// Maps package names to the packages of a catalog
+///
+/// Matches that are installed into environments are recorded as `<flakeref>.<key>`,
+/// so their flakeref should be a channel name.
+#[async_trait(?Send)]
+pub trait PackageResolver: fmt::Debug + Send + Sync {
+    /// Packages named `name` at `stability`, the preferred match first
+    ///
+    /// An empty `name` matches all packages of the catalog.
+    async fn resolve(
+        &self,
+        flox: &Flox,
+        name: &str,
+        stability: &Stability,
+    ) -> Result<Vec<ResolvedInstallableMatch>, ResolvePackageError>;
+}
+
+/// Resolves names in the `nixpkgs-<stability>` channel of flox,
+/// e.g. `hello` to `nixpkgs-stable.hello`
+///
+/// Evaluates with the `Nix` backend and opens local flakes with `Git`.
+pub struct FloxCatalogResolver<Nix = NixCommandLine, Git = GitCommandProvider> {
+    _backends: PhantomData<fn() -> (Nix, Git)>,
+}
+
+impl<Nix, Git> FloxCatalogResolver<Nix, Git> {
+    /// Attributes searched for packages, like for installables of `flox package`
+    const PREFIXES: &'static [(&'static str, bool)] =
+        &[("packages", true), ("legacyPackages", true)];
+
+    pub const fn new() -> Self {
+        FloxCatalogResolver {
+            _backends: PhantomData,
+        }
+    }
+}
+
+impl FloxCatalogResolver {
+    /// Channel holding the packages of `stability`
+    pub fn channel(stability: &Stability) -> String {
+        format!("nixpkgs-{stability}")
+    }
+}
+
+impl<Nix, Git> Default for FloxCatalogResolver<Nix, Git> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Nix, Git> Clone for FloxCatalogResolver<Nix, Git> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Nix, Git> Copy for FloxCatalogResolver<Nix, Git> {}
+
+impl<Nix, Git> fmt::Debug for FloxCatalogResolver<Nix, Git> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FloxCatalogResolver")
+    }
+}
+
+#[async_trait(?Send)]
+impl<Nix: FloxNixApi, Git: GitProvider> PackageResolver for FloxCatalogResolver<Nix, Git>
+where
+    Eval: RunJson<Nix>,
+    ResolveFloxInstallableError<Nix>: std::error::Error + Send + Sync + 'static,
+{
+    async fn resolve(
+        &self,
+        flox: &Flox,
+        name: &str,
+        stability: &Stability,
+    ) -> Result<Vec<ResolvedInstallableMatch>, ResolvePackageError> {
+        let installable = FloxInstallable {
+            source: None,
+            attr_path: if name.is_empty() {
+                vec![]
+            } else {
+                vec![name.to_string()]
+            },
+        };
+        let channel = FloxCatalogResolver::channel(stability);
+
+        let matches = flox
+            .resolve_matches::<Nix, Git>(&[installable], &[&channel], Self::PREFIXES, true, None)
+            .await?;
+        Ok(matches)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ResolvePackageError {
+    #[error(transparent)]
+    Registry(ResolveRegistryError),
+    #[error("Invalid pinned nixpkgs: {0}")]
+    PinnedNixpkgs(FlakeRefError),
+    /// Failure to evaluate the catalog, with any `Nix` backend
+    #[error(transparent)]
+    Catalog(Box<dyn std::error::Error + Send + Sync>),
+    /// Failure of a resolver other than [FloxCatalogResolver]
+    #[error("Failed to resolve '{name}': {source}")]
+    Resolver {
+        name: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+impl<Nix: FloxNixApi> From<ResolveFloxInstallableError<Nix>> for ResolvePackageError
+where
+    Eval: RunJson<Nix>,
+    ResolveFloxInstallableError<Nix>: std::error::Error + Send + Sync + 'static,
+{
+    fn from(e: ResolveFloxInstallableError<Nix>) -> Self {
+        match e {
+            ResolveFloxInstallableError::Registry(e) => ResolvePackageError::Registry(e),
+            ResolveFloxInstallableError::PinnedNixpkgs(e) => ResolvePackageError::PinnedNixpkgs(e),
+            e => ResolvePackageError::Catalog(Box::new(e)),
+        }
+    }
+}
+
+impl ResolvePackageError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            ResolvePackageError::Registry(ResolveRegistryError::NotFound(_)) => {
+                FloxErrorCode::NotFound
+            },
+            ResolvePackageError::Registry(_) | ResolvePackageError::PinnedNixpkgs(_) => {
+                FloxErrorCode::Invalid
+            },
+            ResolvePackageError::Catalog(_) | ResolvePackageError::Resolver { .. } => {
+                FloxErrorCode::Nix
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resolves every name to a package of the same name in `curated`
+    #[derive(Debug)]
+    struct CuratedResolver;
+
+    #[async_trait(?Send)]
+    impl PackageResolver for CuratedResolver {
+        async fn resolve(
+            &self,
+            _flox: &Flox,
+            name: &str,
+            stability: &Stability,
+        ) -> Result<Vec<ResolvedInstallableMatch>, ResolvePackageError> {
+            Ok(vec![ResolvedInstallableMatch::new(
+                "curated".to_string(),
+                stability.to_string(),
+                None,
+                false,
+                vec![name.to_string()],
+                None,
+            )])
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_with_configured_resolver() {
+        assert_eq!(
+            format!("{:?}", Flox::default().package_resolver()),
+            "FloxCatalogResolver"
+        );
+        assert_eq!(
+            FloxCatalogResolver::channel(&Stability::Unknown),
+            "nixpkgs-stable"
+        );
+
+        let flox = Flox {
+            package_resolver: Some(std::sync::Arc::new(CuratedResolver)),
+            ..Default::default()
+        };
+        let matches = flox
+            .resolve_package("hello", &Stability::Unstable)
+            .await
+            .unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].flakeref, "curated");
+        assert_eq!(matches[0].prefix, "unstable");
+        assert_eq!(matches[0].key, ["hello"]);
+    }
+
+    #[test]
+    fn catalog_errors_keep_their_code() {
+        let not_found =
+            ResolvePackageError::from(ResolveFloxInstallableError::<NixCommandLine>::Registry(
+                ResolveRegistryError::NotFound("nixpkgs-unknown".to_string()),
+            ));
+        assert_eq!(not_found.code(), FloxErrorCode::NotFound);
+
+        let parse =
+            ResolvePackageError::from(ResolveFloxInstallableError::<NixCommandLine>::Parse(
+                serde_json::from_str::<()>("").unwrap_err(),
+            ));
+        assert!(matches!(parse, ResolvePackageError::Catalog(_)));
+        assert_eq!(parse.code(), FloxErrorCode::Nix);
+    }
+}
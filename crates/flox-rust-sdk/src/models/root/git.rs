@@ -21,10 +21,18 @@ impl<'flox, Git: GitProvider> RootGuard<'flox, Closed<Git>, Closed<PathBuf>> {
     /// Retrieve the initialized repo or try to create one
     // todo add `bare` option
     pub async fn init_git(self) -> Result<Root<'flox, Closed<Git>>, ProjectInitGitError<Git>> {
+        self.init_git_with(&Default::default()).await
+    }
+
+    /// Like [Self::init_git], creating the repo with `options`
+    pub async fn init_git_with(
+        self,
+        options: &Git::Options,
+    ) -> Result<Root<'flox, Closed<Git>>, ProjectInitGitError<Git>> {
         match self {
             Guard::Initialized(i) => Ok(i),
             Guard::Uninitialized(u) => {
-                let repo = Git::init(&u.state.inner, false)
+                let repo = Git::init_with(options, &u.state.inner, false)
                     .await
                     .map_err(ProjectInitGitError::InitRepoError)?;
 
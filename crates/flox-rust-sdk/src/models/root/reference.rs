@@ -1,5 +1,6 @@
 //! This module provides implementations on root references
 //! Use the methods here to "upgrade" [Root] to sages with more context.
+use std::io;
 use std::path::PathBuf;
 
 use thiserror::Error;
@@ -13,10 +14,35 @@ use crate::utils::guard::Guard;
 /// At this stage the root has not yet been verified.
 /// This state should be handled as a mere reference to a potential root of any kind
 impl<'flox> Root<'flox, Closed<PathBuf>> {
+    /// Guards discovering a git repository at or above the referenced path
+    ///
+    /// - Resolves as initialized if a repository was found
+    /// - Resolves as uninitialized if the path is not part of a repository
+    /// - Fails if the path does not exist or cannot be accessed
     pub async fn guard<Git: GitProvider>(
         self,
     ) -> Result<RootGuard<'flox, Closed<Git>, Closed<PathBuf>>, ProjectDiscoverGitError<Git>> {
-        match Git::discover(&self.state.inner).await {
+        self.guard_with(&Default::default()).await
+    }
+
+    /// Like [Self::guard], accessing the repository with `options`,
+    /// e.g. a pinned git binary
+    pub async fn guard_with<Git: GitProvider>(
+        self,
+        options: &Git::Options,
+    ) -> Result<RootGuard<'flox, Closed<Git>, Closed<PathBuf>>, ProjectDiscoverGitError<Git>> {
+        let path = &self.state.inner;
+        if let Err(err) = tokio::fs::metadata(path).await {
+            return Err(match err.kind() {
+                io::ErrorKind::NotFound => ProjectDiscoverGitError::PathNotFound(path.clone()),
+                io::ErrorKind::PermissionDenied => {
+                    ProjectDiscoverGitError::PermissionDenied(path.clone())
+                },
+                _ => ProjectDiscoverGitError::Io(path.clone(), err),
+            });
+        }
+
+        match Git::discover_with(options, path).await {
             Ok(repo) => Ok(Guard::Initialized(Root {
                 flox: self.flox,
                 state: Closed::new(repo),
@@ -32,6 +58,12 @@ impl<'flox> Root<'flox, Closed<PathBuf>> {
 
 #[derive(Error, Debug)]
 pub enum ProjectDiscoverGitError<Git: GitProvider> {
+    #[error("Path does not exist: {0}")]
+    PathNotFound(PathBuf),
+    #[error("Permission denied accessing {0}")]
+    PermissionDenied(PathBuf),
+    #[error("Could not access {0}: {1}")]
+    Io(PathBuf, io::Error),
     #[error("Error attempting to discover repository: {0}")]
     DiscoverRepoError(Git::DiscoverError),
 }
@@ -1,8 +1,17 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
 use std::rc::Rc;
 
+use fslock::LockFile;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
 
-use crate::providers::git::GitProvider;
+use crate::models::audit::AuditOperation;
+use crate::models::flox_package::FloxPackage;
+use crate::providers::git::{GitProvider, StashId};
 
 #[derive(Debug)]
 pub struct ReadOnly<Git: GitProvider> {
@@ -18,19 +27,220 @@ impl<Git: GitProvider> ReadOnly<Git> {
         GitSandBox {
             original: self.git,
             sandboxed: git,
-            _tempdir: tempdir,
+            commit_strategy: CommitStrategy::default(),
+            shares_objects: false,
+            recorded: RefCell::default(),
+            stash: None,
+            base: BTreeMap::new(),
+            _tempdir: SandboxDir::Temp { _dir: tempdir },
+        }
+    }
+
+    /// Like [Self::to_sandbox_in], but `tempdir` is not removed once the sandbox is dropped
+    ///
+    /// Its location is logged, so that it can be inspected after a failed transaction.
+    pub fn to_kept_sandbox_in(self, tempdir: TempDir, git: Git) -> GitSandBox<Git> {
+        let path = tempdir.into_path();
+        info!("Keeping transaction sandbox at {:?}", path);
+        GitSandBox {
+            original: self.git,
+            sandboxed: git,
+            commit_strategy: CommitStrategy::default(),
+            shares_objects: false,
+            recorded: RefCell::default(),
+            stash: None,
+            base: BTreeMap::new(),
+            _tempdir: SandboxDir::Kept,
+        }
+    }
+
+    /// Use a sandbox directory that is kept across transactions
+    ///
+    /// `lock` guards the directory of `git` against concurrent transactions
+    /// and is released once the sandbox is dropped.
+    pub fn to_persistent_sandbox(self, lock: LockFile, git: Git) -> GitSandBox<Git> {
+        GitSandBox {
+            original: self.git,
+            sandboxed: git,
+            commit_strategy: CommitStrategy::default(),
+            shares_objects: false,
+            recorded: RefCell::default(),
+            stash: None,
+            base: BTreeMap::new(),
+            _tempdir: SandboxDir::Persistent {
+                _lock: SandboxLock(lock),
+            },
+        }
+    }
+
+    /// Reuse an existing sandbox directory, e.g. one left behind by a crashed process
+    ///
+    /// The directory is removed once the sandbox is dropped.
+    pub fn recover_sandbox_in(self, dir: PathBuf, git: Git) -> GitSandBox<Git> {
+        GitSandBox {
+            original: self.git,
+            sandboxed: git,
+            commit_strategy: CommitStrategy::default(),
+            shares_objects: false,
+            recorded: RefCell::default(),
+            stash: None,
+            base: BTreeMap::new(),
+            _tempdir: SandboxDir::Recovered(dir),
         }
     }
 }
 
+/// Directory backing a [GitSandBox], removed when dropped unless persistent
+///
+/// [SandboxDir::Kept] directories are left behind for debugging,
+/// see [Flox::keep_sandboxes](crate::flox::Flox::keep_sandboxes).
+#[derive(Debug)]
+enum SandboxDir {
+    Temp { _dir: TempDir },
+    Recovered(PathBuf),
+    Persistent { _lock: SandboxLock },
+    Kept,
+}
+
+/// Lock held on a persistent sandbox directory
+struct SandboxLock(LockFile);
+
+impl fmt::Debug for SandboxLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SandboxLock")
+    }
+}
+
+impl Drop for SandboxLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.unlock() {
+            debug!("Could not release sandbox lock: {e}");
+        }
+    }
+}
+
+/// Changes of the original stashed when entering a transaction
+///
+/// Restored when dropped, e.g. when a transaction fails before it is committed,
+/// so that they do not silently remain in `git stash list`.
+#[derive(Debug)]
+struct Stash<Git: GitProvider> {
+    git: Rc<Git>,
+    id: Option<StashId>,
+}
+
+impl<Git: GitProvider> Drop for Stash<Git> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            if let Err(e) = self.git.unstash_blocking(&id) {
+                warn!(
+                    "Failed to restore stashed changes, they are kept as stash {}: {e}",
+                    id.0
+                );
+            }
+        }
+    }
+}
+
+impl Drop for SandboxDir {
+    fn drop(&mut self) {
+        if let SandboxDir::Recovered(dir) = self {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// How changes of a [GitSandBox] are committed to the original repository
+///
+/// Environments versioned in git gain one entry in their history, e.g. a generation, per commit.
+/// With [CommitStrategy::Squashed] a batch of transactions results in a single entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CommitStrategy {
+    /// Every committed transaction creates its own commit
+    #[default]
+    PerOperation,
+    /// Committing a transaction only stages its changes,
+    /// the caller commits all staged changes at once
+    Squashed,
+}
+
 #[derive(Debug)]
 pub struct GitSandBox<Git: GitProvider> {
     sandboxed: Git,
     original: Rc<Git>,
-    _tempdir: TempDir,
+    commit_strategy: CommitStrategy,
+    /// Whether the sandbox is a clone sharing the objects of the original
+    shares_objects: bool,
+    /// Operations to add to the audit log once committed
+    recorded: RefCell<Vec<(AuditOperation, Vec<FloxPackage>)>>,
+    /// Changes of the original stashed when entering the transaction
+    stash: Option<Stash<Git>>,
+    /// Contents of the environment definitions when entering the transaction
+    base: BTreeMap<PathBuf, String>,
+    _tempdir: SandboxDir,
 }
 
 impl<Git: GitProvider> GitSandBox<Git> {
+    pub fn with_commit_strategy(mut self, commit_strategy: CommitStrategy) -> Self {
+        self.commit_strategy = commit_strategy;
+        self
+    }
+
+    pub fn commit_strategy(&self) -> CommitStrategy {
+        self.commit_strategy
+    }
+
+    /// Mark the sandbox as a clone sharing the objects of the original,
+    /// whose commits can be transferred to the original
+    pub fn with_shared_objects(mut self, shares_objects: bool) -> Self {
+        self.shares_objects = shares_objects;
+        self
+    }
+
+    pub fn shares_objects(&self) -> bool {
+        self.shares_objects
+    }
+
+    /// Restore `stash` to the original once the sandbox is dropped,
+    /// unless it is [taken](Self::take_stash) to be restored otherwise
+    pub fn with_stash(mut self, stash: Option<StashId>) -> Self {
+        self.stash = stash.map(|id| Stash {
+            git: self.original.clone(),
+            id: Some(id),
+        });
+        self
+    }
+
+    pub fn stash(&self) -> Option<&StashId> {
+        self.stash.as_ref()?.id.as_ref()
+    }
+
+    /// Take over restoring the stashed changes from the sandbox
+    pub fn take_stash(&mut self) -> Option<StashId> {
+        self.stash.as_mut()?.id.take()
+    }
+
+    pub fn with_base(mut self, base: BTreeMap<PathBuf, String>) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Contents of the environment definitions when entering the transaction,
+    /// by their path relative to the project
+    pub fn base(&self) -> &BTreeMap<PathBuf, String> {
+        &self.base
+    }
+
+    /// Remember an operation of this transaction for the [audit log](crate::models::audit)
+    pub(crate) fn record(&self, operation: AuditOperation, packages: Vec<FloxPackage>) {
+        self.recorded.borrow_mut().push((operation, packages));
+    }
+
+    /// Take the operations recorded so far
+    pub(crate) fn take_recorded(&self) -> Vec<(AuditOperation, Vec<FloxPackage>)> {
+        self.recorded.take()
+    }
+
     /// cleans up sandbox
     ///
     /// since we use TempDir, the tempdir will be removed as it gos out of scope
@@ -0,0 +1,160 @@
+//! Copying store paths between nix stores with `nix copy`
+//!
+//! Used to push built environments to a remote store or binary cache
+//! ([Environment::copy_to](super::project::environment::Environment::copy_to))
+//! and to fetch them elsewhere ([Flox::copy_from]).
+
+use std::ffi::OsStr;
+use std::process::Stdio;
+
+use log::debug;
+use runix::command_line::NixCommandLine;
+use runix::installable::Installable;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use super::events::{render_nix_log_line, NixStoreCopies};
+use crate::flox::{Flox, FloxNixApi};
+use crate::utils::errors::FloxErrorCode;
+
+/// Messages of nix indicating that the store rejected our credentials
+const AUTH_ERRORS: [&str; 5] = [
+    "HTTP error 401",
+    "HTTP error 403",
+    "Unauthorized",
+    "Forbidden",
+    "Permission denied",
+];
+
+/// Messages of nix indicating that the store could not be reached
+const CONNECTION_ERRORS: [&str; 6] = [
+    "Could not resolve host",
+    "Couldn't connect",
+    "Connection refused",
+    "Connection timed out",
+    "Timeout was reached",
+    "cannot connect",
+];
+
+impl Flox {
+    /// Copy `installable` and its closure from the store at `store_uri` into the local store
+    ///
+    /// If [Flox::event_sink] is set, progress is reported as
+    /// [FloxEvent::StoreCopyProgress](super::events::FloxEvent::StoreCopyProgress).
+    pub async fn copy_from(
+        &self,
+        store_uri: &str,
+        installable: &Installable,
+    ) -> Result<(), NixCopyError> {
+        nix_copy(self, ["--from", store_uri, &installable.to_string()]).await
+    }
+}
+
+/// Run `nix copy` with `args`, reporting progress to [Flox::event_sink]
+pub(crate) async fn nix_copy(
+    flox: &Flox,
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<(), NixCopyError> {
+    // make sure nix is configured like for any other flox invocation
+    let nix: NixCommandLine = flox.nix(Default::default());
+
+    let mut command = nix.command(&["copy"]);
+    command
+        .args(["--log-format", "internal-json"])
+        .args(args)
+        .stderr(Stdio::piped());
+
+    let mut child = command.spawn().map_err(NixCopyError::Spawn)?;
+
+    let mut copies = NixStoreCopies::default();
+    let mut messages = Vec::new();
+    if let Some(stderr) = child.stderr.take() {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Some(line) = lines.next_line().await.map_err(NixCopyError::Log)? {
+            if let Some(sink) = &flox.event_sink {
+                if let Some(event) = copies.record_log_line(&line) {
+                    sink.emit(&event);
+                }
+            }
+            if let Some(message) = render_nix_log_line(&line, flox.verbosity) {
+                debug!("{message}");
+                messages.push(message);
+            }
+        }
+    }
+
+    let status = child.wait().await.map_err(NixCopyError::Spawn)?;
+    if !status.success() {
+        return Err(classify_failure(
+            status.code().unwrap_or(-1),
+            messages.join("\n"),
+        ));
+    }
+    Ok(())
+}
+
+/// Distinguish authentication and connection failures by the messages of nix
+fn classify_failure(code: i32, stderr: String) -> NixCopyError {
+    if AUTH_ERRORS.iter().any(|error| stderr.contains(error)) {
+        NixCopyError::Auth(stderr)
+    } else if CONNECTION_ERRORS.iter().any(|error| stderr.contains(error)) {
+        NixCopyError::Connection(stderr)
+    } else {
+        NixCopyError::BadExit(code, stderr)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum NixCopyError {
+    #[error("Failed to run nix: {0}")]
+    Spawn(std::io::Error),
+    #[error("Failed to read nix log: {0}")]
+    Log(std::io::Error),
+    #[error("The store rejected the credentials:\n{0}")]
+    Auth(String),
+    #[error("Could not connect to the store:\n{0}")]
+    Connection(String),
+    #[error("Nix copy failed with exit code {0}:\n{1}")]
+    BadExit(i32, String),
+}
+
+impl NixCopyError {
+    pub fn code(&self) -> FloxErrorCode {
+        match self {
+            NixCopyError::Spawn(_) | NixCopyError::Log(_) => FloxErrorCode::Io,
+            NixCopyError::Auth(_) | NixCopyError::Connection(_) | NixCopyError::BadExit(..) => {
+                FloxErrorCode::Nix
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_failures() {
+        assert!(matches!(
+            classify_failure(
+                1,
+                "error: unable to download 'https://cache.example.com/nix-cache-info': HTTP \
+                 error 401"
+                    .to_string()
+            ),
+            NixCopyError::Auth(_)
+        ));
+        assert!(matches!(
+            classify_failure(
+                1,
+                "error: cannot connect to 'ssh://builder': ssh: Could not resolve host builder"
+                    .to_string()
+            ),
+            NixCopyError::Connection(_)
+        ));
+        assert!(matches!(
+            classify_failure(1, "error: path '/nix/store/abc' is not valid".to_string()),
+            NixCopyError::BadExit(1, _)
+        ));
+    }
+}
@@ -0,0 +1,112 @@
+use std::str::FromStr;
+
+use derive_more::Display;
+use thiserror::Error;
+
+/// A nix system double such as `aarch64-darwin`
+///
+/// Parsing only accepts systems known to flox,
+/// so that typos are caught instead of resulting in empty attribute lookups.
+/// Other systems can be used explicitly through [System::Unknown].
+#[derive(Debug, Clone, Display, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum System {
+    #[display(fmt = "x86_64-linux")]
+    X86_64Linux,
+    #[display(fmt = "aarch64-linux")]
+    Aarch64Linux,
+    #[display(fmt = "i686-linux")]
+    I686Linux,
+    #[display(fmt = "x86_64-darwin")]
+    X86_64Darwin,
+    #[display(fmt = "aarch64-darwin")]
+    Aarch64Darwin,
+    /// A system not known to flox, used as is
+    #[display(fmt = "{_0}")]
+    Unknown(String),
+}
+
+impl System {
+    pub const KNOWN: [System; 5] = [
+        System::X86_64Linux,
+        System::Aarch64Linux,
+        System::I686Linux,
+        System::X86_64Darwin,
+        System::Aarch64Darwin,
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            System::X86_64Linux => "x86_64-linux",
+            System::Aarch64Linux => "aarch64-linux",
+            System::I686Linux => "i686-linux",
+            System::X86_64Darwin => "x86_64-darwin",
+            System::Aarch64Darwin => "aarch64-darwin",
+            System::Unknown(system) => system,
+        }
+    }
+
+    /// Parse a known system, falling back to [System::Unknown]
+    ///
+    /// Use for systems that come from nix rather than from users,
+    /// e.g. the system flox was built for.
+    pub fn parse_or_unknown(system: &str) -> Self {
+        system
+            .parse()
+            .unwrap_or_else(|_| System::Unknown(system.to_string()))
+    }
+}
+
+/// The system of the running host
+impl Default for System {
+    fn default() -> Self {
+        let os = match std::env::consts::OS {
+            "macos" => "darwin",
+            os => os,
+        };
+        System::parse_or_unknown(&format!("{}-{os}", std::env::consts::ARCH))
+    }
+}
+
+impl FromStr for System {
+    type Err = UnknownSystemError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        System::KNOWN
+            .into_iter()
+            .find(|system| system.as_str() == s)
+            .ok_or_else(|| UnknownSystemError(s.to_string()))
+    }
+}
+
+impl AsRef<str> for System {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error(
+    "Unknown system '{0}', expected one of: x86_64-linux, aarch64-linux, i686-linux, \
+     x86_64-darwin, aarch64-darwin"
+)]
+pub struct UnknownSystemError(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_known_systems() {
+        for system in System::KNOWN {
+            assert_eq!(system.to_string().parse(), Ok(system));
+        }
+        assert_eq!(
+            "aarch64-darwn".parse::<System>(),
+            Err(UnknownSystemError("aarch64-darwn".to_string()))
+        );
+        assert_eq!(
+            System::parse_or_unknown("riscv64-linux"),
+            System::Unknown("riscv64-linux".to_string())
+        );
+    }
+}
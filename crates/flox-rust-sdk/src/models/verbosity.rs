@@ -0,0 +1,87 @@
+//! How much output flox and the nix processes it runs produce
+
+use log::LevelFilter;
+
+/// Log target of the SDK
+pub const LOG_TARGET: &str = "flox_rust_sdk";
+
+/// Verbosity of the SDK's logs and of nix, see [Flox::verbosity](crate::flox::Flox::verbosity)
+///
+/// Mirrors the `-q`/`-v` flags common to command line tools:
+/// [Verbosity::Quiet] shows errors only, the default shows warnings
+/// and each level of [Verbosity::Verbose] adds detail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    Quiet,
+    Verbose(usize),
+}
+
+impl Default for Verbosity {
+    fn default() -> Self {
+        Verbosity::Verbose(0)
+    }
+}
+
+impl Verbosity {
+    /// Maximum level of log records of the SDK
+    pub fn log_level_filter(&self) -> LevelFilter {
+        match self {
+            Verbosity::Quiet => LevelFilter::Error,
+            Verbosity::Verbose(0) => LevelFilter::Warn,
+            Verbosity::Verbose(1..=2) => LevelFilter::Info,
+            Verbosity::Verbose(3..=4) => LevelFilter::Debug,
+            Verbosity::Verbose(_) => LevelFilter::Trace,
+        }
+    }
+
+    /// Directive restricting the SDK's log target to [Self::log_level_filter],
+    /// to be added to the filter of the logger, e.g. `flox_rust_sdk=warn`
+    ///
+    /// The SDK does not install a logger itself, so the embedding application applies it.
+    pub fn log_directive(&self) -> String {
+        format!(
+            "{LOG_TARGET}={}",
+            self.log_level_filter().as_str().to_lowercase()
+        )
+    }
+
+    /// Verbosity nix runs with given [Self::nix_args], from `0` (`lvlError`) to `7` (`lvlVomit`)
+    ///
+    /// nix defaults to `lvlInfo` and each flag moves one level.
+    pub fn nix_level(&self) -> u64 {
+        match self {
+            Verbosity::Quiet => 2,
+            Verbosity::Verbose(level) => (3 + *level as u64).min(7),
+        }
+    }
+
+    /// Flags passed to every nix invocation, `--quiet` or one `-v` per level
+    pub fn nix_args(&self) -> Vec<String> {
+        match self {
+            Verbosity::Quiet => vec!["--quiet".to_string()],
+            Verbosity::Verbose(level) => vec!["-v".to_string(); *level],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_levels() {
+        assert_eq!(Verbosity::Quiet.log_directive(), "flox_rust_sdk=error");
+        assert_eq!(Verbosity::default().log_directive(), "flox_rust_sdk=warn");
+        assert_eq!(Verbosity::Verbose(1).log_level_filter(), LevelFilter::Info);
+        assert_eq!(Verbosity::Verbose(3).log_level_filter(), LevelFilter::Debug);
+        assert_eq!(Verbosity::Verbose(5).log_level_filter(), LevelFilter::Trace);
+
+        assert_eq!(Verbosity::Quiet.nix_args(), ["--quiet"]);
+        assert!(Verbosity::default().nix_args().is_empty());
+        assert_eq!(Verbosity::Verbose(2).nix_args(), ["-v", "-v"]);
+
+        assert_eq!(Verbosity::Quiet.nix_level(), 2);
+        assert_eq!(Verbosity::default().nix_level(), 3);
+        assert_eq!(Verbosity::Verbose(9).nix_level(), 7);
+    }
+}
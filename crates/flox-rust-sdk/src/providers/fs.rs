@@ -0,0 +1,448 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+/// Minimal async filesystem abstraction
+///
+/// [Project](crate::models::project::Project) performs its file operations
+/// through this trait rather than calling [tokio::fs] directly.
+/// This allows swapping the real filesystem ([TokioFs])
+/// for an in-memory one ([MemFs]) in tests.
+#[async_trait(?Send)]
+pub trait FileSystem: Send + Sync + std::fmt::Debug {
+    async fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    async fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    async fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Remove a file or a directory including its contents
+    async fn remove(&self, path: &Path) -> io::Result<()>;
+    /// Kind of the entry at `path`, [None] if nothing exists there
+    ///
+    /// Symlinks are not followed.
+    async fn kind(&self, path: &Path) -> io::Result<Option<FileKind>>;
+    /// Names of the entries of the directory at `path`, sorted
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+    /// Size in bytes of the file at `path`, symlinks are not followed
+    async fn size(&self, path: &Path) -> io::Result<u64>;
+    /// Modification time of the entry at `path`, [None] if the filesystem keeps none
+    async fn modified(&self, path: &Path) -> io::Result<Option<SystemTime>>;
+    /// Copy the file at `from` to `to`, returns the number of bytes copied
+    ///
+    /// The permissions of `from` are not copied.
+    async fn copy(&self, from: &Path, to: &Path, options: CopyOptions) -> io::Result<u64>;
+}
+
+/// Metadata applied by [FileSystem::copy]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CopyOptions {
+    /// Mode of the copy, see [crate::utils::copy_file_with_mode]
+    pub mode: Option<u32>,
+    /// Give the copy the access and modification times of the original
+    pub preserve_times: bool,
+}
+
+/// Entries below `root` with their kind, relative to `root`
+///
+/// Directories are listed before their contents.
+/// Entries for which `skip` returns true are left out, directories including their contents.
+pub async fn walk<Fs: FileSystem + ?Sized>(
+    fs: &Fs,
+    root: &Path,
+    skip: impl Fn(&Path) -> bool,
+) -> io::Result<Vec<(PathBuf, FileKind)>> {
+    let mut entries = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        let mut subdirs = Vec::new();
+        for name in fs.read_dir(&root.join(&dir)).await? {
+            let path = dir.join(name);
+            if skip(&path) {
+                continue;
+            }
+            // entries removed since listing the directory are ignored
+            if let Some(kind) = fs.kind(&root.join(&path)).await? {
+                if kind == FileKind::Dir {
+                    subdirs.push(path.clone());
+                }
+                entries.push((path, kind));
+            }
+        }
+        pending.extend(subdirs.into_iter().rev());
+    }
+    Ok(entries)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+}
+
+/// [FileSystem] implementation backed by [tokio::fs]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioFs;
+
+#[async_trait(?Send)]
+impl FileSystem for TokioFs {
+    async fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        tokio::fs::read(path).await
+    }
+
+    async fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        tokio::fs::write(path, contents).await
+    }
+
+    async fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        tokio::fs::create_dir_all(path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        crate::utils::safe_rename(from, to).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        if tokio::fs::symlink_metadata(path).await?.is_dir() {
+            tokio::fs::remove_dir_all(path).await
+        } else {
+            tokio::fs::remove_file(path).await
+        }
+    }
+
+    async fn kind(&self, path: &Path) -> io::Result<Option<FileKind>> {
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.is_dir() => Ok(Some(FileKind::Dir)),
+            Ok(_) => Ok(Some(FileKind::File)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let mut names = Vec::new();
+        let mut entries = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            names.push(entry.file_name());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    async fn size(&self, path: &Path) -> io::Result<u64> {
+        Ok(tokio::fs::symlink_metadata(path).await?.len())
+    }
+
+    async fn modified(&self, path: &Path) -> io::Result<Option<SystemTime>> {
+        Ok(Some(tokio::fs::symlink_metadata(path).await?.modified()?))
+    }
+
+    async fn copy(&self, from: &Path, to: &Path, options: CopyOptions) -> io::Result<u64> {
+        // not tokio::fs::copy, which copies the permissions of read only store paths
+        let mut source = tokio::fs::File::open(from).await?;
+        let mut target = tokio::fs::File::create(to).await?;
+        let bytes = tokio::io::copy(&mut source, &mut target).await?;
+        if let Some(mode) = options.mode {
+            tokio::fs::set_permissions(to, std::fs::Permissions::from_mode(mode)).await?;
+        }
+        if options.preserve_times {
+            let metadata = tokio::fs::metadata(from).await?;
+            filetime::set_file_times(
+                to,
+                filetime::FileTime::from_last_access_time(&metadata),
+                filetime::FileTime::from_last_modification_time(&metadata),
+            )?;
+        }
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemFsState {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl MemFsState {
+    /// The filesystem root and the empty (relative) path are always considered present
+    fn is_dir(&self, path: &Path) -> bool {
+        path.parent().is_none() || path.as_os_str().is_empty() || self.dirs.contains(path)
+    }
+
+    fn parent_exists(&self, path: &Path) -> bool {
+        path.parent().map_or(true, |parent| self.is_dir(parent))
+    }
+}
+
+/// In-memory [FileSystem] implementation for deterministic tests
+///
+/// Clones share the same underlying state.
+#[derive(Debug, Default, Clone)]
+pub struct MemFs {
+    state: Arc<Mutex<MemFsState>>,
+}
+
+impl MemFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// List all files currently stored
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.state.lock().unwrap().files.keys().cloned().collect()
+    }
+
+    /// Check whether a file or directory exists at `path`
+    pub fn exists(&self, path: &Path) -> bool {
+        let state = self.state.lock().unwrap();
+        state.files.contains_key(path) || state.is_dir(path)
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+#[async_trait(?Send)]
+impl FileSystem for MemFs {
+    async fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+
+    async fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.parent_exists(path) {
+            return Err(not_found(path.parent().unwrap()));
+        }
+        state.files.insert(path.to_path_buf(), contents.to_vec());
+        Ok(())
+    }
+
+    async fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for ancestor in path.ancestors() {
+            if state.files.contains_key(ancestor) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is a file", ancestor.display()),
+                ));
+            }
+            if !state.is_dir(ancestor) {
+                state.dirs.insert(ancestor.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.parent_exists(to) {
+            return Err(not_found(to.parent().unwrap()));
+        }
+
+        if let Some(contents) = state.files.remove(from) {
+            state.files.insert(to.to_path_buf(), contents);
+            return Ok(());
+        }
+
+        if !state.dirs.contains(from) {
+            return Err(not_found(from));
+        }
+
+        let moved_dirs: Vec<PathBuf> = state
+            .dirs
+            .iter()
+            .filter(|dir| dir.starts_with(from))
+            .cloned()
+            .collect();
+        for dir in moved_dirs {
+            state.dirs.remove(&dir);
+            state.dirs.insert(to.join(dir.strip_prefix(from).unwrap()));
+        }
+
+        let moved_files: Vec<PathBuf> = state
+            .files
+            .keys()
+            .filter(|file| file.starts_with(from))
+            .cloned()
+            .collect();
+        for file in moved_files {
+            let contents = state.files.remove(&file).unwrap();
+            state
+                .files
+                .insert(to.join(file.strip_prefix(from).unwrap()), contents);
+        }
+
+        Ok(())
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.files.remove(path).is_some() {
+            return Ok(());
+        }
+
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+
+        state.dirs.retain(|dir| !dir.starts_with(path));
+        state.files.retain(|file, _| !file.starts_with(path));
+        Ok(())
+    }
+
+    async fn kind(&self, path: &Path) -> io::Result<Option<FileKind>> {
+        let state = self.state.lock().unwrap();
+        if state.files.contains_key(path) {
+            Ok(Some(FileKind::File))
+        } else if state.is_dir(path) {
+            Ok(Some(FileKind::Dir))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let state = self.state.lock().unwrap();
+        if !state.is_dir(path) {
+            return Err(not_found(path));
+        }
+
+        let names: BTreeSet<OsString> = state
+            .dirs
+            .iter()
+            .chain(state.files.keys())
+            .filter(|entry| entry.parent() == Some(path))
+            .filter_map(|entry| entry.file_name().map(OsString::from))
+            .collect();
+        Ok(names.into_iter().collect())
+    }
+
+    async fn size(&self, path: &Path) -> io::Result<u64> {
+        let state = self.state.lock().unwrap();
+        match state.files.get(path) {
+            Some(contents) => Ok(contents.len() as u64),
+            None => Err(not_found(path)),
+        }
+    }
+
+    async fn modified(&self, path: &Path) -> io::Result<Option<SystemTime>> {
+        match self.kind(path).await? {
+            Some(_) => Ok(None),
+            None => Err(not_found(path)),
+        }
+    }
+
+    /// Copies the contents only, [MemFs] keeps no metadata
+    async fn copy(&self, from: &Path, to: &Path, _options: CopyOptions) -> io::Result<u64> {
+        let contents = self.read(from).await?;
+        self.write(to, &contents).await?;
+        Ok(contents.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mem_fs_write_requires_parent() {
+        let fs = MemFs::new();
+
+        fs.write(Path::new("/project/flox.nix"), b"{}")
+            .await
+            .expect_err("parent does not exist yet");
+
+        fs.create_dir_all(Path::new("/project")).await.unwrap();
+        fs.write(Path::new("/project/flox.nix"), b"{}")
+            .await
+            .expect("parent exists");
+
+        assert_eq!(
+            fs.read(Path::new("/project/flox.nix")).await.unwrap(),
+            b"{}".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn mem_fs_rename_and_remove_dirs() {
+        let fs = MemFs::new();
+
+        fs.create_dir_all(Path::new("/a/pkgs/old")).await.unwrap();
+        fs.write(Path::new("/a/pkgs/old/default.nix"), b"old")
+            .await
+            .unwrap();
+
+        fs.rename(Path::new("/a/pkgs/old"), Path::new("/a/pkgs/new"))
+            .await
+            .unwrap();
+
+        assert!(!fs.exists(Path::new("/a/pkgs/old")));
+        assert_eq!(
+            fs.read(Path::new("/a/pkgs/new/default.nix")).await.unwrap(),
+            b"old".to_vec()
+        );
+
+        fs.remove(Path::new("/a/pkgs")).await.unwrap();
+        assert!(fs.files().is_empty());
+        fs.remove(Path::new("/a/pkgs"))
+            .await
+            .expect_err("already removed");
+    }
+
+    #[tokio::test]
+    async fn mem_fs_reads_dirs() {
+        let fs = MemFs::new();
+
+        fs.create_dir_all(Path::new("/a/pkgs/hello")).await.unwrap();
+        fs.write(Path::new("/a/flake.nix"), b"{}").await.unwrap();
+        fs.write(Path::new("/a/pkgs/hello/default.nix"), b"{}")
+            .await
+            .unwrap();
+
+        assert_eq!(fs.read_dir(Path::new("/a")).await.unwrap(), [
+            "flake.nix",
+            "pkgs"
+        ]);
+        assert_eq!(fs.read_dir(Path::new("/a/pkgs")).await.unwrap(), ["hello"]);
+        fs.read_dir(Path::new("/b"))
+            .await
+            .expect_err("does not exist");
+    }
+
+    #[tokio::test]
+    async fn walks_dirs_before_contents() {
+        let fs = MemFs::new();
+
+        fs.create_dir_all(Path::new("/a/pkgs/hello")).await.unwrap();
+        fs.create_dir_all(Path::new("/a/.git")).await.unwrap();
+        fs.write(Path::new("/a/.git/HEAD"), b"").await.unwrap();
+        fs.write(Path::new("/a/flake.nix"), b"{}").await.unwrap();
+        fs.write(Path::new("/a/pkgs/hello/default.nix"), b"{}")
+            .await
+            .unwrap();
+
+        let entries = walk(&fs, Path::new("/a"), |path| path.ends_with(".git"))
+            .await
+            .unwrap();
+        assert_eq!(entries, vec![
+            (PathBuf::from("flake.nix"), FileKind::File),
+            (PathBuf::from("pkgs"), FileKind::Dir),
+            (PathBuf::from("pkgs/hello"), FileKind::Dir),
+            (PathBuf::from("pkgs/hello/default.nix"), FileKind::File),
+        ]);
+    }
+}
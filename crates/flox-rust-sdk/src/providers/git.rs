@@ -1,10 +1,10 @@
+use std::collections::HashSet;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::os::unix::ffi::OsStringExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
 
 use async_trait::async_trait;
-use log::error;
 use thiserror::Error;
 use tokio::process::Command;
 
@@ -15,6 +15,55 @@ pub trait GitDiscoverError {
     fn not_found(&self) -> bool;
 }
 
+pub trait GitTagError {
+    /// Whether creating a tag failed because a tag of the same name exists
+    fn already_exists(&self) -> bool;
+}
+
+impl GitTagError for EmptyError {
+    fn already_exists(&self) -> bool {
+        match *self {}
+    }
+}
+
+pub trait GitShowError {
+    /// Whether showing a file failed because it does not exist at the revision
+    fn not_found(&self) -> bool;
+}
+
+impl GitShowError for EmptyError {
+    fn not_found(&self) -> bool {
+        match *self {}
+    }
+}
+
+pub trait GitStashError {
+    /// Whether stashing failed because there are no changes to stash
+    fn nothing_to_stash(&self) -> bool;
+}
+
+impl GitStashError for EmptyError {
+    fn nothing_to_stash(&self) -> bool {
+        match *self {}
+    }
+}
+
+/// Commit of a stash entry created by [GitProvider::stash]
+///
+/// Unlike `stash@{<n>}` it keeps referring to the same entry
+/// while other entries are pushed or popped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StashId(pub String);
+
+/// Config file written by [GitProvider::set_config]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigScope {
+    /// The config of the repository, `.git/config`
+    Local,
+    /// The config of the user, `~/.gitconfig`
+    Global,
+}
+
 pub struct BranchInfo {
     pub name: String,
     pub remote: Option<String>,
@@ -22,6 +71,22 @@ pub struct BranchInfo {
     pub description: String,
 }
 
+pub struct TagInfo {
+    pub name: String,
+    /// Commit the tag points at, rather than the tag object of annotated tags
+    pub rev: String,
+    /// Subject of the tag message, empty for lightweight tags
+    pub message: String,
+}
+
+pub struct CommitInfo {
+    pub rev: String,
+    /// Commit time in seconds since the unix epoch
+    pub timestamp: i64,
+    /// Subject of the commit message
+    pub message: String,
+}
+
 // simple git provider for the tasks we need to provide in
 // flox
 #[async_trait(?Send)]
@@ -33,12 +98,13 @@ pub trait GitProvider: Send + Sized + std::fmt::Debug {
 
     type CheckoutError: std::error::Error;
     type ListBranchesError: std::error::Error;
+    type ListFilesError: std::error::Error;
 
     type AddRemoteError: std::error::Error;
     type MvError: std::error::Error;
     type RmError: std::error::Error;
     type AddError: std::error::Error;
-    type ShowError: std::error::Error + Send + Sync + 'static;
+    type ShowError: std::error::Error + GitShowError + Send + Sync + 'static;
     type DiscoverError: std::error::Error
         + GitDiscoverError
         + Send
@@ -47,13 +113,69 @@ pub trait GitProvider: Send + Sized + std::fmt::Debug {
         + 'static;
     type FetchError: std::error::Error;
     type SetOriginError: std::error::Error;
+    type TagError: std::error::Error + GitTagError;
+    type LogError: std::error::Error;
+    type HeadError: std::error::Error;
+    type StashError: std::error::Error + GitStashError;
+    type ConfigError: std::error::Error;
+    type ResetError: std::error::Error;
+
+    /// How repositories are accessed, e.g. the git binary of [GitCommandProvider]
+    ///
+    /// Repositories derived from another one, e.g. transaction sandboxes,
+    /// are opened with the [options](GitProvider::options) of the original.
+    type Options: Clone + Default + fmt::Debug;
+
+    /// Options this repository was opened with
+    fn options(&self) -> &Self::Options;
 
-    async fn discover<P: AsRef<Path>>(path: P) -> Result<Self, Self::DiscoverError>;
-    async fn init<P: AsRef<Path>>(path: P, bare: bool) -> Result<Self, Self::InitError>;
+    async fn discover<P: AsRef<Path>>(path: P) -> Result<Self, Self::DiscoverError> {
+        Self::discover_with(&Default::default(), path).await
+    }
+    async fn init<P: AsRef<Path>>(path: P, bare: bool) -> Result<Self, Self::InitError> {
+        Self::init_with(&Default::default(), path, bare).await
+    }
     async fn clone<O: AsRef<OsStr>, P: AsRef<Path>>(
         origin: O,
         path: P,
         bare: bool,
+    ) -> Result<Self, Self::CloneError> {
+        Self::clone_with(&Default::default(), origin, path, bare).await
+    }
+    /// Clone `origin` into `path` sharing its object database
+    ///
+    /// Objects of `origin` are referenced through alternates (`git clone --shared`)
+    /// instead of being copied, and no files are checked out.
+    async fn clone_shared<P: AsRef<Path>>(
+        origin: &Path,
+        path: P,
+    ) -> Result<Self, Self::CloneError> {
+        Self::clone_shared_with(&Default::default(), origin, path).await
+    }
+
+    /// Like [GitProvider::discover], accessing the repository with `options`
+    async fn discover_with<P: AsRef<Path>>(
+        options: &Self::Options,
+        path: P,
+    ) -> Result<Self, Self::DiscoverError>;
+    /// Like [GitProvider::init], accessing the repository with `options`
+    async fn init_with<P: AsRef<Path>>(
+        options: &Self::Options,
+        path: P,
+        bare: bool,
+    ) -> Result<Self, Self::InitError>;
+    /// Like [GitProvider::clone], accessing the repository with `options`
+    async fn clone_with<O: AsRef<OsStr>, P: AsRef<Path>>(
+        options: &Self::Options,
+        origin: O,
+        path: P,
+        bare: bool,
+    ) -> Result<Self, Self::CloneError>;
+    /// Like [GitProvider::clone_shared], accessing the repository with `options`
+    async fn clone_shared_with<P: AsRef<Path>>(
+        options: &Self::Options,
+        origin: &Path,
+        path: P,
     ) -> Result<Self, Self::CloneError>;
 
     async fn checkout(&self, name: &str, orphan: bool) -> Result<(), Self::CheckoutError>;
@@ -68,16 +190,105 @@ pub trait GitProvider: Send + Sized + std::fmt::Debug {
         force: bool,
         cached: bool,
     ) -> Result<(), Self::RmError>;
+    /// Stage `paths`
+    ///
+    /// Idempotent: duplicate paths are added once,
+    /// and adding no paths or unchanged tracked files succeeds.
     async fn add(&self, paths: &[&Path]) -> Result<(), Self::AddError>;
+    /// Files tracked in the index, relative to the repository root
+    ///
+    /// If `paths` are given, only tracked files at or below them are listed.
+    async fn ls_files(&self, paths: &[&Path]) -> Result<Vec<PathBuf>, Self::ListFilesError>;
     async fn commit(&self, message: &str) -> Result<(), Self::CommitError>;
 
     async fn show(&self, object: &str) -> Result<OsString, Self::ShowError>;
-
-    async fn fetch(&self) -> Result<(), Self::FetchError>;
+    /// Contents of `path`, relative to the repository root, at `rev`
+    async fn show_file(&self, rev: &str, path: &Path) -> Result<OsString, Self::ShowError> {
+        self.show(&format!("{rev}:{}", path.display())).await
+    }
+    /// Commits reachable from `HEAD`, newest first
+    ///
+    /// If a `path` relative to the repository root is given,
+    /// only commits changing it are listed.
+    async fn log(
+        &self,
+        path: Option<&Path>,
+        limit: Option<usize>,
+    ) -> Result<Vec<CommitInfo>, Self::LogError>;
+    /// Commits reachable from `rev`, newest first, see [GitProvider::log]
+    async fn log_rev(
+        &self,
+        rev: &str,
+        path: Option<&Path>,
+        limit: Option<usize>,
+    ) -> Result<Vec<CommitInfo>, Self::LogError>;
+
+    /// Name of the checked out branch, [None] if `HEAD` is detached
+    async fn current_branch(&self) -> Result<Option<String>, Self::HeadError>;
+    /// Revision `HEAD` points to
+    async fn head_rev(&self) -> Result<String, Self::HeadError>;
+    /// Point the current branch, or a detached `HEAD`, at `new`
+    ///
+    /// Fails if `HEAD` no longer points to `old`, e.g. after a concurrent commit.
+    /// `message` is recorded in the reflog.
+    async fn update_head(&self, new: &str, old: &str, message: &str)
+        -> Result<(), Self::HeadError>;
+    /// Move the current branch and the index to `rev`, keeping the worktree
+    async fn reset(&self, rev: &str) -> Result<(), Self::ResetError>;
+    /// Set the index entries of `paths` to their state at `rev`, keeping `HEAD` and the worktree
+    async fn reset_paths(&self, rev: &str, paths: &[&Path]) -> Result<(), Self::ResetError>;
+
+    /// Stash all uncommitted changes including untracked files, leaving a clean worktree
+    async fn stash(&self) -> Result<StashId, Self::StashError>;
+    /// Restore the changes and index of stash entry `id` and drop it
+    ///
+    /// If the changes conflict with the worktree the entry is kept.
+    async fn unstash(&self, id: &StashId) -> Result<(), Self::StashError>;
+    /// Like [GitProvider::unstash], but blocking,
+    /// to restore changes where nothing can be awaited, e.g. in [Drop]
+    fn unstash_blocking(&self, id: &StashId) -> Result<(), Self::StashError>;
+
+    /// Effective value of config `key`, e.g. `user.name` or `flox.<setting>`
+    ///
+    /// Returns [None] if `key` is not set in any config file.
+    async fn get_config(&self, key: &str) -> Result<Option<String>, Self::ConfigError>;
+    /// Paths of the submodules declared in the `.gitmodules` of the working directory,
+    /// relative to it
+    ///
+    /// Without a `.gitmodules` there are no submodules.
+    async fn submodule_paths(&self) -> Result<Vec<PathBuf>, Self::ConfigError>;
+    /// Set config `key` to `value` in the config file of `scope`
+    async fn set_config(
+        &self,
+        key: &str,
+        value: &str,
+        scope: ConfigScope,
+    ) -> Result<(), Self::ConfigError>;
+
+    /// Fetch the refs of `remote`
+    async fn fetch(&self, remote: &str) -> Result<(), Self::FetchError>;
+    /// Fetch the refs of all remotes
+    async fn fetch_all(&self) -> Result<(), Self::FetchError>;
+    /// Fetch `HEAD` of the repository at `repository`, returning the fetched revision
+    async fn fetch_head(&self, repository: &Path) -> Result<String, Self::FetchError>;
     async fn push(&self, remote: &str) -> Result<(), Self::PushError>;
     async fn set_origin(&self, branch: &str, origin_name: &str)
         -> Result<(), Self::SetOriginError>;
 
+    /// Create a tag pointing at `target`
+    ///
+    /// Creates an annotated tag if a `message` is given, a lightweight tag otherwise.
+    async fn create_tag(
+        &self,
+        name: &str,
+        target: &str,
+        message: Option<&str>,
+    ) -> Result<(), Self::TagError>;
+    /// All tags of the repository, sorted by name
+    async fn list_tags(&self) -> Result<Vec<TagInfo>, Self::TagError>;
+    /// Delete tag `name`, fails if it does not exist
+    async fn delete_tag(&self, name: &str) -> Result<(), Self::TagError>;
+
     fn workdir(&self) -> Option<&Path>;
     fn path(&self) -> &Path;
 }
@@ -116,23 +327,42 @@ impl GitProvider for LibGit2Provider {
     type CheckoutError = EmptyError;
     type CloneError = EmptyError;
     type CommitError = EmptyError;
+    type ConfigError = EmptyError;
     type DiscoverError = git2::Error;
     type FetchError = EmptyError;
+    type HeadError = EmptyError;
     type InitError = git2::Error;
     type ListBranchesError = EmptyError;
+    type ListFilesError = EmptyError;
+    type LogError = EmptyError;
     type MvError = EmptyError;
+    type Options = ();
     type PushError = EmptyError;
+    type ResetError = EmptyError;
     type RmError = EmptyError;
     type SetOriginError = EmptyError;
     type ShowError = EmptyError;
+    type StashError = EmptyError;
+    type TagError = EmptyError;
 
-    async fn discover<P: AsRef<Path>>(path: P) -> Result<Self, Self::DiscoverError> {
+    fn options(&self) -> &Self::Options {
+        &()
+    }
+
+    async fn discover_with<P: AsRef<Path>>(
+        _options: &Self::Options,
+        path: P,
+    ) -> Result<Self, Self::DiscoverError> {
         Ok(LibGit2Provider {
             repository: git2::Repository::discover(path)?,
         })
     }
 
-    async fn init<P: AsRef<Path>>(path: P, bare: bool) -> Result<LibGit2Provider, Self::InitError> {
+    async fn init_with<P: AsRef<Path>>(
+        _options: &Self::Options,
+        path: P,
+        bare: bool,
+    ) -> Result<LibGit2Provider, Self::InitError> {
         Ok(LibGit2Provider {
             repository: if bare {
                 git2::Repository::init_bare(path)?
@@ -142,7 +372,8 @@ impl GitProvider for LibGit2Provider {
         })
     }
 
-    async fn clone<O: AsRef<OsStr>, P: AsRef<Path>>(
+    async fn clone_with<O: AsRef<OsStr>, P: AsRef<Path>>(
+        _options: &Self::Options,
         _origin: O,
         _path: P,
         _bare: bool,
@@ -150,6 +381,14 @@ impl GitProvider for LibGit2Provider {
         todo!()
     }
 
+    async fn clone_shared_with<P: AsRef<Path>>(
+        _options: &Self::Options,
+        _origin: &Path,
+        _path: P,
+    ) -> Result<Self, Self::CloneError> {
+        todo!()
+    }
+
     async fn checkout(&self, _name: &str, _orphan: bool) -> Result<(), Self::CheckoutError> {
         todo!()
     }
@@ -180,6 +419,10 @@ impl GitProvider for LibGit2Provider {
         todo!()
     }
 
+    async fn ls_files(&self, _paths: &[&Path]) -> Result<Vec<PathBuf>, Self::ListFilesError> {
+        todo!()
+    }
+
     async fn commit(&self, _message: &str) -> Result<(), Self::CommitError> {
         todo!()
     }
@@ -188,7 +431,86 @@ impl GitProvider for LibGit2Provider {
         todo!()
     }
 
-    async fn fetch(&self) -> Result<(), Self::FetchError> {
+    async fn log(
+        &self,
+        _path: Option<&Path>,
+        _limit: Option<usize>,
+    ) -> Result<Vec<CommitInfo>, Self::LogError> {
+        todo!()
+    }
+
+    async fn log_rev(
+        &self,
+        _rev: &str,
+        _path: Option<&Path>,
+        _limit: Option<usize>,
+    ) -> Result<Vec<CommitInfo>, Self::LogError> {
+        todo!()
+    }
+
+    async fn current_branch(&self) -> Result<Option<String>, Self::HeadError> {
+        todo!()
+    }
+
+    async fn head_rev(&self) -> Result<String, Self::HeadError> {
+        todo!()
+    }
+
+    async fn update_head(
+        &self,
+        _new: &str,
+        _old: &str,
+        _message: &str,
+    ) -> Result<(), Self::HeadError> {
+        todo!()
+    }
+
+    async fn reset(&self, _rev: &str) -> Result<(), Self::ResetError> {
+        todo!()
+    }
+
+    async fn reset_paths(&self, _rev: &str, _paths: &[&Path]) -> Result<(), Self::ResetError> {
+        todo!()
+    }
+
+    async fn stash(&self) -> Result<StashId, Self::StashError> {
+        todo!()
+    }
+
+    async fn unstash(&self, _id: &StashId) -> Result<(), Self::StashError> {
+        todo!()
+    }
+
+    fn unstash_blocking(&self, _id: &StashId) -> Result<(), Self::StashError> {
+        todo!()
+    }
+
+    async fn get_config(&self, _key: &str) -> Result<Option<String>, Self::ConfigError> {
+        todo!()
+    }
+
+    async fn submodule_paths(&self) -> Result<Vec<PathBuf>, Self::ConfigError> {
+        todo!()
+    }
+
+    async fn set_config(
+        &self,
+        _key: &str,
+        _value: &str,
+        _scope: ConfigScope,
+    ) -> Result<(), Self::ConfigError> {
+        todo!()
+    }
+
+    async fn fetch(&self, _remote: &str) -> Result<(), Self::FetchError> {
+        todo!()
+    }
+
+    async fn fetch_all(&self) -> Result<(), Self::FetchError> {
+        todo!()
+    }
+
+    async fn fetch_head(&self, _repository: &Path) -> Result<String, Self::FetchError> {
         todo!()
     }
 
@@ -204,6 +526,23 @@ impl GitProvider for LibGit2Provider {
         todo!()
     }
 
+    async fn create_tag(
+        &self,
+        _name: &str,
+        _target: &str,
+        _message: Option<&str>,
+    ) -> Result<(), Self::TagError> {
+        todo!()
+    }
+
+    async fn list_tags(&self) -> Result<Vec<TagInfo>, Self::TagError> {
+        todo!()
+    }
+
+    async fn delete_tag(&self, _name: &str) -> Result<(), Self::TagError> {
+        todo!()
+    }
+
     fn workdir(&self) -> Option<&Path> {
         self.repository.workdir()
     }
@@ -221,15 +560,70 @@ pub enum GitCommandError {
     BadExit(i32, String),
 }
 
+/// How [GitCommandProvider] invokes git
+#[derive(Clone, Debug)]
+pub struct GitCommandOptions {
+    binary: PathBuf,
+    envs: Vec<(OsString, OsString)>,
+}
+
+impl GitCommandOptions {
+    /// Run `binary` instead of the git flox was built with
+    pub fn with_binary(mut self, binary: impl Into<PathBuf>) -> Self {
+        self.binary = binary.into();
+        self
+    }
+
+    /// Set an environment variable for every git invocation,
+    /// e.g. `GIT_CONFIG_NOSYSTEM=1` to ignore the system wide git config
+    pub fn with_env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+}
+
+impl Default for GitCommandOptions {
+    fn default() -> Self {
+        GitCommandOptions {
+            binary: PathBuf::from(env!("GIT_BIN")),
+            envs: Vec::new(),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct GitCommandProvider {
     workdir: Option<PathBuf>,
     path: PathBuf,
+    options: GitCommandOptions,
 }
 
 impl GitCommandProvider {
-    fn new_command<P: AsRef<Path>>(w: &Option<P>) -> Command {
-        let mut c = Command::new(env!("GIT_BIN"));
+    /// Run `binary` instead of the git flox was built with
+    ///
+    /// Applies to all operations on this repository.
+    pub fn with_binary(mut self, binary: impl Into<PathBuf>) -> Self {
+        self.options = self.options.with_binary(binary);
+        self
+    }
+
+    /// Set an environment variable for every git invocation,
+    /// e.g. `GIT_CONFIG_NOSYSTEM=1` to ignore the system wide git config
+    pub fn with_env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.options = self.options.with_env(key, value);
+        self
+    }
+
+    fn new_command<P: AsRef<Path>>(options: &GitCommandOptions, w: &Option<P>) -> Command {
+        Command::from(Self::new_blocking_command(options, w))
+    }
+
+    fn new_blocking_command<P: AsRef<Path>>(
+        options: &GitCommandOptions,
+        w: &Option<P>,
+    ) -> std::process::Command {
+        let mut c = std::process::Command::new(&options.binary);
+        c.envs(options.envs.iter().map(|(k, v)| (k, v)));
 
         if let Some(workdir) = w.as_ref() {
             c.arg("-C");
@@ -239,9 +633,77 @@ impl GitCommandProvider {
         c
     }
 
+    /// Commit of the newest stash entry, [None] if there are no entries
+    async fn stash_rev(&self) -> Result<Option<String>, GitCommandError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.args(["rev-parse", "--quiet", "--verify", "refs/stash"]);
+
+        match GitCommandProvider::run_command(&mut command).await {
+            Ok(rev) => Ok(Some(rev.to_string_lossy().trim().to_string())),
+            // git exits with 1 and no message if the ref does not exist
+            Err(GitCommandError::BadExit(1, stderr)) if stderr.trim().is_empty() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Commits reachable from `rev`, or `HEAD`, newest first
+    async fn log_from(
+        &self,
+        rev: Option<&str>,
+        path: Option<&Path>,
+        limit: Option<usize>,
+    ) -> Result<Vec<CommitInfo>, GitCommandError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.arg("log");
+        command.arg("--format=%H%x09%ct%x09%s");
+        if let Some(limit) = limit {
+            command.arg(format!("--max-count={limit}"));
+        }
+        if let Some(rev) = rev {
+            command.arg(rev);
+        }
+        if let Some(path) = path {
+            command.arg("--");
+            command.arg(path);
+        }
+
+        let log = match GitCommandProvider::run_command(&mut command).await {
+            // a fresh repository has no history yet
+            Err(GitCommandError::BadExit(_, stderr))
+                if stderr.contains("does not have any commits yet") =>
+            {
+                return Ok(Vec::new())
+            },
+            log => log?,
+        };
+
+        let commits = log
+            .to_string_lossy()
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(3, '\t');
+                Some(CommitInfo {
+                    rev: parts.next()?.to_string(),
+                    timestamp: parts.next()?.parse().ok()?,
+                    message: parts.next().unwrap_or_default().to_string(),
+                })
+            })
+            .collect();
+
+        Ok(commits)
+    }
+
     async fn run_command(command: &mut Command) -> Result<OsString, GitCommandError> {
-        let out = command.output().await?;
+        Self::check_output(command.output().await?)
+    }
+
+    fn run_blocking_command(
+        command: &mut std::process::Command,
+    ) -> Result<OsString, GitCommandError> {
+        Self::check_output(command.output()?)
+    }
 
+    fn check_output(out: std::process::Output) -> Result<OsString, GitCommandError> {
         if !out.status.success() {
             return Err(GitCommandError::BadExit(
                 out.status.code().unwrap_or(-1),
@@ -253,6 +715,18 @@ impl GitCommandProvider {
     }
 }
 
+impl GitShowError for GitCommandError {
+    fn not_found(&self) -> bool {
+        match self {
+            GitCommandError::BadExit(_, stderr) => {
+                stderr.contains("does not exist in")
+                    || stderr.contains("exists on disk, but not in")
+            },
+            _ => false,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum GitCommandDiscoverError {
     #[error(transparent)]
@@ -265,14 +739,59 @@ pub enum GitCommandDiscoverError {
 
 impl GitDiscoverError for GitCommandDiscoverError {
     fn not_found(&self) -> bool {
+        // git reports both a missing repository and a path outside of any
+        // repository as "not a git repository"; anything else (e.g. git not
+        // being installed or unsafe ownership) is an actual error
         match self {
-            // TODO: handle errors
-            GitCommandDiscoverError::Command(_) => true,
+            GitCommandDiscoverError::Command(GitCommandError::BadExit(_, stderr)) => {
+                stderr.contains("not a git repository")
+            },
             _ => false,
         }
     }
 }
 
+#[derive(Error, Debug)]
+pub enum GitCommandTagError {
+    #[error(transparent)]
+    Command(#[from] GitCommandError),
+    #[error("Tag '{0}' already exists")]
+    AlreadyExists(String),
+    #[error("Tag '{0}' not found")]
+    NotFound(String),
+}
+
+impl GitTagError for GitCommandTagError {
+    fn already_exists(&self) -> bool {
+        matches!(self, GitCommandTagError::AlreadyExists(_))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum GitCommandStashError {
+    #[error(transparent)]
+    Command(#[from] GitCommandError),
+    #[error("No local changes to stash")]
+    NothingToStash,
+    #[error("Stash entry {0} not found")]
+    NotFound(String),
+}
+
+impl GitStashError for GitCommandStashError {
+    fn nothing_to_stash(&self) -> bool {
+        matches!(self, GitCommandStashError::NothingToStash)
+    }
+}
+
+/// Position of stash entry `id` in the output of `git stash list --format=%H`
+fn stash_index(entries: &OsStr, id: &StashId) -> Result<usize, GitCommandStashError> {
+    entries
+        .to_string_lossy()
+        .lines()
+        .position(|rev| rev == id.0)
+        .ok_or_else(|| GitCommandStashError::NotFound(id.0.clone()))
+}
+
 /// A simple Git Provider that uses the git
 /// command. This would require that git is installed.
 #[async_trait(?Send)]
@@ -282,19 +801,34 @@ impl GitProvider for GitCommandProvider {
     type CheckoutError = GitCommandError;
     type CloneError = GitCommandError;
     type CommitError = GitCommandError;
+    type ConfigError = GitCommandError;
     type DiscoverError = GitCommandDiscoverError;
     type FetchError = GitCommandError;
+    type HeadError = GitCommandError;
     type InitError = GitCommandError;
     type ListBranchesError = GitCommandError;
+    type ListFilesError = GitCommandError;
+    type LogError = GitCommandError;
     type MvError = GitCommandError;
+    type Options = GitCommandOptions;
     type PushError = GitCommandError;
+    type ResetError = GitCommandError;
     type RmError = GitCommandError;
     type SetOriginError = GitCommandError;
     type ShowError = GitCommandError;
+    type StashError = GitCommandStashError;
+    type TagError = GitCommandTagError;
 
-    async fn discover<P: AsRef<Path>>(path: P) -> Result<Self, Self::DiscoverError> {
+    fn options(&self) -> &Self::Options {
+        &self.options
+    }
+
+    async fn discover_with<P: AsRef<Path>>(
+        options: &Self::Options,
+        path: P,
+    ) -> Result<Self, Self::DiscoverError> {
         let out = GitCommandProvider::run_command(
-            GitCommandProvider::new_command(&Some(&path))
+            GitCommandProvider::new_command(options, &Some(&path))
                 .arg("rev-parse")
                 .arg("--is-bare-repository"),
         )
@@ -313,11 +847,12 @@ impl GitProvider for GitCommandProvider {
             return Ok(GitCommandProvider {
                 workdir: None,
                 path: path.as_ref().to_path_buf(),
+                options: options.clone(),
             });
         }
 
         let out = GitCommandProvider::run_command(
-            GitCommandProvider::new_command(&Some(&path))
+            GitCommandProvider::new_command(options, &Some(&path))
                 .arg("rev-parse")
                 .arg("--show-toplevel"),
         )
@@ -332,14 +867,16 @@ impl GitProvider for GitCommandProvider {
         Ok(GitCommandProvider {
             workdir: Some(workdir.clone()),
             path: workdir,
+            options: options.clone(),
         })
     }
 
-    async fn init<P: AsRef<Path>>(
+    async fn init_with<P: AsRef<Path>>(
+        options: &Self::Options,
         path: P,
         bare: bool,
     ) -> Result<GitCommandProvider, Self::InitError> {
-        let mut command = GitCommandProvider::new_command(&Some(&path));
+        let mut command = GitCommandProvider::new_command(options, &Some(&path));
         command.arg("init");
         if bare {
             command.arg("--bare");
@@ -350,15 +887,17 @@ impl GitProvider for GitCommandProvider {
         Ok(GitCommandProvider {
             workdir: Some(path.as_ref().into()),
             path: path.as_ref().into(),
+            options: options.clone(),
         })
     }
 
-    async fn clone<O: AsRef<OsStr>, P: AsRef<Path>>(
+    async fn clone_with<O: AsRef<OsStr>, P: AsRef<Path>>(
+        options: &Self::Options,
         origin: O,
         path: P,
         bare: bool,
     ) -> Result<Self, Self::CloneError> {
-        let mut command = GitCommandProvider::new_command(&Some(&path));
+        let mut command = GitCommandProvider::new_command(options, &Some(&path));
         command.arg("clone");
         if bare {
             command.arg("--bare");
@@ -371,11 +910,31 @@ impl GitProvider for GitCommandProvider {
         Ok(GitCommandProvider {
             workdir: (!bare).then(|| path.as_ref().to_path_buf()),
             path: path.as_ref().into(),
+            options: options.clone(),
+        })
+    }
+
+    async fn clone_shared_with<P: AsRef<Path>>(
+        options: &Self::Options,
+        origin: &Path,
+        path: P,
+    ) -> Result<Self, Self::CloneError> {
+        let mut command = GitCommandProvider::new_command(options, &Some(&path));
+        command
+            .args(["clone", "--shared", "--no-checkout"])
+            .arg(origin)
+            .arg("./");
+
+        let _out = GitCommandProvider::run_command(&mut command).await?;
+        Ok(GitCommandProvider {
+            workdir: Some(path.as_ref().to_path_buf()),
+            path: path.as_ref().into(),
+            options: options.clone(),
         })
     }
 
     async fn checkout(&self, name: &str, orphan: bool) -> Result<(), Self::CheckoutError> {
-        let mut command = GitCommandProvider::new_command(&self.workdir());
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir());
         command.arg("checkout");
         if orphan {
             command.arg("--orphan");
@@ -389,7 +948,7 @@ impl GitProvider for GitCommandProvider {
 
     async fn add_remote(&self, origin_name: &str, url: &str) -> Result<(), Self::AddRemoteError> {
         let _out = GitCommandProvider::run_command(
-            GitCommandProvider::new_command(&self.workdir)
+            GitCommandProvider::new_command(&self.options, &self.workdir)
                 .arg("remote")
                 .arg("add")
                 .arg(origin_name)
@@ -406,7 +965,7 @@ impl GitProvider for GitCommandProvider {
         origin_name: &str,
     ) -> Result<(), Self::SetOriginError> {
         let _out = GitCommandProvider::run_command(
-            GitCommandProvider::new_command(&self.workdir)
+            GitCommandProvider::new_command(&self.options, &self.workdir)
                 .arg("branch")
                 .arg(branch)
                 .arg("--set-upstream")
@@ -419,7 +978,7 @@ impl GitProvider for GitCommandProvider {
 
     async fn mv(&self, from: &Path, to: &Path) -> Result<(), Self::MvError> {
         let _out = GitCommandProvider::run_command(
-            GitCommandProvider::new_command(&self.workdir)
+            GitCommandProvider::new_command(&self.options, &self.workdir)
                 .arg("mv")
                 .arg(format!("{}", from.as_os_str().to_string_lossy()))
                 .arg(format!("{}", to.as_os_str().to_string_lossy())),
@@ -436,7 +995,7 @@ impl GitProvider for GitCommandProvider {
         force: bool,
         cached: bool,
     ) -> Result<(), Self::MvError> {
-        let mut command = GitCommandProvider::new_command(&self.workdir);
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir);
 
         command.arg("rm");
 
@@ -460,7 +1019,18 @@ impl GitProvider for GitCommandProvider {
     }
 
     async fn add(&self, paths: &[&Path]) -> Result<(), Self::MvError> {
-        let mut command = GitCommandProvider::new_command(&self.workdir);
+        let mut unique = HashSet::new();
+        let paths: Vec<&Path> = paths
+            .iter()
+            .copied()
+            .filter(|path| unique.insert(*path))
+            .collect();
+        // `git add` without paths fails with "Nothing specified, nothing added."
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir);
         command.arg("add");
         for path in paths {
             command.arg(path);
@@ -471,8 +1041,22 @@ impl GitProvider for GitCommandProvider {
         Ok(())
     }
 
+    async fn ls_files(&self, paths: &[&Path]) -> Result<Vec<PathBuf>, Self::ListFilesError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir);
+        command.args(["ls-files", "-z", "--"]);
+        command.args(paths);
+
+        let out = GitCommandProvider::run_command(&mut command).await?;
+        Ok(out
+            .as_bytes()
+            .split(|byte| *byte == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+            .collect())
+    }
+
     async fn commit(&self, message: &str) -> Result<(), Self::CommitError> {
-        let mut command = GitCommandProvider::new_command(&self.workdir());
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir());
         command.arg("commit");
         command.args(["-m", message]);
 
@@ -480,16 +1064,267 @@ impl GitProvider for GitCommandProvider {
         Ok(())
     }
 
+    async fn create_tag(
+        &self,
+        name: &str,
+        target: &str,
+        message: Option<&str>,
+    ) -> Result<(), Self::TagError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.arg("tag");
+        if let Some(message) = message {
+            command.args(["--annotate", "--message", message]);
+        }
+        command.arg(name);
+        command.arg(target);
+
+        match GitCommandProvider::run_command(&mut command).await {
+            Err(GitCommandError::BadExit(_, stderr)) if stderr.contains("already exists") => {
+                Err(GitCommandTagError::AlreadyExists(name.to_string()))
+            },
+            result => {
+                result?;
+                Ok(())
+            },
+        }
+    }
+
+    async fn list_tags(&self) -> Result<Vec<TagInfo>, Self::TagError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.arg("tag");
+        command.args([
+            "--list",
+            // only annotated tags carry a message of their own,
+            // `contents` would refer to the tagged commit otherwise
+            // annotated tags are peeled to the tagged commit
+            concat!(
+                "--format=%(refname:strip=2)%09",
+                "%(if)%(*objectname)%(then)%(*objectname)%09%(contents:subject)",
+                "%(else)%(objectname)%09%(end)",
+            ),
+        ]);
+
+        let tags = GitCommandProvider::run_command(&mut command)
+            .await?
+            .to_string_lossy()
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(3, '\t');
+                Some(TagInfo {
+                    name: parts.next()?.to_string(),
+                    rev: parts.next()?.to_string(),
+                    message: parts.next().unwrap_or_default().to_string(),
+                })
+            })
+            .collect();
+
+        Ok(tags)
+    }
+
+    async fn delete_tag(&self, name: &str) -> Result<(), Self::TagError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.arg("tag");
+        command.arg("--delete");
+        command.arg(name);
+
+        match GitCommandProvider::run_command(&mut command).await {
+            Err(GitCommandError::BadExit(_, stderr)) if stderr.contains("not found") => {
+                Err(GitCommandTagError::NotFound(name.to_string()))
+            },
+            result => {
+                result?;
+                Ok(())
+            },
+        }
+    }
+
     async fn show(&self, object: &str) -> Result<OsString, Self::ShowError> {
-        let mut command = GitCommandProvider::new_command(&Some(&self.path));
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
         command.arg("show");
         command.arg(object);
 
         Ok(GitCommandProvider::run_command(&mut command).await?)
     }
 
+    async fn log(
+        &self,
+        path: Option<&Path>,
+        limit: Option<usize>,
+    ) -> Result<Vec<CommitInfo>, Self::LogError> {
+        self.log_from(None, path, limit).await
+    }
+
+    async fn log_rev(
+        &self,
+        rev: &str,
+        path: Option<&Path>,
+        limit: Option<usize>,
+    ) -> Result<Vec<CommitInfo>, Self::LogError> {
+        self.log_from(Some(rev), path, limit).await
+    }
+
+    async fn current_branch(&self) -> Result<Option<String>, Self::HeadError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.args(["symbolic-ref", "--quiet", "--short", "HEAD"]);
+
+        match GitCommandProvider::run_command(&mut command).await {
+            Ok(branch) => Ok(Some(branch.to_string_lossy().trim().to_string())),
+            // git exits with 1 and no message if `HEAD` is detached
+            Err(GitCommandError::BadExit(1, stderr)) if stderr.trim().is_empty() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn head_rev(&self) -> Result<String, Self::HeadError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.args(["rev-parse", "HEAD"]);
+
+        let rev = GitCommandProvider::run_command(&mut command).await?;
+        Ok(rev.to_string_lossy().trim().to_string())
+    }
+
+    async fn update_head(
+        &self,
+        new: &str,
+        old: &str,
+        message: &str,
+    ) -> Result<(), Self::HeadError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.args(["update-ref", "-m", message, "HEAD", new, old]);
+
+        GitCommandProvider::run_command(&mut command).await?;
+        Ok(())
+    }
+
+    async fn reset(&self, rev: &str) -> Result<(), Self::ResetError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir());
+        command.args(["reset", "--quiet", rev, "--"]);
+
+        GitCommandProvider::run_command(&mut command).await?;
+        Ok(())
+    }
+
+    async fn reset_paths(&self, rev: &str, paths: &[&Path]) -> Result<(), Self::ResetError> {
+        // `git reset <rev> --` without paths would move `HEAD`
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir());
+        command.args(["reset", "--quiet", rev, "--"]).args(paths);
+
+        GitCommandProvider::run_command(&mut command).await?;
+        Ok(())
+    }
+
+    async fn stash(&self) -> Result<StashId, Self::StashError> {
+        // git succeeds without creating an entry if there is nothing to stash,
+        // so compare the newest entry before and after
+        let before = self.stash_rev().await?;
+
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir());
+        command.args(["stash", "push", "--include-untracked", "--message", "flox"]);
+        GitCommandProvider::run_command(&mut command).await?;
+
+        match self.stash_rev().await? {
+            Some(rev) if Some(&rev) != before.as_ref() => Ok(StashId(rev)),
+            _ => Err(GitCommandStashError::NothingToStash),
+        }
+    }
+
+    async fn unstash(&self, id: &StashId) -> Result<(), Self::StashError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.args(["stash", "list", "--format=%H"]);
+        let entries = GitCommandProvider::run_command(&mut command).await?;
+        let index = stash_index(&entries, id)?;
+
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir());
+        command.args(["stash", "pop", "--index"]);
+        command.arg(format!("stash@{{{index}}}"));
+        GitCommandProvider::run_command(&mut command).await?;
+        Ok(())
+    }
+
+    fn unstash_blocking(&self, id: &StashId) -> Result<(), Self::StashError> {
+        let mut command =
+            GitCommandProvider::new_blocking_command(&self.options, &Some(&self.path));
+        command.args(["stash", "list", "--format=%H"]);
+        let entries = GitCommandProvider::run_blocking_command(&mut command)?;
+        let index = stash_index(&entries, id)?;
+
+        let mut command = GitCommandProvider::new_blocking_command(&self.options, &self.workdir());
+        command.args(["stash", "pop", "--index"]);
+        command.arg(format!("stash@{{{index}}}"));
+        GitCommandProvider::run_blocking_command(&mut command)?;
+        Ok(())
+    }
+
+    async fn get_config(&self, key: &str) -> Result<Option<String>, Self::ConfigError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.args(["config", "--get"]);
+        command.arg(key);
+
+        match GitCommandProvider::run_command(&mut command).await {
+            // git exits with 1 and no message if the key is not set,
+            // invalid keys are reported on stderr
+            Err(GitCommandError::BadExit(1, stderr)) if stderr.trim().is_empty() => Ok(None),
+            value => Ok(Some(value?.to_string_lossy().trim_end().to_string())),
+        }
+    }
+
+    async fn submodule_paths(&self) -> Result<Vec<PathBuf>, Self::ConfigError> {
+        // bare repositories have no checked out .gitmodules
+        if self.workdir.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir);
+        command.args([
+            "config",
+            "--file",
+            ".gitmodules",
+            "-z",
+            "--get-regexp",
+            r"^submodule\..*\.path$",
+        ]);
+
+        let out = match GitCommandProvider::run_command(&mut command).await {
+            // like for `get_config`, also if there is no .gitmodules
+            Err(GitCommandError::BadExit(1, stderr)) if stderr.trim().is_empty() => {
+                return Ok(Vec::new())
+            },
+            out => out?,
+        };
+
+        // entries are `<key>\n<value>\0`
+        Ok(out
+            .to_string_lossy()
+            .split_terminator('\0')
+            .filter_map(|entry| entry.split_once('\n'))
+            .map(|(_, path)| PathBuf::from(path))
+            .collect())
+    }
+
+    async fn set_config(
+        &self,
+        key: &str,
+        value: &str,
+        scope: ConfigScope,
+    ) -> Result<(), Self::ConfigError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.arg("config");
+        command.arg(match scope {
+            ConfigScope::Local => "--local",
+            ConfigScope::Global => "--global",
+        });
+        command.args([key, value]);
+
+        GitCommandProvider::run_command(&mut command).await?;
+        Ok(())
+    }
+
     async fn list_branches(&self) -> Result<Vec<BranchInfo>, Self::ListBranchesError> {
-        let mut command = GitCommandProvider::new_command(&Some(&self.path));
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
         command.arg("branch");
         command.args(["--all", "--verbose"]);
 
@@ -537,18 +1372,48 @@ impl GitProvider for GitCommandProvider {
         Ok(info)
     }
 
-    async fn fetch(&self) -> Result<(), Self::FetchError> {
+    async fn fetch(&self, remote: &str) -> Result<(), Self::FetchError> {
+        GitCommandProvider::run_command(
+            GitCommandProvider::new_command(
+                &self.options,
+                &self.workdir.as_deref().or(Some(&self.path)),
+            )
+            .arg("fetch")
+            .arg(remote),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn fetch_all(&self) -> Result<(), Self::FetchError> {
         GitCommandProvider::run_command(
-            GitCommandProvider::new_command(&self.workdir.as_deref().or(Some(&self.path)))
-                .arg("fetch")
-                .arg("--all"),
+            GitCommandProvider::new_command(
+                &self.options,
+                &self.workdir.as_deref().or(Some(&self.path)),
+            )
+            .arg("fetch")
+            .arg("--all"),
         )
         .await?;
         Ok(())
     }
 
+    async fn fetch_head(&self, repository: &Path) -> Result<String, Self::FetchError> {
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command
+            .args(["fetch", "--quiet", "--no-tags"])
+            .arg(repository)
+            .arg("HEAD");
+        GitCommandProvider::run_command(&mut command).await?;
+
+        let mut command = GitCommandProvider::new_command(&self.options, &Some(&self.path));
+        command.args(["rev-parse", "FETCH_HEAD"]);
+        let rev = GitCommandProvider::run_command(&mut command).await?;
+        Ok(rev.to_string_lossy().trim().to_string())
+    }
+
     async fn push(&self, remote: &str) -> Result<(), Self::PushError> {
-        let mut command = GitCommandProvider::new_command(&self.workdir());
+        let mut command = GitCommandProvider::new_command(&self.options, &self.workdir());
         command.arg("push");
         command.arg(remote);
         command.arg("HEAD");
@@ -565,3 +1430,323 @@ impl GitProvider for GitCommandProvider {
         self.path.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn manage_tags() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let git = GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        tokio::fs::write(tempdir.path().join("file"), "content")
+            .await
+            .unwrap();
+        git.add(&[Path::new("file")]).await.unwrap();
+        git.commit("initial").await.unwrap();
+        let initial = git.head_rev().await.unwrap();
+        tokio::fs::write(tempdir.path().join("file"), "changed")
+            .await
+            .unwrap();
+        git.add(&[Path::new("file")]).await.unwrap();
+        git.commit("change").await.unwrap();
+
+        git.create_tag("lightweight", "HEAD", None).await.unwrap();
+        git.create_tag("annotated", &initial, Some("a message"))
+            .await
+            .unwrap();
+
+        let err = git
+            .create_tag("annotated", "HEAD", None)
+            .await
+            .expect_err("tag exists");
+        assert!(err.already_exists());
+
+        let tags = git.list_tags().await.unwrap();
+        let names: Vec<_> = tags.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(names, ["annotated", "lightweight"]);
+        assert_eq!(tags[0].message, "a message");
+        assert_eq!(tags[0].rev, initial);
+        assert_eq!(tags[1].message, "");
+        assert_eq!(tags[1].rev, git.head_rev().await.unwrap());
+
+        git.delete_tag("lightweight").await.unwrap();
+        assert!(matches!(
+            git.delete_tag("lightweight").await,
+            Err(GitCommandTagError::NotFound(_))
+        ));
+        assert_eq!(git.list_tags().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reads_head() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let git = GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        git.checkout("main", true).await.unwrap();
+        assert_eq!(git.current_branch().await.unwrap().as_deref(), Some("main"));
+
+        tokio::fs::write(tempdir.path().join("file"), "content")
+            .await
+            .unwrap();
+        git.add(&[Path::new("file")]).await.unwrap();
+        git.commit("initial").await.unwrap();
+
+        let rev = git.head_rev().await.unwrap();
+        assert_eq!(rev, git.log(None, Some(1)).await.unwrap()[0].rev);
+
+        git.checkout(&rev, false).await.unwrap();
+        assert_eq!(git.current_branch().await.unwrap(), None);
+        assert_eq!(git.head_rev().await.unwrap(), rev);
+    }
+
+    #[tokio::test]
+    async fn stashes_next_to_existing_entries() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let git = GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        tokio::fs::write(tempdir.path().join("file"), "committed")
+            .await
+            .unwrap();
+        git.add(&[Path::new("file")]).await.unwrap();
+        git.commit("initial").await.unwrap();
+
+        tokio::fs::write(tempdir.path().join("file"), "first")
+            .await
+            .unwrap();
+        let first = git.stash().await.unwrap();
+        // the existing entry is not mistaken for a new one
+        assert!(git.stash().await.unwrap_err().nothing_to_stash());
+
+        tokio::fs::write(tempdir.path().join("file"), "second")
+            .await
+            .unwrap();
+        let second = git.stash().await.unwrap();
+        assert_ne!(first, second);
+
+        git.unstash_blocking(&first).unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(tempdir.path().join("file"))
+                .await
+                .unwrap(),
+            "first"
+        );
+        assert!(matches!(
+            git.unstash_blocking(&first),
+            Err(GitCommandStashError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn add_is_idempotent() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let git = GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        tokio::fs::write(tempdir.path().join("file"), "content")
+            .await
+            .unwrap();
+        git.add(&[Path::new("file"), Path::new("file")])
+            .await
+            .unwrap();
+        git.commit("initial").await.unwrap();
+
+        git.add(&[Path::new("file")]).await.unwrap();
+        git.add(&[Path::new("file")]).await.unwrap();
+        git.add(&[]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lists_tracked_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let git = GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        std::fs::create_dir(tempdir.path().join("pkgs")).unwrap();
+        for file in ["flake.nix", "pkgs/a b.nix", "untracked"] {
+            std::fs::write(tempdir.path().join(file), "").unwrap();
+        }
+        git.add(&[Path::new("flake.nix"), Path::new("pkgs")])
+            .await
+            .unwrap();
+
+        assert_eq!(git.ls_files(&[]).await.unwrap(), [
+            Path::new("flake.nix"),
+            Path::new("pkgs/a b.nix")
+        ]);
+        assert_eq!(git.ls_files(&[Path::new("pkgs")]).await.unwrap(), [
+            Path::new("pkgs/a b.nix")
+        ]);
+        assert!(git
+            .ls_files(&[Path::new("untracked")])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn stashes_changes() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let git = GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        tokio::fs::write(tempdir.path().join("file"), "committed")
+            .await
+            .unwrap();
+        git.add(&[Path::new("file")]).await.unwrap();
+        git.commit("initial").await.unwrap();
+
+        assert!(git.stash().await.unwrap_err().nothing_to_stash());
+
+        tokio::fs::write(tempdir.path().join("file"), "changed")
+            .await
+            .unwrap();
+        tokio::fs::write(tempdir.path().join("untracked"), "")
+            .await
+            .unwrap();
+        let id = git.stash().await.unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(tempdir.path().join("file"))
+                .await
+                .unwrap(),
+            "committed"
+        );
+        assert!(!tempdir.path().join("untracked").exists());
+
+        git.unstash(&id).await.unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(tempdir.path().join("file"))
+                .await
+                .unwrap(),
+            "changed"
+        );
+        assert!(tempdir.path().join("untracked").exists());
+        assert!(matches!(
+            git.unstash(&id).await,
+            Err(GitCommandStashError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_config() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let repo = tempdir.path().join("repo");
+        tokio::fs::create_dir(&repo).await.unwrap();
+        let global_config = tempdir.path().join("gitconfig");
+        let git = GitCommandProvider::init(&repo, false)
+            .await
+            .unwrap()
+            .with_env("GIT_CONFIG_GLOBAL", &global_config);
+
+        assert_eq!(git.get_config("flox.generation").await.unwrap(), None);
+        assert!(git.get_config("invalid").await.is_err());
+
+        git.set_config("flox.generation", "1", ConfigScope::Local)
+            .await
+            .unwrap();
+        assert_eq!(
+            git.get_config("flox.generation").await.unwrap().as_deref(),
+            Some("1")
+        );
+
+        git.set_config("user.name", "flox user", ConfigScope::Global)
+            .await
+            .unwrap();
+        assert_eq!(
+            git.get_config("user.name").await.unwrap().as_deref(),
+            Some("flox user")
+        );
+        assert!(tokio::fs::read_to_string(&global_config)
+            .await
+            .unwrap()
+            .contains("flox user"));
+    }
+
+    #[tokio::test]
+    async fn lists_submodule_paths() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let git = GitCommandProvider::init(tempdir.path(), false)
+            .await
+            .unwrap();
+        assert!(git.submodule_paths().await.unwrap().is_empty());
+
+        tokio::fs::write(
+            tempdir.path().join(".gitmodules"),
+            "[submodule \"vendored lib\"]\n\
+             \tpath = \"vendor/the lib\"\n\
+             \turl = https://example.com/lib.git\n\
+             [submodule \"docs\"]\n\
+             \tpath = docs ; the manual\n\
+             \turl = https://example.com/docs.git\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(git.submodule_paths().await.unwrap(), [
+            PathBuf::from("vendor/the lib"),
+            PathBuf::from("docs")
+        ]);
+    }
+
+    #[tokio::test]
+    async fn runs_configured_binary() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let repo = tempdir.path().join("repo");
+        tokio::fs::create_dir(&repo).await.unwrap();
+
+        let log = tempdir.path().join("log");
+        let wrapper = tempdir.path().join("git");
+        tokio::fs::write(
+            &wrapper,
+            format!(
+                "#!/bin/sh\necho \"$GIT_CONFIG_NOSYSTEM $*\" >> {log}\nexec {git} \"$@\"\n",
+                log = log.display(),
+                git = env!("GIT_BIN"),
+            ),
+        )
+        .await
+        .unwrap();
+        tokio::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))
+            .await
+            .unwrap();
+
+        let options = GitCommandOptions::default()
+            .with_binary(&wrapper)
+            .with_env("GIT_CONFIG_NOSYSTEM", "1");
+        let git = GitCommandProvider::init_with(&options, &repo, false)
+            .await
+            .unwrap();
+        tokio::fs::write(repo.join("file"), "content")
+            .await
+            .unwrap();
+        git.add(&[Path::new("file")]).await.unwrap();
+
+        // repositories derived from `git` use the same binary
+        let clone = tempdir.path().join("clone");
+        tokio::fs::create_dir(&clone).await.unwrap();
+        let cloned = GitCommandProvider::clone_shared_with(git.options(), &repo, &clone)
+            .await
+            .unwrap();
+        GitCommandProvider::discover_with(cloned.options(), &clone)
+            .await
+            .unwrap();
+
+        let log = tokio::fs::read_to_string(&log).await.unwrap();
+        assert_eq!(log.lines().collect::<Vec<_>>(), [
+            format!("1 -C {} init", repo.display()),
+            format!("1 -C {} add file", repo.display()),
+            format!(
+                "1 -C {} clone --shared --no-checkout {} ./",
+                clone.display(),
+                repo.display()
+            ),
+            format!("1 -C {} rev-parse --is-bare-repository", clone.display()),
+            format!("1 -C {} rev-parse --show-toplevel", clone.display()),
+        ]);
+    }
+}
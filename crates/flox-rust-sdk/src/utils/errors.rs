@@ -3,6 +3,54 @@ use std::path::PathBuf;
 
 use thiserror::Error;
 
+/// Stable, machine readable classification of SDK errors
+///
+/// Frontends can map codes to exit codes or translated messages
+/// instead of matching on the display output of errors.
+/// Codes only ever get added, existing codes keep their meaning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum FloxErrorCode {
+    /// The project has no working directory, e.g. it is a bare repository
+    NoWorkdir,
+    /// Access to a file was denied
+    PermissionDenied,
+    /// Reading or writing files failed
+    Io,
+    /// A git operation failed
+    Git,
+    /// Running nix or evaluating with nix failed
+    Nix,
+    /// A template could not be applied
+    Template,
+    /// A change conflicts with the current state of the project
+    Conflict,
+    /// The requested environment or file does not exist
+    NotFound,
+    /// The object to create exists already
+    AlreadyExists,
+    /// A declaration or state file could not be parsed
+    Invalid,
+}
+
+impl FloxErrorCode {
+    /// Identifier of the code, e.g. to look up translated messages
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FloxErrorCode::NoWorkdir => "no-workdir",
+            FloxErrorCode::PermissionDenied => "permission-denied",
+            FloxErrorCode::Io => "io",
+            FloxErrorCode::Git => "git",
+            FloxErrorCode::Nix => "nix",
+            FloxErrorCode::Template => "template",
+            FloxErrorCode::Conflict => "conflict",
+            FloxErrorCode::NotFound => "not-found",
+            FloxErrorCode::AlreadyExists => "already-exists",
+            FloxErrorCode::Invalid => "invalid",
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum IoError {
     #[error("Couldn't create temp dir in {dir}: {err}")]
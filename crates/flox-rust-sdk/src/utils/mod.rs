@@ -1,6 +1,8 @@
 pub mod errors;
 pub mod guard;
+pub mod permissions;
 
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 
 use ::log::debug;
@@ -52,10 +54,16 @@ pub async fn find_and_replace(
                     .open(entry.path())
                     .await
                 {
-                    Ok(mut f) => f
-                        .write_all(new_contents.as_bytes())
-                        .await
-                        .map_err(FindAndReplaceError::WriteTemplateFile)?,
+                    Ok(mut f) => {
+                        f.write_all(new_contents.as_bytes())
+                            .await
+                            .map_err(FindAndReplaceError::WriteTemplateFile)?;
+                        // tokio completes writes in the background unless flushed,
+                        // callers stage the file right after
+                        f.flush()
+                            .await
+                            .map_err(FindAndReplaceError::WriteTemplateFile)?
+                    },
                     Err(err) => return Err(FindAndReplaceError::OpenTemplateFile(err)),
                 };
             }
@@ -70,12 +78,64 @@ pub async fn find_and_replace(
     Ok(())
 }
 
+/// Error code of rename(2) if source and target are on different filesystems
+const EXDEV: i32 = 18;
+
+/// Move `from` to `to` like [fs::rename], but also across filesystems
+///
+/// Falls back to copying `from` and removing it afterwards if renaming fails with `EXDEV`,
+/// e.g. when moving out of a tempdir on a tmpfs.
+pub async fn safe_rename(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to).await {
+        Err(e) if e.raw_os_error() == Some(EXDEV) => {
+            debug!("Cannot rename {from:?} across filesystems, copying it instead");
+            move_by_copy(from, to).await
+        },
+        result => result,
+    }
+}
+
+/// Copy `from` (recursively) to `to` and remove it, preserving symlinks
+async fn move_by_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if !fs::symlink_metadata(from).await?.is_dir() {
+        copy_entry(from, to).await?;
+        return fs::remove_file(from).await;
+    }
+
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(
+            entry
+                .path()
+                .strip_prefix(from)
+                .expect("walkdir only yields entries below the root"),
+        );
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target).await?;
+        } else {
+            copy_entry(entry.path(), &target).await?;
+        }
+    }
+    fs::remove_dir_all(from).await
+}
+
+/// Copy a file, or recreate a symlink pointing to the same target
+async fn copy_entry(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(from).await?.is_symlink() {
+        fs::symlink(fs::read_link(from).await?, to).await
+    } else {
+        fs::copy(from, to).await.map(|_| ())
+    }
+}
+
 /// Using fs::copy copies permissions from the Nix store, which we don't want, so open (or
 /// create) the files and copy with io::copy
+///
+/// Returns the number of bytes copied.
 pub async fn copy_file_without_permissions(
     from: impl AsRef<Path>,
     to: impl AsRef<Path>,
-) -> Result<(), IoError> {
+) -> Result<u64, IoError> {
     let mut to_file = fs::OpenOptions::new()
         .write(true)
         .truncate(true)
@@ -93,11 +153,80 @@ pub async fn copy_file_without_permissions(
             err: io_err,
         })?;
 
-    io::copy(&mut from_file, &mut to_file)
+    let bytes = io::copy(&mut from_file, &mut to_file)
         .await
         .map_err(|io_err| IoError::Copy {
             file: from.as_ref().to_path_buf(),
             err: io_err,
         })?;
-    Ok(())
+    Ok(bytes)
+}
+
+/// Like [copy_file_without_permissions], but setting the mode of `to` if `mode` is given
+///
+/// See [PermissionsPolicy::project_file_mode](permissions::PermissionsPolicy::project_file_mode).
+pub async fn copy_file_with_mode(
+    from: impl AsRef<Path>,
+    to: impl AsRef<Path>,
+    mode: Option<u32>,
+) -> Result<u64, IoError> {
+    let bytes = copy_file_without_permissions(&from, &to).await?;
+    if let Some(mode) = mode {
+        fs::set_permissions(&to, std::fs::Permissions::from_mode(mode))
+            .await
+            .map_err(|io_err| IoError::Write {
+                file: to.as_ref().to_path_buf(),
+                err: io_err,
+            })?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn safe_rename_moves_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let from = tempdir.path().join("from");
+        let to = tempdir.path().join("to");
+        std::fs::write(&from, "contents").unwrap();
+
+        safe_rename(&from, &to).await.unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "contents");
+    }
+
+    /// The fallback used for renames across filesystems,
+    /// which can not be provoked reliably in tests
+    #[tokio::test]
+    async fn moves_by_copy() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let from = tempdir.path().join("from");
+        let to = tempdir.path().join("to");
+        std::fs::create_dir_all(from.join("pkgs/hello")).unwrap();
+        std::fs::write(from.join("pkgs/hello/flox.nix"), "{}").unwrap();
+        std::os::unix::fs::symlink("pkgs/hello/flox.nix", from.join("flox.nix")).unwrap();
+
+        move_by_copy(&from, &to).await.unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(
+            std::fs::read_to_string(to.join("pkgs/hello/flox.nix")).unwrap(),
+            "{}"
+        );
+        assert_eq!(
+            std::fs::read_link(to.join("flox.nix")).unwrap(),
+            Path::new("pkgs/hello/flox.nix")
+        );
+
+        // single files are moved as well
+        move_by_copy(&to.join("flox.nix"), &tempdir.path().join("link"))
+            .await
+            .unwrap();
+        assert!(!to.join("flox.nix").exists());
+        assert!(tempdir.path().join("link").is_symlink());
+    }
 }
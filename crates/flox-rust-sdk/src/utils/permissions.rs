@@ -0,0 +1,92 @@
+//! Modes of files and directories created by flox
+//!
+//! The config dir holds the channel registry, nix.conf with access tokens
+//! and the audit log, so it is kept private to the user.
+//! Files written into projects follow the umask by default, like git does,
+//! so they end up with the same modes as other files of the repository.
+
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PermissionsPolicy {
+    /// Mode of files in the config dir
+    pub private_file_mode: u32,
+    /// Mode of the config dir
+    pub private_dir_mode: u32,
+    /// Mode of files copied into projects and transaction sandboxes,
+    /// [None] to create them with the default mode limited by the umask
+    pub project_file_mode: Option<u32>,
+}
+
+impl Default for PermissionsPolicy {
+    fn default() -> Self {
+        Self {
+            private_file_mode: 0o600,
+            private_dir_mode: 0o700,
+            project_file_mode: None,
+        }
+    }
+}
+
+impl PermissionsPolicy {
+    /// Create `dir` and its parents, setting the mode of `dir` to [Self::private_dir_mode]
+    ///
+    /// The mode of an existing `dir` is restricted as well.
+    pub fn create_private_dir(&self, dir: &Path) -> std::io::Result<()> {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(self.private_dir_mode)
+            .create(dir)?;
+        // the mode passed on creation is limited by the umask and ignored for existing dirs
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(self.private_dir_mode))
+    }
+
+    /// Set the mode of the existing file `path` to [Self::private_file_mode]
+    pub async fn restrict_private_file(&self, path: &Path) -> std::io::Result<()> {
+        tokio::fs::set_permissions(
+            path,
+            std::fs::Permissions::from_mode(self.private_file_mode),
+        )
+        .await
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::utils::copy_file_with_mode;
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[tokio::test]
+    async fn applies_modes() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let policy = PermissionsPolicy::default();
+
+        let config_dir = tempdir.path().join("config");
+        std::fs::create_dir(&config_dir).unwrap();
+        std::fs::set_permissions(&config_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        policy.create_private_dir(&config_dir).unwrap();
+        assert_eq!(mode(&config_dir), 0o700);
+
+        let private_file = config_dir.join("nix.conf");
+        std::fs::write(&private_file, "").unwrap();
+        policy.restrict_private_file(&private_file).await.unwrap();
+        assert_eq!(mode(&private_file), 0o600);
+
+        let source = tempdir.path().join("flox.nix");
+        std::fs::write(&source, "{}").unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o444)).unwrap();
+        let copy = tempdir.path().join("copy.nix");
+        copy_file_with_mode(&source, &copy, Some(0o640))
+            .await
+            .unwrap();
+        assert_eq!(mode(&copy), 0o640);
+    }
+}
@@ -46,7 +46,7 @@ impl GeneralCommands {
                     }
                 };
 
-                flox.channels.register_channel(
+                flox.channels_mut().register_channel(
                     "nixpkgs",
                     Channel::from_str(&format!("github:flox/nixpkgs/{}", config.flox.stability))?,
                 );
@@ -60,11 +60,11 @@ impl GeneralCommands {
 
             GeneralCommands::ResetMetrics => {
                 let mut metrics_lock =
-                    LockFile::open(&flox.cache_dir.join(METRICS_LOCK_FILE_NAME))?;
+                    LockFile::open(&flox.cache_dir().join(METRICS_LOCK_FILE_NAME))?;
                 tokio::task::spawn_blocking(move || metrics_lock.lock()).await??;
 
                 if let Err(err) =
-                    tokio::fs::remove_file(flox.cache_dir.join(METRICS_EVENTS_FILE_NAME)).await
+                    tokio::fs::remove_file(flox.cache_dir().join(METRICS_EVENTS_FILE_NAME)).await
                 {
                     match err.kind() {
                         std::io::ErrorKind::NotFound => {},
@@ -73,7 +73,7 @@ impl GeneralCommands {
                 }
 
                 if let Err(err) =
-                    tokio::fs::remove_file(flox.data_dir.join(METRICS_UUID_FILE_NAME)).await
+                    tokio::fs::remove_file(flox.data_dir().join(METRICS_UUID_FILE_NAME)).await
                 {
                     match err.kind() {
                         std::io::ErrorKind::NotFound => {},
@@ -81,7 +81,7 @@ impl GeneralCommands {
                     }
                 }
 
-                init_telemetry_consent(&flox.data_dir, &flox.cache_dir).await?;
+                init_telemetry_consent(flox.data_dir(), flox.cache_dir()).await?;
             },
 
             GeneralCommands::Config(config_args) => config_args.handle(config, flox).await?,
@@ -169,14 +169,14 @@ impl ConfigArgs {
 
         match self {
             ConfigArgs::List => println!("{}", config.get(&[])?),
-            ConfigArgs::Reset => write_config(&flox.temp_dir, &flox.config_dir, "").await?,
+            ConfigArgs::Reset => write_config(flox.temp_dir(), flox.config_dir(), "").await?,
             ConfigArgs::Set(ConfigSet { key, value, .. }) => {
-                update_config(&flox.config_dir, &flox.temp_dir, key, Some(value)).await?
+                update_config(flox.config_dir(), flox.temp_dir(), key, Some(value)).await?
             },
             ConfigArgs::SetNumber(ConfigSetNumber { key, value, .. }) => {
                 update_config(
-                    &flox.config_dir,
-                    &flox.temp_dir,
+                    flox.config_dir(),
+                    flox.temp_dir(),
                     key,
                     Some(
                         value
@@ -188,8 +188,8 @@ impl ConfigArgs {
             },
             ConfigArgs::SetBool(ConfigSetBool { key, value, .. }) => {
                 update_config(
-                    &flox.config_dir,
-                    &flox.temp_dir,
+                    flox.config_dir(),
+                    flox.temp_dir(),
                     key,
                     Some(
                         value
@@ -200,7 +200,7 @@ impl ConfigArgs {
                 .await?
             },
             ConfigArgs::Delete(ConfigDelete { key, .. }) => {
-                update_config::<()>(&flox.config_dir, &flox.temp_dir, key, None).await?
+                update_config::<()>(flox.config_dir(), flox.temp_dir(), key, None).await?
             },
         }
         Ok(())
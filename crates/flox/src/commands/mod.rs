@@ -8,7 +8,10 @@ use std::{env, fs};
 
 use anyhow::Result;
 use bpaf::{Bpaf, Parser};
-use flox_rust_sdk::flox::{Flox, FLOX_VERSION};
+use flox_rust_sdk::flox::{FloxBuilder, FLOX_VERSION};
+use flox_rust_sdk::models::flake_ref::ToFlakeRef;
+use flox_rust_sdk::models::system::System;
+use flox_rust_sdk::models::verbosity::Verbosity as SdkVerbosity;
 use flox_rust_sdk::prelude::Channel;
 use log::debug;
 use tempfile::TempDir;
@@ -53,6 +56,20 @@ impl Default for Verbosity {
     }
 }
 
+impl Verbosity {
+    /// Verbosity of the SDK and nix, matching the log filter of [crate::utils::init::init_logger]
+    ///
+    /// `--debug` raises the SDK to the debug and trace levels shown by the logger.
+    pub fn sdk(&self, debug: bool) -> SdkVerbosity {
+        match (debug, self) {
+            (false, Verbosity::Quiet) => SdkVerbosity::Quiet,
+            (false, Verbosity::Verbose(level)) => SdkVerbosity::Verbose(*level),
+            (true, Verbosity::Quiet) => SdkVerbosity::Verbose(3),
+            (true, Verbosity::Verbose(level)) => SdkVerbosity::Verbose(level + 4),
+        }
+    }
+}
+
 #[derive(Bpaf)]
 #[bpaf(options, version(FLOX_VERSION))]
 pub struct FloxArgs {
@@ -105,17 +122,37 @@ impl FloxArgs {
             .expect("User must have a home directory")
             .join(".netrc");
 
-        let flox = Flox {
-            cache_dir: config.flox.cache_dir.clone(),
-            data_dir: config.flox.data_dir.clone(),
-            config_dir: config.flox.config_dir.clone(),
-            channels,
-            access_tokens,
-            netrc_file,
-            temp_dir: temp_dir_path.clone(),
-            system: env!("NIX_TARGET_SYSTEM").to_string(),
-            uuid: init_uuid(&config.flox.data_dir).await?,
-        };
+        let flox = FloxBuilder::new()
+            .cache_dir(&config.flox.cache_dir)
+            .data_dir(&config.flox.data_dir)
+            .config_dir(&config.flox.config_dir)
+            .channels(channels)
+            .access_tokens(access_tokens)
+            .netrc_file(netrc_file)
+            .reject_legacy_layout(config.flox.reject_legacy_layout)
+            .flox_nix_names(config.flox.flox_nix_names.clone())
+            .max_parallel_builds(config.flox.max_parallel_builds)
+            .audit(config.flox.audit)
+            .env_interpolation(config.flox.env_interpolation)
+            .install_policy(config.flox.install_policy.clone().into_install_policy())
+            .nix_connect_timeout(config.flox.nix_connect_timeout)
+            .nix_http_connections(config.flox.nix_http_connections)
+            .keep_sandboxes(config.flox.keep_sandboxes)
+            .pinned_nixpkgs(
+                config
+                    .flox
+                    .pinned_nixpkgs
+                    .as_deref()
+                    .map(ToFlakeRef::from_str)
+                    .transpose()?,
+            )
+            .compat_devshells(config.flox.compat_devshells)
+            .permissions(config.flox.permissions)
+            .verbosity(self.verbosity.sdk(self.debug))
+            .temp_dir(&temp_dir_path)
+            .system(System::parse_or_unknown(env!("NIX_TARGET_SYSTEM")))
+            .uuid(init_uuid(&config.flox.data_dir).await?)
+            .build()?;
 
         // in debug mode keep the tempdir to reproduce nix commands
         if self.debug || matches!(self.verbosity, Verbosity::Verbose(1..)) {
@@ -150,7 +187,7 @@ impl FloxArgs {
 
                 let mut flox = flox;
                 // more mutable state hurray :/
-                flox.channels.register_channel(
+                flox.channels_mut().register_channel(
                     "nixpkgs",
                     Channel::from_str(&format!("github:flox/nixpkgs/{}", config.flox.stability))?,
                 );
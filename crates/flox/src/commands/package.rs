@@ -42,7 +42,7 @@ pub(crate) mod interface {
     use async_trait::async_trait;
     use bpaf::{Bpaf, Parser};
     use flox_rust_sdk::flox::Flox;
-    use flox_rust_sdk::prelude::Installable;
+    use flox_rust_sdk::prelude::{Installable, Stability};
     use flox_rust_sdk::providers::git::GitProvider;
 
     use super::parseable_macro::parseable;
@@ -79,16 +79,24 @@ pub(crate) mod interface {
 
     #[async_trait(?Send)]
     pub trait ResolveInstallable<Git: GitProvider> {
-        async fn installable(&self, flox: &Flox) -> anyhow::Result<Installable>;
+        async fn installable(
+            &self,
+            flox: &Flox,
+            stability: &Stability,
+        ) -> anyhow::Result<Installable>;
     }
 
     #[async_trait(?Send)]
     impl<T: InstallableDef + 'static, Git: GitProvider + 'static> ResolveInstallable<Git>
         for PosOrEnv<T>
     {
-        async fn installable(&self, flox: &Flox) -> anyhow::Result<Installable> {
+        async fn installable(
+            &self,
+            flox: &Flox,
+            stability: &Stability,
+        ) -> anyhow::Result<Installable> {
             Ok(match self {
-                PosOrEnv::Pos(i) => i.resolve_installable(flox).await?,
+                PosOrEnv::Pos(i) => i.resolve_installable(flox, stability).await?,
                 PosOrEnv::Env(n) => env_ref_to_installable::<Git>(flox, T::SUBCOMMAND, n).await?,
             })
         }
@@ -98,13 +106,18 @@ pub(crate) mod interface {
     impl<T: InstallableDef + 'static, Git: GitProvider + 'static> ResolveInstallable<Git>
         for Option<PosOrEnv<T>>
     {
-        async fn installable(&self, flox: &Flox) -> anyhow::Result<Installable> {
+        async fn installable(
+            &self,
+            flox: &Flox,
+            stability: &Stability,
+        ) -> anyhow::Result<Installable> {
             Ok(match self {
-                Some(x) => ResolveInstallable::<Git>::installable(x, flox).await?,
+                Some(x) => ResolveInstallable::<Git>::installable(x, flox, stability).await?,
                 None => {
                     ResolveInstallable::<Git>::installable(
                         &PosOrEnv::Pos(InstallableArgument::<Parsed, T>::default()),
                         flox,
+                        stability,
                     )
                     .await?
                 },
@@ -126,6 +139,9 @@ pub(crate) mod interface {
         pub(crate) name: Option<String>,
         #[bpaf(short('i'), long("init-git"), switch)]
         pub(crate) init_git: bool,
+        /// List the templates of the flox channel instead of initializing a package
+        #[bpaf(long("list-templates"), switch)]
+        pub(crate) list_templates: bool,
     }
     pub(crate) fn template_arg(
     ) -> impl Parser<Option<InstallableArgument<Parsed, TemplateInstallable>>> {
@@ -325,6 +341,16 @@ impl interface::PackageCommands {
             interface::PackageCommands::Init(command) => {
                 subcommand_metric!("init");
 
+                if command.inner.list_templates {
+                    for template in flox.list_templates::<NixCommandLine>().await? {
+                        match template.description {
+                            Some(description) => println!("{}\t{description}", template.name),
+                            None => println!("{}", template.name),
+                        }
+                    }
+                    return Ok(());
+                }
+
                 let cwd = std::env::current_dir()?;
                 let basename = cwd
                     .file_name()
@@ -340,7 +366,7 @@ impl interface::PackageCommands {
                     .inner
                     .template
                     .unwrap_or_default()
-                    .resolve_installable(&flox)
+                    .resolve_installable(&flox, &config.flox.stability)
                     .await?;
 
                 let name = match command.inner.name {
@@ -379,7 +405,7 @@ impl interface::PackageCommands {
                     .inner
                     .installable_arg
                     .unwrap_or_default()
-                    .resolve_installable(&flox)
+                    .resolve_installable(&flox, &config.flox.stability)
                     .await?;
 
                 flox.package(installable_arg, config.flox.stability, command.nix_args)
@@ -393,7 +419,7 @@ impl interface::PackageCommands {
                     .inner
                     .installable_arg
                     .unwrap_or_default()
-                    .resolve_installable(&flox)
+                    .resolve_installable(&flox, &config.flox.stability)
                     .await?;
 
                 flox.package(installable_arg, config.flox.stability, command.nix_args)
@@ -407,7 +433,7 @@ impl interface::PackageCommands {
                     .inner
                     .installable_arg
                     .unwrap_or_default()
-                    .resolve_installable(&flox)
+                    .resolve_installable(&flox, &config.flox.stability)
                     .await?;
 
                 flox.package(installable_arg, config.flox.stability, command.nix_args)
@@ -421,7 +447,7 @@ impl interface::PackageCommands {
                     .inner
                     .installable_arg
                     .unwrap_or_default()
-                    .resolve_installable(&flox)
+                    .resolve_installable(&flox, &config.flox.stability)
                     .await?;
 
                 flox.package(installable_arg, config.flox.stability, command.nix_args)
@@ -448,6 +474,7 @@ impl interface::PackageCommands {
                 let installable_arg = ResolveInstallable::<GitCommandProvider>::installable(
                     &command.inner.installable_arg,
                     &flox,
+                    &config.flox.stability,
                 )
                 .await?;
 
@@ -455,7 +482,7 @@ impl interface::PackageCommands {
                     .inner
                     .bundler_arg
                     .unwrap_or_default()
-                    .resolve_installable(&flox)
+                    .resolve_installable(&flox, &config.flox.stability)
                     .await?;
 
                 flox.package(installable_arg, config.flox.stability, command.nix_args)
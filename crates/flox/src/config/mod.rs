@@ -4,7 +4,10 @@ use std::{env, fs};
 
 use anyhow::{Context, Result};
 use config::{Config as HierarchicalConfig, Environment};
+use flox_rust_sdk::models::flox_nix::{EnvInterpolation, FloxNixNames};
+use flox_rust_sdk::models::policy::PackagePolicy;
 use flox_rust_sdk::prelude::Stability;
+use flox_rust_sdk::utils::permissions::PermissionsPolicy;
 use itertools::{Either, Itertools};
 use log::{debug, trace};
 use once_cell::sync::OnceCell;
@@ -47,6 +50,43 @@ pub struct FloxConfig {
     pub config_dir: PathBuf,
     #[serde(default)]
     pub stability: Stability,
+    /// Fail on templates using the legacy `pkgs/default.nix` layout
+    #[serde(default)]
+    pub reject_legacy_layout: bool,
+    /// Filenames of environment definitions, in order of preference
+    #[serde(default)]
+    pub flox_nix_names: FloxNixNames,
+    /// Maximum number of environments built at once, 1 if unset
+    #[serde(default)]
+    pub max_parallel_builds: usize,
+    /// Record install, uninstall and upgrade operations in an audit log in `config_dir`
+    #[serde(default)]
+    pub audit: bool,
+    /// Whether environment variables and hooks may reference host variables as `${NAME}`
+    #[serde(default)]
+    pub env_interpolation: EnvInterpolation,
+    /// Packages that may (`allow`) or may not (`deny`) be installed, by `<channel>.<name>` glob
+    #[serde(default)]
+    pub install_policy: PackagePolicy,
+    /// Seconds nix waits for connections to be established
+    #[serde(default)]
+    pub nix_connect_timeout: Option<u64>,
+    /// Maximum number of parallel connections of nix, e.g. lower on slow links
+    #[serde(default)]
+    pub nix_http_connections: Option<u64>,
+    /// Keep transaction sandboxes in the cache dir for debugging, also set by `FLOX_KEEP_SANDBOXES`
+    #[serde(default)]
+    pub keep_sandboxes: bool,
+    /// Flakeref of a nixpkgs all channels are evaluated against when resolving and searching
+    /// packages
+    #[serde(default)]
+    pub pinned_nixpkgs: Option<String>,
+    /// Treat `devShells` of flakes without `floxEnvs` as (limited) environments
+    #[serde(default)]
+    pub compat_devshells: bool,
+    /// Modes of the config dir, its files and files copied into projects
+    #[serde(default)]
+    pub permissions: PermissionsPolicy,
 
     pub default_substituter: String, // Todo: use Url type?
 
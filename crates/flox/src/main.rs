@@ -211,7 +211,7 @@ pub async fn run_in_flox(
     debug!("Running in flox with arguments: {:?}", args);
 
     if let Some(flox) = flox {
-        sync_bash_metrics_consent(&flox.data_dir, &flox.cache_dir).await?;
+        sync_bash_metrics_consent(flox.data_dir(), flox.cache_dir()).await?;
     }
 
     let status = Command::new(FLOX_SH)
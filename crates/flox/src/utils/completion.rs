@@ -1,8 +1,11 @@
 use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 
 use anyhow::{bail, Result};
 use async_trait::async_trait;
-use flox_rust_sdk::flox::{Flox, FloxInstallable};
+use flox_rust_sdk::flox::{Flox, FloxBuilder, FloxInstallable};
+use flox_rust_sdk::models::flake_ref::ToFlakeRef;
+use flox_rust_sdk::models::system::System;
 use flox_rust_sdk::providers::git::GitCommandProvider;
 use log::debug;
 use tempfile::TempDir;
@@ -64,17 +67,35 @@ impl FloxCompletionExt for Flox {
             .expect("User must have a home directory")
             .join(".netrc");
 
-        Ok(Flox {
-            cache_dir: config.flox.cache_dir,
-            data_dir: config.flox.data_dir,
-            config_dir: config.flox.config_dir,
-            channels,
-            temp_dir: temp_dir.into_path(),
-            system: env!("NIX_TARGET_SYSTEM").to_string(),
-            netrc_file,
-            access_tokens,
-            uuid: uuid::Uuid::nil(),
-        })
+        Ok(FloxBuilder::new()
+            .cache_dir(config.flox.cache_dir)
+            .data_dir(config.flox.data_dir)
+            .config_dir(config.flox.config_dir)
+            .channels(channels)
+            .temp_dir(temp_dir.into_path())
+            .system(System::parse_or_unknown(env!("NIX_TARGET_SYSTEM")))
+            .netrc_file(netrc_file)
+            .access_tokens(access_tokens)
+            .reject_legacy_layout(config.flox.reject_legacy_layout)
+            .flox_nix_names(config.flox.flox_nix_names)
+            .max_parallel_builds(config.flox.max_parallel_builds)
+            .audit(config.flox.audit)
+            .env_interpolation(config.flox.env_interpolation)
+            .install_policy(config.flox.install_policy.clone().into_install_policy())
+            .nix_connect_timeout(config.flox.nix_connect_timeout)
+            .nix_http_connections(config.flox.nix_http_connections)
+            .keep_sandboxes(config.flox.keep_sandboxes)
+            .pinned_nixpkgs(
+                config
+                    .flox
+                    .pinned_nixpkgs
+                    .as_deref()
+                    .map(ToFlakeRef::from_str)
+                    .transpose()?,
+            )
+            .compat_devshells(config.flox.compat_devshells)
+            .permissions(config.flox.permissions)
+            .build()?)
     }
 
     async fn complete_installable(
@@ -51,32 +51,33 @@ pub fn init_logger(verbosity: Option<Verbosity>, debug: Option<bool>) {
     let verbosity = verbosity.unwrap_or_default();
     let debug = debug.unwrap_or(false);
 
+    // the SDK's logs follow the verbosity it passes on to nix
+    let sdk = verbosity.sdk(debug).log_directive();
+
     let log_filter = match (debug, verbosity) {
         // Show only errors
-        (false, Verbosity::Quiet) => "off,flox=error",
+        (false, Verbosity::Quiet) => format!("off,flox=error,{sdk}"),
         // Show our own info logs
-        (false, Verbosity::Verbose(0)) => "off,flox=info",
+        (false, Verbosity::Verbose(0)) => format!("off,flox=info,{sdk}"),
         // Also show POSIX info
-        (false, Verbosity::Verbose(1)) => "off,flox=info,posix=info",
+        (false, Verbosity::Verbose(1)) => format!("off,flox=info,{sdk},posix=info"),
         // Also show info from our libraries and POSIX debug
-        (false, Verbosity::Verbose(2)) => {
-            "off,flox=debug,flox-rust-sdk=info,runix=info,posix=debug"
-        },
+        (false, Verbosity::Verbose(2)) => format!("off,flox=debug,{sdk},runix=info,posix=debug"),
         // Also show debug from our libraries
         (true, Verbosity::Quiet) | (false, Verbosity::Verbose(3)) => {
-            "off,flox=debug,flox-rust-sdk=debug,runix=debug,posix=debug"
+            "off,flox=debug,flox_rust_sdk=debug,runix=debug,posix=debug".to_string()
         },
         // Also show debug from everything
-        (true, Verbosity::Verbose(0)) | (false, Verbosity::Verbose(4)) => "debug",
+        (true, Verbosity::Verbose(0)) | (false, Verbosity::Verbose(4)) => "debug".to_string(),
         // Also show trace from everything
-        (true, Verbosity::Verbose(_)) | (false, Verbosity::Verbose(_)) => "trace",
+        (true, Verbosity::Verbose(_)) | (false, Verbosity::Verbose(_)) => "trace".to_string(),
     };
 
     let (filter_handle, fmt_handle) = LOGGER_HANDLE.get_or_init(|| {
         debug!("Initializing logger (how are you seeing this?)");
 
         let filter = tracing_subscriber::filter::EnvFilter::try_from_default_env()
-            .or_else(|_| tracing_subscriber::filter::EnvFilter::try_new(log_filter))
+            .or_else(|_| tracing_subscriber::filter::EnvFilter::try_new(&log_filter))
             .unwrap();
         let (filter_reloadable, filter_reload_handle) =
             tracing_subscriber::reload::Layer::new(filter);
@@ -99,7 +100,7 @@ pub fn init_logger(verbosity: Option<Verbosity>, debug: Option<bool>) {
 
     if let Err(err) = filter_handle.modify(|layer| {
         *layer = tracing_subscriber::filter::EnvFilter::try_from_default_env()
-            .or_else(|_| tracing_subscriber::filter::EnvFilter::try_new(log_filter))
+            .or_else(|_| tracing_subscriber::filter::EnvFilter::try_new(&log_filter))
             .unwrap();
     }) {
         error!("Updating logger filter failed: {}", err);
@@ -8,7 +8,7 @@ use std::str::FromStr;
 use anyhow::{anyhow, bail, Context, Result};
 use bpaf::Parser;
 use flox_rust_sdk::flox::{EnvironmentRef, Flox, FloxInstallable, ResolvedInstallableMatch};
-use flox_rust_sdk::prelude::{Channel, ChannelRegistry, Installable};
+use flox_rust_sdk::prelude::{Channel, ChannelRegistry, Installable, Stability};
 use flox_rust_sdk::providers::git::{GitCommandProvider, GitProvider};
 use indoc::indoc;
 use itertools::Itertools;
@@ -226,9 +226,22 @@ impl<Matching: InstallableDef + 'static> InstallableArgument<Parsed, Matching> {
     }
 
     /// called at runtime to extract single installable from CLI input
-    pub async fn resolve_installable(&self, flox: &Flox) -> Result<Installable> {
+    ///
+    /// Package names without a match in the default flakes, e.g. `hello`,
+    /// are resolved at `stability` with the package resolver of flox.
+    pub async fn resolve_installable(
+        &self,
+        flox: &Flox,
+        stability: &Stability,
+    ) -> Result<Installable> {
         let drv = InstallableKind::any(Matching::DERIVATION_TYPES).unwrap();
-        let matches = self.resolve_matches(flox).await?;
+        let mut matches = self.resolve_matches(flox).await?;
+
+        if matches.is_empty() && Matching::DERIVATION_TYPES.contains(&InstallableKind::package()) {
+            if let (None, [name]) = (&self.installable.source, &self.installable.attr_path[..]) {
+                matches = flox.resolve_package(name, stability).await?;
+            }
+        }
 
         resolve_installable_from_matches(
             Matching::SUBCOMMAND,